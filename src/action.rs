@@ -1,22 +1,502 @@
 //! Filesystem operations to stage files.
 
+use std::borrow;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
 use std::fmt;
 use std::fs;
+use std::io;
+use std::io::{Read, Write};
 use std::path;
+#[cfg(any(feature = "shell-hooks", feature = "exec"))]
+use std::process;
+use std::str;
+use std::sync;
+use std::thread;
+use std::time;
+
+#[cfg(feature = "tar")]
+use bzip2;
+#[cfg(feature = "tar")]
+use flate2;
+#[cfg(all(feature = "de", feature = "serde_json"))]
+use humantime;
+#[cfg(all(feature = "de", feature = "serde_json"))]
+use serde_json;
+use sha2;
+#[cfg(feature = "tar")]
+use tar;
+use sha2::Digest;
+#[cfg(feature = "tar")]
+use walkdir;
+#[cfg(feature = "tar")]
+use xz2;
 
 use error;
+use filetime;
 
 // `Display` is required for dry-runs / previews.
 /// Operation for setting up staged directory tree.
 pub trait Action: fmt::Display + fmt::Debug {
     /// Execute the current action, writing to the stage.
-    fn perform(&self) -> Result<(), error::StagingError>;
+    fn perform(&self, options: &PerformOptions) -> Result<(), error::StagingError>;
+
+    /// The path within the stage that `perform` writes to, if any.
+    fn affects_path(&self) -> Option<&path::Path> {
+        None
+    }
+
+    /// Paths outside of the stage that `perform` reads, for dependency tracking.
+    fn source_paths(&self) -> Vec<&path::Path> {
+        vec![]
+    }
+
+    /// Approximate number of bytes `perform` will write, for progress reporting.
+    fn estimated_byte_count(&self) -> Option<u64> {
+        None
+    }
+
+    /// Structured summary of the action, for introspection.
+    ///
+    /// Defaults to `ActionKind::Other`, with the action's `Display` under the `display` extra.
+    fn describe(&self) -> ActionDescription {
+        let destination = self.affects_path()
+            .map(|p| p.to_owned())
+            .unwrap_or_default();
+        ActionDescription::new(ActionKind::Other, destination).extra("display", self.to_string())
+    }
+}
+
+/// Settings for a whole staging run, passed to each `Action::perform`.
+///
+/// Settings left unset keep each action's own configuration.
+#[derive(Clone, Debug, Default)]
+pub struct PerformOptions {
+    pub(crate) rate_limit_bps: Option<u64>,
+    pub(crate) buffer_size: Option<usize>,
+    pub(crate) on_conflict: Option<ConflictPolicy>,
+    pub(crate) temp_dir: Option<path::PathBuf>,
+}
+
+impl PerformOptions {
+    /// Settings that keep each action's own configuration.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Limit how fast files are copied, in bytes per second, overriding `CopyFile::rate_limit`.
+    pub fn rate_limit(mut self, bps: Option<u64>) -> Self {
+        self.rate_limit_bps = bps;
+        self
+    }
+
+    /// Copy files through a buffer of `size` bytes, overriding `CopyFile::buffer_size`.
+    pub fn buffer_size(mut self, size: Option<usize>) -> Self {
+        self.buffer_size = size;
+        self
+    }
+
+    /// What to do when a copied file already exists, overriding `CopyFile::on_conflict`.
+    pub fn on_conflict(mut self, policy: Option<ConflictPolicy>) -> Self {
+        self.on_conflict = policy;
+        self
+    }
+
+    /// Write copied files in `dir` first, moving each into the stage once it is complete.
+    ///
+    /// Programs run on staged files are pointed at `dir` for their own temporary files.
+    pub fn temp_dir(mut self, dir: Option<path::PathBuf>) -> Self {
+        self.temp_dir = dir;
+        self
+    }
+}
+
+/// Category of `Action`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "de", derive(Serialize, Deserialize))]
+pub enum ActionKind {
+    /// Copies a file into the stage.
+    CopyFile,
+    /// Creates a directory in the stage.
+    CreateDirectory,
+    /// Creates a symbolic link in the stage.
+    Symlink,
+    /// Creates a hard link in the stage.
+    HardLink,
+    /// Changes permissions of a staged file.
+    SetPermissions,
+    /// Writes generated content into the stage.
+    WriteContent,
+    /// Action not covered by the other kinds.
+    Other,
+}
+
+/// Structured summary of an `Action`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "de", derive(Serialize, Deserialize))]
+pub struct ActionDescription {
+    /// Category of action.
+    pub kind: ActionKind,
+    /// Path read by the action, if any.
+    pub source: Option<path::PathBuf>,
+    /// Path written by the action.
+    pub destination: path::PathBuf,
+    /// Kind-specific details.
+    pub extra: BTreeMap<String, String>,
+}
+
+impl ActionDescription {
+    /// Describe an action of `kind` that writes to `destination`.
+    pub fn new<P>(kind: ActionKind, destination: P) -> Self
+    where
+        P: Into<path::PathBuf>,
+    {
+        Self {
+            kind,
+            source: None,
+            destination: destination.into(),
+            extra: Default::default(),
+        }
+    }
+
+    /// Specifies the path read by the action.
+    pub fn source<P>(mut self, source: Option<P>) -> Self
+    where
+        P: Into<path::PathBuf>,
+    {
+        self.source = source.map(|s| s.into());
+        self
+    }
+
+    /// Adds a kind-specific detail.
+    pub fn extra<K, V>(mut self, key: K, value: V) -> Self
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.extra.insert(key.into(), value.into());
+        self
+    }
+
+    /// Compare the stage against what the described action would write, without modifying it.
+    ///
+    /// Only copies without a transform, directories, links, permissions, and inline content can
+    /// be compared.  Other actions are `Change::Unknown`.
+    pub fn change(&self) -> Result<Change, error::StagingError> {
+        let staged = &self.destination;
+        match self.kind {
+            ActionKind::CopyFile if !self.extra.contains_key("transform") => {
+                let source = match self.source {
+                    Some(ref source) => source,
+                    None => return Ok(Change::Unknown),
+                };
+                file_change(staged, || {
+                    fs::read(source)
+                        .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))
+                })
+            }
+            ActionKind::CreateDirectory => {
+                let change = match fs::symlink_metadata(staged) {
+                    Ok(ref metadata) if metadata.is_dir() => Change::Unchanged,
+                    Ok(_) => Change::Modified,
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => Change::Added,
+                    Err(e) => return Err(error::ErrorKind::StagingFailed.error().set_cause(e)),
+                };
+                Ok(change)
+            }
+            ActionKind::Symlink => {
+                let target = match self.extra.get("target") {
+                    Some(target) => path::Path::new(target),
+                    None => return Ok(Change::Unknown),
+                };
+                let change = match fs::read_link(staged) {
+                    Ok(ref existing) if existing == target => Change::Unchanged,
+                    Ok(_) => Change::Modified,
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => Change::Added,
+                    // Exists but isn't a symlink.
+                    Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => Change::Modified,
+                    Err(e) => return Err(error::ErrorKind::StagingFailed.error().set_cause(e)),
+                };
+                Ok(change)
+            }
+            ActionKind::HardLink => {
+                let target = match self.extra.get("target") {
+                    Some(target) => path::Path::new(target),
+                    None => return Ok(Change::Unknown),
+                };
+                let metadata = match fs::symlink_metadata(staged) {
+                    Ok(metadata) => metadata,
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Change::Added),
+                    Err(e) => return Err(error::ErrorKind::StagingFailed.error().set_cause(e)),
+                };
+                let change = match fs::metadata(target) {
+                    Ok(ref target) if same_file(&metadata, target) => Change::Unchanged,
+                    _ => Change::Modified,
+                };
+                Ok(change)
+            }
+            ActionKind::SetPermissions => {
+                match self.extra.get("mode").and_then(|m| u32::from_str_radix(m, 8).ok()) {
+                    Some(mode) => mode_change(staged, mode),
+                    None => Ok(Change::Unknown),
+                }
+            }
+            ActionKind::WriteContent => {
+                let expected = match self.extra.get("sha256") {
+                    Some(expected) => expected,
+                    None => return Ok(Change::Unknown),
+                };
+                let change = match fs::symlink_metadata(staged) {
+                    Ok(ref metadata) if metadata.is_file() => {
+                        if sha256(staged)? == *expected {
+                            Change::Unchanged
+                        } else {
+                            Change::Modified
+                        }
+                    }
+                    Ok(_) => Change::Modified,
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => Change::Added,
+                    Err(e) => return Err(error::ErrorKind::StagingFailed.error().set_cause(e)),
+                };
+                Ok(change)
+            }
+            _ => Ok(Change::Unknown),
+        }
+    }
+}
+
+/// How performing an `Action` would change the stage.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// The stage already matches.
+    Unchanged,
+    /// The staged path will be created.
+    Added,
+    /// The staged path will be overwritten.
+    Modified,
+    /// The action cannot compare against the stage.
+    Unknown,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let change = match *self {
+            Change::Unchanged => "unchanged",
+            Change::Added => "added",
+            Change::Modified => "modified",
+            Change::Unknown => "unknown",
+        };
+        f.pad(change)
+    }
+}
+
+/// Find pairs of actions that write the same path, as indexes into `actions`.
+///
+/// Only actions that create content (copies, links, and generated content) are considered, so
+/// directories and permission changes on an already staged path are not conflicts.  Each later
+/// writer is paired with the first.
+pub fn detect_conflicts(actions: &[Box<Action>]) -> Vec<(usize, usize)> {
+    let mut writers: HashMap<&path::Path, usize> = HashMap::new();
+    let mut conflicts = Vec::new();
+    for (index, action) in actions.iter().enumerate() {
+        let path = match action.describe().kind {
+            ActionKind::CopyFile
+            | ActionKind::Symlink
+            | ActionKind::HardLink
+            | ActionKind::WriteContent => action.affects_path(),
+            _ => None,
+        };
+        let path = match path {
+            Some(path) => path,
+            None => continue,
+        };
+        match writers.get(path) {
+            Some(&first) => conflicts.push((first, index)),
+            None => {
+                writers.insert(path, index);
+            }
+        }
+    }
+    conflicts
+}
+
+/// Write the planned actions to `path` as JSON, to later resume an interrupted run.
+#[cfg(all(feature = "de", feature = "serde_json"))]
+pub fn save_plan(
+    actions: &[ActionDescription],
+    path: &path::Path,
+) -> Result<(), error::StagingError> {
+    let file =
+        fs::File::create(path).map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+    serde_json::to_writer_pretty(file, actions).map_err(|e| {
+        error::ErrorKind::StagingFailed
+            .error()
+            .set_context(format!("Failed writing plan {:?}", path))
+            .set_cause(e)
+    })
+}
+
+/// Read planned actions written by `save_plan`.
+#[cfg(all(feature = "de", feature = "serde_json"))]
+pub fn load_plan(path: &path::Path) -> Result<Vec<ActionDescription>, error::StagingError> {
+    let file =
+        fs::File::open(path).map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+    serde_json::from_reader(file).map_err(|e| {
+        error::ErrorKind::InvalidConfiguration
+            .error()
+            .set_context(format!("Failed reading plan {:?}", path))
+            .set_cause(e)
+    })
+}
+
+/// Record of performed actions, written as newline-delimited JSON.
+///
+/// Each entry is flushed as it is logged so the log is complete up to any failure.
+#[cfg(all(feature = "de", feature = "serde_json"))]
+#[derive(Debug)]
+pub struct AuditLog {
+    path: path::PathBuf,
+    writer: sync::Mutex<io::BufWriter<fs::File>>,
+}
+
+#[cfg(all(feature = "de", feature = "serde_json"))]
+impl AuditLog {
+    /// Append audit entries to `path`, creating it if needed.
+    pub fn new<P>(path: P) -> Result<Self, error::StagingError>
+    where
+        P: Into<path::PathBuf>,
+    {
+        let path = path.into();
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        let writer = sync::Mutex::new(io::BufWriter::new(file));
+        Ok(Self { path, writer })
+    }
+
+    /// Record that the action described by `desc` finished just now, taking `duration`.
+    pub fn log_action(
+        &self,
+        desc: &ActionDescription,
+        duration: time::Duration,
+        result: &Result<(), error::StagingError>,
+    ) -> Result<(), error::StagingError> {
+        let end = time::SystemTime::now();
+        let start = end - duration;
+        let entry = AuditEntry {
+            action: desc,
+            start: humantime::format_rfc3339(start).to_string(),
+            end: humantime::format_rfc3339(end).to_string(),
+            success: result.is_ok(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        };
+
+        let mut writer = self.writer.lock().map_err(|_| {
+            error::ErrorKind::StagingFailed
+                .error()
+                .set_context(format!("Audit log {:?} is poisoned", self.path))
+        })?;
+        serde_json::to_writer(&mut *writer, &entry).map_err(|e| {
+            error::ErrorKind::StagingFailed
+                .error()
+                .set_context(format!("Failed writing audit log {:?}", self.path))
+                .set_cause(e)
+        })?;
+        writer
+            .write_all(b"\n")
+            .and_then(|_| writer.flush())
+            .map_err(|e| {
+                error::ErrorKind::StagingFailed
+                    .error()
+                    .set_context(format!("Failed writing audit log {:?}", self.path))
+                    .set_cause(e)
+            })?;
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "de", feature = "serde_json"))]
+#[derive(Serialize)]
+struct AuditEntry<'a> {
+    #[serde(flatten)]
+    action: &'a ActionDescription,
+    start: String,
+    end: String,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[cfg(unix)]
+fn set_mode(staged: &path::Path, mode: u32) -> Result<(), error::StagingError> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(staged, fs::Permissions::from_mode(mode))
+        .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))
+}
+
+#[cfg(not(unix))]
+fn set_mode(staged: &path::Path, mode: u32) -> Result<(), error::StagingError> {
+    warn!("Ignoring mode {:o} for {:?}: unsupported platform", mode, staged);
+    Ok(())
+}
+
+#[cfg(unix)]
+fn mode_change(staged: &path::Path, mode: u32) -> Result<Change, error::StagingError> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let change = match fs::metadata(staged) {
+        Ok(ref metadata) if metadata.permissions().mode() & 0o7777 == mode => Change::Unchanged,
+        Ok(_) => Change::Modified,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Change::Added,
+        Err(e) => return Err(error::ErrorKind::StagingFailed.error().set_cause(e)),
+    };
+    Ok(change)
+}
+
+#[cfg(not(unix))]
+fn mode_change(_staged: &path::Path, _mode: u32) -> Result<Change, error::StagingError> {
+    Ok(Change::Unknown)
+}
+
+fn file_change<F>(staged: &path::Path, expected: F) -> Result<Change, error::StagingError>
+where
+    F: FnOnce() -> Result<Vec<u8>, error::StagingError>,
+{
+    let metadata = match fs::symlink_metadata(staged) {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Change::Added),
+        Err(e) => return Err(error::ErrorKind::StagingFailed.error().set_cause(e)),
+    };
+    if !metadata.is_file() {
+        return Ok(Change::Modified);
+    }
+
+    let expected = expected()?;
+    if metadata.len() != expected.len() as u64 {
+        return Ok(Change::Modified);
+    }
+    let actual =
+        fs::read(staged).map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+    if actual == expected {
+        Ok(Change::Unchanged)
+    } else {
+        Ok(Change::Modified)
+    }
 }
 
 /// Specifies a staged directory to be created.
+///
+/// The directory already existing is not an error, unless `must_be_empty` is set and it has
+/// contents.
 #[derive(Clone, Debug)]
 pub struct CreateDirectory {
     staged: path::PathBuf,
+    mode: Option<u32>,
+    must_be_empty: bool,
 }
 
 impl CreateDirectory {
@@ -29,30 +509,109 @@ impl CreateDirectory {
     {
         Self {
             staged: staged.into(),
+            mode: None,
+            must_be_empty: false,
         }
     }
+
+    /// Fail if the directory already exists and is not empty, to guarantee a clean stage.
+    ///
+    /// Combine with `--clean` to remove stale files first.
+    pub fn must_be_empty(mut self, yes: bool) -> Self {
+        self.must_be_empty = yes;
+        self
+    }
+
+    /// Specifies the unix permissions for the directory.  Default is to follow the umask.
+    ///
+    /// Only the leaf directory is given `mode`; any missing parents are created following the
+    /// umask.  This is ignored on non-unix platforms.
+    pub fn mode(mut self, mode: Option<u32>) -> Self {
+        self.mode = mode;
+        self
+    }
 }
 
 impl fmt::Display for CreateDirectory {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "mkdir {:?}", self.staged)
+        match self.mode {
+            Some(mode) => write!(f, "mkdir -m {:o} {:?}", mode, self.staged),
+            None => write!(f, "mkdir {:?}", self.staged),
+        }
     }
 }
 
 impl Action for CreateDirectory {
-    fn perform(&self) -> Result<(), error::StagingError> {
+    fn perform(&self, _options: &PerformOptions) -> Result<(), error::StagingError> {
+        if self.must_be_empty {
+            match fs::read_dir(&self.staged) {
+                Ok(mut entries) => {
+                    if entries.next().is_some() {
+                        return Err(error::ErrorKind::StagingFailed
+                            .error()
+                            .set_context(format!("{:?} is not empty", self.staged)));
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(error::ErrorKind::StagingFailed.error().set_cause(e)),
+            }
+        }
         fs::create_dir_all(&self.staged)
             .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        if let Some(mode) = self.mode {
+            set_mode(&self.staged, mode)?;
+        }
 
         Ok(())
     }
+
+    fn affects_path(&self) -> Option<&path::Path> {
+        Some(&self.staged)
+    }
+
+    fn describe(&self) -> ActionDescription {
+        let description = ActionDescription::new(ActionKind::CreateDirectory, &self.staged);
+        match self.mode {
+            Some(mode) => description.extra("mode", format!("{:o}", mode)),
+            None => description,
+        }
+    }
+}
+
+/// Checks a source file before it is copied, e.g. `require_min_size`.
+#[cfg(feature = "validators")]
+pub type Validator = Fn(&path::Path) -> Result<(), error::StagingError> + Send + Sync;
+
+/// What to do when a file is staged over one that already exists.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// Replace the existing file.
+    #[default]
+    Overwrite,
+    /// Leave the existing file alone.
+    Skip,
+    /// Fail with `StagingFailed`, leaving the existing file alone.
+    Error,
+    /// Replace the existing file only if its content differs.
+    Verify,
 }
 
 /// Specifies a file to be staged into the target directory.
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct CopyFile {
     staged: path::PathBuf,
     source: path::PathBuf,
+    rate_limit_bps: Option<u64>,
+    buffer_size: Option<usize>,
+    on_conflict: ConflictPolicy,
+    transform: Option<String>,
+    transform_registry: Option<sync::Arc<TransformRegistry>>,
+    #[cfg(feature = "xattr")]
+    preserve_xattrs: bool,
+    #[cfg(feature = "reflink")]
+    prefer_reflink: bool,
+    #[cfg(feature = "validators")]
+    validators: Vec<sync::Arc<Validator>>,
 }
 
 impl CopyFile {
@@ -68,69 +627,2512 @@ impl CopyFile {
         Self {
             staged: staged.into(),
             source: source.into(),
+            rate_limit_bps: None,
+            buffer_size: None,
+            on_conflict: ConflictPolicy::default(),
+            transform: None,
+            transform_registry: None,
+            #[cfg(feature = "xattr")]
+            preserve_xattrs: false,
+            #[cfg(feature = "reflink")]
+            prefer_reflink: false,
+            #[cfg(feature = "validators")]
+            validators: Vec::new(),
+        }
+    }
+
+    /// Limit how fast the file is copied, in bytes per second.
+    pub fn rate_limit(mut self, bps: Option<u64>) -> Self {
+        self.rate_limit_bps = bps;
+        self
+    }
+
+    /// Copy through a buffer of `size` bytes, for tuning I/O to a filesystem or device.  Default
+    /// is `fs::copy`'s.
+    ///
+    /// `rate_limit` and `transform` take precedence.
+    pub fn buffer_size(mut self, size: Option<usize>) -> Self {
+        self.buffer_size = size;
+        self
+    }
+
+    /// What to do if `staged` already exists.
+    pub fn on_conflict(mut self, policy: ConflictPolicy) -> Self {
+        self.on_conflict = policy;
+        self
+    }
+
+    /// Rewrite the content with the transform registered as `name` in the
+    /// `TransformRegistry::global()` while copying.
+    ///
+    /// The whole file is read into memory, and `rate_limit` is ignored.
+    pub fn transform(mut self, name: Option<String>) -> Self {
+        self.transform = name;
+        self
+    }
+
+    /// Look up `transform` in `registry` rather than `TransformRegistry::global()`.
+    pub fn transform_registry(mut self, registry: sync::Arc<TransformRegistry>) -> Self {
+        self.transform_registry = Some(registry);
+        self
+    }
+
+    /// When true, extended attributes (like SELinux labels and capabilities) are copied too.
+    #[cfg(feature = "xattr")]
+    pub fn preserve_xattrs(mut self, yes: bool) -> Self {
+        self.preserve_xattrs = yes;
+        self
+    }
+
+    /// When true, share the source's data on copy-on-write filesystems (like btrfs and XFS)
+    /// rather than copying it, falling back to a copy where that isn't supported.
+    ///
+    /// Only Linux is supported, and `rate_limit` and `transform` take precedence.
+    #[cfg(feature = "reflink")]
+    pub fn prefer_reflink(mut self, yes: bool) -> Self {
+        self.prefer_reflink = yes;
+        self
+    }
+
+    /// Check the source file with `validator` before copying it, failing the copy on error.
+    ///
+    /// Validators run in the order they were added.
+    #[cfg(feature = "validators")]
+    pub fn add_validator<F>(mut self, validator: F) -> Self
+    where
+        F: Fn(&path::Path) -> Result<(), error::StagingError> + Send + Sync + 'static,
+    {
+        self.validators.push(sync::Arc::new(validator));
+        self
+    }
+
+    fn validate(&self) -> Result<(), error::StagingError> {
+        #[cfg(feature = "validators")]
+        {
+            for validator in &self.validators {
+                validator(&self.source)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn copy(&self, dest: &path::Path, options: &PerformOptions) -> io::Result<()> {
+        #[cfg(all(target_os = "linux", feature = "reflink"))]
+        {
+            if self.prefer_reflink && copy_reflink(&self.source, dest)? {
+                return Ok(());
+            }
+        }
+        match options.buffer_size.or(self.buffer_size) {
+            Some(size) => copy_buffered(&self.source, dest, size),
+            None => copy_file(&self.source, dest),
         }
     }
+
+    /// The transformed content, if there is a `transform`.
+    fn transformed(&self) -> Result<Option<Vec<u8>>, error::StagingError> {
+        let name = match self.transform {
+            Some(ref name) => name,
+            None => return Ok(None),
+        };
+        let registry = match self.transform_registry {
+            Some(ref registry) => registry.as_ref(),
+            None => TransformRegistry::global(),
+        };
+        let transform = registry.get(name).ok_or_else(|| {
+            error::ErrorKind::StagingFailed
+                .error()
+                .set_context(format!("Unknown transform {:?}", name))
+        })?;
+        let content = fs::read(&self.source)
+            .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        transform(&content).map(Some)
+    }
+}
+
+impl fmt::Debug for CopyFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut debug = f.debug_struct("CopyFile");
+        debug
+            .field("staged", &self.staged)
+            .field("source", &self.source)
+            .field("rate_limit_bps", &self.rate_limit_bps)
+            .field("buffer_size", &self.buffer_size)
+            .field("on_conflict", &self.on_conflict)
+            .field("transform", &self.transform)
+            .field("transform_registry", &self.transform_registry);
+        #[cfg(feature = "xattr")]
+        debug.field("preserve_xattrs", &self.preserve_xattrs);
+        #[cfg(feature = "reflink")]
+        debug.field("prefer_reflink", &self.prefer_reflink);
+        #[cfg(feature = "validators")]
+        debug.field("validators", &self.validators.len());
+        debug.finish()
+    }
 }
 
 impl fmt::Display for CopyFile {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "cp {:?} {:?}", self.source, self.staged)
+        match self.transform {
+            Some(ref name) => write!(
+                f,
+                "cp --transform {} {:?} {:?}",
+                name, self.source, self.staged
+            ),
+            None => write!(f, "cp {:?} {:?}", self.source, self.staged),
+        }
     }
 }
 
 impl Action for CopyFile {
-    fn perform(&self) -> Result<(), error::StagingError> {
+    fn perform(&self, options: &PerformOptions) -> Result<(), error::StagingError> {
+        if self.source.is_dir() {
+            return Err(error::ErrorKind::StagingFailed.error().set_context(format!(
+                "Source is a directory; use SourceFiles with a glob pattern instead: {:?}",
+                self.source
+            )));
+        }
+        self.validate()?;
+        if self.staged.exists() {
+            let overwrite = match options.on_conflict.unwrap_or(self.on_conflict) {
+                // Compare against what would be written, not the source.
+                ConflictPolicy::Verify if self.transform.is_some() => {
+                    let change = file_change(&self.staged, || {
+                        self.transformed().map(|content| content.unwrap_or_default())
+                    })?;
+                    change != Change::Unchanged
+                }
+                policy => should_overwrite(&self.staged, &self.source, policy)?,
+            };
+            if !overwrite {
+                debug!("Keeping existing {:?}", self.staged);
+                return Ok(());
+            }
+        }
         if let Some(parent) = self.staged.parent() {
             fs::create_dir_all(parent)
                 .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
         }
-        fs::copy(&self.source, &self.staged)
-            .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        let transformed = self.transformed()?;
+        let rate_limit_bps = options.rate_limit_bps.or(self.rate_limit_bps);
+        write_via_temp(&self.staged, options.temp_dir.as_ref(), |dest| {
+            match (transformed, rate_limit_bps) {
+                (Some(content), _) => fs::write(dest, content),
+                (None, Some(bps)) => copy_throttled(&self.source, dest, bps),
+                (None, None) => self.copy(dest, options),
+            }
+        }).map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        #[cfg(feature = "xattr")]
+        {
+            if self.preserve_xattrs {
+                copy_xattrs(&self.source, &self.staged).map_err(|e| {
+                    error::ErrorKind::StagingFailed
+                        .error()
+                        .set_context(format!(
+                            "Failed copying extended attributes to {:?}",
+                            self.staged
+                        ))
+                        .set_cause(e)
+                })?;
+            }
+        }
 
         Ok(())
     }
+
+    fn affects_path(&self) -> Option<&path::Path> {
+        Some(&self.staged)
+    }
+
+    fn source_paths(&self) -> Vec<&path::Path> {
+        vec![&self.source]
+    }
+
+    fn estimated_byte_count(&self) -> Option<u64> {
+        self.source.metadata().map(|m| m.len()).ok()
+    }
+
+    fn describe(&self) -> ActionDescription {
+        let description =
+            ActionDescription::new(ActionKind::CopyFile, &self.staged).source(Some(&self.source));
+        match self.transform {
+            Some(ref name) => description.extra("transform", name.as_str()),
+            None => description,
+        }
+    }
 }
 
-/// Specifies a symbolic link file to be staged into the target directory.
-#[derive(Clone, Debug)]
-pub struct Symlink {
-    staged: path::PathBuf,
-    target: path::PathBuf,
+/// Rewrites file content for `CopyFile::transform`, e.g. stripping comments.
+pub type ContentTransform = Fn(&[u8]) -> Result<Vec<u8>, error::StagingError> + Send + Sync;
+
+/// Named `ContentTransform`s, so configuration can refer to them with `CopyFile::transform`.
+///
+/// Applications register their transforms with the `global()` registry at startup.
+#[derive(Default)]
+pub struct TransformRegistry {
+    transforms: sync::RwLock<HashMap<String, sync::Arc<ContentTransform>>>,
 }
 
-impl Symlink {
-    /// Specifies a symbolic link file to be staged into the target directory.
-    ///
-    /// - `staged`: full path for future symlink.
-    /// - `target`: path that symlink will point to.
-    pub fn new<S, T>(staged: S, target: T) -> Self
+impl TransformRegistry {
+    /// The registry used by `CopyFile`, unless given another with `CopyFile::transform_registry`.
+    pub fn global() -> &'static TransformRegistry {
+        lazy_static! {
+            static ref GLOBAL: TransformRegistry = Default::default();
+        }
+        &GLOBAL
+    }
+
+    /// An empty registry, for passing to `CopyFile::transform_registry`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Make `transform` available as `name`, replacing any transform already registered as it.
+    pub fn register<N, F>(&self, name: N, transform: F)
     where
-        S: Into<path::PathBuf>,
-        T: Into<path::PathBuf>,
+        N: Into<String>,
+        F: Fn(&[u8]) -> Result<Vec<u8>, error::StagingError> + Send + Sync + 'static,
     {
-        Self {
-            staged: staged.into(),
-            target: target.into(),
-        }
+        self.transforms
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(name.into(), sync::Arc::new(transform));
+    }
+
+    /// The transform registered as `name`.
+    pub fn get(&self, name: &str) -> Option<sync::Arc<ContentTransform>> {
+        self.transforms
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(name)
+            .cloned()
     }
 }
 
-impl fmt::Display for Symlink {
+impl fmt::Debug for TransformRegistry {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "ln -s {:?} {:?}", self.target, self.staged)
+        let transforms = self.transforms.read().unwrap_or_else(|e| e.into_inner());
+        let mut names: Vec<_> = transforms.keys().collect();
+        names.sort();
+        f.debug_struct("TransformRegistry")
+            .field("transforms", &names)
+            .finish()
     }
 }
 
-impl Action for Symlink {
-    fn perform(&self) -> Result<(), error::StagingError> {
-        if let Some(parent) = self.staged.parent() {
-            fs::create_dir_all(parent)
-                .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+/// Validator requiring the source file to be at least `min` bytes.
+#[cfg(feature = "validators")]
+pub fn require_min_size(
+    min: u64,
+) -> impl Fn(&path::Path) -> Result<(), error::StagingError> + Send + Sync {
+    move |source| {
+        let len = source
+            .metadata()
+            .map_err(|e| validation_error(source).set_cause(e))?
+            .len();
+        if len < min {
+            return Err(validation_error(source).set_context(format!(
+                "{:?} is {} bytes, expected at least {}",
+                source, len, min
+            )));
         }
-        #[allow(deprecated)]
-        fs::soft_link(&self.staged, &self.target)
-            .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        Ok(())
+    }
+}
 
+/// Validator requiring the source file to be an ELF binary.
+#[cfg(feature = "validators")]
+pub fn require_elf() -> impl Fn(&path::Path) -> Result<(), error::StagingError> + Send + Sync {
+    |source| {
+        let mut magic = [0; 4];
+        fs::File::open(source)
+            .and_then(|mut f| f.read_exact(&mut magic))
+            .map_err(|e| validation_error(source).set_cause(e))?;
+        if &magic != b"\x7fELF" {
+            return Err(validation_error(source)
+                .set_context(format!("{:?} is not an ELF binary", source)));
+        }
         Ok(())
     }
 }
+
+/// Validator requiring the source file to be text, containing no NUL bytes.
+#[cfg(feature = "validators")]
+pub fn require_text() -> impl Fn(&path::Path) -> Result<(), error::StagingError> + Send + Sync {
+    |source| {
+        let mut file =
+            fs::File::open(source).map_err(|e| validation_error(source).set_cause(e))?;
+        let mut buffer = [0; 8 * 1024];
+        loop {
+            let read = file.read(&mut buffer)
+                .map_err(|e| validation_error(source).set_cause(e))?;
+            if read == 0 {
+                return Ok(());
+            }
+            if buffer[..read].contains(&0) {
+                return Err(validation_error(source)
+                    .set_context(format!("{:?} is not a text file", source)));
+            }
+        }
+    }
+}
+
+#[cfg(feature = "validators")]
+fn validation_error(source: &path::Path) -> error::StagingError {
+    error::ErrorKind::StagingFailed
+        .error()
+        .set_context(format!("Failed validating {:?}", source))
+}
+
+/// Whether to replace the `existing` staged file with `source`, according to `policy`.
+fn should_overwrite(
+    existing: &path::Path,
+    source: &path::Path,
+    policy: ConflictPolicy,
+) -> Result<bool, error::StagingError> {
+    match policy {
+        ConflictPolicy::Overwrite => Ok(true),
+        ConflictPolicy::Skip => Ok(false),
+        ConflictPolicy::Error => Err(error::ErrorKind::StagingFailed
+            .error()
+            .set_context(format!("{:?} already exists", existing))),
+        ConflictPolicy::Verify => {
+            let change = file_change(existing, || {
+                fs::read(source).map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))
+            })?;
+            Ok(change != Change::Unchanged)
+        }
+    }
+}
+
+/// Have `write` create the file in `temp_dir`, if set, and move it to `staged` once done.
+fn write_via_temp<F>(
+    staged: &path::Path,
+    temp_dir: Option<&path::PathBuf>,
+    write: F,
+) -> io::Result<()>
+where
+    F: FnOnce(&path::Path) -> io::Result<()>,
+{
+    let temp_dir = match temp_dir {
+        Some(temp_dir) => tempfile::Builder::new().tempdir_in(temp_dir)?,
+        None => return write(staged),
+    };
+    let temp = temp_dir.path().join(staged.file_name().unwrap_or_else(|| "file".as_ref()));
+    write(&temp)?;
+    if fs::rename(&temp, staged).is_err() {
+        // `temp_dir` may be on a different filesystem than the stage.
+        fs::copy(&temp, staged)?;
+    }
+    Ok(())
+}
+
+/// Like `fs::copy`, but taking advantage of platform-specific optimizations that are enabled.
+fn copy_file(source: &path::Path, staged: &path::Path) -> io::Result<()> {
+    #[cfg(all(target_os = "linux", feature = "sparse"))]
+    {
+        if copy_sparse(source, staged)? {
+            return Ok(());
+        }
+    }
+    #[cfg(all(target_os = "linux", feature = "sendfile"))]
+    {
+        if copy_sendfile(source, staged)? {
+            return Ok(());
+        }
+    }
+    fs::copy(source, staged).map(|_| ())
+}
+
+#[cfg(feature = "xattr")]
+fn copy_xattrs(source: &path::Path, staged: &path::Path) -> io::Result<()> {
+    for name in xattr::list(source)? {
+        if let Some(value) = xattr::get(source, &name)? {
+            xattr::set(staged, &name, &value)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(target_os = "linux", feature = "reflink"))]
+ioctl_write_int!(
+    /// `FICLONE`, making the file share the data of the file passed in.
+    ficlone,
+    0x94,
+    9
+);
+
+/// Share `source`'s data with `staged` on copy-on-write filesystems, returning `false` if the
+/// filesystem doesn't support it.
+#[cfg(all(target_os = "linux", feature = "reflink"))]
+fn copy_reflink(source: &path::Path, staged: &path::Path) -> io::Result<bool> {
+    use nix::errno::Errno;
+    use std::os::unix::io::AsRawFd;
+
+    let reader = fs::File::open(source)?;
+    let metadata = reader.metadata()?;
+    let writer = fs::File::create(staged)?;
+    // `ficlone` is only unsafe for an invalid file descriptor.
+    match unsafe { ficlone(writer.as_raw_fd(), reader.as_raw_fd() as nix::libc::c_ulong) } {
+        Ok(_) => (),
+        Err(Errno::EOPNOTSUPP) | Err(Errno::EXDEV) | Err(Errno::EINVAL) | Err(Errno::ENOTTY) => {
+            return Ok(false)
+        }
+        Err(e) => return Err(e.into()),
+    }
+    fs::set_permissions(staged, metadata.permissions())?;
+    Ok(true)
+}
+
+/// Smallest file worth the overhead of `sendfile`.
+#[cfg(all(target_os = "linux", feature = "sendfile"))]
+const SENDFILE_MIN_SIZE: u64 = 1024 * 1024;
+
+/// Copy large files within a filesystem in the kernel, returning `false` if `source` isn't
+/// suitable.
+#[cfg(all(target_os = "linux", feature = "sendfile"))]
+fn copy_sendfile(source: &path::Path, staged: &path::Path) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    let reader = fs::File::open(source)?;
+    let metadata = reader.metadata()?;
+    if metadata.len() < SENDFILE_MIN_SIZE || !same_filesystem(source, staged)? {
+        return Ok(false);
+    }
+
+    let writer = fs::File::create(staged)?;
+    let mut remaining = metadata.len();
+    while remaining > 0 {
+        let count = cmp::min(remaining, isize::MAX as u64) as usize;
+        let sent = nix::sys::sendfile::sendfile(writer.as_raw_fd(), reader.as_raw_fd(), None, count)
+            .map_err(io::Error::from)?;
+        if sent == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "{:?} shrank while being copied, {} bytes short",
+                    source, remaining
+                ),
+            ));
+        }
+        remaining -= sent as u64;
+    }
+    fs::set_permissions(staged, metadata.permissions())?;
+    Ok(true)
+}
+
+/// Copy only the data regions of a sparse file, leaving holes in `staged`, returning `false` if
+/// `source` isn't sparse.
+#[cfg(all(target_os = "linux", feature = "sparse"))]
+fn copy_sparse(source: &path::Path, staged: &path::Path) -> io::Result<bool> {
+    use nix::errno::Errno;
+    use nix::unistd::{lseek, Whence};
+    use std::io::{Seek, SeekFrom};
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+
+    let mut reader = fs::File::open(source)?;
+    let metadata = reader.metadata()?;
+    if metadata.len() <= metadata.blocks() * 512 {
+        return Ok(false);
+    }
+
+    let mut writer = fs::File::create(staged)?;
+    writer.set_len(metadata.len())?;
+    let mut offset = 0;
+    loop {
+        let data = match lseek(reader.as_raw_fd(), offset, Whence::SeekData) {
+            Ok(data) => data,
+            // No data after `offset`.
+            Err(Errno::ENXIO) => break,
+            Err(e) => return Err(e.into()),
+        };
+        let hole = lseek(reader.as_raw_fd(), data, Whence::SeekHole).map_err(io::Error::from)?;
+        reader.seek(SeekFrom::Start(data as u64))?;
+        writer.seek(SeekFrom::Start(data as u64))?;
+        io::copy(&mut (&mut reader).take((hole - data) as u64), &mut writer)?;
+        offset = hole;
+    }
+    fs::set_permissions(staged, metadata.permissions())?;
+    Ok(true)
+}
+
+#[cfg(all(target_os = "linux", feature = "sendfile"))]
+fn same_filesystem(source: &path::Path, staged: &path::Path) -> io::Result<bool> {
+    let staged_dir = staged.parent().unwrap_or_else(|| path::Path::new("."));
+    let source = nix::sys::statvfs::statvfs(source).map_err(io::Error::from)?;
+    let staged = nix::sys::statvfs::statvfs(staged_dir).map_err(io::Error::from)?;
+    Ok(source.filesystem_id() == staged.filesystem_id())
+}
+
+const THROTTLE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Like `fs::copy`, but writing no more than `bps` bytes per second on average.
+fn copy_throttled(source: &path::Path, staged: &path::Path, bps: u64) -> io::Result<()> {
+    let mut reader = fs::File::open(source)?;
+    let permissions = reader.metadata()?.permissions();
+    let mut writer = fs::File::create(staged)?;
+    let mut bucket = TokenBucket::new(bps, THROTTLE_CHUNK_SIZE as u64);
+    let mut buffer = vec![0; THROTTLE_CHUNK_SIZE];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        bucket.take(read as u64);
+        writer.write_all(&buffer[..read])?;
+    }
+    fs::set_permissions(staged, permissions)?;
+    Ok(())
+}
+
+/// Copy `source` to `staged` through a buffer of `size` bytes, preserving permissions like
+/// `fs::copy`.
+fn copy_buffered(source: &path::Path, staged: &path::Path, size: usize) -> io::Result<()> {
+    let mut reader = fs::File::open(source)?;
+    let permissions = reader.metadata()?.permissions();
+    let mut writer = fs::File::create(staged)?;
+    let mut buffer = vec![0; cmp::max(size, 1)];
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buffer[..read])?;
+    }
+    fs::set_permissions(staged, permissions)?;
+    Ok(())
+}
+
+/// Token-bucket rate limiter, allowing bursts of up to one second's worth of bytes.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: time::Instant,
+}
+
+impl TokenBucket {
+    fn new(bps: u64, min_capacity: u64) -> Self {
+        let rate = cmp::max(bps, 1) as f64;
+        let capacity = cmp::max(bps, min_capacity) as f64;
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            last: time::Instant::now(),
+        }
+    }
+
+    fn take(&mut self, count: u64) {
+        let count = count as f64;
+        let now = time::Instant::now();
+        let elapsed = now.duration_since(self.last);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+
+        if self.tokens < count {
+            let wait = (count - self.tokens) / self.rate;
+            thread::sleep(time::Duration::from_millis((wait * 1000.0).ceil() as u64));
+            self.tokens = 0.0;
+            self.last = time::Instant::now();
+        } else {
+            self.tokens -= count;
+        }
+    }
+}
+
+/// Specifies the unix permissions of a staged file.
+#[derive(Clone, Debug)]
+pub struct SetPermissions {
+    staged: path::PathBuf,
+    mode: u32,
+}
+
+impl SetPermissions {
+    /// Specifies the unix permissions of a staged file.
+    ///
+    /// - `staged`: full path to the staged file.
+    /// - `mode`: unix permission bits, like `0o755`.  This is ignored on non-unix platforms.
+    pub fn new<P>(staged: P, mode: u32) -> Self
+    where
+        P: Into<path::PathBuf>,
+    {
+        Self {
+            staged: staged.into(),
+            mode,
+        }
+    }
+}
+
+impl fmt::Display for SetPermissions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "chmod {:o} {:?}", self.mode, self.staged)
+    }
+}
+
+impl Action for SetPermissions {
+    fn perform(&self, _options: &PerformOptions) -> Result<(), error::StagingError> {
+        set_mode(&self.staged, self.mode)
+    }
+
+    fn affects_path(&self) -> Option<&path::Path> {
+        Some(&self.staged)
+    }
+
+    fn describe(&self) -> ActionDescription {
+        ActionDescription::new(ActionKind::SetPermissions, &self.staged)
+            .extra("mode", format!("{:o}", self.mode))
+    }
+}
+
+/// Specifies a staged file to be compared against its source after copying.
+#[derive(Clone, Debug)]
+pub struct VerifyChecksum {
+    staged: path::PathBuf,
+    source: path::PathBuf,
+}
+
+impl VerifyChecksum {
+    /// Specifies a staged file to be compared against its source after copying.
+    ///
+    /// - `staged`: full path to the staged file.
+    /// - `source`: full path to the file `staged` was copied from.
+    pub fn new<D, S>(staged: D, source: S) -> Self
+    where
+        D: Into<path::PathBuf>,
+        S: Into<path::PathBuf>,
+    {
+        Self {
+            staged: staged.into(),
+            source: source.into(),
+        }
+    }
+}
+
+impl fmt::Display for VerifyChecksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "verify {:?} {:?}", self.source, self.staged)
+    }
+}
+
+impl Action for VerifyChecksum {
+    fn perform(&self, _options: &PerformOptions) -> Result<(), error::StagingError> {
+        let source = sha256(&self.source)?;
+        let staged = sha256(&self.staged)?;
+        if source != staged {
+            return Err(error::ErrorKind::StagingFailed
+                .error()
+                .set_context(format!(
+                    "{:?} does not match {:?}: sha256 {} != {}",
+                    self.staged, self.source, staged, source
+                )));
+        }
+        Ok(())
+    }
+
+    fn source_paths(&self) -> Vec<&path::Path> {
+        vec![&self.source]
+    }
+
+    fn describe(&self) -> ActionDescription {
+        ActionDescription::new(ActionKind::Other, &self.staged).source(Some(&self.source))
+    }
+}
+
+/// Text encoding for `VerifyEncoding`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "de", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "de", serde(rename_all = "lowercase"))]
+pub enum Encoding {
+    /// UTF-8.
+    Utf8,
+    /// 7-bit ASCII.
+    Ascii,
+    /// ISO-8859-1, which leaves bytes `0x80` through `0x9F` unassigned.
+    Latin1,
+}
+
+impl Encoding {
+    /// Offset of the first byte of `data` that isn't valid in this encoding.
+    fn invalid_offset(self, data: &[u8]) -> Option<usize> {
+        match self {
+            Encoding::Utf8 => str::from_utf8(data).err().map(|e| e.valid_up_to()),
+            Encoding::Ascii => data.iter().position(|b| !b.is_ascii()),
+            Encoding::Latin1 => data.iter().position(|b| (0x80..=0x9F).contains(b)),
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Encoding::Utf8 => write!(f, "utf8"),
+            Encoding::Ascii => write!(f, "ascii"),
+            Encoding::Latin1 => write!(f, "latin1"),
+        }
+    }
+}
+
+/// Specifies a source file to be checked for its text encoding before it is staged.
+#[derive(Clone, Debug)]
+pub struct VerifyEncoding {
+    source: path::PathBuf,
+    encoding: Encoding,
+}
+
+impl VerifyEncoding {
+    /// Specifies a source file to be checked for its text encoding before it is staged.
+    ///
+    /// - `source`: full path to the file to check.
+    /// - `encoding`: the encoding `source` must be valid in.
+    pub fn new<P>(source: P, encoding: Encoding) -> Self
+    where
+        P: Into<path::PathBuf>,
+    {
+        Self {
+            source: source.into(),
+            encoding,
+        }
+    }
+}
+
+impl fmt::Display for VerifyEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "verify --encoding {} {:?}", self.encoding, self.source)
+    }
+}
+
+impl Action for VerifyEncoding {
+    fn perform(&self, _options: &PerformOptions) -> Result<(), error::StagingError> {
+        let data = fs::read(&self.source)
+            .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?;
+        if let Some(offset) = self.encoding.invalid_offset(&data) {
+            return Err(error::ErrorKind::HarvestingFailed
+                .error()
+                .set_context(format!(
+                    "{:?} is not valid {}: invalid byte at offset {}",
+                    self.source, self.encoding, offset
+                )));
+        }
+        Ok(())
+    }
+
+    fn source_paths(&self) -> Vec<&path::Path> {
+        vec![&self.source]
+    }
+
+    fn describe(&self) -> ActionDescription {
+        ActionDescription::new(ActionKind::Other, &self.source)
+            .source(Some(&self.source))
+            .extra("encoding", self.encoding.to_string())
+    }
+}
+
+/// Hex-encoded SHA-256 digest of the file at `path`.
+fn sha256(path: &path::Path) -> Result<String, error::StagingError> {
+    let mut file = fs::File::open(path)
+        .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+    let mut hasher = sha2::Sha256::default();
+    io::copy(&mut file, &mut hasher)
+        .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+    Ok(format!("{:x}", hasher.result()))
+}
+
+/// Specifies a symbolic link file to be staged into the target directory.
+#[derive(Clone, Debug)]
+pub struct Symlink {
+    staged: path::PathBuf,
+    target: path::PathBuf,
+    overwrite_existing: bool,
+}
+
+impl Symlink {
+    /// Specifies a symbolic link file to be staged into the target directory.
+    ///
+    /// - `staged`: full path for future symlink.
+    /// - `target`: path that symlink will point to.
+    pub fn new<S, T>(staged: S, target: T) -> Self
+    where
+        S: Into<path::PathBuf>,
+        T: Into<path::PathBuf>,
+    {
+        Self {
+            staged: staged.into(),
+            target: target.into(),
+            overwrite_existing: false,
+        }
+    }
+
+    /// When true, a symbolic link already at `staged` is replaced.  Default is `false`.
+    pub fn overwrite_existing(mut self, yes: bool) -> Self {
+        self.overwrite_existing = yes;
+        self
+    }
+}
+
+/// Create a symbolic link at `staged` pointing to `target`.
+#[cfg(unix)]
+pub(crate) fn symlink(target: &path::Path, staged: &path::Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, staged)
+}
+
+/// Create a symbolic link at `staged` pointing to `target`.
+///
+/// Windows distinguishes links to directories, so `target` is resolved relative to `staged`.
+#[cfg(windows)]
+pub(crate) fn symlink(target: &path::Path, staged: &path::Path) -> io::Result<()> {
+    let resolved = staged
+        .parent()
+        .map(|parent| parent.join(target))
+        .unwrap_or_else(|| target.to_owned());
+    if resolved.is_dir() {
+        std::os::windows::fs::symlink_dir(target, staged)
+    } else {
+        std::os::windows::fs::symlink_file(target, staged)
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+compile_error!("Symlinks are only supported on unix and windows");
+
+impl fmt::Display for Symlink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ln -s {:?} {:?}", self.target, self.staged)
+    }
+}
+
+impl Action for Symlink {
+    fn perform(&self, _options: &PerformOptions) -> Result<(), error::StagingError> {
+        if let Some(parent) = self.staged.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        }
+        let existing = fs::read_link(&self.staged).ok();
+        if self.overwrite_existing && existing.is_some() {
+            fs::remove_file(&self.staged)
+                .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        }
+        symlink(&self.target, &self.staged).map_err(|e| {
+            let error = error::ErrorKind::StagingFailed.error();
+            let error = match existing {
+                Some(ref existing) if !self.overwrite_existing => error.set_context(format!(
+                    "{:?} already exists, pointing to {:?}",
+                    self.staged, existing
+                )),
+                _ => error,
+            };
+            error.set_cause(e)
+        })?;
+
+        Ok(())
+    }
+
+    fn affects_path(&self) -> Option<&path::Path> {
+        Some(&self.staged)
+    }
+
+    fn describe(&self) -> ActionDescription {
+        ActionDescription::new(ActionKind::Symlink, &self.staged)
+            .extra("target", self.target.to_string_lossy())
+    }
+}
+
+/// Specifies a hard link to another staged file.
+#[derive(Clone, Debug)]
+pub struct HardLink {
+    staged: path::PathBuf,
+    target: path::PathBuf,
+}
+
+impl HardLink {
+    /// Specifies a hard link to another staged file.
+    ///
+    /// - `staged`: full path for future link.
+    /// - `target`: full path to the staged file being linked to.
+    pub fn new<S, T>(staged: S, target: T) -> Self
+    where
+        S: Into<path::PathBuf>,
+        T: Into<path::PathBuf>,
+    {
+        Self {
+            staged: staged.into(),
+            target: target.into(),
+        }
+    }
+}
+
+impl fmt::Display for HardLink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ln {:?} {:?}", self.target, self.staged)
+    }
+}
+
+impl Action for HardLink {
+    fn perform(&self, _options: &PerformOptions) -> Result<(), error::StagingError> {
+        if let Some(parent) = self.staged.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        }
+        match fs::remove_file(&self.staged) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(error::ErrorKind::StagingFailed.error().set_cause(e)),
+        }
+        fs::hard_link(&self.target, &self.staged)
+            .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+
+        Ok(())
+    }
+
+    fn affects_path(&self) -> Option<&path::Path> {
+        Some(&self.staged)
+    }
+
+    fn describe(&self) -> ActionDescription {
+        ActionDescription::new(ActionKind::HardLink, &self.staged)
+            .extra("target", self.target.to_string_lossy())
+    }
+}
+
+#[cfg(unix)]
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+// Without inodes, an identical file is assumed to be the same.
+#[cfg(not(unix))]
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    a.is_file() && a.len() == b.len() && a.modified().ok() == b.modified().ok()
+}
+
+/// Specifies a file to be written into the target directory with the given content.
+#[derive(Clone, Debug)]
+pub struct WriteInlineContent {
+    staged: path::PathBuf,
+    content: Vec<u8>,
+    mode: Option<u32>,
+}
+
+impl WriteInlineContent {
+    /// Specifies a file to be written into the target directory with the given content.
+    ///
+    /// - `staged`: full path to future file.
+    /// - `content`: data to be written to `staged`.
+    pub fn new<P, S>(staged: P, content: S) -> Self
+    where
+        P: Into<path::PathBuf>,
+        S: Into<String>,
+    {
+        Self::from_bytes(staged, content.into().into_bytes())
+    }
+
+    /// Specifies a file to be written into the target directory with the given binary content.
+    ///
+    /// - `staged`: full path to future file.
+    /// - `content`: data to be written to `staged`.
+    pub fn from_bytes<P, B>(staged: P, content: B) -> Self
+    where
+        P: Into<path::PathBuf>,
+        B: Into<Vec<u8>>,
+    {
+        Self {
+            staged: staged.into(),
+            content: content.into(),
+            mode: None,
+        }
+    }
+
+    /// Specifies the unix permissions for the file.  Default is to keep those of the file being
+    /// replaced, or to follow the umask for a new file.  This is ignored on non-unix platforms.
+    pub fn mode(mut self, mode: Option<u32>) -> Self {
+        self.mode = mode;
+        self
+    }
+}
+
+impl fmt::Display for WriteInlineContent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "write {:?}", self.staged)
+    }
+}
+
+impl Action for WriteInlineContent {
+    /// Writes to a temporary file, in `PerformOptions::temp_dir` or next to `staged`, and moves it
+    /// into place, so `staged` is never left partially written.
+    fn perform(&self, options: &PerformOptions) -> Result<(), error::StagingError> {
+        let parent = match self.staged.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => path::Path::new("."),
+        };
+        fs::create_dir_all(parent)
+            .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        let existing = fs::metadata(&self.staged)
+            .ok()
+            .filter(|m| m.is_file())
+            .map(|m| m.permissions());
+        let temp_dir = options.temp_dir.clone().unwrap_or_else(|| parent.to_owned());
+        write_via_temp(&self.staged, Some(&temp_dir), |dest| {
+            fs::write(dest, &self.content)?;
+            match existing {
+                Some(permissions) => fs::set_permissions(dest, permissions),
+                None => Ok(()),
+            }
+        }).map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        if let Some(mode) = self.mode {
+            set_mode(&self.staged, mode)?;
+        }
+
+        Ok(())
+    }
+
+    fn affects_path(&self) -> Option<&path::Path> {
+        Some(&self.staged)
+    }
+
+    fn estimated_byte_count(&self) -> Option<u64> {
+        Some(self.content.len() as u64)
+    }
+
+    fn describe(&self) -> ActionDescription {
+        let description = ActionDescription::new(ActionKind::WriteContent, &self.staged)
+            .extra("sha256", format!("{:x}", sha2::Sha256::digest(&self.content)));
+        match self.mode {
+            Some(mode) => description.extra("mode", format!("{:o}", mode)),
+            None => description,
+        }
+    }
+}
+
+/// Specifies a program to run on a staged file.
+///
+/// This is a last resort, for transformations that can't be expressed otherwise, like `strip`.
+#[cfg(feature = "shell-hooks")]
+#[derive(Clone, Debug)]
+pub struct RunCommand {
+    staged: path::PathBuf,
+    program: String,
+    args: Vec<String>,
+}
+
+#[cfg(feature = "shell-hooks")]
+impl RunCommand {
+    /// Specifies a program to run on a staged file.
+    ///
+    /// - `staged`: full path to the staged file, passed as the last argument.
+    /// - `program`: program to run.
+    /// - `args`: arguments to pass before `staged`.
+    pub fn new<P, S>(staged: P, program: S, args: Vec<String>) -> Self
+    where
+        P: Into<path::PathBuf>,
+        S: Into<String>,
+    {
+        Self {
+            staged: staged.into(),
+            program: program.into(),
+            args,
+        }
+    }
+}
+
+#[cfg(feature = "shell-hooks")]
+impl fmt::Display for RunCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.program)?;
+        for arg in &self.args {
+            write!(f, " {}", arg)?;
+        }
+        write!(f, " {:?}", self.staged)
+    }
+}
+
+#[cfg(feature = "shell-hooks")]
+impl Action for RunCommand {
+    fn perform(&self, options: &PerformOptions) -> Result<(), error::StagingError> {
+        let mut command = process::Command::new(&self.program);
+        command.args(&self.args).arg(&self.staged);
+        set_command_temp_dir(&mut command, options);
+        let status = command.status().map_err(|e| {
+            error::ErrorKind::StagingFailed
+                .error()
+                .set_context(format!("Failed to run {:?}", self.program))
+                .set_cause(e)
+        })?;
+        if !status.success() {
+            return Err(error::ErrorKind::StagingFailed
+                .error()
+                .set_context(format!("`{}` failed: {}", self, status)));
+        }
+
+        Ok(())
+    }
+
+    fn describe(&self) -> ActionDescription {
+        let mut description = ActionDescription::new(ActionKind::Other, &self.staged)
+            .extra("program", self.program.as_str());
+        for (i, arg) in self.args.iter().enumerate() {
+            description = description.extra(format!("arg{}", i), arg.as_str());
+        }
+        description
+    }
+}
+
+/// Specifies a file to be written into the target directory with the standard output of a
+/// program.
+///
+/// The program is only run when the action is performed, without a shell.
+#[cfg(feature = "exec")]
+#[derive(Clone, Debug)]
+pub struct WriteCommandOutput {
+    staged: path::PathBuf,
+    program: String,
+    args: Vec<String>,
+    allow_empty: bool,
+}
+
+#[cfg(feature = "exec")]
+impl WriteCommandOutput {
+    /// Specifies a file to be written into the target directory with the standard output of a
+    /// program.
+    ///
+    /// - `staged`: full path to future file.
+    /// - `program`: program to run.
+    /// - `args`: arguments to pass to `program`.
+    pub fn new<P, S>(staged: P, program: S, args: Vec<String>) -> Self
+    where
+        P: Into<path::PathBuf>,
+        S: Into<String>,
+    {
+        Self {
+            staged: staged.into(),
+            program: program.into(),
+            args,
+            allow_empty: false,
+        }
+    }
+
+    /// Toggles whether `program` writing nothing is an error.
+    pub fn allow_empty(mut self, yes: bool) -> Self {
+        self.allow_empty = yes;
+        self
+    }
+}
+
+#[cfg(feature = "exec")]
+impl fmt::Display for WriteCommandOutput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.program)?;
+        for arg in &self.args {
+            write!(f, " {}", arg)?;
+        }
+        write!(f, " > {:?}", self.staged)
+    }
+}
+
+#[cfg(feature = "exec")]
+impl Action for WriteCommandOutput {
+    fn perform(&self, options: &PerformOptions) -> Result<(), error::StagingError> {
+        let mut command = process::Command::new(&self.program);
+        command.args(&self.args).stdin(process::Stdio::null());
+        set_command_temp_dir(&mut command, options);
+        let output = command.output().map_err(|e| {
+            error::ErrorKind::StagingFailed
+                .error()
+                .set_context(format!("Failed to run {:?}", self.program))
+                .set_cause(e)
+        })?;
+        if !output.status.success() {
+            return Err(error::ErrorKind::StagingFailed
+                .error()
+                .set_context(format!(
+                    "{:?} failed ({}): {}",
+                    self.program,
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )));
+        }
+        if output.stdout.is_empty() && !self.allow_empty {
+            return Err(error::ErrorKind::StagingFailed
+                .error()
+                .set_context(format!("{:?} wrote nothing to stdout", self.program)));
+        }
+        WriteInlineContent::from_bytes(&self.staged, output.stdout).perform(options)
+    }
+
+    fn affects_path(&self) -> Option<&path::Path> {
+        Some(&self.staged)
+    }
+
+    fn describe(&self) -> ActionDescription {
+        let mut description = ActionDescription::new(ActionKind::WriteContent, &self.staged)
+            .extra("program", self.program.as_str());
+        for (i, arg) in self.args.iter().enumerate() {
+            description = description.extra(format!("arg{}", i), arg.as_str());
+        }
+        description
+    }
+}
+
+/// Point the temporary directory of `command` at `PerformOptions::temp_dir`, if set.
+#[cfg(any(feature = "shell-hooks", feature = "exec"))]
+fn set_command_temp_dir(command: &mut process::Command, options: &PerformOptions) {
+    if let Some(ref dir) = options.temp_dir {
+        if cfg!(windows) {
+            command.env("TMP", dir).env("TEMP", dir);
+        } else {
+            command.env("TMPDIR", dir);
+        }
+    }
+}
+
+/// Specifies a staged file to have its modification time updated to now.
+///
+/// The file is created empty if it doesn't exist.
+#[derive(Clone, Debug)]
+pub struct Touch {
+    staged: path::PathBuf,
+}
+
+impl Touch {
+    /// Specifies a staged file to have its modification time updated to now.
+    ///
+    /// - `staged`: full path to the file.
+    pub fn new<P>(staged: P) -> Self
+    where
+        P: Into<path::PathBuf>,
+    {
+        Self {
+            staged: staged.into(),
+        }
+    }
+}
+
+impl fmt::Display for Touch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "touch {:?}", self.staged)
+    }
+}
+
+impl Action for Touch {
+    fn perform(&self, _options: &PerformOptions) -> Result<(), error::StagingError> {
+        if let Some(parent) = self.staged.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        }
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.staged)
+            .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        let now = filetime::FileTime::now();
+        filetime::set_file_times(&self.staged, now, now)
+            .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+
+        Ok(())
+    }
+
+    fn affects_path(&self) -> Option<&path::Path> {
+        Some(&self.staged)
+    }
+
+    fn describe(&self) -> ActionDescription {
+        ActionDescription::new(ActionKind::Other, &self.staged).extra("operation", "touch")
+    }
+}
+
+/// File format for `WriteManifest`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "de", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "de", serde(rename_all = "lowercase"))]
+pub enum ManifestFormat {
+    /// One path per line.
+    #[cfg_attr(feature = "de", serde(rename = "text"))]
+    #[default]
+    PlainText,
+    /// JSON array of paths.  Requires the `serde_json` feature.
+    Json,
+    /// `"path","size","sha256"` rows, with a header.
+    Csv,
+}
+
+impl fmt::Display for ManifestFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ManifestFormat::PlainText => write!(f, "text"),
+            ManifestFormat::Json => write!(f, "json"),
+            ManifestFormat::Csv => write!(f, "csv"),
+        }
+    }
+}
+
+/// Specifies a file listing staged paths to be written into the target directory.
+#[derive(Clone, Debug)]
+pub struct WriteManifest {
+    staged: path::PathBuf,
+    root: path::PathBuf,
+    paths: Vec<path::PathBuf>,
+    format: ManifestFormat,
+}
+
+impl WriteManifest {
+    /// Specifies a file listing staged paths to be written into the target directory.
+    ///
+    /// - `staged`: full path to future file.
+    /// - `root`: stage root that `paths` are relative to.
+    /// - `paths`: staged paths to list.  Directories are skipped.
+    pub fn new<P, R>(staged: P, root: R, paths: Vec<path::PathBuf>) -> Self
+    where
+        P: Into<path::PathBuf>,
+        R: Into<path::PathBuf>,
+    {
+        Self {
+            staged: staged.into(),
+            root: root.into(),
+            paths,
+            format: Default::default(),
+        }
+    }
+
+    /// Specifies the file format.  Default is `PlainText`.
+    pub fn format(mut self, format: ManifestFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    fn render(&self) -> Result<Vec<u8>, error::StagingError> {
+        let mut paths = Vec::with_capacity(self.paths.len());
+        for path in &self.paths {
+            let metadata = fs::symlink_metadata(self.root.join(path))
+                .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+            if !metadata.is_dir() {
+                paths.push((path.as_path(), metadata.len()));
+            }
+        }
+
+        let mut content = String::new();
+        match self.format {
+            ManifestFormat::PlainText => {
+                for &(path, _) in &paths {
+                    content.push_str(&path.to_string_lossy());
+                    content.push('\n');
+                }
+            }
+            ManifestFormat::Json => {
+                let paths: Vec<_> = paths.iter().map(|&(p, _)| p.to_string_lossy()).collect();
+                content = manifest_json(&paths)?;
+                content.push('\n');
+            }
+            ManifestFormat::Csv => {
+                content.push_str("\"path\",\"size\",\"sha256\"\n");
+                for &(path, size) in &paths {
+                    let data = fs::read(self.root.join(path))
+                        .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+                    let path = path.to_string_lossy().replace('"', "\"\"");
+                    content.push_str(&format!(
+                        "\"{}\",\"{}\",\"{:x}\"\n",
+                        path,
+                        size,
+                        sha2::Sha256::digest(&data)
+                    ));
+                }
+            }
+        }
+        Ok(content.into_bytes())
+    }
+}
+
+#[cfg(all(feature = "de", feature = "serde_json"))]
+fn manifest_json(paths: &[borrow::Cow<str>]) -> Result<String, error::StagingError> {
+    serde_json::to_string_pretty(paths)
+        .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))
+}
+
+#[cfg(not(all(feature = "de", feature = "serde_json")))]
+fn manifest_json(_paths: &[borrow::Cow<str>]) -> Result<String, error::StagingError> {
+    Err(error::ErrorKind::StagingFailed
+        .error()
+        .set_context("JSON manifests require the `serde_json` feature"))
+}
+
+impl fmt::Display for WriteManifest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "manifest --format {} {:?}", self.format, self.staged)
+    }
+}
+
+impl Action for WriteManifest {
+    fn perform(&self, _options: &PerformOptions) -> Result<(), error::StagingError> {
+        let content = self.render()?;
+        if let Some(parent) = self.staged.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        }
+        fs::write(&self.staged, &content)
+            .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+
+        Ok(())
+    }
+
+    fn affects_path(&self) -> Option<&path::Path> {
+        Some(&self.staged)
+    }
+
+    fn describe(&self) -> ActionDescription {
+        ActionDescription::new(ActionKind::WriteContent, &self.staged)
+            .extra("format", self.format.to_string())
+    }
+}
+
+/// Assertion about the stage as a whole, verified by `VerifyStaging`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StagingCheck {
+    /// Staged files total at most this many bytes.
+    MaxTotalSize(u64),
+    /// This path, relative to the stage root, is staged.
+    FileExists(path::PathBuf),
+    /// Between `min` and `max` files, inclusive, are staged.
+    FileCount {
+        /// Fewest files allowed.
+        min: usize,
+        /// Most files allowed.
+        max: usize,
+    },
+    /// The file staged at this path, relative to the stage root, has this hex-encoded SHA-256.
+    StagedFileMatchesHash(path::PathBuf, String),
+}
+
+/// Specifies checks of the stage to run once everything else has been staged.
+#[derive(Clone, Debug)]
+pub struct VerifyStaging {
+    root: path::PathBuf,
+    paths: Vec<path::PathBuf>,
+    planned_bytes: u64,
+    checks: Vec<StagingCheck>,
+}
+
+impl VerifyStaging {
+    /// Specifies checks of the stage to run once everything else has been staged.
+    ///
+    /// - `root`: stage root that `paths` are relative to.
+    /// - `paths`: staged file paths.
+    /// - `planned_bytes`: estimate of the bytes staged, for `check_plan`.
+    pub fn new<R>(
+        root: R,
+        paths: Vec<path::PathBuf>,
+        planned_bytes: u64,
+        checks: Vec<StagingCheck>,
+    ) -> Self
+    where
+        R: Into<path::PathBuf>,
+    {
+        Self {
+            root: root.into(),
+            paths,
+            planned_bytes,
+            checks,
+        }
+    }
+
+    /// Specifies `checks` of the files `actions` will stage under `root`, estimating their
+    /// total size for `check_plan`.
+    pub fn for_actions<R>(root: R, actions: &[Box<Action>], checks: Vec<StagingCheck>) -> Self
+    where
+        R: Into<path::PathBuf>,
+    {
+        let root = root.into();
+        let mut paths: Vec<_> = actions
+            .iter()
+            .filter(|a| a.describe().kind != ActionKind::CreateDirectory)
+            .filter_map(|a| a.affects_path())
+            .filter_map(|p| p.strip_prefix(&root).ok())
+            .map(|p| p.to_owned())
+            .collect();
+        paths.sort();
+        paths.dedup();
+        let planned_bytes = actions
+            .iter()
+            .filter_map(|a| a.estimated_byte_count())
+            .sum();
+        Self::new(root, paths, planned_bytes, checks)
+    }
+
+    /// Check the planned paths and estimated sizes, without anything being staged.  Hashes can't
+    /// be checked until the files are staged.
+    pub fn check_plan(&self) -> Result<(), error::StagingError> {
+        self.verify(&self.paths, self.planned_bytes, &|_| Ok(None))
+    }
+
+    fn verify(
+        &self,
+        files: &[path::PathBuf],
+        total_size: u64,
+        staged_hash: &Fn(&path::Path) -> Result<Option<String>, error::StagingError>,
+    ) -> Result<(), error::StagingError> {
+        for check in &self.checks {
+            let failure = match *check {
+                StagingCheck::MaxTotalSize(max) if max < total_size => Some(format!(
+                    "{} bytes staged is more than the maximum {}",
+                    total_size, max
+                )),
+                StagingCheck::FileExists(ref path) if !files.contains(path) => {
+                    Some(format!("{:?} is not staged", path))
+                }
+                StagingCheck::FileCount { min, max } if files.len() < min || max < files.len() => {
+                    Some(format!(
+                        "{} files staged is outside of {}..={}",
+                        files.len(),
+                        min,
+                        max
+                    ))
+                }
+                StagingCheck::StagedFileMatchesHash(ref path, ref expected) => {
+                    match staged_hash(path)? {
+                        Some(ref actual) if !actual.eq_ignore_ascii_case(expected) => Some(
+                            format!("{:?} has sha256 {}, expected {}", path, actual, expected),
+                        ),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+            if let Some(failure) = failure {
+                Err(error::ErrorKind::StagingFailed
+                    .error()
+                    .set_context(format!("Post-staging check failed: {}", failure)))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for VerifyStaging {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "verify {} checks {:?}", self.checks.len(), self.root)
+    }
+}
+
+impl Action for VerifyStaging {
+    fn perform(&self, _options: &PerformOptions) -> Result<(), error::StagingError> {
+        let mut files = Vec::with_capacity(self.paths.len());
+        let mut total_size = 0;
+        for path in &self.paths {
+            if let Ok(metadata) = fs::symlink_metadata(self.root.join(path)) {
+                if !metadata.is_dir() {
+                    files.push(path.clone());
+                    total_size += metadata.len();
+                }
+            }
+        }
+        self.verify(&files, total_size, &|path| {
+            sha256(&self.root.join(path)).map(Some)
+        })
+    }
+
+    fn describe(&self) -> ActionDescription {
+        ActionDescription::new(ActionKind::Other, &self.root)
+            .extra("checks", self.checks.len().to_string())
+    }
+}
+
+/// Writes staged files into a `.tar` archive, like with `append_tree` after staging into a
+/// temporary directory.
+///
+/// The archive is compressed according to its extension: `.tar.gz`, `.tar.bz2`, or `.tar.xz`.
+#[cfg(feature = "tar")]
+pub struct TarWriter {
+    path: path::PathBuf,
+    root: path::PathBuf,
+    builder: tar::Builder<TarEncoder>,
+}
+
+#[cfg(feature = "tar")]
+impl TarWriter {
+    /// Create the archive at `path`.
+    ///
+    /// - `root`: the target directory actions were built for; entries are named relative to it.
+    pub fn create<P, R>(path: P, root: R) -> Result<Self, error::StagingError>
+    where
+        P: Into<path::PathBuf>,
+        R: Into<path::PathBuf>,
+    {
+        let path = path.into();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        }
+        let file = fs::File::create(&path).map_err(|e| {
+            error::ErrorKind::StagingFailed
+                .error()
+                .set_context(format!("Failed to create {:?}", path))
+                .set_cause(e)
+        })?;
+        let name = path.to_string_lossy().into_owned();
+        let encoder = if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            TarEncoder::Gzip(flate2::write::GzEncoder::new(
+                file,
+                flate2::Compression::default(),
+            ))
+        } else if name.ends_with(".tar.bz2") {
+            TarEncoder::Bzip2(bzip2::write::BzEncoder::new(
+                file,
+                bzip2::Compression::default(),
+            ))
+        } else if name.ends_with(".tar.xz") {
+            TarEncoder::Xz(xz2::write::XzEncoder::new(file, 6))
+        } else {
+            TarEncoder::Plain(file)
+        };
+        let mut builder = tar::Builder::new(encoder);
+        builder.follow_symlinks(false);
+        Ok(Self {
+            path,
+            root: root.into(),
+            builder,
+        })
+    }
+
+    /// Add everything under `root`, in sorted order.
+    ///
+    /// Symbolic links are archived as links rather than followed, and files hard linked together
+    /// are archived as hard links to the first of them.
+    pub fn append_tree(&mut self) -> Result<(), error::StagingError> {
+        let root = self.root.clone();
+        let mut linked: HashMap<(u64, u64), path::PathBuf> = HashMap::new();
+        let walker = walkdir::WalkDir::new(&root)
+            .min_depth(1)
+            .sort_by(|a, b| a.file_name().cmp(b.file_name()));
+        for entry in walker {
+            let entry = entry.map_err(|e| {
+                error::ErrorKind::StagingFailed
+                    .error()
+                    .set_context(format!("Failed reading {:?}", root))
+                    .set_cause(e)
+            })?;
+            let staged = entry.path();
+            let metadata = entry
+                .metadata()
+                .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+            if metadata.is_dir() {
+                self.append_dir(staged, file_mode(&metadata))?;
+            } else if metadata.file_type().is_symlink() {
+                let target = fs::read_link(staged)
+                    .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+                self.append_symlink(staged, &target)?;
+            } else {
+                if let Some(key) = hard_link_key(&metadata) {
+                    if let Some(first) = linked.get(&key) {
+                        self.append_hard_link(staged, first)?;
+                        continue;
+                    }
+                    linked.insert(key, staged.to_owned());
+                }
+                self.append_file(staged, staged)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the file at `source` as `staged`.
+    pub fn append_file(
+        &mut self,
+        staged: &path::Path,
+        source: &path::Path,
+    ) -> Result<(), error::StagingError> {
+        let name = self.entry_name(staged)?.to_owned();
+        let mut file = fs::File::open(source)
+            .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        self.builder
+            .append_file(name, &mut file)
+            .map_err(|e| self.append_error(staged, e))
+    }
+
+    /// Add a directory entry for `staged`.
+    pub fn append_dir(
+        &mut self,
+        staged: &path::Path,
+        mode: u32,
+    ) -> Result<(), error::StagingError> {
+        let name = self.entry_name(staged)?.to_owned();
+        let mut header = Self::header(tar::EntryType::Directory, mode);
+        self.builder
+            .append_data(&mut header, name, io::empty())
+            .map_err(|e| self.append_error(staged, e))
+    }
+
+    /// Add a symbolic link entry for `staged`, pointing to `target`.
+    pub fn append_symlink(
+        &mut self,
+        staged: &path::Path,
+        target: &path::Path,
+    ) -> Result<(), error::StagingError> {
+        let name = self.entry_name(staged)?.to_owned();
+        let mut header = Self::header(tar::EntryType::Symlink, 0o777);
+        self.builder
+            .append_link(&mut header, name, target)
+            .map_err(|e| self.append_error(staged, e))
+    }
+
+    /// Add a hard link entry for `staged`, linking to the already archived `target`.
+    pub fn append_hard_link(
+        &mut self,
+        staged: &path::Path,
+        target: &path::Path,
+    ) -> Result<(), error::StagingError> {
+        let name = self.entry_name(staged)?.to_owned();
+        let target = self.entry_name(target)?.to_owned();
+        let mut header = Self::header(tar::EntryType::Link, 0o644);
+        self.builder
+            .append_link(&mut header, name, target)
+            .map_err(|e| self.append_error(staged, e))
+    }
+
+    /// Add a file entry for `staged` holding `content`.
+    pub fn append_data(
+        &mut self,
+        staged: &path::Path,
+        content: &[u8],
+        mode: u32,
+    ) -> Result<(), error::StagingError> {
+        let name = self.entry_name(staged)?.to_owned();
+        let mut header = Self::header(tar::EntryType::Regular, mode);
+        header.set_size(content.len() as u64);
+        self.builder
+            .append_data(&mut header, name, content)
+            .map_err(|e| self.append_error(staged, e))
+    }
+
+    /// Write the end of the archive and flush any compression.
+    pub fn finish(self) -> Result<(), error::StagingError> {
+        let path = self.path;
+        self.builder
+            .into_inner()
+            .and_then(|encoder| encoder.finish())
+            .map_err(|e| {
+                error::ErrorKind::StagingFailed
+                    .error()
+                    .set_context(format!("Failed to finish {:?}", path))
+                    .set_cause(e)
+            })
+    }
+
+    fn header(entry_type: tar::EntryType, mode: u32) -> tar::Header {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header.set_size(0);
+        let now = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        header.set_mtime(now);
+        header
+    }
+
+    fn entry_name<'p>(
+        &self,
+        staged: &'p path::Path,
+    ) -> Result<&'p path::Path, error::StagingError> {
+        staged.strip_prefix(&self.root).map_err(|_| {
+            error::ErrorKind::StagingFailed.error().set_context(format!(
+                "{:?} is outside of the archive's root {:?}",
+                staged, self.root
+            ))
+        })
+    }
+
+    fn append_error(&self, staged: &path::Path, error: io::Error) -> error::StagingError {
+        error::ErrorKind::StagingFailed
+            .error()
+            .set_context(format!("Failed adding {:?} to {:?}", staged, self.path))
+            .set_cause(error)
+    }
+}
+
+#[cfg(all(feature = "tar", unix))]
+fn file_mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(all(feature = "tar", not(unix)))]
+fn file_mode(_metadata: &fs::Metadata) -> u32 {
+    0o755
+}
+
+/// Identifies a file with other hard links to it.
+#[cfg(all(feature = "tar", unix))]
+fn hard_link_key(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    if 1 < metadata.nlink() {
+        Some((metadata.dev(), metadata.ino()))
+    } else {
+        None
+    }
+}
+
+#[cfg(all(feature = "tar", not(unix)))]
+fn hard_link_key(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(feature = "tar")]
+impl fmt::Debug for TarWriter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TarWriter")
+            .field("path", &self.path)
+            .field("root", &self.root)
+            .finish()
+    }
+}
+
+/// Compression applied to a `TarWriter`'s archive.
+#[cfg(feature = "tar")]
+enum TarEncoder {
+    Plain(fs::File),
+    Gzip(flate2::write::GzEncoder<fs::File>),
+    Bzip2(bzip2::write::BzEncoder<fs::File>),
+    Xz(xz2::write::XzEncoder<fs::File>),
+}
+
+#[cfg(feature = "tar")]
+impl TarEncoder {
+    fn finish(self) -> io::Result<()> {
+        let mut file = match self {
+            TarEncoder::Plain(file) => file,
+            TarEncoder::Gzip(encoder) => encoder.finish()?,
+            TarEncoder::Bzip2(encoder) => encoder.finish()?,
+            TarEncoder::Xz(encoder) => encoder.finish()?,
+        };
+        file.flush()
+    }
+}
+
+#[cfg(feature = "tar")]
+impl Write for TarEncoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            TarEncoder::Plain(ref mut file) => file.write(buf),
+            TarEncoder::Gzip(ref mut encoder) => encoder.write(buf),
+            TarEncoder::Bzip2(ref mut encoder) => encoder.write(buf),
+            TarEncoder::Xz(ref mut encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            TarEncoder::Plain(ref mut file) => file.flush(),
+            TarEncoder::Gzip(ref mut encoder) => encoder.flush(),
+            TarEncoder::Bzip2(ref mut encoder) => encoder.flush(),
+            TarEncoder::Xz(ref mut encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::ffi;
+
+    #[test]
+    fn describe_defaults_to_display() {
+        #[derive(Debug)]
+        struct Noop;
+
+        impl fmt::Display for Noop {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "do nothing")
+            }
+        }
+
+        impl Action for Noop {
+            fn perform(&self, _options: &PerformOptions) -> Result<(), error::StagingError> {
+                Ok(())
+            }
+        }
+
+        let description = Noop.describe();
+        assert_eq!(description.kind, ActionKind::Other);
+        assert_eq!(description.destination, path::PathBuf::new());
+        assert_eq!(description.extra["display"], "do nothing");
+    }
+
+    #[test]
+    fn action_description_change() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let source = dir.join("source");
+        fs::write(&source, "content").unwrap();
+        let actions: Vec<Box<Action>> = vec![
+            Box::new(CreateDirectory::new(dir.join("bin"))),
+            Box::new(CopyFile::new(dir.join("bin/copy"), &source)),
+            Box::new(WriteInlineContent::new(dir.join("bin/inline"), "inline")),
+            Box::new(HardLink::new(dir.join("bin/link"), dir.join("bin/copy"))),
+        ];
+        for action in &actions {
+            assert_eq!(action.describe().change().unwrap(), Change::Added);
+            action.perform(&Default::default()).unwrap();
+            assert_eq!(action.describe().change().unwrap(), Change::Unchanged);
+        }
+
+        fs::write(dir.join("bin/inline"), "changed").unwrap();
+        assert_eq!(actions[2].describe().change().unwrap(), Change::Modified);
+        let touch = Touch::new(dir.join("bin/copy"));
+        assert_eq!(touch.describe().change().unwrap(), Change::Unknown);
+    }
+
+    #[test]
+    fn create_directory_existing_is_ok() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        fs::write(dir.join("file"), "content").unwrap();
+        CreateDirectory::new(dir).perform(&Default::default()).unwrap();
+        assert!(dir.join("file").exists());
+    }
+
+    #[test]
+    fn create_directory_must_be_empty() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let action = CreateDirectory::new(dir).must_be_empty(true);
+        action.perform(&Default::default()).unwrap();
+        fs::write(dir.join("file"), "content").unwrap();
+        let error = action.perform(&Default::default()).unwrap_err();
+        assert_eq!(error.kind(), error::ErrorKind::StagingFailed);
+    }
+
+    #[test]
+    fn verify_checksum() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let source = dir.join("source");
+        fs::write(&source, "content").unwrap();
+        let staged = dir.join("staged");
+        CopyFile::new(&staged, &source).perform(&Default::default()).unwrap();
+
+        let action = VerifyChecksum::new(&staged, &source);
+        action.perform(&Default::default()).unwrap();
+        fs::write(&staged, "corrupt").unwrap();
+        let error = action.perform(&Default::default()).unwrap_err();
+        assert_eq!(error.kind(), error::ErrorKind::StagingFailed);
+    }
+
+    #[test]
+    fn write_inline_content_replaces_atomically() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let staged = dir.join("nested/staged");
+        WriteInlineContent::new(&staged, "old").perform(&Default::default()).unwrap();
+        WriteInlineContent::new(&staged, "new").perform(&Default::default()).unwrap();
+        assert_eq!(fs::read_to_string(&staged).unwrap(), "new");
+        let entries: Vec<_> = fs::read_dir(dir.join("nested"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, vec![ffi::OsString::from("staged")]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_inline_content_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let mode = |path: &path::Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+
+        // New files follow the umask, like `fs::write`.
+        let umasked = dir.join("umasked");
+        fs::write(&umasked, "").unwrap();
+        let staged = dir.join("staged");
+        WriteInlineContent::new(&staged, "new").perform(&Default::default()).unwrap();
+        assert_eq!(mode(&staged), mode(&umasked));
+
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o700)).unwrap();
+        WriteInlineContent::new(&staged, "replaced").perform(&Default::default()).unwrap();
+        assert_eq!(mode(&staged), 0o700);
+
+        WriteInlineContent::new(&staged, "executable")
+            .mode(Some(0o755))
+            .perform(&Default::default())
+            .unwrap();
+        assert_eq!(mode(&staged), 0o755);
+        assert_eq!(fs::read_to_string(&staged).unwrap(), "executable");
+    }
+
+    #[test]
+    fn write_inline_content_temp_dir() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let temp_dir = dir.join("tmp");
+        fs::create_dir(&temp_dir).unwrap();
+        let staged = dir.join("stage/staged");
+        WriteInlineContent::new(&staged, "content")
+            .perform(&PerformOptions::new().temp_dir(Some(temp_dir.clone())))
+            .unwrap();
+        assert_eq!(fs::read_to_string(&staged).unwrap(), "content");
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+        assert_eq!(fs::read_dir(dir.join("stage")).unwrap().count(), 1);
+    }
+
+    #[cfg(all(feature = "tar", unix))]
+    #[test]
+    fn tar_writer_append_tree() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let source = dir.join("source");
+        fs::write(&source, "hello").unwrap();
+        let root = dir.join("stage");
+        let archive = dir.join("stage.tar.gz");
+
+        let actions: Vec<Box<Action>> = vec![
+            Box::new(CreateDirectory::new(root.join("bin"))),
+            Box::new(CopyFile::new(root.join("bin/hello"), &source)),
+            Box::new(HardLink::new(root.join("bin/link"), root.join("bin/hello"))),
+            Box::new(Symlink::new(root.join("hello"), "bin/hello")),
+            Box::new(WriteInlineContent::new(root.join("README"), "world")),
+        ];
+        for action in &actions {
+            action.perform(&Default::default()).unwrap();
+        }
+        let mut writer = TarWriter::create(&archive, &root).unwrap();
+        writer.append_tree().unwrap();
+        writer.finish().unwrap();
+
+        let file = fs::File::open(&archive).unwrap();
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(file));
+        let entries: Vec<_> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let path = entry.path().unwrap().into_owned();
+                let link = entry.link_name().unwrap().map(|l| l.into_owned());
+                let mut content = String::new();
+                entry.read_to_string(&mut content).unwrap();
+                (entry.header().entry_type(), path, link, content)
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (tar::EntryType::Regular, "README".into(), None, "world".into()),
+                (tar::EntryType::Directory, "bin".into(), None, "".into()),
+                (tar::EntryType::Regular, "bin/hello".into(), None, "hello".into()),
+                (tar::EntryType::Link, "bin/link".into(), Some("bin/hello".into()), "".into()),
+                (tar::EntryType::Symlink, "hello".into(), Some("bin/hello".into()), "".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn copy_file_transform() {
+        let registry = sync::Arc::new(TransformRegistry::new());
+        registry.register("uppercase", |content: &[u8]| Ok(content.to_ascii_uppercase()));
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let source = dir.join("source");
+        fs::write(&source, "hello").unwrap();
+
+        let staged = dir.join("staged");
+        let copy = CopyFile::new(&staged, &source)
+            .transform(Some("uppercase".into()))
+            .transform_registry(registry.clone());
+        copy.perform(&Default::default()).unwrap();
+        assert_eq!(fs::read_to_string(&staged).unwrap(), "HELLO");
+        // The transformed content isn't described, so can't be compared.
+        assert_eq!(copy.describe().change().unwrap(), Change::Unknown);
+
+        let unknown = CopyFile::new(dir.join("unknown"), &source)
+            .transform(Some("missing".into()))
+            .transform_registry(registry);
+        let error = unknown.perform(&Default::default()).unwrap_err();
+        assert!(error.to_string().contains("Unknown transform"));
+    }
+
+    #[test]
+    fn detect_conflicts_same_path() {
+        let actions: Vec<Box<Action>> = vec![
+            Box::new(CreateDirectory::new("/stage/bin")),
+            Box::new(CopyFile::new("/stage/bin/a", "/src/a")),
+            Box::new(SetPermissions::new("/stage/bin/a", 0o755)),
+            Box::new(CopyFile::new("/stage/bin/b", "/src/b")),
+            Box::new(CreateDirectory::new("/stage/bin")),
+            Box::new(CopyFile::new("/stage/bin/a", "/src/other")),
+        ];
+        assert_eq!(detect_conflicts(&actions), vec![(1, 5)]);
+    }
+
+    #[test]
+    fn source_paths() {
+        let copy = CopyFile::new("/stage/a", "/src/a");
+        assert_eq!(copy.source_paths(), vec![path::Path::new("/src/a")]);
+        let link = Symlink::new("/stage/b", "/src/a");
+        assert!(link.source_paths().is_empty());
+    }
+
+    #[cfg(all(target_os = "linux", feature = "sendfile"))]
+    #[test]
+    fn copy_sendfile_same_filesystem() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let small = dir.join("small");
+        fs::write(&small, "small").unwrap();
+        assert!(!copy_sendfile(&small, &dir.join("staged-small")).unwrap());
+
+        let large = dir.join("large");
+        let content = vec![7; SENDFILE_MIN_SIZE as usize];
+        fs::write(&large, &content).unwrap();
+        let staged = dir.join("staged-large");
+        assert!(same_filesystem(&large, &staged).unwrap());
+        assert!(copy_sendfile(&large, &staged).unwrap());
+        assert_eq!(fs::read(&staged).unwrap(), content);
+
+        assert!(!same_filesystem(&large, path::Path::new("/proc/staged")).unwrap());
+        assert!(!copy_sendfile(&large, path::Path::new("/proc/staged")).unwrap());
+    }
+
+    #[cfg(all(target_os = "linux", feature = "sparse"))]
+    #[test]
+    fn copy_sparse_preserves_holes() {
+        use std::io::{Seek, SeekFrom};
+        use std::os::unix::fs::MetadataExt;
+
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let source = dir.join("source");
+        let mut file = fs::File::create(&source).unwrap();
+        file.write_all(b"head").unwrap();
+        file.seek(SeekFrom::Start(1024 * 1024)).unwrap();
+        file.write_all(b"tail").unwrap();
+        drop(file);
+        let metadata = source.metadata().unwrap();
+        // Not every filesystem supports holes.
+        let is_sparse = metadata.blocks() * 512 < metadata.len();
+
+        let staged = dir.join("staged");
+        assert_eq!(copy_sparse(&source, &staged).unwrap(), is_sparse);
+        if is_sparse {
+            assert_eq!(fs::read(&staged).unwrap(), fs::read(&source).unwrap());
+            let staged = staged.metadata().unwrap();
+            assert_eq!(staged.len(), metadata.len());
+            assert!(staged.blocks() * 512 < staged.len());
+        }
+
+        let copied = dir.join("copied");
+        copy_file(&source, &copied).unwrap();
+        assert_eq!(fs::read(&copied).unwrap(), fs::read(&source).unwrap());
+    }
+
+    #[test]
+    fn copy_file_temp_dir() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let temp_dir = dir.join("tmp");
+        fs::create_dir(&temp_dir).unwrap();
+        let staged = dir.join("staged");
+        write_via_temp(&staged, Some(&temp_dir), |dest| {
+            assert!(dest.starts_with(&temp_dir));
+            fs::write(dest, "content")
+        }).unwrap();
+        assert_eq!(fs::read_to_string(&staged).unwrap(), "content");
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+
+        let source = dir.join("source");
+        fs::write(&source, "copied").unwrap();
+        CopyFile::new(&staged, &source)
+            .perform(&PerformOptions::new().temp_dir(Some(temp_dir.clone())))
+            .unwrap();
+        assert_eq!(fs::read_to_string(&staged).unwrap(), "copied");
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn copy_file_rate_limit() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let source = dir.join("source");
+        let content = vec![b'x'; 3 * THROTTLE_CHUNK_SIZE];
+        fs::write(&source, &content).unwrap();
+        // A burst of two chunks is allowed, so the third waits about half a second.
+        let bps = 2 * THROTTLE_CHUNK_SIZE as u64;
+
+        let staged = dir.join("staged");
+        let start = time::Instant::now();
+        CopyFile::new(&staged, &source)
+            .rate_limit(Some(bps))
+            .perform(&Default::default())
+            .unwrap();
+        assert!(start.elapsed() >= time::Duration::from_millis(400));
+        assert_eq!(fs::read(&staged).unwrap(), content);
+
+        let staged = dir.join("staged-with-options");
+        let start = time::Instant::now();
+        CopyFile::new(&staged, &source)
+            .perform(&PerformOptions::new().rate_limit(Some(bps)))
+            .unwrap();
+        assert!(start.elapsed() >= time::Duration::from_millis(400));
+        assert_eq!(fs::read(&staged).unwrap(), content);
+    }
+
+    #[cfg(all(feature = "shell-hooks", unix))]
+    #[test]
+    fn run_command_temp_dir() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let staged = dir.join("staged");
+        let command = RunCommand::new(
+            &staged,
+            "sh",
+            vec!["-c".to_owned(), "printf %s \"$TMPDIR\" > \"$0\"".to_owned()],
+        );
+        command
+            .perform(&PerformOptions::new().temp_dir(Some(dir.to_owned())))
+            .unwrap();
+        assert_eq!(fs::read_to_string(&staged).unwrap(), dir.to_str().unwrap());
+    }
+
+    #[test]
+    fn copy_file_buffer_size() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let source = dir.join("source");
+        fs::write(&source, "more than one buffer").unwrap();
+        let staged = dir.join("staged");
+        CopyFile::new(&staged, &source)
+            .buffer_size(Some(3))
+            .perform(&Default::default())
+            .unwrap();
+        assert_eq!(fs::read_to_string(&staged).unwrap(), "more than one buffer");
+
+        let staged = dir.join("staged-with-options");
+        CopyFile::new(&staged, &source)
+            .perform(&PerformOptions::new().buffer_size(Some(3)))
+            .unwrap();
+        assert_eq!(fs::read_to_string(&staged).unwrap(), "more than one buffer");
+    }
+
+    #[test]
+    fn copy_file_source_is_dir() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let error = CopyFile::new(dir.join("staged"), dir)
+            .perform(&Default::default())
+            .unwrap_err();
+        assert_eq!(error.kind(), error::ErrorKind::StagingFailed);
+        assert!(error.to_string().contains("Source is a directory"));
+        assert!(!dir.join("staged").exists());
+    }
+
+    #[test]
+    fn copy_file_on_conflict() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let source = dir.join("source");
+        fs::write(&source, "new").unwrap();
+        let staged = dir.join("staged");
+        fs::write(&staged, "old").unwrap();
+
+        let error = CopyFile::new(&staged, &source)
+            .on_conflict(ConflictPolicy::Error)
+            .perform(&Default::default())
+            .unwrap_err();
+        assert_eq!(error.kind(), error::ErrorKind::StagingFailed);
+        assert!(error.to_string().contains("already exists"));
+        assert_eq!(fs::read_to_string(&staged).unwrap(), "old");
+
+        let options = PerformOptions::new().on_conflict(Some(ConflictPolicy::Error));
+        let error = CopyFile::new(&staged, &source)
+            .on_conflict(ConflictPolicy::Overwrite)
+            .perform(&options)
+            .unwrap_err();
+        assert_eq!(error.kind(), error::ErrorKind::StagingFailed);
+        assert_eq!(fs::read_to_string(&staged).unwrap(), "old");
+
+        CopyFile::new(&staged, &source)
+            .on_conflict(ConflictPolicy::Skip)
+            .perform(&Default::default())
+            .unwrap();
+        assert_eq!(fs::read_to_string(&staged).unwrap(), "old");
+
+        CopyFile::new(&staged, &source)
+            .on_conflict(ConflictPolicy::Verify)
+            .perform(&Default::default())
+            .unwrap();
+        assert_eq!(fs::read_to_string(&staged).unwrap(), "new");
+
+        CopyFile::new(&staged, &source).perform(&Default::default()).unwrap();
+        assert_eq!(fs::read_to_string(&staged).unwrap(), "new");
+    }
+
+    #[test]
+    fn verify_encoding() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let source = dir.join("source");
+        fs::write(&source, b"caf\xc3\xa9").unwrap();
+        VerifyEncoding::new(&source, Encoding::Utf8)
+            .perform(&Default::default())
+            .unwrap();
+        let error = VerifyEncoding::new(&source, Encoding::Ascii)
+            .perform(&Default::default())
+            .unwrap_err();
+        assert_eq!(error.kind(), error::ErrorKind::HarvestingFailed);
+        assert!(error.to_string().contains("offset 3"));
+
+        fs::write(&source, b"caf\xe9").unwrap();
+        VerifyEncoding::new(&source, Encoding::Latin1)
+            .perform(&Default::default())
+            .unwrap();
+        let error = VerifyEncoding::new(&source, Encoding::Utf8)
+            .perform(&Default::default())
+            .unwrap_err();
+        assert!(error.to_string().contains("offset 3"));
+    }
+
+    #[cfg(feature = "validators")]
+    #[test]
+    fn copy_file_validators() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let source = dir.join("source");
+        fs::write(&source, "content").unwrap();
+
+        let staged = dir.join("staged");
+        let action = CopyFile::new(&staged, &source)
+            .add_validator(require_text())
+            .add_validator(require_min_size(4));
+        action.perform(&Default::default()).unwrap();
+        assert!(staged.exists());
+
+        let staged = dir.join("elf");
+        let action = CopyFile::new(&staged, &source).add_validator(require_elf());
+        assert!(action.perform(&Default::default()).is_err());
+        assert!(!staged.exists());
+    }
+
+    #[test]
+    fn symlink_overwrite_existing() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let staged = dir.join("link");
+        Symlink::new(&staged, "old").perform(&Default::default()).unwrap();
+
+        let error = Symlink::new(&staged, "new").perform(&Default::default()).unwrap_err();
+        assert!(error.to_string().contains("\"old\""));
+
+        Symlink::new(&staged, "new")
+            .overwrite_existing(true)
+            .perform(&Default::default())
+            .unwrap();
+        assert_eq!(fs::read_link(&staged).unwrap(), path::Path::new("new"));
+    }
+
+    #[test]
+    fn symlink_points_staged_to_target() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        fs::write(dir.join("target"), "content").unwrap();
+        let staged = dir.join("sub/link");
+        let action = Symlink::new(&staged, "../target");
+        assert_eq!(action.describe().change().unwrap(), Change::Added);
+
+        action.perform(&Default::default()).unwrap();
+        assert_eq!(fs::read_link(&staged).unwrap(), path::Path::new("../target"));
+        assert_eq!(fs::read_to_string(&staged).unwrap(), "content");
+        assert_eq!(action.describe().change().unwrap(), Change::Unchanged);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn symlink_to_directory() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        fs::create_dir_all(dir.join("target")).unwrap();
+        let staged = dir.join("link");
+        Symlink::new(&staged, "target").perform(&Default::default()).unwrap();
+        assert!(staged.is_dir());
+    }
+
+    #[cfg(feature = "reflink")]
+    #[test]
+    fn copy_file_prefer_reflink() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let source = dir.join("source");
+        fs::write(&source, "content").unwrap();
+        let staged = dir.join("staged");
+        fs::write(&staged, "previous content").unwrap();
+        // Falls back to copying when the filesystem doesn't support reflinks.
+        CopyFile::new(&staged, &source)
+            .prefer_reflink(true)
+            .perform(&Default::default())
+            .unwrap();
+        assert_eq!(fs::read_to_string(&staged).unwrap(), "content");
+    }
+
+    #[cfg(feature = "xattr")]
+    #[test]
+    fn copy_file_preserves_xattrs() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let source = dir.join("source");
+        fs::write(&source, "content").unwrap();
+        if xattr::set(&source, "user.stager", b"value").is_err() {
+            // Filesystem doesn't support user xattrs.
+            return;
+        }
+        let staged = dir.join("staged");
+        CopyFile::new(&staged, &source)
+            .preserve_xattrs(true)
+            .perform(&Default::default())
+            .unwrap();
+        assert_eq!(
+            xattr::get(&staged, "user.stager").unwrap(),
+            Some(b"value".to_vec())
+        );
+    }
+
+    #[test]
+    fn verify_staging_checks() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        fs::write(dir.join("file"), "content").unwrap();
+        let paths = vec![path::PathBuf::from("file"), path::PathBuf::from("missing")];
+        let verify = |check| VerifyStaging::new(dir, paths.clone(), 100, vec![check]);
+
+        let hash = "ed7002b439e9ac845f22357d822bac1444730fbdb6016d3ec9432297b9ec9f73".to_owned();
+        let matches = verify(StagingCheck::StagedFileMatchesHash("file".into(), hash));
+        assert!(matches.perform(&Default::default()).is_ok());
+        let mismatch = verify(StagingCheck::StagedFileMatchesHash("file".into(), "00".into()));
+        assert!(mismatch.perform(&Default::default()).is_err());
+        assert!(mismatch.check_plan().is_ok());
+
+        let count = verify(StagingCheck::FileCount { min: 2, max: 2 });
+        assert!(count.check_plan().is_ok());
+        assert!(count.perform(&Default::default()).is_err());
+
+        let size = verify(StagingCheck::MaxTotalSize(10));
+        assert!(size.perform(&Default::default()).is_ok());
+        assert!(size.check_plan().is_err());
+
+        let exists = verify(StagingCheck::FileExists("file".into()));
+        assert!(exists.perform(&Default::default()).is_ok());
+        assert!(verify(StagingCheck::FileExists("other".into())).check_plan().is_err());
+
+        let actions: Vec<Box<Action>> = vec![
+            Box::new(CreateDirectory::new(dir.join("bin"))),
+            Box::new(WriteInlineContent::new(dir.join("bin/a"), "12345")),
+            Box::new(WriteInlineContent::new(dir.join("bin/b"), "12345")),
+        ];
+        let plan = |check| VerifyStaging::for_actions(dir, &actions, vec![check]);
+        assert!(plan(StagingCheck::FileCount { min: 2, max: 2 }).check_plan().is_ok());
+        assert!(plan(StagingCheck::FileExists("bin/b".into())).check_plan().is_ok());
+        assert!(plan(StagingCheck::MaxTotalSize(9)).check_plan().is_err());
+    }
+}
@@ -5,27 +5,42 @@ extern crate exitcode;
 extern crate globwalk;
 extern crate liquid;
 extern crate stager;
+extern crate tempfile;
+extern crate walkdir;
 
 #[macro_use]
 extern crate failure;
+#[cfg(not(feature = "tracing"))]
 #[macro_use]
 extern crate log;
 #[macro_use]
 extern crate structopt;
 
 #[cfg(feature = "serde_json")]
+#[macro_use]
 extern crate serde_json;
 #[cfg(feature = "serde_yaml")]
 extern crate serde_yaml;
 #[cfg(feature = "toml")]
 extern crate toml;
+#[cfg(feature = "tracing")]
+#[macro_use]
+extern crate tracing;
+#[cfg(feature = "tracing")]
+extern crate tracing_subscriber;
 
+use std::collections;
+use std::env;
 use std::ffi;
+use std::fmt;
 use std::fs;
 use std::io;
 use std::io::Write;
 use std::path;
 use std::process;
+use std::str;
+use std::sync;
+use std::time;
 
 use failure::ResultExt;
 use structopt::StructOpt;
@@ -33,34 +48,36 @@ use structopt::StructOpt;
 use stager::builder::ActionBuilder;
 use stager::de::ActionRender;
 
+mod pipeline;
+
 mod stage {
     use super::*;
     use std::io::Read;
 
     #[cfg(feature = "serde_yaml")]
-    pub fn load_yaml(path: &path::Path) -> Result<stager::de::MapStage, failure::Error> {
+    pub fn load_yaml(path: &path::Path) -> Result<stager::de::Staging, failure::Error> {
         let f = fs::File::open(path)?;
         serde_yaml::from_reader(f).map_err(|e| e.into())
     }
 
     #[cfg(not(feature = "serde_yaml"))]
-    pub fn load_yaml(_path: &path::Path) -> Result<stager::de::MapStage, failure::Error> {
+    pub fn load_yaml(_path: &path::Path) -> Result<stager::de::Staging, failure::Error> {
         bail!("yaml is unsupported");
     }
 
     #[cfg(feature = "serde_json")]
-    pub fn load_json(path: &path::Path) -> Result<stager::de::MapStage, failure::Error> {
+    pub fn load_json(path: &path::Path) -> Result<stager::de::Staging, failure::Error> {
         let f = fs::File::open(path)?;
         serde_json::from_reader(f).map_err(|e| e.into())
     }
 
     #[cfg(not(feature = "serde_json"))]
-    pub fn load_json(_path: &path::Path) -> Result<stager::de::MapStage, failure::Error> {
+    pub fn load_json(_path: &path::Path) -> Result<stager::de::Staging, failure::Error> {
         bail!("json is unsupported");
     }
 
     #[cfg(feature = "toml")]
-    pub fn load_toml(path: &path::Path) -> Result<stager::de::MapStage, failure::Error> {
+    pub fn load_toml(path: &path::Path) -> Result<stager::de::Staging, failure::Error> {
         let mut f = fs::File::open(path)?;
         let mut text = String::new();
         f.read_to_string(&mut text)?;
@@ -68,12 +85,115 @@ mod stage {
     }
 
     #[cfg(not(feature = "toml"))]
-    pub fn load_toml(_path: &path::Path) -> Result<stager::de::MapStage, failure::Error> {
+    pub fn load_toml(_path: &path::Path) -> Result<stager::de::Staging, failure::Error> {
+        bail!("toml is unsupported");
+    }
+
+    #[cfg(feature = "cbor")]
+    pub fn load_cbor(path: &path::Path) -> Result<stager::de::Staging, failure::Error> {
+        let f = io::BufReader::new(fs::File::open(path)?);
+        stager::de::Staging::from_cbor_reader(f).map_err(|e| e.into())
+    }
+
+    #[cfg(not(feature = "cbor"))]
+    pub fn load_cbor(_path: &path::Path) -> Result<stager::de::Staging, failure::Error> {
+        bail!("cbor is unsupported");
+    }
+
+    #[cfg(feature = "serde_yaml")]
+    pub fn save_yaml(
+        path: &path::Path,
+        stage: &stager::de::Staging,
+    ) -> Result<(), failure::Error> {
+        let f = fs::File::create(path)?;
+        serde_yaml::to_writer(f, stage).map_err(|e| e.into())
+    }
+
+    #[cfg(not(feature = "serde_yaml"))]
+    pub fn save_yaml(
+        _path: &path::Path,
+        _stage: &stager::de::Staging,
+    ) -> Result<(), failure::Error> {
+        bail!("yaml is unsupported");
+    }
+
+    #[cfg(feature = "serde_json")]
+    pub fn save_json(
+        path: &path::Path,
+        stage: &stager::de::Staging,
+    ) -> Result<(), failure::Error> {
+        let f = fs::File::create(path)?;
+        serde_json::to_writer_pretty(f, stage).map_err(|e| e.into())
+    }
+
+    #[cfg(not(feature = "serde_json"))]
+    pub fn save_json(
+        _path: &path::Path,
+        _stage: &stager::de::Staging,
+    ) -> Result<(), failure::Error> {
+        bail!("json is unsupported");
+    }
+
+    #[cfg(feature = "cbor")]
+    pub fn save_cbor(
+        path: &path::Path,
+        stage: &stager::de::Staging,
+    ) -> Result<(), failure::Error> {
+        let mut f = io::BufWriter::new(fs::File::create(path)?);
+        stage.to_cbor_writer(&mut f)?;
+        f.flush()?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "cbor"))]
+    pub fn save_cbor(
+        _path: &path::Path,
+        _stage: &stager::de::Staging,
+    ) -> Result<(), failure::Error> {
+        bail!("cbor is unsupported");
+    }
+
+    #[cfg(feature = "toml")]
+    pub fn save_toml(
+        path: &path::Path,
+        stage: &stager::de::Staging,
+    ) -> Result<(), failure::Error> {
+        let text = toml::to_string(stage)?;
+        let mut f = fs::File::create(path)?;
+        f.write_all(text.as_bytes())?;
+        Ok(())
+    }
+
+    #[cfg(feature = "serde_yaml")]
+    pub fn to_yaml(stage: &stager::de::Staging) -> Result<String, failure::Error> {
+        serde_yaml::to_string(stage).map_err(|e| e.into())
+    }
+
+    #[cfg(not(feature = "serde_yaml"))]
+    pub fn to_yaml(_stage: &stager::de::Staging) -> Result<String, failure::Error> {
+        bail!("yaml is unsupported");
+    }
+
+    #[cfg(feature = "serde_json")]
+    pub fn to_json(stage: &stager::de::Staging) -> Result<String, failure::Error> {
+        serde_json::to_string_pretty(stage).map_err(|e| e.into())
+    }
+
+    #[cfg(not(feature = "serde_json"))]
+    pub fn to_json(_stage: &stager::de::Staging) -> Result<String, failure::Error> {
+        bail!("json is unsupported");
+    }
+
+    #[cfg(not(feature = "toml"))]
+    pub fn save_toml(
+        _path: &path::Path,
+        _stage: &stager::de::Staging,
+    ) -> Result<(), failure::Error> {
         bail!("toml is unsupported");
     }
 }
 
-fn load_stage(path: &path::Path) -> Result<stager::de::MapStage, failure::Error> {
+fn load_stage(path: &path::Path) -> Result<stager::de::Staging, failure::Error> {
     let extension = path.extension().unwrap_or_default();
     let value = if extension == ffi::OsStr::new("yaml") {
         stage::load_yaml(path)
@@ -81,6 +201,8 @@ fn load_stage(path: &path::Path) -> Result<stager::de::MapStage, failure::Error>
         stage::load_toml(path)
     } else if extension == ffi::OsStr::new("json") {
         stage::load_json(path)
+    } else if extension == ffi::OsStr::new("cbor") {
+        stage::load_cbor(path)
     } else {
         bail!("Unsupported file type");
     }?;
@@ -88,6 +210,465 @@ fn load_stage(path: &path::Path) -> Result<stager::de::MapStage, failure::Error>
     Ok(value)
 }
 
+const STAGE_FILE_NAMES: &[&str] = &[
+    ".stager.yaml",
+    ".stager.toml",
+    ".stager.json",
+    "stager.yaml",
+    "stager.toml",
+    "stager.json",
+];
+
+fn discover_stage(cwd: &path::Path) -> Result<path::PathBuf, failure::Error> {
+    for dir in cwd.ancestors() {
+        for name in STAGE_FILE_NAMES {
+            let candidate = dir.join(name);
+            if candidate.is_file() {
+                debug!("Discovered stage file {:?}", candidate);
+                return Ok(candidate);
+            }
+        }
+    }
+    bail!(
+        "No stage file ({}) found in {:?} or any parent directory",
+        STAGE_FILE_NAMES.join(", "),
+        cwd
+    );
+}
+
+fn save_stage(path: &path::Path, stage: &stager::de::Staging) -> Result<(), failure::Error> {
+    let extension = path.extension().unwrap_or_default();
+    if extension == ffi::OsStr::new("yaml") {
+        stage::save_yaml(path, stage)
+    } else if extension == ffi::OsStr::new("toml") {
+        stage::save_toml(path, stage)
+    } else if extension == ffi::OsStr::new("json") {
+        stage::save_json(path, stage)
+    } else if extension == ffi::OsStr::new("cbor") {
+        stage::save_cbor(path, stage)
+    } else {
+        bail!("Unsupported file type");
+    }
+}
+
+mod plan {
+    use super::*;
+
+    #[cfg(feature = "serde_json")]
+    pub fn load(
+        path: &path::Path,
+    ) -> Result<Vec<stager::action::ActionDescription>, failure::Error> {
+        stager::action::load_plan(path).map_err(|e| e.into())
+    }
+
+    #[cfg(not(feature = "serde_json"))]
+    pub fn load(
+        _path: &path::Path,
+    ) -> Result<Vec<stager::action::ActionDescription>, failure::Error> {
+        bail!("json is unsupported");
+    }
+
+    #[cfg(feature = "serde_json")]
+    pub fn save(
+        path: &path::Path,
+        actions: &[stager::action::ActionDescription],
+    ) -> Result<(), failure::Error> {
+        stager::action::save_plan(actions, path).map_err(|e| e.into())
+    }
+
+    #[cfg(not(feature = "serde_json"))]
+    pub fn save(
+        _path: &path::Path,
+        _actions: &[stager::action::ActionDescription],
+    ) -> Result<(), failure::Error> {
+        bail!("json is unsupported");
+    }
+
+    #[cfg(feature = "serde_json")]
+    pub fn to_json(
+        actions: &[stager::action::ActionDescription],
+    ) -> Result<String, failure::Error> {
+        serde_json::to_string_pretty(actions).map_err(|e| e.into())
+    }
+
+    #[cfg(not(feature = "serde_json"))]
+    pub fn to_json(
+        _actions: &[stager::action::ActionDescription],
+    ) -> Result<String, failure::Error> {
+        bail!("json is unsupported");
+    }
+
+    #[cfg(feature = "serde_yaml")]
+    pub fn to_yaml(
+        actions: &[stager::action::ActionDescription],
+    ) -> Result<String, failure::Error> {
+        serde_yaml::to_string(actions).map_err(|e| e.into())
+    }
+
+    #[cfg(not(feature = "serde_yaml"))]
+    pub fn to_yaml(
+        _actions: &[stager::action::ActionDescription],
+    ) -> Result<String, failure::Error> {
+        bail!("yaml is unsupported");
+    }
+}
+
+mod audit {
+    use super::*;
+
+    #[cfg(feature = "serde_json")]
+    pub type AuditLog = stager::action::AuditLog;
+
+    #[cfg(not(feature = "serde_json"))]
+    pub type AuditLog = ();
+
+    #[cfg(feature = "serde_json")]
+    pub fn open(path: &path::Path) -> Result<AuditLog, failure::Error> {
+        let log = AuditLog::new(path).with_context(|_| format!("Failed opening {:?}", path))?;
+        Ok(log)
+    }
+
+    #[cfg(not(feature = "serde_json"))]
+    pub fn open(_path: &path::Path) -> Result<AuditLog, failure::Error> {
+        bail!("json is unsupported");
+    }
+
+    #[cfg(feature = "serde_json")]
+    pub fn log(
+        log: &AuditLog,
+        action: &stager::action::Action,
+        duration: time::Duration,
+        result: &Result<(), stager::error::StagingError>,
+    ) -> Result<(), failure::Error> {
+        log.log_action(&action.describe(), duration, result)?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "serde_json"))]
+    pub fn log(
+        _log: &AuditLog,
+        _action: &stager::action::Action,
+        _duration: time::Duration,
+        _result: &Result<(), stager::error::StagingError>,
+    ) -> Result<(), failure::Error> {
+        bail!("json is unsupported");
+    }
+}
+
+mod report {
+    use super::*;
+
+    /// Result of performing an action, for the `--output-json` report.
+    #[derive(Debug)]
+    #[cfg_attr(not(feature = "serde_json"), allow(dead_code))]
+    pub struct Outcome {
+        pub action: stager::action::ActionDescription,
+        pub duration: time::Duration,
+        pub error: Option<String>,
+    }
+
+    impl Outcome {
+        pub fn new(
+            action: &stager::action::Action,
+            duration: time::Duration,
+            result: &Result<(), stager::error::StagingError>,
+        ) -> Self {
+            Self {
+                action: action.describe(),
+                duration,
+                error: result.as_ref().err().map(|e| e.to_string()),
+            }
+        }
+    }
+
+    #[cfg(feature = "serde_json")]
+    pub fn save(
+        path: &path::Path,
+        success: bool,
+        outcomes: &[Outcome],
+        throughput: Option<&Throughput>,
+    ) -> Result<(), failure::Error> {
+        let actions: Vec<_> = outcomes
+            .iter()
+            .map(|o| {
+                json!({
+                    "type": o.action.kind,
+                    "destination": o.action.destination,
+                    "source": o.action.source,
+                    "duration_ms": o.duration.as_millis() as u64,
+                    "error": o.error,
+                })
+            })
+            .collect();
+        let mut report = json!({ "success": success, "actions": actions });
+        if let Some(throughput) = throughput {
+            report["stats"] = json!({
+                "estimated_bytes": throughput.estimated_bytes,
+                "files": throughput.files,
+                "elapsed_ms": throughput.elapsed.as_millis() as u64,
+                "megabytes_per_second": throughput.megabytes_per_second(),
+            });
+        }
+        let f = fs::File::create(path)?;
+        serde_json::to_writer_pretty(f, &report).map_err(|e| e.into())
+    }
+
+    #[cfg(not(feature = "serde_json"))]
+    pub fn save(
+        _path: &path::Path,
+        _success: bool,
+        _outcomes: &[Outcome],
+        _throughput: Option<&Throughput>,
+    ) -> Result<(), failure::Error> {
+        bail!("json is unsupported");
+    }
+}
+
+mod tree {
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct Node(collections::BTreeMap<ffi::OsString, Node>);
+
+    /// Render the paths staged by `actions` into `target_dir` as a tree rooted at `output`, like
+    /// `tree(1)`.
+    pub fn render(
+        output: &path::Path,
+        target_dir: &path::Path,
+        actions: &[Box<stager::action::Action>],
+    ) -> String {
+        let mut root = Node::default();
+        for action in actions {
+            let destination = action.describe().destination;
+            // Skip actions that only read, like checking a source file.
+            let destination = match destination.strip_prefix(target_dir) {
+                Ok(destination) => destination,
+                Err(_) => continue,
+            };
+            let mut node = &mut root;
+            for component in destination.components() {
+                node = node.0
+                    .entry(component.as_os_str().to_owned())
+                    .or_insert_with(Node::default);
+            }
+        }
+
+        let mut tree = format!("{}\n", output.display());
+        render_children(&root, "", &mut tree);
+        tree
+    }
+
+    fn render_children(node: &Node, indent: &str, tree: &mut String) {
+        let mut children = node.0.iter().peekable();
+        while let Some((name, child)) = children.next() {
+            let last = children.peek().is_none();
+            let (branch, nested) = if last {
+                ("\u{2514}\u{2500}\u{2500} ", "    ")
+            } else {
+                ("\u{251c}\u{2500}\u{2500} ", "\u{2502}   ")
+            };
+            tree.push_str(indent);
+            tree.push_str(branch);
+            tree.push_str(&name.to_string_lossy());
+            tree.push('\n');
+            render_children(child, &format!("{}{}", indent, nested), tree);
+        }
+    }
+}
+
+mod man {
+    use super::*;
+
+    use std::iter;
+
+    use structopt::clap;
+
+    /// Help is wrapped to fit a man page rather than the terminal.
+    const WIDTH: usize = 78;
+
+    const EXAMPLE_STAGE: &str = r#"# stage.yaml
+"/bin":
+  - type: SourceFile
+    path: target/release/app
+    executable: true
+"/share/doc/app":
+  - type: SourceFiles
+    path: .
+    pattern: ["README.md", "LICENSE*"]
+"#;
+
+    /// Render a `staging(1)` man page in roff, built from the `--help` of each command.
+    pub fn render() -> Result<String, failure::Error> {
+        let mut page = String::new();
+        page.push_str(&format!(
+            ".TH STAGING 1 \"\" \"staging {}\" \"User Commands\"\n",
+            env!("CARGO_PKG_VERSION")
+        ));
+        page.push_str(".SH NAME\n");
+        page.push_str(&format!(
+            "staging \\- {}\n",
+            escape(env!("CARGO_PKG_DESCRIPTION"))
+        ));
+        page.push_str(".SH SYNOPSIS\n");
+        page.push_str(".B staging\n[FLAGS] [OPTIONS] [SUBCOMMAND]\n");
+
+        let top = help(&[])?;
+        page.push_str(".SH OPTIONS\n");
+        preformatted(&mut page, &top);
+        page.push_str(".SH SUBCOMMANDS\n");
+        for (name, description) in subcommands(&top) {
+            page.push_str(&format!(".SS {}\n{}\n", name, escape(&description)));
+            preformatted(&mut page, &help(&[name])?);
+        }
+
+        page.push_str(".SH ENVIRONMENT\n");
+        page.push_str(".TP\n.B STAGER_BASE_PATH\n");
+        page.push_str(
+            "Directory that relative source paths are resolved against, unless the stage file \
+             sets base_path.\n",
+        );
+        page.push_str(".TP\n.B STAGER_MAX_TARGETS\n");
+        page.push_str(
+            "Fail before staging anything if the stage file has more targets than this, unless \
+             it sets max_target_count.\n",
+        );
+        page.push_str(".SH FILES\n");
+        page.push_str(&format!(
+            "Without \\fB\\-\\-input\\fR, the first of {} found in the current directory or its \
+             parents is used.\n",
+            STAGE_FILE_NAMES
+                .iter()
+                .map(|n| format!("\\fI{}\\fR", escape(n)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+        page.push_str(".SH EXAMPLES\n");
+        page.push_str("Stage a release build and its documentation into \\fIdist/\\fR:\n");
+        preformatted(&mut page, EXAMPLE_STAGE);
+        preformatted(&mut page, "staging -i stage.yaml -o dist/\n");
+        Ok(page)
+    }
+
+    /// The long help for the subcommand at `path`, or the top-level help if empty, from `USAGE:`
+    /// on.
+    fn help(path: &[&str]) -> Result<String, failure::Error> {
+        let app = Arguments::clap().set_term_width(WIDTH);
+        let args = iter::once("staging")
+            .chain(path.iter().cloned())
+            .chain(iter::once("--help"));
+        match app.get_matches_from_safe(args) {
+            Err(ref e) if e.kind == clap::ErrorKind::HelpDisplayed => {
+                // Skip the name, version, and description, which are already in the page.
+                let usage = e.message.find("USAGE:").unwrap_or(0);
+                Ok(e.message[usage..].to_owned())
+            }
+            Err(e) => Err(e.into()),
+            Ok(_) => bail!("No help for {:?}", path),
+        }
+    }
+
+    /// Names and descriptions listed under `SUBCOMMANDS:` in `help`, except `help` itself.
+    fn subcommands(help: &str) -> Vec<(&str, String)> {
+        let mut subcommands: Vec<(&str, String)> = vec![];
+        let lines = help.lines()
+            .skip_while(|line| line.trim() != "SUBCOMMANDS:")
+            .skip(1)
+            .take_while(|line| !line.trim().is_empty());
+        for line in lines {
+            let mut words = line.split_whitespace();
+            // Wrapped descriptions are indented further than the names.
+            if line.starts_with("    ") && !line[4..].starts_with(' ') {
+                let name = words.next().unwrap_or_default();
+                subcommands.push((name, words.collect::<Vec<_>>().join(" ")));
+            } else if let Some(&mut (_, ref mut description)) = subcommands.last_mut() {
+                for word in words {
+                    description.push(' ');
+                    description.push_str(word);
+                }
+            }
+        }
+        subcommands.retain(|&(name, _)| name != "help");
+        subcommands
+    }
+
+    fn preformatted(page: &mut String, text: &str) {
+        page.push_str(".PP\n.nf\n");
+        for line in text.trim_end().lines() {
+            let line = escape(line);
+            // Lines starting with a control character would be read as requests.
+            if line.starts_with('.') || line.starts_with('\'') {
+                page.push_str("\\&");
+            }
+            page.push_str(&line);
+            page.push('\n');
+        }
+        page.push_str(".fi\n");
+    }
+
+    fn escape(text: &str) -> String {
+        text.replace('\\', "\\e").replace('-', "\\-")
+    }
+}
+
+mod archive {
+    use super::*;
+
+    /// Whether `--output` names a tar archive rather than a directory.
+    pub fn is_archive(path: &path::Path) -> bool {
+        let name = path.to_string_lossy();
+        [".tar", ".tar.gz", ".tgz", ".tar.bz2", ".tar.xz"]
+            .iter()
+            .any(|extension| name.ends_with(extension))
+    }
+
+    /// Check `tar` is supported before staging anything.
+    #[cfg(feature = "tar")]
+    pub fn check_supported() -> Result<(), failure::Error> {
+        Ok(())
+    }
+
+    #[cfg(not(feature = "tar"))]
+    pub fn check_supported() -> Result<(), failure::Error> {
+        bail!("tar is unsupported");
+    }
+
+    /// Write everything staged in `staged_dir` to the archive at `path`.
+    #[cfg(feature = "tar")]
+    pub fn write(path: &path::Path, staged_dir: &path::Path) -> Result<(), failure::Error> {
+        let mut writer = stager::action::TarWriter::create(path, staged_dir)?;
+        writer.append_tree()?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "tar"))]
+    pub fn write(_path: &path::Path, _staged_dir: &path::Path) -> Result<(), failure::Error> {
+        bail!("tar is unsupported");
+    }
+}
+
+/// Find actions already completed by an interrupted run, recording the current plan for the
+/// next one.
+fn resume(
+    plan_path: &path::Path,
+    actions: &[Box<stager::action::Action>],
+) -> Result<collections::BTreeSet<usize>, failure::Error> {
+    let descriptions: Vec<_> = actions.iter().map(|a| a.describe()).collect();
+    let mut completed = collections::BTreeSet::new();
+    if plan_path.exists() {
+        let previous: collections::HashSet<_> = plan::load(plan_path)?.into_iter().collect();
+        for (i, description) in descriptions.iter().enumerate() {
+            if previous.contains(description)
+                && description.change()? == stager::action::Change::Unchanged
+            {
+                completed.insert(i);
+            }
+        }
+    }
+    plan::save(plan_path, &descriptions)?;
+    Ok(completed)
+}
+
 mod object {
     use super::*;
     use std::io::Read;
@@ -176,7 +757,7 @@ fn load_data_dirs(roots: &[path::PathBuf]) -> Result<liquid::Object, failure::Er
     let mut object = liquid::Object::new();
     // TODO(epage): swap out globwalk for something that uses gitignore so we can have
     // exclusion support.
-    let patterns = [
+    let patterns: &[&str] = &[
         #[cfg(feature = "serde_yaml")]
         "*.yaml",
         #[cfg(feature = "serde_json")]
@@ -185,7 +766,7 @@ fn load_data_dirs(roots: &[path::PathBuf]) -> Result<liquid::Object, failure::Er
         "*.toml",
     ];
     for root in roots {
-        for entry in globwalk::GlobWalker::from_patterns(root, &patterns)? {
+        for entry in globwalk::GlobWalkerBuilder::from_patterns(root, patterns).build()? {
             let entry = entry?;
             let data_file = entry.path();
             let data = load_data(data_file)?;
@@ -223,32 +804,584 @@ fn load_data_dirs(roots: &[path::PathBuf]) -> Result<liquid::Object, failure::Er
     Ok(object)
 }
 
+fn load_env_vars(object: &mut liquid::Object, prefixes: &[String]) {
+    for prefix in prefixes {
+        for (key, value) in env::vars_os() {
+            let (key, value) = match (key.to_str(), value.to_str()) {
+                (Some(key), Some(value)) => (key.to_owned(), value.to_owned()),
+                _ => {
+                    if key.to_string_lossy().starts_with(prefix.as_str()) {
+                        warn!("Skipping non-UTF-8 environment variable: {:?}", key);
+                    }
+                    continue;
+                }
+            };
+            if !key.starts_with(prefix.as_str()) {
+                continue;
+            }
+            let key = key[prefix.len()..].to_lowercase();
+            if key.is_empty() {
+                continue;
+            }
+            object
+                .entry(key)
+                .or_insert_with(|| liquid::Value::scalar(value));
+        }
+    }
+}
+
+/// Load `.env` files, in order, as the `env` template global.  Later files take precedence.
+fn load_env_files(
+    object: &mut liquid::Object,
+    paths: &[path::PathBuf],
+) -> Result<(), failure::Error> {
+    if paths.is_empty() {
+        return Ok(());
+    }
+    let mut env = liquid::Object::new();
+    for path in paths {
+        let text =
+            fs::read_to_string(path).with_context(|_| format!("Failed to read {:?}", path))?;
+        let vars =
+            parse_env_file(&text).with_context(|_| format!("Failed to parse {:?}", path))?;
+        for (key, value) in vars {
+            env.insert(key, liquid::Value::scalar(value));
+        }
+    }
+    object.insert("env".to_owned(), liquid::Value::Object(env));
+    Ok(())
+}
+
+/// Parse `KEY=VALUE` lines, skipping blank lines and `#` comments.
+///
+/// Values may be wrapped in single quotes, taken literally, or double quotes, which support `\n`,
+/// `\"`, and `\\` escapes.  Unquoted values end at a ` #` comment.  A leading `export ` is
+/// ignored.
+fn parse_env_file(text: &str) -> Result<Vec<(String, String)>, failure::Error> {
+    let mut vars = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.trim_start_matches("export ").trim_start();
+        let mut parts = line.splitn(2, '=');
+        let key = parts.next().unwrap_or_default().trim();
+        let value = parts
+            .next()
+            .ok_or_else(|| format_err!("Line {}: expected KEY=VALUE", index + 1))?
+            .trim();
+        if key.is_empty() {
+            bail!("Line {}: missing KEY", index + 1);
+        }
+        let value = if value.len() >= 2 && value.starts_with('\'') && value.ends_with('\'') {
+            value[1..value.len() - 1].to_owned()
+        } else if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+            let mut unescaped = String::new();
+            let mut chars = value[1..value.len() - 1].chars();
+            while let Some(c) = chars.next() {
+                if c != '\\' {
+                    unescaped.push(c);
+                    continue;
+                }
+                match chars.next() {
+                    Some('n') => unescaped.push('\n'),
+                    Some(c) => unescaped.push(c),
+                    None => unescaped.push('\\'),
+                }
+            }
+            unescaped
+        } else {
+            match value.find(" #") {
+                Some(end) => value[..end].trim_end().to_owned(),
+                None => value.to_owned(),
+            }
+        };
+        vars.push((key.to_owned(), value));
+    }
+    Ok(vars)
+}
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "staging")]
 struct Arguments {
+    /// Stage file.  When missing or `auto`, the nearest `.stager.{yaml,toml,json}` is used.
     #[structopt(short = "i", long = "input", name = "STAGE", parse(from_os_str))]
-    input_stage: path::PathBuf,
+    input_stage: Option<path::PathBuf>,
+    /// Stage file to load before STAGE, which is then merged on top.  Applied in order, with a
+    /// later file's variables taking precedence.
+    #[structopt(
+        long = "include", name = "INCLUDE", parse(from_os_str), raw(number_of_values = "1")
+    )]
+    include: Vec<path::PathBuf>,
     #[structopt(short = "d", long = "data", name = "DATA_DIR", parse(from_os_str))]
     data_dir: Vec<path::PathBuf>,
+    /// Import environment variables starting with PREFIX as template globals, with PREFIX
+    /// stripped and the rest lowercased.  Values from DATA_DIR take precedence.
+    #[structopt(long = "vars-from-env-prefix", name = "PREFIX", raw(number_of_values = "1"))]
+    vars_from_env_prefix: Vec<String>,
+    /// Load KEY=VALUE lines from ENV_FILE as the `env` template global, e.g. `{{ env.KEY }}`.
+    /// Applied in order, with later files taking precedence.
+    #[structopt(
+        long = "env-file", name = "ENV_FILE", parse(from_os_str), raw(number_of_values = "1")
+    )]
+    env_file: Vec<path::PathBuf>,
+    /// Set the template global KEY to VALUE, taking precedence over DATA_DIR and the
+    /// environment.  Applied in order.
+    #[structopt(
+        long = "vars-override", name = "KEY=VALUE", parse(try_from_str = "parse_var"),
+        raw(number_of_values = "1")
+    )]
+    vars_override: Vec<(String, String)>,
+    /// Directory to stage into.  Ending in `.tar`, `.tar.gz`, `.tar.bz2`, or `.tar.xz` instead
+    /// stages into a temporary directory, then writes it to a tar archive.
     #[structopt(short = "o", long = "output", name = "OUT_DIR", parse(from_os_str))]
-    output_dir: path::PathBuf,
+    output_dir: Option<path::PathBuf>,
     #[structopt(short = "n", long = "dry-run")]
     dry_run: bool,
+    /// Only check that STAGE is valid, without staging.  Missing source files are ignored.
+    #[structopt(long = "config-check")]
+    config_check: bool,
+    /// Only check STAGE's `post_staging_checks` against the planned files, without staging.
+    #[structopt(long = "validate")]
+    validate: bool,
+    /// Stop at the first error, when building or performing actions, and report only it.  Takes
+    /// precedence over --ignore-errors.
+    #[structopt(long = "fail-fast")]
+    fail_fast: bool,
+    /// Report errors grouped by kind, with a count of each, rather than in the order found.
+    #[structopt(long = "group-errors")]
+    group_errors: bool,
+    /// Report at most MAX errors, or every error with 0.
+    #[structopt(long = "max-errors", name = "MAX", default_value = "50")]
+    max_errors: usize,
+    /// Print how many files, directories, and symlinks were staged.
+    #[structopt(long = "summary")]
+    summary: bool,
+    /// Print the bytes copied, files staged, elapsed time, and throughput of the run.  Bytes are
+    /// estimated from the planned actions, like the size of each source file.
+    #[structopt(long = "stats")]
+    stats: bool,
+    /// Where `--stats` prints to.
+    #[structopt(
+        long = "stats-output",
+        name = "STREAM",
+        default_value = "stderr",
+        raw(possible_values = "&[\"stderr\", \"stdout\"]")
+    )]
+    stats_output: StatsOutput,
+    /// Remove files from OUT_DIR that are not staged.
+    #[structopt(long = "clean")]
+    clean: bool,
+    /// Plan file for resuming an interrupted run, skipping actions that were completed.
+    #[structopt(long = "resume-from", name = "PLAN", parse(from_os_str))]
+    resume_from: Option<path::PathBuf>,
+    /// Create OUT_DIR with octal MODE (e.g. `0755`), or change it if OUT_DIR exists.
+    #[structopt(
+        long = "output-dir-permissions", name = "MODE", parse(try_from_str = "parse_mode")
+    )]
+    output_dir_permissions: Option<u32>,
+    /// Limit how fast files are copied, in bytes per second.
+    #[structopt(long = "rate-limit", name = "BPS")]
+    rate_limit: Option<u64>,
+    /// Copy files through a buffer of BYTES, for tuning I/O.  Defaults to the OS's choice.
+    #[structopt(long = "buffer-size", name = "BYTES", parse(try_from_str = "parse_buffer_size"))]
+    buffer_size: Option<usize>,
+    /// Fail rather than overwrite files that already exist in OUT_DIR, or that more than one source
+    /// stages.  With `--clean`, all files in OUT_DIR are removed before staging, so it ends up
+    /// holding only what this run staged.
+    #[structopt(long = "abort-on-overwrite")]
+    abort_on_overwrite: bool,
+    /// Append a JSON line to AUDIT_LOG for each performed action.
+    #[structopt(long = "audit-log", name = "AUDIT_LOG", parse(from_os_str))]
+    audit_log: Option<path::PathBuf>,
+    /// Write a JSON report of each performed action, its duration, and any error to REPORT.
+    #[structopt(long = "output-json", name = "REPORT", parse(from_os_str))]
+    output_json: Option<path::PathBuf>,
+    /// Directory for temporary files, including copies in progress and those of commands run on
+    /// staged files.  Defaults to the system's.
+    #[structopt(long = "temp-dir", name = "TEMP_DIR", parse(from_os_str))]
+    temp_dir: Option<path::PathBuf>,
+    /// Retry actions that fail to stage from interrupted or timed out I/O up to N times, waiting
+    /// longer between each attempt.
+    #[structopt(long = "retry", name = "N", default_value = "0")]
+    retry: u32,
+    /// Keep staging after an action fails, then report every failure and exit with an error.
+    #[structopt(long = "ignore-errors")]
+    ignore_errors: bool,
+    /// Print the planned contents of OUT_DIR as a tree.
+    #[structopt(long = "output-tree")]
+    output_tree: bool,
     #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
     verbosity: u8,
+    /// Write logs, at the level set by `-v`, to LOG_FILE.  Only errors are then written to stderr.
+    #[structopt(long = "log-file", name = "LOG_FILE", parse(from_os_str))]
+    log_file: Option<path::PathBuf>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
-fn run() -> Result<exitcode::ExitCode, failure::Error> {
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Rewrite a stage file in a canonical form.
+    #[structopt(name = "normalize")]
+    Normalize {
+        #[structopt(short = "i", long = "input", name = "STAGE", parse(from_os_str))]
+        input_stage: path::PathBuf,
+        #[structopt(short = "o", long = "output", name = "OUT_STAGE", parse(from_os_str))]
+        output_stage: path::PathBuf,
+    },
+    /// Generate a starter stage file from the layout of a directory.
+    #[structopt(name = "init")]
+    Init {
+        #[structopt(
+            short = "i", long = "input", name = "DIR", parse(from_os_str), default_value = "."
+        )]
+        input_dir: path::PathBuf,
+        /// Stage file to write.  Defaults to printing YAML to stdout.
+        #[structopt(short = "o", long = "output", name = "OUT_STAGE", parse(from_os_str))]
+        output_stage: Option<path::PathBuf>,
+        /// Also write a starter data file, for use with `--data`.
+        #[structopt(long = "vars", name = "VARS", parse(from_os_str))]
+        vars: Option<path::PathBuf>,
+    },
+    /// Show how staging would change OUT_DIR, without changing it.
+    #[structopt(name = "diff")]
+    Diff,
+    /// Print the planned actions, without performing them.  OUT_DIR defaults to the current
+    /// directory.
+    #[structopt(name = "list")]
+    List {
+        #[structopt(
+            long = "format",
+            default_value = "text",
+            raw(possible_values = "&[\"text\", \"json\", \"yaml\"]")
+        )]
+        format: ListFormat,
+    },
+    /// Print the stage file with its templates rendered, as it would be staged.
+    #[structopt(name = "config")]
+    Config {
+        #[structopt(short = "i", long = "input", name = "STAGE", parse(from_os_str))]
+        input_stage: Option<path::PathBuf>,
+        #[structopt(
+            long = "format",
+            default_value = "yaml",
+            raw(possible_values = "&[\"yaml\", \"json\"]")
+        )]
+        format: ConfigFormat,
+    },
+    /// Print a completion script for SHELL.
+    #[structopt(name = "completions")]
+    Completions {
+        #[structopt(
+            name = "SHELL",
+            raw(possible_values = "&[\"bash\", \"zsh\", \"fish\", \"powershell\"]")
+        )]
+        shell: structopt::clap::Shell,
+    },
+    /// Print a man page, in roff.
+    #[structopt(name = "man")]
+    Man,
+}
+
+/// Stream that `--stats` prints to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StatsOutput {
+    Stderr,
+    Stdout,
+}
+
+impl str::FromStr for StatsOutput {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, failure::Error> {
+        match s {
+            "stderr" => Ok(StatsOutput::Stderr),
+            "stdout" => Ok(StatsOutput::Stdout),
+            _ => bail!("Unsupported stream: {}", s),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ConfigFormat {
+    Yaml,
+    Json,
+}
+
+impl str::FromStr for ConfigFormat {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, failure::Error> {
+        match s {
+            "yaml" => Ok(ConfigFormat::Yaml),
+            "json" => Ok(ConfigFormat::Json),
+            _ => bail!("Unsupported format: {}", s),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ListFormat {
+    Text,
+    Json,
+    Yaml,
+}
+
+impl str::FromStr for ListFormat {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, failure::Error> {
+        match s {
+            "text" => Ok(ListFormat::Text),
+            "json" => Ok(ListFormat::Json),
+            "yaml" => Ok(ListFormat::Yaml),
+            _ => bail!("Unsupported format: {}", s),
+        }
+    }
+}
+
+fn list(actions: &[Box<stager::action::Action>], format: ListFormat) -> Result<(), failure::Error> {
+    let descriptions: Vec<_> = actions.iter().map(|a| a.describe()).collect();
+    match format {
+        ListFormat::Text => {
+            for action in actions {
+                println!("{}", action);
+            }
+        }
+        ListFormat::Json => println!("{}", plan::to_json(&descriptions)?),
+        ListFormat::Yaml => println!("{}", plan::to_yaml(&descriptions)?),
+    }
+    Ok(())
+}
+
+fn completions(shell: structopt::clap::Shell, out: &mut io::Write) -> Result<(), failure::Error> {
+    let mut script = vec![];
+    Arguments::clap().gen_completions_to("staging", shell, &mut script);
+    match shell {
+        structopt::clap::Shell::Bash => {
+            let script = bash_complete_stage_files(str::from_utf8(&script)?);
+            out.write_all(script.as_bytes())?;
+        }
+        _ => out.write_all(&script)?,
+    }
+    Ok(())
+}
+
+/// Complete stage file arguments with `*.yaml`, `*.toml`, and `*.json` files rather than any file.
+///
+/// clap only knows they are paths, so its bash script is patched; `init --input` is a directory
+/// and left alone.
+fn bash_complete_stage_files(script: &str) -> String {
+    const STAGE_FILES: &str =
+        r#"COMPREPLY=($(compgen -o plusdirs -f -X '!*.@(yaml|toml|json)' -- "${cur}"))"#;
+    let mut patched = String::with_capacity(script.len());
+    let mut command = "";
+    let mut stage_arg = false;
+    for line in script.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with("staging") && trimmed.ends_with(')') {
+            command = trimmed;
+        }
+        if stage_arg && trimmed.starts_with("COMPREPLY=($(compgen -f") {
+            let indent = line.find(|c: char| !c.is_whitespace()).unwrap_or(0);
+            patched.push_str(&line[..indent]);
+            patched.push_str(STAGE_FILES);
+        } else {
+            patched.push_str(line);
+        }
+        patched.push('\n');
+        stage_arg = command != "staging__init)" && (trimmed == "--input)" || trimmed == "-i)");
+    }
+    patched
+}
+
+fn normalize(input_stage: &path::Path, output_stage: &path::Path) -> Result<(), failure::Error> {
+    let mut staging = load_stage(input_stage)
+        .with_context(|_| format!("Failed to load {:?}", input_stage))?;
+    staging.normalize();
+    save_stage(output_stage, &staging)
+        .with_context(|_| format!("Failed to write {:?}", output_stage))?;
+    Ok(())
+}
+
+fn init(
+    input_dir: &path::Path,
+    output_stage: Option<&path::Path>,
+    vars: Option<&path::Path>,
+) -> Result<(), failure::Error> {
+    let input_dir = input_dir
+        .canonicalize()
+        .with_context(|_| format!("Failed to read {:?}", input_dir))?;
+    let mut subdirs = vec![];
+    for entry in fs::read_dir(&input_dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let hidden = name.to_str().map(|n| n.starts_with('.')).unwrap_or(false);
+        if entry.file_type()?.is_dir() && !hidden {
+            subdirs.push(entry.path());
+        }
+    }
+    subdirs.sort();
+
+    let mut targets = stager::de::MapStage::default();
+    for subdir in subdirs {
+        let name = subdir
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| format_err!("Invalid directory name: {:?}", subdir))?;
+        let path = subdir
+            .to_str()
+            .ok_or_else(|| format_err!("Invalid directory name: {:?}", subdir))?;
+        let source = stager::de::SourceFiles::new(
+            stager::de::Template::new(path),
+            stager::de::Template::new("**/*").into(),
+        );
+        targets.push(
+            stager::de::Template::new(format!("/{}", name)),
+            stager::de::Source::SourceFiles(source),
+        );
+    }
+    let staging = stager::de::Staging::from(targets);
+
+    match output_stage {
+        Some(output_stage) => save_stage(output_stage, &staging)
+            .with_context(|_| format!("Failed to write {:?}", output_stage))?,
+        None => println!("{}", stage::to_yaml(&staging)?),
+    }
+
+    if let Some(vars) = vars {
+        let text = format!(
+            "# Variables for stage file templates.  Load with `staging --data <DIR>`.\n\
+             source_root: {:?}\n",
+            input_dir
+        );
+        fs::write(vars, text).with_context(|_| format!("Failed to write {:?}", vars))?;
+    }
+
+    Ok(())
+}
+
+fn stale_files(
+    output_dir: &path::Path,
+    actions: &[Box<stager::action::Action>],
+) -> Result<Vec<path::PathBuf>, failure::Error> {
+    if !output_dir.exists() {
+        return Ok(vec![]);
+    }
+
+    let staged: collections::BTreeSet<_> =
+        actions.iter().filter_map(|a| a.affects_path()).collect();
+    let mut stale = vec![];
+    for entry in walkdir::WalkDir::new(output_dir) {
+        let entry = entry?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        if !staged.contains(entry.path()) {
+            stale.push(entry.path().to_owned());
+        }
+    }
+    Ok(stale)
+}
+
+fn diff(
+    actions: &[Box<stager::action::Action>],
+    stale: &[path::PathBuf],
+) -> Result<(), failure::Error> {
+    let mut added = 0;
+    let mut modified = 0;
+    let mut unknown = 0;
+    for action in actions {
+        let change = action
+            .describe()
+            .change()
+            .with_context(|_| format!("Failed comparing: {}", action))?;
+        match change {
+            stager::action::Change::Unchanged => continue,
+            stager::action::Change::Added => added += 1,
+            stager::action::Change::Modified => modified += 1,
+            stager::action::Change::Unknown => unknown += 1,
+        }
+        println!(" {:<9} | {}", change, action);
+    }
+    for path in stale {
+        println!(" {:<9} | rm {:?}", "removed", path);
+    }
+    println!(
+        " {} changes, {} added, {} modified, {} removed, {} unknown",
+        added + modified + stale.len() + unknown,
+        added,
+        modified,
+        stale.len(),
+        unknown
+    );
+    Ok(())
+}
+
+fn parse_var(var: &str) -> Result<(String, String), failure::Error> {
+    let mut parts = var.splitn(2, '=');
+    let key = parts.next().unwrap_or_default();
+    let value = parts
+        .next()
+        .ok_or_else(|| format_err!("Expected KEY=VALUE: {:?}", var))?;
+    if key.is_empty() {
+        bail!("Missing KEY in {:?}", var);
+    }
+    Ok((key.to_owned(), value.to_owned()))
+}
+
+fn parse_buffer_size(size: &str) -> Result<usize, failure::Error> {
+    let size = size.parse()?;
+    if size == 0 {
+        bail!("Buffer size must be at least 1 byte");
+    }
+    Ok(size)
+}
+
+fn parse_mode(mode: &str) -> Result<u32, failure::Error> {
+    let mode = u32::from_str_radix(mode, 8)?;
+    if 0o7777 < mode {
+        bail!("Mode is out of range: {:o}", mode);
+    }
+    Ok(mode)
+}
+
+#[cfg(unix)]
+fn set_output_dir_permissions(output_dir: &path::Path, mode: u32) -> Result<(), failure::Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::create_dir_all(output_dir)?;
+    fs::set_permissions(output_dir, fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_output_dir_permissions(output_dir: &path::Path, mode: u32) -> Result<(), failure::Error> {
+    warn!(
+        "Ignoring mode {:o} for {:?}: unsupported platform",
+        mode, output_dir
+    );
+    fs::create_dir_all(output_dir)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "tracing"))]
+fn init_logging(verbosity: u8, log_file: Option<&path::Path>) -> Result<(), failure::Error> {
     let mut builder = env_logger::Builder::new();
-    let args = Arguments::from_args();
-    let level = match args.verbosity {
+    let level = match verbosity {
         0 => log::LevelFilter::Error,
         1 => log::LevelFilter::Warn,
         2 => log::LevelFilter::Info,
         3 => log::LevelFilter::Debug,
         _ => log::LevelFilter::Trace,
     };
-    builder.filter(None, level);
+    if log_file.is_some() {
+        builder.filter(None, log::LevelFilter::Error);
+    } else {
+        builder.filter(None, level);
+    }
     if level == log::LevelFilter::Trace {
         builder.default_format_timestamp(false);
     } else {
@@ -261,42 +1394,339 @@ fn run() -> Result<exitcode::ExitCode, failure::Error> {
             )
         });
     }
-    builder.init();
+    match log_file {
+        Some(path) => {
+            let file = fs::File::create(path)
+                .with_context(|_| format!("Failed to create log file {:?}", path))?;
+            let logger = TeeLogger {
+                stderr: builder.build(),
+                file: sync::Mutex::new(io::LineWriter::new(file)),
+                level,
+            };
+            log::set_boxed_logger(Box::new(logger))?;
+            log::set_max_level(level);
+        }
+        None => builder.init(),
+    }
+    Ok(())
+}
 
-    let data = load_data_dirs(&args.data_dir)?;
-    let engine = stager::de::TemplateEngine::new(data)?;
+/// Logs errors to stderr, and everything up to `level` to `file`.
+#[cfg(not(feature = "tracing"))]
+struct TeeLogger {
+    stderr: env_logger::Logger,
+    file: sync::Mutex<io::LineWriter<fs::File>>,
+    level: log::LevelFilter,
+}
 
-    let staging = load_stage(&args.input_stage)
-        .with_context(|_| format!("Failed to load {:?}", args.input_stage))?;
+#[cfg(not(feature = "tracing"))]
+impl log::Log for TeeLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level || self.stderr.enabled(metadata)
+    }
 
-    let staging = staging.format(&engine);
-    let staging = match staging {
-        Ok(s) => s,
-        Err(e) => {
-            error!("Failed reading stage file: {}", e);
-            return Ok(exitcode::DATAERR);
+    fn log(&self, record: &log::Record) {
+        if self.stderr.matches(record) {
+            self.stderr.log(record);
         }
-    };
+        if record.level() <= self.level {
+            if let Ok(mut file) = self.file.lock() {
+                let _ = writeln!(
+                    file,
+                    "[{}] {}",
+                    record.level().to_string().to_lowercase(),
+                    record.args()
+                );
+            }
+        }
+    }
 
-    let staging = staging.build(&args.output_dir);
-    let staging = match staging {
-        Ok(s) => s,
-        Err(e) => {
-            error!("Failed preparing staging: {}", e);
-            return Ok(exitcode::IOERR);
+    fn flush(&self) {
+        self.stderr.flush();
+        if let Ok(mut file) = self.file.lock() {
+            let _ = file.flush();
         }
+    }
+}
+
+#[cfg(feature = "tracing")]
+fn init_logging(verbosity: u8, log_file: Option<&path::Path>) -> Result<(), failure::Error> {
+    let level = match verbosity {
+        0 => tracing::Level::ERROR,
+        1 => tracing::Level::WARN,
+        2 => tracing::Level::INFO,
+        3 => tracing::Level::DEBUG,
+        _ => tracing::Level::TRACE,
     };
+    if let Some(path) = log_file {
+        use tracing_subscriber::filter::LevelFilter;
+        use tracing_subscriber::prelude::*;
+
+        let file = fs::File::create(path)
+            .with_context(|_| format!("Failed to create log file {:?}", path))?;
+        let stderr = tracing_subscriber::fmt::layer()
+            .with_writer(io::stderr)
+            .without_time()
+            .with_target(false)
+            .with_filter(LevelFilter::ERROR);
+        let file = tracing_subscriber::fmt::layer()
+            .with_writer(sync::Mutex::new(file))
+            .with_ansi(false)
+            .with_filter(LevelFilter::from_level(level));
+        tracing_subscriber::registry().with(stderr).with(file).init();
+        return Ok(());
+    }
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(io::stderr);
+    if level == tracing::Level::TRACE {
+        builder.init();
+    } else {
+        builder.without_time().with_target(false).init();
+    }
+    Ok(())
+}
+
+/// Counts of performed actions, by kind.
+#[derive(Debug, Default)]
+struct ActionStats {
+    copies: usize,
+    directories: usize,
+    symlinks: usize,
+    errors: usize,
+    estimated_bytes: u64,
+}
+
+impl ActionStats {
+    fn record(&mut self, action: &stager::action::Action, success: bool) {
+        if !success {
+            self.errors += 1;
+            return;
+        }
+        self.estimated_bytes += action.estimated_byte_count().unwrap_or(0);
+        match action.describe().kind {
+            stager::action::ActionKind::CopyFile
+            | stager::action::ActionKind::HardLink
+            | stager::action::ActionKind::WriteContent => self.copies += 1,
+            stager::action::ActionKind::CreateDirectory => self.directories += 1,
+            stager::action::ActionKind::Symlink => self.symlinks += 1,
+            _ => (),
+        }
+    }
 
-    for action in staging {
-        debug!("{}", action);
-        if !args.dry_run {
-            action
-                .perform()
-                .with_context(|_| format!("Failed staging files: {}", action))?;
+    /// Print to stdout with `summary`, otherwise log it.
+    fn report(&self, summary: bool) {
+        if summary {
+            println!("{}", self);
+        } else {
+            info!("{}", self);
         }
     }
 
-    Ok(exitcode::OK)
+    fn throughput(&self, elapsed: time::Duration) -> Throughput {
+        Throughput {
+            estimated_bytes: self.estimated_bytes,
+            files: self.copies,
+            elapsed,
+        }
+    }
+}
+
+impl fmt::Display for ActionStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Staged {} files, created {} directories, created {} symlinks",
+            self.copies, self.directories, self.symlinks
+        )?;
+        if self.errors != 0 {
+            write!(f, ", {} errors", self.errors)?;
+        }
+        Ok(())
+    }
+}
+
+/// How much a run copied, and how fast, for `--stats`.
+///
+/// Bytes are estimated from the actions, not counted as they are written.
+#[derive(Debug, Copy, Clone)]
+struct Throughput {
+    estimated_bytes: u64,
+    files: usize,
+    elapsed: time::Duration,
+}
+
+impl Throughput {
+    fn megabytes_per_second(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds == 0.0 {
+            0.0
+        } else {
+            self.estimated_bytes as f64 / 1_000_000.0 / seconds
+        }
+    }
+
+    fn report(&self, output: StatsOutput) {
+        match output {
+            StatsOutput::Stderr => eprintln!("{}", self),
+            StatsOutput::Stdout => println!("{}", self),
+        }
+    }
+}
+
+impl fmt::Display for Throughput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Copied an estimated {} bytes in {} files in {:.3}s ({:.2} MB/s)",
+            self.estimated_bytes,
+            self.files,
+            self.elapsed.as_secs_f64(),
+            self.megabytes_per_second()
+        )
+    }
+}
+
+/// With `fail_fast`, keep only the first of `errors`, otherwise the first `max_errors` of them.
+/// A `max_errors` of 0 keeps every error.
+fn truncate_errors(
+    mut errors: stager::error::Errors,
+    fail_fast: bool,
+    max_errors: usize,
+) -> stager::error::Errors {
+    if fail_fast {
+        errors.into_iter().take(1).collect()
+    } else {
+        if max_errors != 0 {
+            errors.truncate(max_errors);
+        }
+        errors
+    }
+}
+
+fn run() -> Result<exitcode::ExitCode, failure::Error> {
+    let args = Arguments::from_args();
+    init_logging(args.verbosity, args.log_file.as_deref())?;
+
+    match args.command {
+        Some(Command::Normalize {
+            ref input_stage,
+            ref output_stage,
+        }) => {
+            normalize(input_stage, output_stage)?;
+            return Ok(exitcode::OK);
+        }
+        Some(Command::Init {
+            ref input_dir,
+            ref output_stage,
+            ref vars,
+        }) => {
+            init(
+                input_dir,
+                output_stage.as_ref().map(|p| p.as_path()),
+                vars.as_ref().map(|p| p.as_path()),
+            )?;
+            return Ok(exitcode::OK);
+        }
+        Some(Command::Completions { shell }) => {
+            completions(shell, &mut io::stdout())?;
+            return Ok(exitcode::OK);
+        }
+        Some(Command::Man) => {
+            print!("{}", man::render()?);
+            return Ok(exitcode::OK);
+        }
+        Some(Command::Diff)
+        | Some(Command::List { .. })
+        | Some(Command::Config { .. })
+        | None => (),
+    }
+
+    let current_dir = path::PathBuf::from(".");
+    let (input_stage, output_dir) = match args.command {
+        Some(Command::List { .. }) => (
+            args.input_stage.as_ref(),
+            Some(args.output_dir.as_ref().unwrap_or(&current_dir)),
+        ),
+        // Rendering the configuration doesn't stage anything.
+        Some(Command::Config {
+            ref input_stage, ..
+        }) => (
+            input_stage.as_ref().or(args.input_stage.as_ref()),
+            Some(&current_dir),
+        ),
+        _ => (args.input_stage.as_ref(), args.output_dir.as_ref()),
+    };
+    let input_stage = match input_stage {
+        Some(input_stage) if input_stage != path::Path::new("auto") => input_stage.clone(),
+        _ => discover_stage(&env::current_dir()?)?,
+    };
+    let output_dir = output_dir.ok_or_else(|| format_err!("--output is required"))?;
+
+    let stager = pipeline::StagerBuilder::new()
+        .input(input_stage)
+        .output(output_dir.clone())
+        .with_includes(args.include.clone())
+        .with_data_dirs(args.data_dir.clone())
+        .with_env_globals(args.vars_from_env_prefix.clone())
+        .with_env_files(args.env_file.clone())
+        .with_var_overrides(args.vars_override.clone())
+        .fail_fast(args.fail_fast)
+        .group_errors(args.group_errors)
+        .with_max_errors(args.max_errors)
+        .dry_run(args.dry_run)
+        .summary(args.summary)
+        .clean(args.clean)
+        .with_resume_from(args.resume_from.clone())
+        .with_output_dir_permissions(args.output_dir_permissions)
+        .with_rate_limit(args.rate_limit)
+        .with_buffer_size(args.buffer_size)
+        .with_temp_dir(args.temp_dir.clone())
+        .abort_on_overwrite(args.abort_on_overwrite)
+        .with_audit_log(args.audit_log.clone())
+        .with_output_json(args.output_json.clone())
+        .with_stats(if args.stats {
+            Some(args.stats_output)
+        } else {
+            None
+        })
+        .output_tree(args.output_tree)
+        .with_retry(args.retry)
+        .ignore_errors(args.ignore_errors);
+
+    match args.command {
+        Some(Command::Diff) => {
+            let actions = match stager.actions() {
+                Ok(actions) => actions,
+                Err(exit) => return exit.code(),
+            };
+            let stale = stager.stale_files(&actions)?;
+            diff(&actions, &stale)?;
+            Ok(exitcode::OK)
+        }
+        Some(Command::List { format, .. }) => {
+            let actions = match stager.actions() {
+                Ok(actions) => actions,
+                Err(exit) => return exit.code(),
+            };
+            list(&actions, format)?;
+            Ok(exitcode::OK)
+        }
+        Some(Command::Config { format, .. }) => {
+            let staging = match stager.config() {
+                Ok(staging) => staging,
+                Err(exit) => return exit.code(),
+            };
+            match format {
+                ConfigFormat::Yaml => println!("{}", stage::to_yaml(&staging)?),
+                ConfigFormat::Json => println!("{}", stage::to_json(&staging)?),
+            }
+            Ok(exitcode::OK)
+        }
+        _ if args.config_check => stager.check(),
+        _ if args.validate => stager.validate(),
+        _ => stager.run(),
+    }
 }
 
 fn main() {
@@ -0,0 +1,703 @@
+//! A complete staging run: load the stage file, build its actions, and perform them.
+
+use std::mem;
+use std::thread;
+
+use stager::de::TemplateRender;
+
+use super::*;
+
+/// `StagerBuilder` state for an `input` or `output` that hasn't been set yet.
+#[derive(Debug, Default)]
+pub struct Unset;
+
+/// Where a staging run stopped early.
+#[derive(Debug)]
+pub enum Exit {
+    /// The errors were already reported; exit with this code.
+    Code(exitcode::ExitCode),
+    /// Unexpected failure, not yet reported.
+    Error(failure::Error),
+}
+
+impl Exit {
+    /// Convert to the result of `run`.
+    pub fn code(self) -> Result<exitcode::ExitCode, failure::Error> {
+        match self {
+            Exit::Code(code) => Ok(code),
+            Exit::Error(error) => Err(error),
+        }
+    }
+}
+
+impl From<failure::Error> for Exit {
+    fn from(error: failure::Error) -> Self {
+        Exit::Error(error)
+    }
+}
+
+/// Stage files merged by `load`, ready to render.
+struct Loaded {
+    staging: stager::de::Staging,
+    /// The rendered `variables` of every file.
+    variables: collections::BTreeMap<String, String>,
+    /// Engine with the `variables` under its globals.
+    engine: stager::de::TemplateEngine,
+}
+
+#[derive(Debug, Default)]
+struct Options {
+    includes: Vec<path::PathBuf>,
+    data_dirs: Vec<path::PathBuf>,
+    env_prefixes: Vec<String>,
+    env_files: Vec<path::PathBuf>,
+    var_overrides: Vec<(String, String)>,
+    fail_fast: bool,
+    group_errors: bool,
+    max_errors: usize,
+    dry_run: bool,
+    summary: bool,
+    clean: bool,
+    resume_from: Option<path::PathBuf>,
+    output_dir_permissions: Option<u32>,
+    rate_limit: Option<u64>,
+    buffer_size: Option<usize>,
+    temp_dir: Option<path::PathBuf>,
+    abort_on_overwrite: bool,
+    audit_log: Option<path::PathBuf>,
+    output_json: Option<path::PathBuf>,
+    stats: Option<StatsOutput>,
+    output_tree: bool,
+    retry: u32,
+    ignore_errors: bool,
+}
+
+/// Configures a staging run.
+///
+/// The `input` stage file and `output` directory must be set before the run can be started.
+#[derive(Debug)]
+pub struct StagerBuilder<I, O> {
+    input: I,
+    output: O,
+    options: Options,
+}
+
+impl StagerBuilder<Unset, Unset> {
+    pub fn new() -> Self {
+        Self {
+            input: Unset,
+            output: Unset,
+            options: Default::default(),
+        }
+    }
+}
+
+impl<I, O> StagerBuilder<I, O> {
+    /// Stage file to load.
+    pub fn input(self, stage: path::PathBuf) -> StagerBuilder<path::PathBuf, O> {
+        StagerBuilder {
+            input: stage,
+            output: self.output,
+            options: self.options,
+        }
+    }
+
+    /// Directory to stage into, or a tar archive to write.
+    pub fn output(self, dir: path::PathBuf) -> StagerBuilder<I, path::PathBuf> {
+        StagerBuilder {
+            input: self.input,
+            output: dir,
+            options: self.options,
+        }
+    }
+
+    /// Stage files to load before `input`, which is then merged on top.
+    pub fn with_includes(mut self, includes: Vec<path::PathBuf>) -> Self {
+        self.options.includes = includes;
+        self
+    }
+
+    /// Directories of data files to load as template globals.
+    pub fn with_data_dirs(mut self, dirs: Vec<path::PathBuf>) -> Self {
+        self.options.data_dirs = dirs;
+        self
+    }
+
+    /// Import environment variables starting with any of `prefixes` as template globals.
+    pub fn with_env_globals(mut self, prefixes: Vec<String>) -> Self {
+        self.options.env_prefixes = prefixes;
+        self
+    }
+
+    /// `.env` files to load as the `env` template global.  Later files win.
+    pub fn with_env_files(mut self, files: Vec<path::PathBuf>) -> Self {
+        self.options.env_files = files;
+        self
+    }
+
+    /// Set template globals, taking precedence over data files and the environment.  Later
+    /// overrides win.
+    pub fn with_var_overrides(mut self, vars: Vec<(String, String)>) -> Self {
+        self.options.var_overrides = vars;
+        self
+    }
+
+    /// Stop at the first error, when building or performing actions, and report only it.
+    ///
+    /// Takes precedence over `ignore_errors`.
+    pub fn fail_fast(mut self, yes: bool) -> Self {
+        self.options.fail_fast = yes;
+        self
+    }
+
+    /// Report errors grouped by kind, with a count of each, rather than in the order found.
+    pub fn group_errors(mut self, yes: bool) -> Self {
+        self.options.group_errors = yes;
+        self
+    }
+
+    /// Report at most `count` errors, or every error with 0.
+    pub fn with_max_errors(mut self, count: usize) -> Self {
+        self.options.max_errors = count;
+        self
+    }
+
+    /// Report what would be done without changing anything.
+    pub fn dry_run(mut self, yes: bool) -> Self {
+        self.options.dry_run = yes;
+        self
+    }
+
+    /// Print the `ActionStats` rather than logging them.
+    pub fn summary(mut self, yes: bool) -> Self {
+        self.options.summary = yes;
+        self
+    }
+
+    /// Remove files from `output` that are not staged.
+    pub fn clean(mut self, yes: bool) -> Self {
+        self.options.clean = yes;
+        self
+    }
+
+    /// Plan file for resuming an interrupted run.
+    pub fn with_resume_from(mut self, plan: Option<path::PathBuf>) -> Self {
+        self.options.resume_from = plan;
+        self
+    }
+
+    /// Create or change `output` to have `mode`.
+    pub fn with_output_dir_permissions(mut self, mode: Option<u32>) -> Self {
+        self.options.output_dir_permissions = mode;
+        self
+    }
+
+    /// Limit how fast files are copied, in bytes per second.
+    pub fn with_rate_limit(mut self, bps: Option<u64>) -> Self {
+        self.options.rate_limit = bps;
+        self
+    }
+
+    /// Copy files through a buffer of this many bytes.
+    pub fn with_buffer_size(mut self, size: Option<usize>) -> Self {
+        self.options.buffer_size = size;
+        self
+    }
+
+    /// Write copied files in this directory before moving them into `output`.
+    pub fn with_temp_dir(mut self, dir: Option<path::PathBuf>) -> Self {
+        self.options.temp_dir = dir;
+        self
+    }
+
+    /// Fail rather than overwrite files that already exist in `output` or that another action
+    /// stages.  With `clean`, the existing files are removed first instead.
+    pub fn abort_on_overwrite(mut self, yes: bool) -> Self {
+        self.options.abort_on_overwrite = yes;
+        self
+    }
+
+    /// Append a JSON line to `log` for each performed action.
+    pub fn with_audit_log(mut self, log: Option<path::PathBuf>) -> Self {
+        self.options.audit_log = log;
+        self
+    }
+
+    /// Write a JSON report of the performed actions to `report`.
+    pub fn with_output_json(mut self, report: Option<path::PathBuf>) -> Self {
+        self.options.output_json = report;
+        self
+    }
+
+    /// Report how many bytes were copied, and how fast, to `output`.
+    pub fn with_stats(mut self, output: Option<StatsOutput>) -> Self {
+        self.options.stats = output;
+        self
+    }
+
+    /// Retry actions that fail to stage up to `count` times, backing off exponentially.
+    pub fn with_retry(mut self, count: u32) -> Self {
+        self.options.retry = count;
+        self
+    }
+
+    /// Keep performing actions after one fails, reporting every failure at the end.
+    pub fn ignore_errors(mut self, yes: bool) -> Self {
+        self.options.ignore_errors = yes;
+        self
+    }
+
+    /// Print the planned contents of `output` as a tree before staging.
+    pub fn output_tree(mut self, yes: bool) -> Self {
+        self.options.output_tree = yes;
+        self
+    }
+}
+
+impl StagerBuilder<path::PathBuf, path::PathBuf> {
+    /// Load each of the includes and then the stage file, merging each on top of the ones before
+    /// it.
+    ///
+    /// Only each file's `variables` are rendered here, with a later file's taking precedence.  The
+    /// rest of the merged stage is rendered once, by `render`, so values like `--vars-override`
+    /// are never evaluated as templates themselves.
+    fn load(&self) -> Result<Loaded, Exit> {
+        let options = &self.options;
+        let engine = self.engine()?;
+        let mut staging = stager::de::Staging::default();
+        let mut variables = collections::BTreeMap::new();
+        let paths = options.includes.iter().map(|p| p.as_path());
+        for path in paths.chain(Some(self.input.as_path())) {
+            let mut layer = load_stage(path)
+                .with_context(|_| format!("Failed to load {:?}", path))
+                .map_err(failure::Error::from)?;
+            let mut errors = vec![];
+            for (key, value) in mem::take(&mut layer.variables) {
+                match value.format(&engine) {
+                    Ok(value) => {
+                        variables.insert(key, value);
+                    }
+                    Err(error) => errors.push(error),
+                }
+            }
+            if !errors.is_empty() {
+                let e = errors.into_iter().collect();
+                let e = truncate_errors(e, options.fail_fast, options.max_errors);
+                error!("Failed reading {:?}: {}", path, e.display(options.error_display()));
+                return Err(Exit::Code(exitcode::DATAERR));
+            }
+            staging.merge(layer);
+        }
+        let globals = variables
+            .iter()
+            .map(|(k, v)| (k.clone(), liquid::Value::scalar(v.clone())))
+            .collect();
+        let engine = engine
+            .with_default_globals(globals)
+            .map_err(failure::Error::from)?;
+        Ok(Loaded {
+            staging,
+            variables,
+            engine,
+        })
+    }
+
+    /// Load the stage file and render its templates.
+    fn staging(&self) -> Result<Box<stager::builder::ActionBuilder>, Exit> {
+        let loaded = self.load()?;
+        self.render(&loaded)
+    }
+
+    /// Template engine with globals from the data directories, environment, and overrides.
+    fn engine(&self) -> Result<stager::de::TemplateEngine, Exit> {
+        let options = &self.options;
+        let mut engine = stager::de::TemplateEngine::new(load_data_dirs(&options.data_dirs)?)
+            .map_err(failure::Error::from)?;
+        load_env_vars(engine.globals_mut(), &options.env_prefixes);
+        load_env_files(engine.globals_mut(), &options.env_files)?;
+        for (key, value) in &options.var_overrides {
+            engine
+                .globals_mut()
+                .insert(key.clone(), liquid::Value::scalar(value.clone()));
+        }
+        #[cfg(feature = "chrono")]
+        let engine = engine.with_now().map_err(failure::Error::from)?;
+        Ok(engine)
+    }
+
+    /// Render the templates of a loaded stage file.
+    fn render(&self, loaded: &Loaded) -> Result<Box<stager::builder::ActionBuilder>, Exit> {
+        let options = &self.options;
+        let mut staging = loaded.staging.clone();
+        if options.fail_fast {
+            staging.fail_fast = Some(true);
+        }
+        match staging.format(&loaded.engine) {
+            Ok(s) => Ok(s),
+            Err(e) => {
+                let e = truncate_errors(e, options.fail_fast, options.max_errors);
+                error!("Failed reading stage file: {}", e.display(options.error_display()));
+                Err(Exit::Code(exitcode::DATAERR))
+            }
+        }
+    }
+
+    /// Load the stage file and render its templates, for showing the effective configuration.
+    pub fn config(&self) -> Result<stager::de::Staging, Exit> {
+        let options = &self.options;
+        let loaded = self.load()?;
+        let mut staging = loaded.staging.render(&loaded.engine).map_err(|e| {
+            let e = truncate_errors(e, options.fail_fast, options.max_errors);
+            error!("Failed rendering stage file: {}", e.display(options.error_display()));
+            Exit::Code(exitcode::DATAERR)
+        })?;
+        staging.variables = loaded
+            .variables
+            .into_iter()
+            .map(|(k, v)| (k, stager::de::Template::new(v)))
+            .collect();
+        Ok(staging)
+    }
+
+    /// Check the stage file is valid, ignoring missing source files.
+    pub fn check(&self) -> Result<exitcode::ExitCode, failure::Error> {
+        let staging = match self.staging() {
+            Ok(staging) => staging,
+            Err(exit) => return exit.code(),
+        };
+        let errors = match staging.build(&self.output) {
+            Ok(_) => return Ok(exitcode::OK),
+            Err(errors) => errors,
+        };
+        let errors: stager::error::Errors = errors
+            .into_iter()
+            .filter(|e| e.kind() == stager::error::ErrorKind::InvalidConfiguration)
+            .collect();
+        if errors.is_empty() {
+            return Ok(exitcode::OK);
+        }
+        let errors = truncate_errors(errors, self.options.fail_fast, self.options.max_errors);
+        error!(
+            "Invalid stage file: {}",
+            errors.display(self.options.error_display())
+        );
+        Ok(exitcode::DATAERR)
+    }
+
+    /// Check the stage file's `post_staging_checks` against the planned actions, without
+    /// performing them.
+    pub fn validate(&self) -> Result<exitcode::ExitCode, failure::Error> {
+        let options = &self.options;
+        let loaded = match self.load() {
+            Ok(loaded) => loaded,
+            Err(exit) => return exit.code(),
+        };
+        let checks = match loaded.staging.format_checks(&loaded.engine) {
+            Ok(checks) => checks,
+            Err(e) => {
+                let e = truncate_errors(e, options.fail_fast, options.max_errors);
+                error!("Failed reading stage file: {}", e.display(options.error_display()));
+                return Ok(exitcode::DATAERR);
+            }
+        };
+        let actions = match self.render(&loaded)
+            .and_then(|s| self.build_actions(s, &self.output))
+        {
+            Ok(actions) => actions,
+            Err(exit) => return exit.code(),
+        };
+        let plan = stager::action::VerifyStaging::for_actions(&self.output, &actions, checks);
+        if let Err(error) = plan.check_plan() {
+            error!("Invalid stage plan: {}", error);
+            return Ok(exitcode::DATAERR);
+        }
+        Ok(exitcode::OK)
+    }
+
+    /// Load the stage file and build the actions to stage it.
+    pub fn actions(&self) -> Result<Vec<Box<stager::action::Action>>, Exit> {
+        let staging = self.staging()?;
+        self.build_actions(staging, &self.output)
+    }
+
+    fn build_actions(
+        &self,
+        staging: Box<stager::builder::ActionBuilder>,
+        target_dir: &path::Path,
+    ) -> Result<Vec<Box<stager::action::Action>>, Exit> {
+        let options = &self.options;
+        let actions = match staging.into_actions(target_dir) {
+            Ok(s) => s,
+            Err(e) => {
+                let e = truncate_errors(e, options.fail_fast, options.max_errors);
+                error!("Failed preparing staging: {}", e.display(options.error_display()));
+                return Err(Exit::Code(exitcode::IOERR));
+            }
+        };
+        let conflicts = stager::action::detect_conflicts(&actions);
+        for &(first, second) in &conflicts {
+            let message = format!(
+                "`{}` overwrites `{}`",
+                actions[second], actions[first]
+            );
+            if options.abort_on_overwrite {
+                error!("{}", message);
+            } else {
+                warn!("{}", message);
+            }
+        }
+        if options.abort_on_overwrite && !conflicts.is_empty() {
+            return Err(Exit::Code(exitcode::DATAERR));
+        }
+        Ok(actions)
+    }
+
+    /// Files in `output` that `clean` would remove.
+    ///
+    /// With `abort_on_overwrite`, that is every file, since restaging one would be rejected as
+    /// an overwrite.  Files a resumed run won't restage are kept.
+    pub fn stale_files(
+        &self,
+        actions: &[Box<stager::action::Action>],
+    ) -> Result<Vec<path::PathBuf>, failure::Error> {
+        if !self.options.clean {
+            return Ok(vec![]);
+        }
+        let staged = if self.options.abort_on_overwrite && self.options.resume_from.is_none() {
+            &[][..]
+        } else {
+            actions
+        };
+        let stale = stale_files(&self.output, staged)
+            .with_context(|_| format!("Failed cleaning {:?}", self.output))?;
+        Ok(stale)
+    }
+
+    /// Stage `input` into `output`.
+    pub fn run(&self) -> Result<exitcode::ExitCode, failure::Error> {
+        let loaded = match self.load() {
+            Ok(loaded) => loaded,
+            Err(exit) => return exit.code(),
+        };
+        // The stage file can only turn dry-run on, so a checked-in stage stays safe.
+        let dry_run = self.options.dry_run || loaded.staging.dry_run.unwrap_or(false);
+        if dry_run && !self.options.dry_run {
+            info!("Dry run enabled by the stage file");
+        }
+        // Archives are staged into a temporary directory, then written out.
+        let archive_dir = if archive::is_archive(&self.output) && !dry_run {
+            archive::check_supported()?;
+            let dir = match self.options.temp_dir {
+                Some(ref temp_dir) => tempfile::Builder::new().tempdir_in(temp_dir),
+                None => tempfile::tempdir(),
+            };
+            let dir = dir.with_context(|_| {
+                format!("Failed creating a directory to stage {:?}", self.output)
+            })?;
+            Some(dir)
+        } else {
+            None
+        };
+        let target_dir = archive_dir
+            .as_ref()
+            .map_or(self.output.as_path(), |dir| dir.path());
+        let actions = match self.render(&loaded)
+            .and_then(|s| self.build_actions(s, target_dir))
+        {
+            Ok(actions) => actions,
+            Err(exit) => return exit.code(),
+        };
+        let stale = self.stale_files(&actions)?;
+        if self.options.output_tree {
+            print!("{}", tree::render(&self.output, target_dir, &actions));
+        }
+        let failures = self.perform(actions, stale, dry_run)?;
+        if let Some(dir) = archive_dir {
+            archive::write(&self.output, dir.path())
+                .with_context(|_| format!("Failed writing {:?}", self.output))?;
+        }
+        if !failures.is_empty() {
+            let failures =
+                truncate_errors(failures, self.options.fail_fast, self.options.max_errors);
+            error!(
+                "Failed staging files: {}",
+                failures.display(self.options.error_display())
+            );
+            return Ok(exitcode::SOFTWARE);
+        }
+        Ok(exitcode::OK)
+    }
+
+    /// Perform `actions`, returning the failures skipped with `ignore_errors`.
+    fn perform(
+        &self,
+        actions: Vec<Box<stager::action::Action>>,
+        stale: Vec<path::PathBuf>,
+        dry_run: bool,
+    ) -> Result<stager::error::Errors, failure::Error> {
+        let options = &self.options;
+        let output_dir = &self.output;
+        if archive::is_archive(output_dir) {
+            if options.clean {
+                bail!("--clean is unsupported when writing an archive");
+            }
+            if options.resume_from.is_some() {
+                bail!("--resume-from is unsupported when writing an archive");
+            }
+            if options.output_dir_permissions.is_some() {
+                bail!("--output-dir-permissions is unsupported when writing an archive");
+            }
+        }
+        if let Some(mode) = options.output_dir_permissions {
+            info!("mkdir -m {:o} {:?}", mode, output_dir);
+            if !dry_run {
+                set_output_dir_permissions(output_dir, mode)
+                    .with_context(|_| format!("Failed setting permissions on {:?}", output_dir))?;
+            }
+        }
+
+        for path in stale {
+            info!("rm {:?}", path);
+            if !dry_run {
+                fs::remove_file(&path).with_context(|_| format!("Failed removing {:?}", path))?;
+            }
+        }
+
+        let completed = match options.resume_from {
+            Some(ref plan_path) if !dry_run => resume(plan_path, &actions)
+                .with_context(|_| format!("Failed resuming from {:?}", plan_path))?,
+            _ => collections::BTreeSet::new(),
+        };
+
+        let audit_log = match options.audit_log {
+            Some(ref path) if !dry_run => Some(audit::open(path)?),
+            _ => None,
+        };
+
+        let perform_options = options.perform_options();
+        let mut stats = ActionStats::default();
+        let started = time::Instant::now();
+        let mut outcomes = vec![];
+        let mut failures = vec![];
+        for (i, action) in actions.into_iter().enumerate() {
+            if completed.contains(&i) {
+                debug!("Already completed: {}", action);
+                continue;
+            }
+            #[cfg(feature = "tracing")]
+            let _span = info_span!("perform", action = %action).entered();
+            debug!("{}", action);
+            if !dry_run {
+                let start = time::Instant::now();
+                let result = perform_with_retry(action.as_ref(), &perform_options, options.retry);
+                let duration = start.elapsed();
+                if let Some(ref audit_log) = audit_log {
+                    audit::log(audit_log, action.as_ref(), duration, &result)?;
+                }
+                if options.output_json.is_some() {
+                    outcomes.push(report::Outcome::new(action.as_ref(), duration, &result));
+                }
+                stats.record(action.as_ref(), result.is_ok());
+                if options.ignore_errors && !options.fail_fast {
+                    if let Err(error) = result {
+                        failures.push(
+                            stager::error::StagingError::new(error.kind())
+                                .set_context(format!("Failed staging files: {}", action))
+                                .set_cause(error),
+                        );
+                    }
+                    continue;
+                }
+                let result = result.with_context(|_| format!("Failed staging files: {}", action));
+                if result.is_err() {
+                    stats.report(options.summary);
+                    if let Some(ref report_path) = options.output_json {
+                        report::save(report_path, false, &outcomes, None)?;
+                    }
+                }
+                result?;
+            } else {
+                stats.record(action.as_ref(), true);
+            }
+        }
+        stats.report(options.summary);
+        let throughput = match options.stats {
+            Some(output) if !dry_run => {
+                let throughput = stats.throughput(started.elapsed());
+                throughput.report(output);
+                Some(throughput)
+            }
+            _ => None,
+        };
+        if let Some(ref report_path) = options.output_json {
+            if !dry_run {
+                report::save(report_path, failures.is_empty(), &outcomes, throughput.as_ref())
+                    .with_context(|_| format!("Failed writing {:?}", report_path))?;
+            }
+        }
+
+        if let Some(ref plan_path) = options.resume_from {
+            // Keep the plan so failed actions can be resumed.
+            if !dry_run && failures.is_empty() {
+                fs::remove_file(plan_path)
+                    .with_context(|_| format!("Failed removing {:?}", plan_path))?;
+            }
+        }
+
+        Ok(failures.into_iter().collect())
+    }
+}
+
+impl Options {
+    fn perform_options(&self) -> stager::action::PerformOptions {
+        stager::action::PerformOptions::new()
+            .rate_limit(self.rate_limit)
+            .buffer_size(self.buffer_size)
+            .temp_dir(self.temp_dir.clone())
+            .on_conflict(if self.abort_on_overwrite {
+                Some(stager::action::ConflictPolicy::Error)
+            } else {
+                None
+            })
+    }
+
+    fn error_display(&self) -> stager::error::DisplayMode {
+        if self.group_errors {
+            stager::error::DisplayMode::Grouped
+        } else {
+            stager::error::DisplayMode::List
+        }
+    }
+}
+
+/// Longest wait between attempts at an action.
+const MAX_BACKOFF: time::Duration = time::Duration::from_secs(10);
+
+/// Perform `action`, retrying up to `retries` times if staging fails.
+///
+/// Only transient failures, like interrupted or timed out I/O, are retried, since others won't
+/// go away on their own.
+fn perform_with_retry(
+    action: &stager::action::Action,
+    options: &stager::action::PerformOptions,
+    retries: u32,
+) -> Result<(), stager::error::StagingError> {
+    let mut attempt = 0;
+    loop {
+        match action.perform(options) {
+            Err(ref error) if attempt < retries && error.is_transient() => {
+                let backoff = 1u32
+                    .checked_shl(attempt)
+                    .and_then(|factor| time::Duration::from_millis(100).checked_mul(factor))
+                    .map_or(MAX_BACKOFF, |backoff| backoff.min(MAX_BACKOFF));
+                attempt += 1;
+                debug!(
+                    "Retrying in {:?} (attempt {} of {}): {}: {}",
+                    backoff, attempt, retries, action, error
+                );
+                thread::sleep(backoff);
+            }
+            result => return result,
+        }
+    }
+}
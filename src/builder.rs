@@ -13,13 +13,37 @@
 //! ```
 
 use std::collections::BTreeMap;
+use std::collections::HashMap;
+#[cfg(feature = "shell-hooks")]
+use std::env;
 use std::ffi;
 use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Read;
 use std::iter;
 use std::path;
+use std::sync;
+use std::time;
 
+#[cfg(feature = "tar")]
+use bzip2;
+#[cfg(feature = "tar")]
+use flate2;
+use globset;
+#[cfg(not(feature = "gitignore"))]
 use globwalk;
+#[cfg(feature = "gitignore")]
+use ignore;
+use indexmap::IndexMap;
+use regex;
+use sha2;
+use sha2::Digest;
 use walkdir;
+#[cfg(feature = "tar")]
+use tar;
+#[cfg(feature = "tar")]
+use xz2;
 
 use action;
 use error;
@@ -32,32 +56,341 @@ pub trait ActionBuilder: fmt::Debug {
     ///
     /// - `target_dir`: The location everything will be written to (ie the stage).
     fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::Action>>, error::Errors>;
+
+    /// Consume the builder, creating concrete filesystem actions.
+    ///
+    /// - `target_dir`: The location everything will be written to (ie the stage).
+    fn into_actions(
+        self,
+        target_dir: &path::Path,
+    ) -> Result<Vec<Box<action::Action>>, error::Errors>
+    where
+        Self: Sized,
+    {
+        self.build(target_dir)
+    }
+
+    /// Describe the actions `build` would create, using only the builder's configuration.
+    ///
+    /// Unlike `build`, this never touches the filesystem, so files matched by a pattern are
+    /// described by the pattern rather than listed.
+    ///
+    /// - `target_dir`: The location everything will be written to (ie the stage).
+    fn dry_run_display(&self, target_dir: &path::Path) -> Vec<String> {
+        vec![format!("stage {:?} into {:?}", self, target_dir)]
+    }
 }
 
 impl<A: ActionBuilder + ?Sized> ActionBuilder for Box<A> {
     fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::Action>>, error::Errors> {
-        let target: &A = &self;
+        let target: &A = self;
         target.build(target_dir)
     }
+
+    fn dry_run_display(&self, target_dir: &path::Path) -> Vec<String> {
+        let target: &A = self;
+        target.dry_run_display(target_dir)
+    }
+}
+
+impl<A: ActionBuilder> ActionBuilder for Vec<A> {
+    fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::Action>>, error::Errors> {
+        let mut actions = vec![];
+        let mut errors = error::Errors::new();
+        for builder_actions in self.iter().map(|b| b.build(target_dir)) {
+            match builder_actions {
+                Ok(builder_actions) => actions.extend(builder_actions),
+                Err(builder_errors) => errors.extend(builder_errors),
+            }
+        }
+        errors.ok(actions)
+    }
+
+    fn dry_run_display(&self, target_dir: &path::Path) -> Vec<String> {
+        self.iter()
+            .flat_map(|b| b.dry_run_display(target_dir))
+            .collect()
+    }
 }
 
 /// For each stage target, a list of sources to populate it with.
 ///
-/// The target is a path relative to the stage root.
-#[derive(Default, Debug)]
-pub struct Stage(BTreeMap<path::PathBuf, Vec<Box<ActionBuilder>>>);
+/// The target is a path relative to the stage root.  Targets are built in the order they were
+/// added, except that a target is always built after the targets it `depends_on`.
+#[derive(Default)]
+pub struct Stage {
+    targets: IndexMap<path::PathBuf, Vec<Box<ActionBuilder>>>,
+    dependencies: IndexMap<path::PathBuf, Vec<path::PathBuf>>,
+    per_target_error_policy: HashMap<path::PathBuf, ErrorPolicy>,
+    target_exists_policy: Option<action::ConflictPolicy>,
+    fail_fast: bool,
+    pre_build_hooks: Vec<Box<BuildHook>>,
+    post_build_hooks: Vec<Box<BuildHook>>,
+}
+
+/// Callback run by `Stage::build`, given the stage's `target_dir`.
+pub type BuildHook = Fn(&path::Path) -> Result<(), error::StagingError>;
+
+impl fmt::Debug for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Stage")
+            .field("targets", &self.targets)
+            .field("dependencies", &self.dependencies)
+            .field("per_target_error_policy", &self.per_target_error_policy)
+            .field("target_exists_policy", &self.target_exists_policy)
+            .field("fail_fast", &self.fail_fast)
+            .field("pre_build_hooks", &self.pre_build_hooks.len())
+            .field("post_build_hooks", &self.post_build_hooks.len())
+            .finish()
+    }
+}
 
 impl Stage {
-    pub(crate) fn new(stage: BTreeMap<path::PathBuf, Vec<Box<ActionBuilder>>>) -> Self {
-        Self { 0: stage }
+    #[cfg(feature = "de")]
+    pub(crate) fn new(stage: IndexMap<path::PathBuf, Vec<Box<ActionBuilder>>>) -> Self {
+        Self {
+            targets: stage,
+            ..Default::default()
+        }
+    }
+
+    /// Build `target` after `depends_on`, e.g. when `target` has symlinks into `depends_on`.
+    ///
+    /// Both must be targets of this stage.  Unknown targets and circular dependencies are
+    /// reported on `build`.
+    pub fn depends_on(mut self, target: &path::Path, depends_on: &path::Path) -> Self {
+        self.dependencies
+            .entry(target.to_owned())
+            .or_default()
+            .push(depends_on.to_owned());
+        self
+    }
+
+    /// How to handle `target` failing to build, e.g. to make an optional target non-fatal.
+    ///
+    /// Default is `ErrorPolicy::Fail`.  `target` must be a target of this stage, else it is
+    /// reported on `build`.
+    pub fn on_error(mut self, target: &path::Path, policy: ErrorPolicy) -> Self {
+        self.per_target_error_policy.insert(target.to_owned(), policy);
+        self
+    }
+
+    /// How copied files handle one already existing in the stage, e.g. from an earlier run.
+    ///
+    /// When unset, each file's own policy is kept, which defaults to
+    /// `ConflictPolicy::Overwrite`.  A policy set in the `PerformOptions` takes precedence.
+    pub fn target_exists_policy(mut self, policy: Option<action::ConflictPolicy>) -> Self {
+        self.target_exists_policy = policy;
+        self
+    }
+
+    /// Stop building at the first source that fails, rather than reporting every failure.
+    ///
+    /// Failures in targets with an `ErrorPolicy` other than `Fail` don't stop the build.
+    pub fn fail_fast(mut self, yes: bool) -> Self {
+        self.fail_fast = yes;
+        self
+    }
+
+    /// Run `hook` at the start of `build`, before any actions are built.
+    ///
+    /// Hooks run in the order they were added.  If any fail, no actions are built.
+    pub fn with_pre_build_hook(mut self, hook: Box<BuildHook>) -> Self {
+        self.pre_build_hooks.push(hook);
+        self
+    }
+
+    /// Run `hook` at the end of `build`, after all actions are built but before any are
+    /// performed.
+    ///
+    /// Hooks run in the order they were added.  They are skipped if building the actions failed.
+    pub fn with_post_build_hook(mut self, hook: Box<BuildHook>) -> Self {
+        self.post_build_hooks.push(hook);
+        self
+    }
+
+    fn run_hooks(hooks: &[Box<BuildHook>], target_dir: &path::Path) -> Result<(), error::Errors> {
+        let mut errors = error::Errors::new();
+        for hook in hooks {
+            if let Err(error) = hook(target_dir) {
+                errors.push(error);
+            }
+        }
+        errors.ok(())
+    }
+
+    fn ordered_targets(&self) -> Result<Vec<&path::Path>, error::Errors> {
+        let mut errors = error::Errors::new();
+        for target in self.dependencies.keys() {
+            if !self.targets.contains_key(target) {
+                errors.push(error::ErrorKind::InvalidConfiguration.error().set_context(
+                    format!("dependencies given for unknown target {:?}", target),
+                ));
+            }
+        }
+        let mut policy_targets: Vec<_> = self.per_target_error_policy.keys().collect();
+        policy_targets.sort();
+        for target in policy_targets {
+            if !self.targets.contains_key(target) {
+                errors.push(error::ErrorKind::InvalidConfiguration.error().set_context(
+                    format!("error policy given for unknown target {:?}", target),
+                ));
+            }
+        }
+
+        let mut order = Vec::with_capacity(self.targets.len());
+        // Targets already reported, so a cycle is only reported once rather than once per member.
+        let mut failed = vec![];
+        for target in self.targets.keys() {
+            let mut chain = vec![];
+            if let Err(error) = self.visit_target(target, &mut chain, &mut order, &failed) {
+                errors.push(error);
+                failed.extend(chain);
+            }
+        }
+        errors.ok(order)
+    }
+
+    fn visit_target<'s>(
+        &'s self,
+        target: &'s path::Path,
+        chain: &mut Vec<&'s path::Path>,
+        order: &mut Vec<&'s path::Path>,
+        failed: &[&'s path::Path],
+    ) -> Result<(), error::StagingError> {
+        if order.contains(&target) || failed.contains(&target) {
+            return Ok(());
+        }
+        if let Some(start) = chain.iter().position(|t| *t == target) {
+            let cycle: Vec<_> = chain[start..].iter().chain(Some(&target)).collect();
+            return Err(error::ErrorKind::InvalidConfiguration
+                .error()
+                .set_context(format!("circular dependency between targets: {:?}", cycle)));
+        }
+        chain.push(target);
+        for depends_on in self.dependencies.get(target).into_iter().flatten() {
+            if !self.targets.contains_key(depends_on) {
+                return Err(error::ErrorKind::InvalidConfiguration
+                    .error()
+                    .set_context(format!(
+                        "target {:?} depends on unknown target {:?}",
+                        target, depends_on
+                    )));
+            }
+            self.visit_target(depends_on, chain, order, failed)?;
+        }
+        chain.pop();
+        order.push(target);
+        Ok(())
+    }
+
+    /// Create a `Stage`, reporting invalid targets immediately rather than on `build`.
+    ///
+    /// Targets must be relative to the stage root, must not contain `..`, and must not contain
+    /// NUL bytes.
+    pub fn from_iter_validated<I>(iter: I) -> Result<Self, error::Errors>
+    where
+        I: IntoIterator<Item = (path::PathBuf, Vec<Box<ActionBuilder>>)>,
+    {
+        let mut errors = error::Errors::new();
+        let mut stage = IndexMap::new();
+        for (target, sources) in iter {
+            match validate_target(&target) {
+                Ok(()) => {
+                    stage.insert(target, sources);
+                }
+                Err(error) => errors.push(error),
+            }
+        }
+        errors.ok(Self {
+            targets: stage,
+            ..Default::default()
+        })
+    }
+}
+
+/// How to handle a `Stage` target failing to build.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "de", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "de", serde(rename_all = "lowercase"))]
+pub enum ErrorPolicy {
+    /// Report the failures as errors.
+    #[default]
+    Fail,
+    /// Skip the target, only logging the failures at debug level.
+    Skip,
+    /// Skip the target, logging the failures as warnings.
+    Warn,
+}
+
+impl ErrorPolicy {
+    fn handle(self, target: &path::Path, errors: error::Errors) -> error::Errors {
+        match self {
+            ErrorPolicy::Fail => return errors,
+            ErrorPolicy::Skip => {
+                for error in errors {
+                    debug!("Skipping target {:?}: {}", target, error);
+                }
+            }
+            ErrorPolicy::Warn => {
+                for error in errors {
+                    warn!("Skipping target {:?}: {}", target, error);
+                }
+            }
+        }
+        error::Errors::new()
+    }
+}
+
+/// Whether `cached` is recent enough to be staged, given its `ttl`.
+///
+/// Changes to the source are caught by the cache key, so only the cached copy's age matters.
+fn is_cache_fresh(cached: &path::Path, ttl: Option<time::Duration>) -> bool {
+    let cached_at = match cached.metadata().and_then(|m| m.modified()) {
+        Ok(cached_at) => cached_at,
+        Err(_) => return false,
+    };
+    match ttl {
+        Some(ttl) => match cached_at.elapsed() {
+            Ok(age) => age <= ttl,
+            Err(_) => false,
+        },
+        None => true,
+    }
+}
+
+fn validate_target(target: &path::Path) -> Result<(), error::StagingError> {
+    if target.is_absolute() {
+        return Err(error::ErrorKind::InvalidConfiguration
+            .error()
+            .set_context(format!(
+                "target must be relative to the stage root: {:?}",
+                target
+            )));
+    }
+    if target
+        .components()
+        .any(|c| c == path::Component::ParentDir)
+    {
+        return Err(error::ErrorKind::InvalidConfiguration
+            .error()
+            .set_context(format!("target must not contain `..`: {:?}", target)));
     }
+    if target.to_string_lossy().contains('\0') {
+        return Err(error::ErrorKind::InvalidConfiguration
+            .error()
+            .set_context(format!("target must not contain NUL: {:?}", target)));
+    }
+    Ok(())
 }
 
 impl ActionBuilder for Stage {
     fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::Action>>, error::Errors> {
+        Self::run_hooks(&self.pre_build_hooks, target_dir)?;
         let mut actions = vec![];
         let mut errors = error::Errors::new();
-        for (target, sources) in &self.0 {
+        for target in self.ordered_targets()? {
+            let sources = &self.targets[target];
             if target.is_absolute() {
                 errors.push(
                     error::ErrorKind::HarvestingFailed
@@ -69,15 +402,97 @@ impl ActionBuilder for Stage {
                 );
                 continue;
             }
+            let policy = self.per_target_error_policy
+                .get(target)
+                .cloned()
+                .unwrap_or_default();
             let target = target_dir.join(target);
-            for source_actions in sources.into_iter().map(|s| s.build(&target)) {
-                match source_actions {
-                    Ok(source_actions) => actions.extend(source_actions),
-                    Err(source_errors) => errors.extend(source_errors),
+            #[cfg(feature = "tracing")]
+            let _span = info_span!("staging_target", target = ?target).entered();
+            let mut target_actions = vec![];
+            let mut target_errors = error::Errors::new();
+            for source in sources {
+                match source.build(&target) {
+                    Ok(source_actions) => target_actions.extend(source_actions),
+                    Err(source_errors) => {
+                        target_errors.extend(source_errors);
+                        if self.fail_fast {
+                            break;
+                        }
+                    }
+                }
+            }
+            if target_errors.is_empty() {
+                actions.extend(target_actions);
+            } else {
+                errors.extend(policy.handle(&target, target_errors));
+                if self.fail_fast && !errors.is_empty() {
+                    return Err(errors);
                 }
             }
         }
-        errors.ok(actions)
+        let mut actions = errors.ok(actions)?;
+        if let Some(policy) = self.target_exists_policy {
+            actions = actions
+                .into_iter()
+                .map(|action| -> Box<action::Action> {
+                    if action.describe().kind == action::ActionKind::CopyFile {
+                        Box::new(WithConflictPolicy { action, policy })
+                    } else {
+                        action
+                    }
+                })
+                .collect();
+        }
+        Self::run_hooks(&self.post_build_hooks, target_dir)?;
+        Ok(actions)
+    }
+
+    fn dry_run_display(&self, target_dir: &path::Path) -> Vec<String> {
+        let targets = self.ordered_targets()
+            .unwrap_or_else(|_| self.targets.keys().map(|t| t.as_path()).collect());
+        targets
+            .into_iter()
+            .flat_map(|target| self.targets[target].dry_run_display(&target_dir.join(target)))
+            .collect()
+    }
+}
+
+/// Performs `action` with `Stage::target_exists_policy`, unless the run sets its own policy.
+#[derive(Debug)]
+struct WithConflictPolicy {
+    action: Box<action::Action>,
+    policy: action::ConflictPolicy,
+}
+
+impl fmt::Display for WithConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.action.fmt(f)
+    }
+}
+
+impl action::Action for WithConflictPolicy {
+    fn perform(&self, options: &action::PerformOptions) -> Result<(), error::StagingError> {
+        if options.on_conflict.is_some() {
+            return self.action.perform(options);
+        }
+        self.action.perform(&options.clone().on_conflict(Some(self.policy)))
+    }
+
+    fn affects_path(&self) -> Option<&path::Path> {
+        self.action.affects_path()
+    }
+
+    fn source_paths(&self) -> Vec<&path::Path> {
+        self.action.source_paths()
+    }
+
+    fn estimated_byte_count(&self) -> Option<u64> {
+        self.action.estimated_byte_count()
+    }
+
+    fn describe(&self) -> action::ActionDescription {
+        self.action.describe()
     }
 }
 
@@ -87,7 +502,10 @@ impl iter::FromIterator<(path::PathBuf, Vec<Box<ActionBuilder>>)> for Stage {
         I: IntoIterator<Item = (path::PathBuf, Vec<Box<ActionBuilder>>)>,
     {
         let staging = iter.into_iter().collect();
-        Self { 0: staging }
+        Self {
+            targets: staging,
+            ..Default::default()
+        }
     }
 }
 
@@ -97,6 +515,19 @@ pub struct SourceFile {
     path: path::PathBuf,
     rename: Option<String>,
     symlink: Vec<String>,
+    hard_link: Vec<String>,
+    executable: bool,
+    mode: Option<u32>,
+    extra_targets: Vec<path::PathBuf>,
+    verify_after: bool,
+    encoding_check: Option<action::Encoding>,
+    transform: Option<String>,
+    cache_dir: Option<path::PathBuf>,
+    cache_ttl: Option<time::Duration>,
+    #[cfg(feature = "shell-hooks")]
+    post_copy_command: Option<(String, Vec<String>)>,
+    #[cfg(feature = "shell-hooks")]
+    strip_debug: bool,
 }
 
 impl SourceFile {
@@ -111,6 +542,19 @@ impl SourceFile {
             path: source.into(),
             rename: None,
             symlink: Default::default(),
+            hard_link: Default::default(),
+            executable: false,
+            mode: None,
+            extra_targets: Default::default(),
+            verify_after: false,
+            encoding_check: None,
+            transform: None,
+            cache_dir: None,
+            cache_ttl: None,
+            #[cfg(feature = "shell-hooks")]
+            post_copy_command: None,
+            #[cfg(feature = "shell-hooks")]
+            strip_debug: false,
         }
     }
 
@@ -126,6 +570,151 @@ impl SourceFile {
         self.symlink.extend(symlinks);
         self
     }
+
+    /// Specifies hard links to `rename` in the same target directory.
+    pub fn push_hard_links<I: Iterator<Item = String>>(mut self, links: I) -> Self {
+        self.hard_link.extend(links);
+        self
+    }
+
+    /// When true, the staged file is made executable (`0o755`).  This is ignored on non-unix
+    /// platforms.
+    pub fn executable(mut self, yes: bool) -> Self {
+        self.executable = yes;
+        self
+    }
+
+    /// Specifies the permissions of the staged file, overriding `executable`.  This is ignored
+    /// on non-unix platforms.
+    pub fn mode(mut self, mode: Option<u32>) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Also stage the file into each of `paths`, as copies of the staged file.
+    ///
+    /// `paths` are directories within the target directory, like `compat`.  Each copy is made
+    /// after the staged file's permissions are set, and changes nothing that is later done to it.
+    pub fn also_stage_to<I>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = path::PathBuf>,
+    {
+        self.extra_targets.extend(paths);
+        self
+    }
+
+    /// When true, re-read the staged file after copying and fail if its SHA-256 digest doesn't
+    /// match the source's, catching corruption from unreliable filesystems.
+    pub fn verify_after(mut self, yes: bool) -> Self {
+        self.verify_after = yes;
+        self
+    }
+
+    /// Fail if the source file isn't valid text in `encoding`, checked before it is copied.
+    pub fn encoding_check(mut self, encoding: Option<action::Encoding>) -> Self {
+        self.encoding_check = encoding;
+        self
+    }
+
+    /// Rewrite the file's content while copying it, with the transform registered as `name` in
+    /// `action::TransformRegistry::global()`.
+    pub fn transform(mut self, name: Option<String>) -> Self {
+        self.transform = name;
+        self
+    }
+
+    /// Cache the staged file in `dir`, as it is after any `transform` or `post_copy_command`,
+    /// and stage the cached copy on later runs rather than recomputing it.
+    ///
+    /// Cached copies are keyed by the source file's path, size, and modification time, along
+    /// with the steps applied to it, so changing any of them stages a fresh copy.  Permissions,
+    /// links, and verification are not cached and are applied on every run.
+    pub fn cache_dir(mut self, dir: Option<path::PathBuf>) -> Self {
+        self.cache_dir = dir;
+        self
+    }
+
+    /// How long a copy in `cache_dir` stays fresh.  Default is until the source file changes.
+    pub fn cache_ttl(mut self, ttl: Option<time::Duration>) -> Self {
+        self.cache_ttl = ttl;
+        self
+    }
+
+    /// Where the staged file is cached, if there is a `cache_dir`, and whether that copy can be
+    /// staged as is.
+    ///
+    /// Copies are keyed by the source file's path, size, and modification time, and by
+    /// everything else that goes into computing the staged file.  A missing source isn't
+    /// cached.
+    fn cache_entry(&self) -> Option<(path::PathBuf, bool)> {
+        let cache_dir = self.cache_dir.as_ref()?;
+        let metadata = self.path.metadata().ok()?;
+        let modified = metadata
+            .modified()
+            .ok()?
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut hasher = sha2::Sha256::default();
+        hasher.input(self.path.to_string_lossy().as_bytes());
+        hasher.input(
+            format!(
+                "\0size\0{}\0modified\0{}.{:09}",
+                metadata.len(),
+                modified.as_secs(),
+                modified.subsec_nanos()
+            ).as_bytes(),
+        );
+        if let Some(ref transform) = self.transform {
+            hasher.input(b"\0transform\0");
+            hasher.input(transform.as_bytes());
+        }
+        #[cfg(feature = "shell-hooks")]
+        {
+            for (program, args) in self.post_copy_commands() {
+                hasher.input(b"\0post_copy_command");
+                for arg in iter::once(&program).chain(&args) {
+                    hasher.input(b"\0");
+                    hasher.input(arg.as_bytes());
+                }
+            }
+        }
+        let cached = cache_dir.join(format!("{:x}", hasher.result()));
+        let fresh = is_cache_fresh(&cached, self.cache_ttl);
+        Some((cached, fresh))
+    }
+
+    /// Run `program` with `args` on the staged file, passed as the last argument, after it is
+    /// copied.
+    ///
+    /// This is a last resort, for transformations that can't be expressed otherwise, like
+    /// `strip`.
+    #[cfg(feature = "shell-hooks")]
+    pub fn post_copy_command(mut self, command: Option<(String, Vec<String>)>) -> Self {
+        self.post_copy_command = command;
+        self
+    }
+
+    /// When true, strip debug information from the staged file after it is copied, with
+    /// `strip --strip-debug`.
+    ///
+    /// The `STRIP` environment variable overrides the program (e.g. `llvm-strip`).  This does
+    /// nothing on Windows, where debug information is kept in separate PDB files.
+    #[cfg(feature = "shell-hooks")]
+    pub fn strip_debug(mut self, yes: bool) -> Self {
+        self.strip_debug = yes;
+        self
+    }
+
+    /// Programs to run on the staged file after it is copied, in order.
+    #[cfg(feature = "shell-hooks")]
+    fn post_copy_commands(&self) -> Vec<(String, Vec<String>)> {
+        let mut commands: Vec<_> = self.post_copy_command.iter().cloned().collect();
+        if self.strip_debug && !cfg!(windows) {
+            let strip = env::var("STRIP").unwrap_or_else(|_| "strip".to_owned());
+            commands.push((strip, vec!["--strip-debug".to_owned()]));
+        }
+        commands
+    }
 }
 
 impl ActionBuilder for SourceFile {
@@ -139,7 +728,7 @@ impl ActionBuilder for SourceFile {
 
         let filename = self.rename
             .as_ref()
-            .map(|n| ffi::OsStr::new(n))
+            .map(ffi::OsStr::new)
             .unwrap_or_else(|| path.file_name().unwrap_or_default());
         let filename = path::Path::new(filename);
         if filename.file_name() != Some(filename.as_os_str()) {
@@ -150,10 +739,69 @@ impl ActionBuilder for SourceFile {
                     filename
                 )))?;
         }
+        if let Some(ref name) = self.transform {
+            if action::TransformRegistry::global().get(name).is_none() {
+                Err(error::ErrorKind::InvalidConfiguration
+                    .error()
+                    .set_context(format!("Unknown transform {:?}", name)))?;
+            }
+            if self.verify_after {
+                Err(error::ErrorKind::InvalidConfiguration
+                    .error()
+                    .set_context(format!(
+                        "SourceFile can't verify_after a transform: {:?}",
+                        path
+                    )))?;
+            }
+        }
         let copy_target = target_dir.join(filename);
-        let copy: Box<action::Action> = Box::new(action::CopyFile::new(&copy_target, path));
+        let cache = self.cache_entry();
+        let (copy_source, cache_hit) = match cache {
+            Some((ref cached, true)) => (cached.as_path(), true),
+            _ => (path, false),
+        };
+        let copy = if cache_hit {
+            action::CopyFile::new(&copy_target, copy_source)
+        } else {
+            action::CopyFile::new(&copy_target, path).transform(self.transform.clone())
+        };
+        let copy: Box<action::Action> = Box::new(copy);
 
-        let mut actions = vec![copy];
+        let mut actions = vec![];
+        if let Some(encoding) = self.encoding_check {
+            let check: Box<action::Action> = Box::new(action::VerifyEncoding::new(path, encoding));
+            actions.push(check);
+        }
+        actions.push(copy);
+        if self.verify_after {
+            let verify: Box<action::Action> =
+                Box::new(action::VerifyChecksum::new(&copy_target, copy_source));
+            actions.push(verify);
+        }
+        #[cfg(feature = "shell-hooks")]
+        {
+            if self.strip_debug && cfg!(windows) {
+                warn!("Not stripping {:?}: unsupported on Windows", copy_target);
+            }
+            if !cache_hit {
+                for (program, args) in self.post_copy_commands() {
+                    let command: Box<action::Action> =
+                        Box::new(action::RunCommand::new(&copy_target, program, args));
+                    actions.push(command);
+                }
+            }
+        }
+        if let Some((ref cached, false)) = cache {
+            let store: Box<action::Action> = Box::new(action::CopyFile::new(cached, &copy_target));
+            actions.push(store);
+        }
+        let mode = self.mode
+            .or(if self.executable { Some(0o755) } else { None });
+        if let Some(mode) = mode {
+            let chmod: Box<action::Action> =
+                Box::new(action::SetPermissions::new(&copy_target, mode));
+            actions.push(chmod);
+        }
         actions.extend(self.symlink.iter().map(|s| {
             let s = path::Path::new(s);
             // TODO(epage): Re-enable this error check
@@ -165,18 +813,153 @@ impl ActionBuilder for SourceFile {
             a
         }));
         // TODO(epage): Set symlink permissions
+        for link in &self.hard_link {
+            let link_target = target_dir.join(link);
+            if normalize_path(&link_target) == normalize_path(&copy_target) {
+                Err(error::ErrorKind::HarvestingFailed
+                    .error()
+                    .set_context(format!(
+                        "SourceFile hard link must not be the staged file: {:?}",
+                        link
+                    )))?;
+            }
+            let a: Box<action::Action> = Box::new(action::HardLink::new(link_target, &copy_target));
+            actions.push(a);
+        }
+        let root = normalize_path(target_dir);
+        for extra in &self.extra_targets {
+            let extra_dir = normalize_path(&target_dir.join(extra));
+            if !extra_dir.starts_with(&root) {
+                Err(error::ErrorKind::HarvestingFailed
+                    .error()
+                    .set_context(format!(
+                        "SourceFile also_stage_to must be within the target directory: {:?}",
+                        extra
+                    )))?;
+            }
+            if extra_dir == root {
+                Err(error::ErrorKind::HarvestingFailed
+                    .error()
+                    .set_context(format!(
+                        "SourceFile also_stage_to must not be the target directory: {:?}",
+                        extra
+                    )))?;
+            }
+            // Copied rather than linked, so later changes to one don't show up in the other.
+            let staged = extra_dir.join(filename);
+            let copy: Box<action::Action> = Box::new(action::CopyFile::new(staged, &copy_target));
+            actions.push(copy);
+        }
 
         Ok(actions)
     }
+
+    fn dry_run_display(&self, target_dir: &path::Path) -> Vec<String> {
+        let path = self.path.as_path();
+        let filename = self.rename
+            .as_ref()
+            .map(ffi::OsStr::new)
+            .unwrap_or_else(|| path.file_name().unwrap_or_default());
+        let copy_target = target_dir.join(filename);
+        // Whether a cached copy is fresh can't be known without looking at the filesystem.
+        let cached = match self.cache_dir {
+            Some(ref dir) => format!(" (cached in {:?} if fresh)", dir),
+            None => String::new(),
+        };
+
+        let mut lines = vec![];
+        if let Some(encoding) = self.encoding_check {
+            lines.push(format!("verify --encoding {} {:?}", encoding, path));
+        }
+        lines.push(match self.transform {
+            Some(ref name) => format!(
+                "cp --transform {} {:?} {:?}{}",
+                name, path, copy_target, cached
+            ),
+            None => format!("cp {:?} {:?}{}", path, copy_target, cached),
+        });
+        if self.verify_after {
+            lines.push(format!("verify {:?} {:?}", path, copy_target));
+        }
+        #[cfg(feature = "shell-hooks")]
+        {
+            for (program, args) in self.post_copy_commands() {
+                let mut line = program;
+                for arg in args {
+                    line.push(' ');
+                    line.push_str(&arg);
+                }
+                lines.push(format!("{} {:?}", line, copy_target));
+            }
+        }
+        let mode = self.mode
+            .or(if self.executable { Some(0o755) } else { None });
+        if let Some(mode) = mode {
+            lines.push(format!("chmod {:o} {:?}", mode, copy_target));
+        }
+        for link in &self.symlink {
+            lines.push(format!("ln -s {:?} {:?}", copy_target, target_dir.join(link)));
+        }
+        for link in &self.hard_link {
+            lines.push(format!("ln {:?} {:?}", copy_target, target_dir.join(link)));
+        }
+        for extra in &self.extra_targets {
+            let staged = target_dir.join(extra).join(filename);
+            lines.push(format!("cp {:?} {:?}", copy_target, staged));
+        }
+        lines
+    }
+}
+
+/// Lexically resolve `.` and `..` in `path`.
+fn normalize_path(path: &path::Path) -> path::PathBuf {
+    let mut normalized = path::PathBuf::new();
+    for component in path.components() {
+        match component {
+            path::Component::CurDir => (),
+            path::Component::ParentDir => {
+                normalized.pop();
+            }
+            c => normalized.push(c.as_os_str()),
+        }
+    }
+    normalized
 }
 
+/// Whether `path` is relative and has no `..` components, so it cannot escape the directory it
+/// is joined onto.
+fn is_contained(path: &path::Path) -> bool {
+    path.components()
+        .all(|c| matches!(c, path::Component::Normal(_) | path::Component::CurDir))
+}
+
+/// Maps a matched file's path, relative to the source root, to its path relative to the target.
+pub type PathTransform =
+    Fn(&path::Path) -> Result<path::PathBuf, error::StagingError> + Send + Sync;
+
 /// Specifies a collection of files to be staged into the target directory.
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct SourceFiles {
     path: path::PathBuf,
     pattern: Vec<String>,
     follow_links: bool,
+    follow_links_to_dirs: bool,
     allow_empty: bool,
+    case_sensitive: bool,
+    max_depth: Option<usize>,
+    path_transform: Option<sync::Arc<PathTransform>>,
+    modified_after: Option<time::SystemTime>,
+    modified_before: Option<time::SystemTime>,
+    min_file_size: Option<u64>,
+    file_type_filter: Option<FileTypeFilter>,
+    strip_prefix: Option<path::PathBuf>,
+    relative_to: Option<path::PathBuf>,
+    target_prefix: Option<path::PathBuf>,
+    path_regex_filter: Option<regex::Regex>,
+    max_count: Option<usize>,
+    max_path_length: Option<usize>,
+    explicit_order: Vec<String>,
+    on_walk_error: WalkErrorPolicy,
 }
 
 impl SourceFiles {
@@ -192,13 +975,30 @@ impl SourceFiles {
             path: source.into(),
             pattern: Default::default(),
             follow_links: false,
+            follow_links_to_dirs: false,
             allow_empty: false,
+            case_sensitive: true,
+            max_depth: None,
+            path_transform: None,
+            modified_after: None,
+            modified_before: None,
+            min_file_size: None,
+            file_type_filter: None,
+            strip_prefix: None,
+            relative_to: None,
+            target_prefix: None,
+            path_regex_filter: None,
+            max_count: None,
+            max_path_length: None,
+            explicit_order: Default::default(),
+            on_walk_error: WalkErrorPolicy::Fail,
         }
     }
 
     /// Specifies the `pattern` for executing the recursive/multifile match.
     ///
-    /// `pattern` uses [gitignore][gitignore] syntax.
+    /// `pattern` uses [gitignore][gitignore] syntax, so a pattern without a `/`, including a
+    /// lone `*`, matches at any depth.
     ///
     /// [gitignore]: https://git-scm.com/docs/gitignore#_pattern_format
     pub fn push_patterns<I: Iterator<Item = String>>(mut self, patterns: I) -> Self {
@@ -222,70 +1022,842 @@ impl SourceFiles {
         self.allow_empty = yes;
         self
     }
-}
-
-impl ActionBuilder for SourceFiles {
-    fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::Action>>, error::Errors> {
-        let source_root = self.path.as_path();
-        if !source_root.is_absolute() {
-            Err(error::ErrorKind::HarvestingFailed
-                .error()
-                .set_context(format!(
-                    "SourceFiles path must be absolute: {:?}",
-                    source_root
-                )))?
-        }
 
-        let mut errors = error::Errors::new();
-        let actions: Vec<_> = {
-            let actions = globwalk::GlobWalker::from_patterns(source_root, &self.pattern)
-                .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?;
-            let actions = actions
-                .follow_links(self.follow_links)
-                .into_iter()
-                .map(|entry| copy_entry(entry, source_root, target_dir))
-                .filter_map(|action| action.map(|o| o.map(Ok)).unwrap_or_else(|e| Some(Err(e))));
-            let actions = error::ErrorPartition::new(actions, &mut errors);
-            let actions: Vec<_> = actions.collect();
-            actions
-        };
+    /// When true, symbolic links to directories are walked as if they were normal directories,
+    /// while symbolic links to files, including broken ones, are staged as symbolic links rather
+    /// than copied.
+    ///
+    /// `follow_links` takes precedence, following both.
+    pub fn follow_links_to_dirs(mut self, yes: bool) -> Self {
+        self.follow_links_to_dirs = yes;
+        self
+    }
+
+    /// Toggles whether `pattern` matches case sensitively.
+    ///
+    /// The default of `true` applies on every platform, including those with case-insensitive
+    /// filesystems like macOS and Windows, matching how `git` treats `.gitignore` patterns.
+    pub fn case_sensitive(mut self, yes: bool) -> Self {
+        self.case_sensitive = yes;
+        self
+    }
+
+    /// Only match files at most `depth` directories deep, where `Some(1)` is only the files
+    /// directly in the source root.
+    pub fn max_depth(mut self, depth: Option<usize>) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Rewrite where each matched file is staged.
+    ///
+    /// `transform` receives the file's path relative to the source root and returns the path,
+    /// relative to the target directory, to stage it to.  For example, to strip a leading
+    /// directory or add a version directory.  The returned path must be relative and must not
+    /// contain `..`.
+    pub fn with_path_transform<F>(mut self, transform: F) -> Self
+    where
+        F: Fn(&path::Path) -> Result<path::PathBuf, error::StagingError> + Send + Sync + 'static,
+    {
+        self.path_transform = Some(sync::Arc::new(transform));
+        self
+    }
+
+    /// Only stage files modified at or after `since`.
+    #[deprecated(note = "use `modified_after`")]
+    pub fn min_modified_since(self, since: Option<time::SystemTime>) -> Self {
+        self.modified_after(since)
+    }
+
+    /// Only stage files modified at or after `after`.
+    pub fn modified_after(mut self, after: Option<time::SystemTime>) -> Self {
+        self.modified_after = after;
+        self
+    }
+
+    /// Only stage files modified before `before`.
+    ///
+    /// Combine with `modified_after` to select a range.
+    pub fn modified_before(mut self, before: Option<time::SystemTime>) -> Self {
+        self.modified_before = before;
+        self
+    }
+
+    /// Skip files smaller than `size` bytes, like empty outputs of a failed build step.
+    pub fn min_file_size(mut self, size: Option<u64>) -> Self {
+        self.min_file_size = size;
+        self
+    }
+
+    /// Only stage files of this type.
+    pub fn file_type_filter(mut self, filter: Option<FileTypeFilter>) -> Self {
+        self.file_type_filter = filter;
+        self
+    }
+
+    /// Remove `prefix` from the path of each matched file, relative to the source root, like
+    /// `tar --strip-components`.  Matched files outside of `prefix` are an error.
+    pub fn strip_prefix(mut self, prefix: Option<path::PathBuf>) -> Self {
+        self.strip_prefix = prefix;
+        self
+    }
+
+    /// Stage each matched file relative to `root` rather than the source root, e.g. to stage only
+    /// the part of the path under `<source>/install`.  Matched files outside of `root` are an
+    /// error.
+    pub fn relative_to(mut self, root: Option<path::PathBuf>) -> Self {
+        self.relative_to = root;
+        self
+    }
+
+    /// Stage each matched file under `prefix`, a subdirectory of the target directory.  `prefix`
+    /// must be relative and must not contain `..`.
+    pub fn target_prefix(mut self, prefix: Option<path::PathBuf>) -> Self {
+        self.target_prefix = prefix;
+        self
+    }
+
+    /// Only stage files whose name matches `filter`, in addition to `pattern`.
+    pub fn path_regex_filter(mut self, filter: Option<regex::Regex>) -> Self {
+        self.path_regex_filter = filter;
+        self
+    }
+
+    /// Error if more than `max` files would be staged, guarding against a runaway `pattern`.
+    ///
+    /// Harvesting stops as soon as the limit is exceeded.
+    pub fn max_count(mut self, max: Option<usize>) -> Self {
+        self.max_count = max;
+        self
+    }
+
+    /// Error if a staged path, including the target directory, is longer than `max` bytes, like
+    /// for filesystems or archive formats with path length limits.
+    pub fn max_path_length(mut self, max: Option<usize>) -> Self {
+        self.max_path_length = max;
+        self
+    }
+
+    /// Stage files matching these globs, relative to the source root, first and in this order.
+    /// Remaining files follow.  Each glob must match at least one file.
+    pub fn push_explicit_order<I: Iterator<Item = String>>(mut self, order: I) -> Self {
+        self.explicit_order.extend(order);
+        self
+    }
+
+    /// What to do when walking `path` fails, like on a broken symbolic link with `follow_links`.
+    pub fn on_walk_error(mut self, policy: WalkErrorPolicy) -> Self {
+        self.on_walk_error = policy;
+        self
+    }
+
+    fn order(
+        &self,
+        actions: Vec<Box<action::Action>>,
+    ) -> Result<Vec<Box<action::Action>>, error::Errors> {
+        let mut matchers = Vec::with_capacity(self.explicit_order.len());
+        for glob in &self.explicit_order {
+            let matcher = globset::Glob::new(glob)
+                .map_err(|e| {
+                    error::ErrorKind::InvalidConfiguration
+                        .error()
+                        .set_context(format!("Invalid explicit_order entry: {:?}", glob))
+                        .set_cause(e)
+                })?
+                .compile_matcher();
+            matchers.push(matcher);
+        }
+
+        let mut matched = vec![false; matchers.len()];
+        let mut ranked: Vec<_> = actions
+            .into_iter()
+            .map(|action| {
+                let rank = action
+                    .describe()
+                    .source
+                    .as_ref()
+                    .and_then(|source| source.strip_prefix(&self.path).ok().map(|s| s.to_owned()))
+                    .and_then(|rel| matchers.iter().position(|m| m.is_match(&rel)));
+                if let Some(rank) = rank {
+                    matched[rank] = true;
+                }
+                (rank.unwrap_or(matchers.len()), action)
+            })
+            .collect();
 
-        if actions.is_empty() {
-            if self.allow_empty {
+        let errors: error::Errors = self.explicit_order
+            .iter()
+            .zip(matched)
+            .filter(|&(_, matched)| !matched)
+            .map(|(glob, _)| {
+                error::ErrorKind::HarvestingFailed
+                    .error()
+                    .set_context(format!(
+                        "explicit_order entry {:?} not matched by patterns {:?} under {:?}",
+                        glob, self.pattern, self.path
+                    ))
+            })
+            .collect();
+
+        // Stable, so files not in `explicit_order` keep their order.
+        ranked.sort_by_key(|&(rank, _)| rank);
+        errors.ok(ranked.into_iter().map(|(_, action)| action).collect())
+    }
+
+    fn copy_entry(
+        &self,
+        source_file: &path::Path,
+        target_dir: &path::Path,
+    ) -> Result<Option<Box<action::Action>>, error::StagingError> {
+        if source_file.is_dir() {
+            return Ok(None);
+        }
+        if let Some(ref filter) = self.path_regex_filter {
+            let name = source_file.file_name().unwrap_or_default().to_string_lossy();
+            if !filter.is_match(&name) {
+                return Ok(None);
+            }
+        }
+        if let Some(filter) = self.file_type_filter {
+            if !filter.matches(source_file)? {
+                return Ok(None);
+            }
+        }
+        if self.modified_after.is_some() || self.modified_before.is_some() {
+            let modified = source_file
+                .metadata()
+                .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?
+                .modified()
+                .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?;
+            if let Some(after) = self.modified_after {
+                if modified < after {
+                    return Ok(None);
+                }
+            }
+            if let Some(before) = self.modified_before {
+                if before <= modified {
+                    return Ok(None);
+                }
+            }
+        }
+        if let Some(min_size) = self.min_file_size {
+            let size = source_file
+                .metadata()
+                .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?
+                .len();
+            if size < min_size {
                 info!(
-                    "No files found under {:?} with patterns {:?}",
-                    self.path, self.pattern
+                    "Skipping {:?}: {} bytes is under min_file_size {}",
+                    source_file, size, min_size
                 );
+                return Ok(None);
+            }
+        }
+        let relative_to = self.relative_to.as_ref().unwrap_or(&self.path);
+        let rel_source = source_file.strip_prefix(relative_to).map_err(|e| {
+            error::ErrorKind::HarvestingFailed
+                .error()
+                .set_context(format!(
+                    "SourceFiles match {:?} is outside of {:?}",
+                    source_file, relative_to
+                ))
+                .set_cause(e)
+        })?;
+        let rel_source = match self.strip_prefix {
+            Some(ref prefix) => rel_source.strip_prefix(prefix).map_err(|e| {
+                error::ErrorKind::HarvestingFailed
+                    .error()
+                    .set_context(format!(
+                        "SourceFiles match {:?} is outside of strip_prefix {:?}",
+                        rel_source, prefix
+                    ))
+                    .set_cause(e)
+            })?,
+            None => rel_source,
+        };
+        let rel_target = match self.path_transform {
+            Some(ref transform) => transform(rel_source)?,
+            None => rel_source.to_owned(),
+        };
+        let rel_target = match self.target_prefix {
+            Some(ref prefix) => prefix.join(rel_target),
+            None => rel_target,
+        };
+        if !is_contained(&rel_target) {
+            Err(error::ErrorKind::HarvestingFailed
+                .error()
+                .set_context(format!(
+                    "SourceFiles path transform must return a relative path without `..`: {:?}",
+                    rel_target
+                )))?;
+        }
+        let copy_target = target_dir.join(rel_target);
+        if let Some(max) = self.max_path_length {
+            let len = copy_target.to_string_lossy().len();
+            if max < len {
+                Err(error::ErrorKind::HarvestingFailed
+                    .error()
+                    .set_context(format!(
+                        "Staged path is {} bytes, more than max_path_length {}: {:?}",
+                        len, max, copy_target
+                    )))?;
+            }
+        }
+        if self.follow_links_to_dirs && !self.follow_links {
+            let link = source_file
+                .symlink_metadata()
+                .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?;
+            if link.file_type().is_symlink() {
+                let link_target = fs::read_link(source_file)
+                    .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?;
+                let link: Box<action::Action> =
+                    Box::new(action::Symlink::new(&copy_target, link_target));
+                return Ok(Some(link));
+            }
+        }
+        let copy: Box<action::Action> = Box::new(action::CopyFile::new(&copy_target, source_file));
+        Ok(Some(copy))
+    }
+}
+
+impl fmt::Debug for SourceFiles {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SourceFiles")
+            .field("path", &self.path)
+            .field("pattern", &self.pattern)
+            .field("follow_links", &self.follow_links)
+            .field("follow_links_to_dirs", &self.follow_links_to_dirs)
+            .field("allow_empty", &self.allow_empty)
+            .field("case_sensitive", &self.case_sensitive)
+            .field("max_depth", &self.max_depth)
+            .field("path_transform", &self.path_transform.as_ref().map(|_| "?"))
+            .field("modified_after", &self.modified_after)
+            .field("modified_before", &self.modified_before)
+            .field("min_file_size", &self.min_file_size)
+            .field("file_type_filter", &self.file_type_filter)
+            .field("strip_prefix", &self.strip_prefix)
+            .field("relative_to", &self.relative_to)
+            .field("target_prefix", &self.target_prefix)
+            .field("path_regex_filter", &self.path_regex_filter)
+            .field("max_count", &self.max_count)
+            .field("max_path_length", &self.max_path_length)
+            .field("explicit_order", &self.explicit_order)
+            .field("on_walk_error", &self.on_walk_error)
+            .finish()
+    }
+}
+
+/// Kind of file to select when harvesting files.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "de", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "de", serde(rename_all = "lowercase"))]
+pub enum FileTypeFilter {
+    /// Files with no NUL bytes in their first 8KB.
+    Text,
+    /// Files that are not `Text`.
+    Binary,
+    /// Files with an executable permission bit set.  All files match on non-unix platforms.
+    Executable,
+}
+
+impl FileTypeFilter {
+    fn matches(self, path: &path::Path) -> Result<bool, error::StagingError> {
+        match self {
+            FileTypeFilter::Text => is_text(path),
+            FileTypeFilter::Binary => is_text(path).map(|text| !text),
+            FileTypeFilter::Executable => is_executable(path),
+        }
+    }
+}
+
+/// How to handle failures while walking for files to harvest.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "de", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "de", serde(rename_all = "lowercase"))]
+pub enum WalkErrorPolicy {
+    /// Report the failure as an error.
+    #[default]
+    Fail,
+    /// Skip the entry, only logging the failure at debug level.
+    Skip,
+    /// Skip the entry, logging the failure as a warning.
+    Warn,
+}
+
+impl WalkErrorPolicy {
+    fn handle<T>(
+        self,
+        entry: Result<T, error::StagingError>,
+    ) -> Option<Result<T, error::StagingError>> {
+        match entry {
+            Ok(entry) => Some(Ok(entry)),
+            Err(error) => match self {
+                WalkErrorPolicy::Fail => Some(Err(error)),
+                WalkErrorPolicy::Skip => {
+                    debug!("Skipping entry: {}", error);
+                    None
+                }
+                WalkErrorPolicy::Warn => {
+                    warn!("Skipping entry: {}", error);
+                    None
+                }
+            },
+        }
+    }
+}
+
+fn is_text(path: &path::Path) -> Result<bool, error::StagingError> {
+    let file = fs::File::open(path).map_err(|e| {
+        error::ErrorKind::HarvestingFailed
+            .error()
+            .set_context(format!("Failed reading {:?}", path))
+            .set_cause(e)
+    })?;
+    let mut buffer = Vec::with_capacity(8 * 1024);
+    file.take(8 * 1024).read_to_end(&mut buffer).map_err(|e| {
+        error::ErrorKind::HarvestingFailed
+            .error()
+            .set_context(format!("Failed reading {:?}", path))
+            .set_cause(e)
+    })?;
+    Ok(!buffer.contains(&0))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &path::Path) -> Result<bool, error::StagingError> {
+    use std::os::unix::fs::PermissionsExt;
+    let metadata = path.metadata().map_err(|e| {
+        error::ErrorKind::HarvestingFailed
+            .error()
+            .set_context(format!("Failed reading {:?}", path))
+            .set_cause(e)
+    })?;
+    Ok(metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(_path: &path::Path) -> Result<bool, error::StagingError> {
+    Ok(true)
+}
+
+impl ActionBuilder for SourceFiles {
+    fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::Action>>, error::Errors> {
+        if let Some(ref prefix) = self.target_prefix {
+            if !is_contained(prefix) {
+                Err(error::ErrorKind::InvalidConfiguration
+                    .error()
+                    .set_context(format!(
+                        "SourceFiles target_prefix must be a relative path without `..`: {:?}",
+                        prefix
+                    )))?;
+            }
+        }
+        let source_root = self.path.as_path();
+        harvest(
+            "SourceFiles",
+            source_root,
+            &self.pattern,
+            HarvestOptions {
+                follow_links: self.follow_links,
+                follow_links_to_dirs: self.follow_links_to_dirs,
+                allow_empty: self.allow_empty,
+                max_count: self.max_count,
+                on_walk_error: self.on_walk_error,
+                case_sensitive: self.case_sensitive,
+                max_depth: self.max_depth,
+            },
+            |entry| self.copy_entry(entry, target_dir),
+        ).and_then(|actions| {
+            if self.explicit_order.is_empty() {
+                Ok(actions)
             } else {
+                self.order(actions)
+            }
+        })
+    }
+
+    fn dry_run_display(&self, target_dir: &path::Path) -> Vec<String> {
+        let target_dir = match self.target_prefix {
+            Some(ref prefix) => target_dir.join(prefix),
+            None => target_dir.to_owned(),
+        };
+        self.pattern
+            .iter()
+            .map(|pattern| {
+                format!(
+                    "cp {:?} {:?}",
+                    self.path.join(pattern.trim_start_matches('/')),
+                    target_dir
+                )
+            })
+            .collect()
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct HarvestOptions {
+    follow_links: bool,
+    follow_links_to_dirs: bool,
+    allow_empty: bool,
+    max_count: Option<usize>,
+    on_walk_error: WalkErrorPolicy,
+    case_sensitive: bool,
+    max_depth: Option<usize>,
+}
+
+fn harvest<F>(
+    kind: &str,
+    source_root: &path::Path,
+    patterns: &[String],
+    options: HarvestOptions,
+    stage_entry: F,
+) -> Result<Vec<Box<action::Action>>, error::Errors>
+where
+    F: Fn(&path::Path) -> Result<Option<Box<action::Action>>, error::StagingError>,
+{
+    let HarvestOptions {
+        follow_links,
+        follow_links_to_dirs,
+        allow_empty,
+        max_count,
+        on_walk_error,
+        case_sensitive,
+        max_depth,
+    } = options;
+    if !source_root.is_absolute() {
+        Err(error::ErrorKind::HarvestingFailed
+            .error()
+            .set_context(format!(
+                "{} path must be absolute: {:?}",
+                kind, source_root
+            )))?
+    }
+    if !source_root.exists() {
+        Err(error::ErrorKind::HarvestingFailed
+            .error()
+            .set_context(format!("Source root does not exist: {:?}", source_root)))?
+    }
+
+    let mut errors = error::Errors::new();
+    let mut actions = Vec::new();
+    {
+        let mut entries = walk(source_root, patterns, follow_links, case_sensitive, max_depth)?;
+        if follow_links_to_dirs && !follow_links {
+            let linked = walk_linked_dirs(source_root, patterns, case_sensitive, max_depth)?;
+            entries = Box::new(entries.chain(linked));
+        }
+        let staged = entries
+            .filter_map(|entry| on_walk_error.handle(entry))
+            .map(|entry| entry.and_then(|source_file| stage_entry(&source_file)))
+            .filter_map(|action| action.map(|o| o.map(Ok)).unwrap_or_else(|e| Some(Err(e))));
+        for action in error::ErrorPartition::new(staged, &mut errors) {
+            if max_count.map(|max| max <= actions.len()).unwrap_or(false) {
+                // Stop walking rather than harvesting a runaway pattern.
                 Err(error::ErrorKind::HarvestingFailed
                     .error()
                     .set_context(format!(
-                        "No files found under {:?} with patterns {:?}",
-                        self.path, self.pattern
+                        "More than {} files found under {:?} with patterns {:?}",
+                        actions.len(),
+                        source_root,
+                        patterns
                     )))?
             }
+            actions.push(action);
         }
+    }
 
-        errors.ok(actions)
+    if actions.is_empty() {
+        if allow_empty {
+            info!(
+                "No files found under {:?} with patterns {:?}",
+                source_root, patterns
+            );
+        } else {
+            Err(error::ErrorKind::HarvestingFailed
+                .error()
+                .set_context(format!(
+                    "No files found under {:?} with patterns {:?}",
+                    source_root, patterns
+                )))?
+        }
+    }
+
+    errors.ok(actions)
+}
+
+#[cfg(not(feature = "gitignore"))]
+fn walk(
+    source_root: &path::Path,
+    patterns: &[String],
+    follow_links: bool,
+    case_sensitive: bool,
+    max_depth: Option<usize>,
+) -> Result<Box<Iterator<Item = Result<path::PathBuf, error::StagingError>>>, error::StagingError> {
+    let patterns: Vec<_> = patterns.iter().map(|p| globwalk_pattern(p)).collect();
+    let mut walker = globwalk::GlobWalkerBuilder::from_patterns(source_root, &patterns)
+        .follow_links(follow_links)
+        .case_insensitive(!case_sensitive);
+    if let Some(depth) = max_depth {
+        walker = walker.max_depth(depth);
+    }
+    let walker = walker
+        .build()
+        .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?;
+    let entries = walker.into_iter().map(|entry| {
+        entry
+            .map(|e| e.path().to_owned())
+            .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))
+    });
+    Ok(Box::new(entries))
+}
+
+/// `globwalk` 0.9 rewrites a lone `*` to `/*`, anchoring it to `source_root`.  Undo that so
+/// patterns keep their `.gitignore` meaning, as with `globwalk` 0.4 and the `gitignore` walker.
+///
+/// Every other pattern, like `*.txt` or `dir/*`, is passed to `ignore` unchanged.
+#[cfg(not(feature = "gitignore"))]
+fn globwalk_pattern(pattern: &str) -> &str {
+    if pattern == "*" {
+        "**/*"
+    } else {
+        pattern
+    }
+}
+
+// Unlike `globwalk`, this respects `.gitignore` files, including those in parent directories.
+#[cfg(feature = "gitignore")]
+fn walk(
+    source_root: &path::Path,
+    patterns: &[String],
+    follow_links: bool,
+    case_sensitive: bool,
+    max_depth: Option<usize>,
+) -> Result<Box<Iterator<Item = Result<path::PathBuf, error::StagingError>>>, error::StagingError> {
+    let mut overrides = ignore::overrides::OverrideBuilder::new(source_root);
+    overrides
+        .case_insensitive(!case_sensitive)
+        .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?;
+    for pattern in patterns {
+        overrides
+            .add(pattern)
+            .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?;
+    }
+    let overrides = overrides
+        .build()
+        .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?;
+    // Not using `WalkBuilder::overrides` since those take precedence over `.gitignore`.
+    let walker = ignore::WalkBuilder::new(source_root)
+        .follow_links(follow_links)
+        .max_depth(max_depth)
+        .hidden(false)
+        .filter_entry(move |entry| {
+            let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+            !overrides.matched(entry.path(), is_dir).is_ignore()
+        })
+        .build();
+    let entries = walker.map(|entry| {
+        entry
+            .map(|e| e.into_path())
+            .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))
+    });
+    Ok(Box::new(entries))
+}
+
+/// Files matching `patterns` within the directories that symbolic links under `source_root`
+/// point to, as if the links were normal directories.
+///
+/// Unlike having the walker follow links, links to files and broken links are left for the caller
+/// to stage as they are.  A link to one of its own ancestors is skipped rather than walked forever.
+fn walk_linked_dirs(
+    source_root: &path::Path,
+    patterns: &[String],
+    case_sensitive: bool,
+    max_depth: Option<usize>,
+) -> Result<Box<Iterator<Item = Result<path::PathBuf, error::StagingError>>>, error::StagingError> {
+    let mut linked_dirs = Vec::new();
+    let mut errors = Vec::new();
+    let mut pending = vec![source_root.to_owned()];
+    while let Some(dir) = pending.pop() {
+        let is_linked = dir != source_root;
+        for entry in walk_dir(&dir) {
+            let (entry, is_symlink) = match entry {
+                Ok(entry) => entry,
+                // The walk of `source_root` itself already reports its errors.
+                Err(error) => {
+                    if is_linked {
+                        errors.push(Err(error));
+                    }
+                    continue;
+                }
+            };
+            if is_symlink && entry.is_dir() {
+                match is_link_loop(&entry) {
+                    Ok(true) => debug!("Skipping link to its own ancestor {:?}", entry),
+                    Ok(false) => {
+                        linked_dirs.push(entry.clone());
+                        pending.push(entry);
+                    }
+                    Err(e) => errors.push(Err(error::ErrorKind::HarvestingFailed
+                        .error()
+                        .set_context(format!("Failed resolving {:?}", entry))
+                        .set_cause(e))),
+                }
+            }
+        }
+    }
+    if linked_dirs.is_empty() {
+        return Ok(Box::new(errors.into_iter()));
+    }
+
+    // Match with the walker itself, following links, so patterns mean the same as they do for
+    // the rest of `source_root`.  Its errors are dropped: broken links are staged by the main
+    // walk, and failures within linked directories were reported above.
+    let files = walk(source_root, patterns, true, case_sensitive, max_depth)?
+        .filter_map(|entry| entry.ok())
+        .filter(move |entry| {
+            !entry.is_dir() && linked_dirs.iter().any(|dir| entry.starts_with(dir))
+        })
+        .map(Ok);
+    Ok(Box::new(errors.into_iter().chain(files)))
+}
+
+fn is_link_loop(link: &path::Path) -> io::Result<bool> {
+    let resolved = link.canonicalize()?;
+    let parent = link.parent().unwrap_or(link).canonicalize()?;
+    Ok(parent.starts_with(resolved))
+}
+
+/// Entries under `dir`, and whether each is a symbolic link, without following links.
+#[cfg(not(feature = "gitignore"))]
+fn walk_dir(
+    dir: &path::Path,
+) -> Box<Iterator<Item = Result<(path::PathBuf, bool), error::StagingError>>> {
+    let entries = walkdir::WalkDir::new(dir).min_depth(1).into_iter().map(|entry| {
+        entry
+            .map(|e| (e.path().to_owned(), e.path_is_symlink()))
+            .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))
+    });
+    Box::new(entries)
+}
+
+/// Entries under `dir`, and whether each is a symbolic link, without following links.
+#[cfg(feature = "gitignore")]
+fn walk_dir(
+    dir: &path::Path,
+) -> Box<Iterator<Item = Result<(path::PathBuf, bool), error::StagingError>>> {
+    let entries = ignore::WalkBuilder::new(dir)
+        .hidden(false)
+        .build()
+        .filter(|entry| entry.as_ref().map(|e| e.depth() != 0).unwrap_or(true))
+        .map(|entry| {
+            entry
+                .map(|e| {
+                    let is_symlink = e.path_is_symlink();
+                    (e.into_path(), is_symlink)
+                })
+                .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))
+        });
+    Box::new(entries)
+}
+
+/// Specifies a collection of files to be staged into the target directory as symbolic links.
+#[derive(Clone, Debug)]
+pub struct SymlinkTree {
+    path: path::PathBuf,
+    pattern: Vec<String>,
+    follow_links: bool,
+    allow_empty: bool,
+}
+
+impl SymlinkTree {
+    /// Specifies a collection of files to be staged into the target directory as symbolic links.
+    ///
+    /// - `source`: the root path that `pattern` will be run on to identify files to be linked into
+    ///   the target directory.
+    pub fn new<P>(source: P) -> Self
+    where
+        P: Into<path::PathBuf>,
+    {
+        Self {
+            path: source.into(),
+            pattern: Default::default(),
+            follow_links: false,
+            allow_empty: false,
+        }
+    }
+
+    /// Specifies the `pattern` for executing the recursive/multifile match.
+    ///
+    /// `pattern` uses [gitignore][gitignore] syntax.
+    ///
+    /// [gitignore]: https://git-scm.com/docs/gitignore#_pattern_format
+    pub fn push_patterns<I: Iterator<Item = String>>(mut self, patterns: I) -> Self {
+        self.pattern.extend(patterns);
+        self
+    }
+
+    /// When true, symbolic links are followed as if they were normal directories and files.
+    /// If a symbolic link is broken or is involved in a loop, an error is yielded.
+    pub fn follow_links(mut self, yes: bool) -> Self {
+        self.follow_links = yes;
+        self
+    }
+
+    /// Toggles whether no results for the pattern constitutes an error.
+    pub fn allow_empty(mut self, yes: bool) -> Self {
+        self.allow_empty = yes;
+        self
+    }
+}
+
+impl ActionBuilder for SymlinkTree {
+    fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::Action>>, error::Errors> {
+        let source_root = self.path.as_path();
+        harvest(
+            "SymlinkTree",
+            source_root,
+            &self.pattern,
+            HarvestOptions {
+                follow_links: self.follow_links,
+                follow_links_to_dirs: false,
+                allow_empty: self.allow_empty,
+                max_count: None,
+                on_walk_error: WalkErrorPolicy::Fail,
+                case_sensitive: true,
+                max_depth: None,
+            },
+            |entry| link_entry(entry, source_root, target_dir),
+        )
+    }
+
+    fn dry_run_display(&self, target_dir: &path::Path) -> Vec<String> {
+        self.pattern
+            .iter()
+            .map(|pattern| {
+                format!(
+                    "ln -s {:?} {:?}",
+                    self.path.join(pattern.trim_start_matches('/')),
+                    target_dir
+                )
+            })
+            .collect()
     }
 }
 
-fn copy_entry(
-    entry: Result<walkdir::DirEntry, globwalk::WalkError>,
+fn link_entry(
+    source_file: &path::Path,
     source_root: &path::Path,
     target_dir: &path::Path,
 ) -> Result<Option<Box<action::Action>>, error::StagingError> {
-    let entry = entry.map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?;
-    let source_file = entry.path();
     if source_file.is_dir() {
         return Ok(None);
     }
     let rel_source = source_file
         .strip_prefix(source_root)
         .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?;
-    let copy_target = target_dir.join(rel_source);
-    let copy: Box<action::Action> = Box::new(action::CopyFile::new(&copy_target, source_file));
-    Ok(Some(copy))
+    let staged = target_dir.join(rel_source);
+    let link: Box<action::Action> = Box::new(action::Symlink::new(staged, source_file));
+    Ok(Some(link))
 }
 
 /// Specifies a symbolic link file to be staged into the target directory.
@@ -293,6 +1865,8 @@ fn copy_entry(
 pub struct Symlink {
     target: path::PathBuf,
     rename: Option<String>,
+    must_exist: bool,
+    relative_to_target: bool,
 }
 
 impl Symlink {
@@ -306,6 +1880,8 @@ impl Symlink {
         Self {
             target: target.into(),
             rename: None,
+            must_exist: false,
+            relative_to_target: false,
         }
     }
 
@@ -315,15 +1891,48 @@ impl Symlink {
         self.rename = filename.map(|f| f.into());
         self
     }
-}
 
-impl ActionBuilder for Symlink {
-    fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::Action>>, error::Errors> {
-        let target = self.target.as_path();
+    /// When true, fail if `target` does not exist rather than staging a dangling symlink.
+    /// Default is `false`.
+    pub fn must_exist(mut self, yes: bool) -> Self {
+        self.must_exist = yes;
+        self
+    }
 
-        let filename = self.rename
+    /// When true, point the symlink at an absolute `target` by a path relative to where it is
+    /// staged, so a `target` within the stage stays valid when the whole stage is moved.  A
+    /// relative `target` is already relative to the link and is used as-is.  Default is `false`.
+    pub fn relative_to_target(mut self, yes: bool) -> Self {
+        self.relative_to_target = yes;
+        self
+    }
+
+    /// The path the symlink staged into `target_dir` points to.
+    fn link_target(&self, target_dir: &path::Path) -> Result<path::PathBuf, error::StagingError> {
+        if !self.relative_to_target || self.target.is_relative() {
+            return Ok(self.target.clone());
+        }
+        pathdiff::diff_paths(&self.target, target_dir).ok_or_else(|| {
+            error::ErrorKind::HarvestingFailed.error().set_context(format!(
+                "Cannot make symlink target {:?} relative to {:?}",
+                self.target, target_dir
+            ))
+        })
+    }
+}
+
+impl ActionBuilder for Symlink {
+    fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::Action>>, error::Errors> {
+        let target = self.target.as_path();
+        if self.must_exist && !target.exists() {
+            Err(error::ErrorKind::HarvestingFailed
+                .error()
+                .set_context(format!("Symlink target {:?} does not exist", target)))?
+        }
+
+        let filename = self.rename
             .as_ref()
-            .map(|n| ffi::OsStr::new(n))
+            .map(ffi::OsStr::new)
             .unwrap_or_else(|| target.file_name().unwrap_or_default());
         let filename = path::Path::new(filename);
         if filename.file_name() != Some(filename.as_os_str()) {
@@ -335,10 +1944,1851 @@ impl ActionBuilder for Symlink {
                 )))?
         }
         let staged = target_dir.join(filename);
-        let link: Box<action::Action> = Box::new(action::Symlink::new(&staged, target));
+        let link_target = self.link_target(target_dir)?;
+        let link: Box<action::Action> = Box::new(action::Symlink::new(&staged, link_target));
 
         let actions = vec![link];
 
         Ok(actions)
     }
+
+    fn dry_run_display(&self, target_dir: &path::Path) -> Vec<String> {
+        let target = self.target.as_path();
+        let filename = self.rename
+            .as_ref()
+            .map(ffi::OsStr::new)
+            .unwrap_or_else(|| target.file_name().unwrap_or_default());
+        let link_target = self.link_target(target_dir).unwrap_or_else(|_| target.to_owned());
+        vec![format!("ln -s {:?} {:?}", link_target, target_dir.join(filename))]
+    }
+}
+
+/// Specifies a file to be generated in the target directory from in-memory content.
+#[derive(Clone, Debug)]
+pub struct InlineContent {
+    content: Vec<u8>,
+    rename: String,
+}
+
+impl InlineContent {
+    /// Specifies a file to be generated in the target directory from in-memory content.
+    ///
+    /// - `rename`: the name of the generated file.
+    /// - `content`: the data to write to the file.
+    pub fn new<R, S>(rename: R, content: S) -> Self
+    where
+        R: Into<String>,
+        S: Into<String>,
+    {
+        Self::from_bytes(rename, content.into().into_bytes())
+    }
+
+    /// Specifies a file to be generated in the target directory from in-memory binary content.
+    ///
+    /// - `rename`: the name of the generated file.
+    /// - `content`: the data to write to the file.
+    pub fn from_bytes<R, B>(rename: R, content: B) -> Self
+    where
+        R: Into<String>,
+        B: Into<Vec<u8>>,
+    {
+        Self {
+            content: content.into(),
+            rename: rename.into(),
+        }
+    }
+}
+
+impl ActionBuilder for InlineContent {
+    fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::Action>>, error::Errors> {
+        let filename = path::Path::new(&self.rename);
+        if filename.file_name() != Some(filename.as_os_str()) {
+            Err(error::ErrorKind::HarvestingFailed
+                .error()
+                .set_context(format!(
+                    "InlineContent rename must not change directories: {:?}",
+                    filename
+                )))?
+        }
+        let staged = target_dir.join(filename);
+        let write: Box<action::Action> =
+            Box::new(action::WriteInlineContent::from_bytes(staged, self.content.clone()));
+
+        let actions = vec![write];
+
+        Ok(actions)
+    }
+
+    fn dry_run_display(&self, target_dir: &path::Path) -> Vec<String> {
+        vec![format!("write {:?}", target_dir.join(&self.rename))]
+    }
+}
+
+/// Specifies a file to be generated in the target directory from the standard output of a
+/// program.
+///
+/// The program is only run when the staging is performed, not when it is built.
+#[cfg(feature = "exec")]
+#[derive(Clone, Debug)]
+pub struct CommandOutput {
+    program: String,
+    args: Vec<String>,
+    rename: String,
+    allow_empty: bool,
+}
+
+#[cfg(feature = "exec")]
+impl CommandOutput {
+    /// Specifies a file to be generated in the target directory from the standard output of a
+    /// program.
+    ///
+    /// - `rename`: the name of the generated file.
+    /// - `program`: the program to run.
+    pub fn new<R, S>(rename: R, program: S) -> Self
+    where
+        R: Into<String>,
+        S: Into<String>,
+    {
+        Self {
+            program: program.into(),
+            args: Default::default(),
+            rename: rename.into(),
+            allow_empty: false,
+        }
+    }
+
+    /// Specifies the arguments to pass to the program.
+    pub fn push_args<I: Iterator<Item = String>>(mut self, args: I) -> Self {
+        self.args.extend(args);
+        self
+    }
+
+    /// Toggles whether the program writing nothing is an error.
+    pub fn allow_empty(mut self, yes: bool) -> Self {
+        self.allow_empty = yes;
+        self
+    }
+}
+
+#[cfg(feature = "exec")]
+impl ActionBuilder for CommandOutput {
+    fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::Action>>, error::Errors> {
+        let filename = path::Path::new(&self.rename);
+        if filename.file_name() != Some(filename.as_os_str()) {
+            Err(error::ErrorKind::HarvestingFailed
+                .error()
+                .set_context(format!(
+                    "CommandOutput rename must not change directories: {:?}",
+                    filename
+                )))?
+        }
+        let staged = target_dir.join(filename);
+        let write: Box<action::Action> = Box::new(
+            action::WriteCommandOutput::new(staged, self.program.clone(), self.args.clone())
+                .allow_empty(self.allow_empty),
+        );
+
+        Ok(vec![write])
+    }
+
+    fn dry_run_display(&self, target_dir: &path::Path) -> Vec<String> {
+        let mut line = self.program.clone();
+        for arg in &self.args {
+            line.push(' ');
+            line.push_str(arg);
+        }
+        vec![format!("{} > {:?}", line, target_dir.join(&self.rename))]
+    }
+}
+
+/// Specifies a file in the target directory to have its modification time updated to now.
+///
+/// The file is created empty if it doesn't exist.
+#[derive(Clone, Debug)]
+pub struct TouchFile {
+    rename: String,
+}
+
+impl TouchFile {
+    /// Specifies a file in the target directory to have its modification time updated to now.
+    ///
+    /// - `rename`: the name of the file.
+    pub fn new<R>(rename: R) -> Self
+    where
+        R: Into<String>,
+    {
+        Self {
+            rename: rename.into(),
+        }
+    }
+}
+
+impl ActionBuilder for TouchFile {
+    fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::Action>>, error::Errors> {
+        let filename = path::Path::new(&self.rename);
+        if filename.file_name() != Some(filename.as_os_str()) {
+            Err(error::ErrorKind::HarvestingFailed
+                .error()
+                .set_context(format!(
+                    "TouchFile rename must not change directories: {:?}",
+                    filename
+                )))?
+        }
+        let staged = target_dir.join(filename);
+        let touch: Box<action::Action> = Box::new(action::Touch::new(staged));
+
+        let actions = vec![touch];
+
+        Ok(actions)
+    }
+
+    fn dry_run_display(&self, target_dir: &path::Path) -> Vec<String> {
+        vec![format!("touch {:?}", target_dir.join(&self.rename))]
+    }
+}
+
+/// Specifies members of a tar archive to be staged into the target directory.
+///
+/// Regular files are written from the archive and symbolic links are recreated.  Other members,
+/// like directories, are skipped.  Symbolic links pointing outside of the target directory, and
+/// members within a symbolic link, are errors.
+#[cfg(feature = "tar")]
+#[derive(Clone, Debug)]
+pub struct TarMembers {
+    archive: path::PathBuf,
+    pattern: Vec<String>,
+    strip_components: usize,
+}
+
+#[cfg(feature = "tar")]
+impl TarMembers {
+    /// Specifies members of a tar archive to be staged into the target directory.
+    ///
+    /// - `archive`: path to a `.tar`, `.tar.gz`, `.tar.bz2`, or `.tar.xz` archive.
+    pub fn new<P>(archive: P) -> Self
+    where
+        P: Into<path::PathBuf>,
+    {
+        Self {
+            archive: archive.into(),
+            pattern: Default::default(),
+            strip_components: 0,
+        }
+    }
+
+    /// Specifies the glob `pattern`s members of the archive must match.
+    pub fn push_patterns<I: Iterator<Item = String>>(mut self, patterns: I) -> Self {
+        self.pattern.extend(patterns);
+        self
+    }
+
+    /// Remove `count` leading components from each member's path, like `tar
+    /// --strip-components`.  Members with no more components than that are skipped.
+    pub fn strip_components(mut self, count: usize) -> Self {
+        self.strip_components = count;
+        self
+    }
+
+    fn open(&self) -> Result<Box<Read>, error::StagingError> {
+        let file = fs::File::open(&self.archive).map_err(|e| {
+            error::ErrorKind::HarvestingFailed
+                .error()
+                .set_context(format!("Failed to open {:?}", self.archive))
+                .set_cause(e)
+        })?;
+        let name = self.archive.to_string_lossy();
+        let reader: Box<Read> = if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Box::new(flate2::read::GzDecoder::new(file))
+        } else if name.ends_with(".tar.bz2") {
+            Box::new(bzip2::read::BzDecoder::new(file))
+        } else if name.ends_with(".tar.xz") {
+            Box::new(xz2::read::XzDecoder::new(file))
+        } else {
+            Box::new(file)
+        };
+        Ok(reader)
+    }
+
+    fn matcher(&self) -> Result<globset::GlobSet, error::StagingError> {
+        let mut builder = globset::GlobSetBuilder::new();
+        for pattern in &self.pattern {
+            let glob = globset::Glob::new(pattern).map_err(|e| {
+                error::ErrorKind::InvalidConfiguration
+                    .error()
+                    .set_context(format!("Invalid pattern: {:?}", pattern))
+                    .set_cause(e)
+            })?;
+            builder.add(glob);
+        }
+        builder
+            .build()
+            .map_err(|e| error::ErrorKind::InvalidConfiguration.error().set_cause(e))
+    }
+
+    /// Create the action staging `entry` at `stripped`, relative to `target_dir`.
+    fn stage_entry<R: Read>(
+        &self,
+        entry: &mut tar::Entry<R>,
+        stripped: &path::Path,
+        target_dir: &path::Path,
+    ) -> Result<Option<Box<action::Action>>, error::StagingError> {
+        let staged = target_dir.join(stripped);
+        let entry_type = entry.header().entry_type();
+        let action: Box<action::Action> = if entry_type.is_file() {
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content).map_err(|e| {
+                error::ErrorKind::HarvestingFailed
+                    .error()
+                    .set_context(format!("Failed to read {:?} from {:?}", staged, self.archive))
+                    .set_cause(e)
+            })?;
+            let mode = entry.header().mode().map_err(|e| {
+                error::ErrorKind::HarvestingFailed
+                    .error()
+                    .set_context(format!("Failed to read the mode of {:?}", staged))
+                    .set_cause(e)
+            })?;
+            let write = action::WriteInlineContent::from_bytes(staged, content);
+            Box::new(write.mode(Some(mode & 0o7777)))
+        } else if entry_type.is_symlink() {
+            let target = entry
+                .link_name()
+                .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?
+                .ok_or_else(|| {
+                    error::ErrorKind::HarvestingFailed
+                        .error()
+                        .set_context(format!("Symlink {:?} has no target", staged))
+                })?;
+            // Resolved lexically from the link's directory, tracking how deep within
+            // `target_dir` it is.
+            let mut depth = normalize_path(stripped).components().count().saturating_sub(1);
+            let contained = target.components().all(|c| match c {
+                path::Component::Normal(_) => {
+                    depth += 1;
+                    true
+                }
+                path::Component::CurDir => true,
+                path::Component::ParentDir if depth > 0 => {
+                    depth -= 1;
+                    true
+                }
+                _ => false,
+            });
+            if !contained {
+                return Err(error::ErrorKind::HarvestingFailed
+                    .error()
+                    .set_context(format!(
+                        "Symlink {:?} in {:?} points outside of the target directory: {:?}",
+                        stripped, self.archive, target
+                    )));
+            }
+            Box::new(action::Symlink::new(staged, target.into_owned()))
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(action))
+    }
+}
+
+#[cfg(feature = "tar")]
+impl ActionBuilder for TarMembers {
+    fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::Action>>, error::Errors> {
+        let matcher = self.matcher()?;
+        let mut archive = tar::Archive::new(self.open()?);
+        let entries = archive.entries().map_err(|e| {
+            error::ErrorKind::HarvestingFailed
+                .error()
+                .set_context(format!("Failed to read {:?}", self.archive))
+                .set_cause(e)
+        })?;
+
+        let mut actions = Vec::new();
+        let mut errors = error::Errors::new();
+        // Members can't be written through a symlink, which could point anywhere.
+        let mut symlinks: Vec<path::PathBuf> = Vec::new();
+        for entry in entries {
+            let mut entry = entry.map_err(|e| {
+                error::ErrorKind::HarvestingFailed
+                    .error()
+                    .set_context(format!("Failed to read {:?}", self.archive))
+                    .set_cause(e)
+            })?;
+            let path = entry
+                .path()
+                .map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e))?
+                .into_owned();
+            if !matcher.is_match(&path) {
+                continue;
+            }
+            let stripped: path::PathBuf = path.components().skip(self.strip_components).collect();
+            if stripped.as_os_str().is_empty() {
+                continue;
+            }
+            // A leading `./`, from `tar -C dir .`, can't escape `target_dir`.
+            let contained = stripped.components().all(|c| {
+                if let path::Component::Normal(_) = c {
+                    true
+                } else {
+                    c == path::Component::CurDir
+                }
+            });
+            if !contained {
+                errors.push(
+                    error::ErrorKind::HarvestingFailed
+                        .error()
+                        .set_context(format!(
+                            "Member {:?} of {:?} must be a relative path without `..`",
+                            path, self.archive
+                        )),
+                );
+                continue;
+            }
+            if let Some(link) = symlinks.iter().find(|l| stripped.starts_with(l)) {
+                errors.push(
+                    error::ErrorKind::HarvestingFailed
+                        .error()
+                        .set_context(format!(
+                            "Member {:?} of {:?} is within the symlink {:?}",
+                            path, self.archive, link
+                        )),
+                );
+                continue;
+            }
+            if entry.header().entry_type().is_symlink() {
+                symlinks.push(normalize_path(&stripped));
+            }
+            match self.stage_entry(&mut entry, &stripped, target_dir) {
+                Ok(Some(action)) => actions.push(action),
+                Ok(None) => (),
+                Err(e) => errors.push(e),
+            }
+        }
+
+        if actions.is_empty() && errors.is_empty() {
+            errors.push(
+                error::ErrorKind::HarvestingFailed
+                    .error()
+                    .set_context(format!(
+                        "No members of {:?} found matching {:?}",
+                        self.archive, self.pattern
+                    )),
+            );
+        }
+
+        errors.ok(actions)
+    }
+
+    fn dry_run_display(&self, target_dir: &path::Path) -> Vec<String> {
+        self.pattern
+            .iter()
+            .map(|pattern| format!("tar -x {:?} {} {:?}", self.archive, pattern, target_dir))
+            .collect()
+    }
+}
+
+/// Specifies a manifest of everything staged by another `ActionBuilder`.
+///
+/// The manifest is written after everything else.
+#[derive(Debug)]
+pub struct Manifest {
+    stage: Box<ActionBuilder>,
+    path: path::PathBuf,
+    format: action::ManifestFormat,
+}
+
+impl Manifest {
+    /// Specifies a manifest of everything staged by `stage`.
+    ///
+    /// - `path`: location of the manifest, relative to the target directory.
+    pub fn new<P>(stage: Box<ActionBuilder>, path: P) -> Self
+    where
+        P: Into<path::PathBuf>,
+    {
+        Self {
+            stage,
+            path: path.into(),
+            format: Default::default(),
+        }
+    }
+
+    /// Specifies the manifest's file format.
+    pub fn format(mut self, format: action::ManifestFormat) -> Self {
+        self.format = format;
+        self
+    }
+}
+
+impl ActionBuilder for Manifest {
+    fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::Action>>, error::Errors> {
+        if self.path.is_absolute() {
+            Err(error::ErrorKind::HarvestingFailed
+                .error()
+                .set_context(format!(
+                    "Manifest path must be relative: {:?}",
+                    self.path
+                )))?;
+        }
+        let mut actions = self.stage.build(target_dir)?;
+
+        let mut paths: Vec<_> = actions
+            .iter()
+            .filter_map(|a| a.affects_path())
+            .filter_map(|p| p.strip_prefix(target_dir).ok())
+            .map(|p| p.to_owned())
+            .collect();
+        paths.sort();
+        paths.dedup();
+        let staged = target_dir.join(&self.path);
+        let manifest: Box<action::Action> = Box::new(
+            action::WriteManifest::new(staged, target_dir, paths).format(self.format),
+        );
+        actions.push(manifest);
+
+        Ok(actions)
+    }
+
+    fn dry_run_display(&self, target_dir: &path::Path) -> Vec<String> {
+        let mut lines = self.stage.dry_run_display(target_dir);
+        lines.push(format!(
+            "manifest --format {} {:?}",
+            self.format,
+            target_dir.join(&self.path)
+        ));
+        lines
+    }
+}
+
+/// Specifies checks of everything staged by `stage`, run after it is staged.
+#[derive(Debug)]
+pub struct PostStagingChecks {
+    stage: Box<ActionBuilder>,
+    checks: Vec<action::StagingCheck>,
+}
+
+impl PostStagingChecks {
+    /// Specifies `checks` of everything staged by `stage`.
+    ///
+    /// Paths in `checks` are relative to the target directory.
+    pub fn new(stage: Box<ActionBuilder>, checks: Vec<action::StagingCheck>) -> Self {
+        Self { stage, checks }
+    }
+}
+
+impl ActionBuilder for PostStagingChecks {
+    fn build(&self, target_dir: &path::Path) -> Result<Vec<Box<action::Action>>, error::Errors> {
+        let mut actions = self.stage.build(target_dir)?;
+        let verify: Box<action::Action> = Box::new(action::VerifyStaging::for_actions(
+            target_dir,
+            &actions,
+            self.checks.clone(),
+        ));
+        actions.push(verify);
+
+        Ok(actions)
+    }
+
+    fn dry_run_display(&self, target_dir: &path::Path) -> Vec<String> {
+        let mut lines = self.stage.dry_run_display(target_dir);
+        lines.push(format!(
+            "verify {} checks {:?}",
+            self.checks.len(),
+            target_dir
+        ));
+        lines
+    }
+}
+
+/// Files in a directory and their digests, for comparing against the directory later.
+///
+/// Take a snapshot of the stage before performing actions, then call `changes_since_snapshot`
+/// afterwards to see what staging changed, or `restore` to undo it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirectorySnapshot {
+    root: path::PathBuf,
+    files: BTreeMap<path::PathBuf, SnapshotFile>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct SnapshotFile {
+    digest: String,
+    content: Option<SnapshotContent>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum SnapshotContent {
+    File(Vec<u8>, fs::Permissions),
+    Symlink(path::PathBuf),
+}
+
+impl DirectorySnapshot {
+    /// Record each file under `root`, relative to `root`, with its content and the SHA-256 of it.
+    ///
+    /// Symbolic links are recorded by their target rather than followed.  A missing `root` is
+    /// an empty snapshot.  Content is held in memory, for `restore`.
+    pub fn take<P>(root: P) -> Result<Self, error::StagingError>
+    where
+        P: Into<path::PathBuf>,
+    {
+        Self::walk(root.into(), true)
+    }
+
+    fn walk(root: path::PathBuf, keep_content: bool) -> Result<Self, error::StagingError> {
+        let mut files = BTreeMap::new();
+        if root.exists() {
+            for entry in walkdir::WalkDir::new(&root).min_depth(1) {
+                let entry =
+                    entry.map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+                if entry.file_type().is_dir() {
+                    continue;
+                }
+                let rel = entry
+                    .path()
+                    .strip_prefix(&root)
+                    .map_err(|e| {
+                        error::ErrorKind::StagingFailed
+                            .error()
+                            .set_context(format!("{:?} is outside of {:?}", entry.path(), root))
+                            .set_cause(e)
+                    })?
+                    .to_owned();
+                files.insert(rel, snapshot_file(&entry, keep_content)?);
+            }
+        }
+        Ok(Self { root, files })
+    }
+
+    /// The directory the snapshot is of.
+    pub fn root(&self) -> &path::Path {
+        &self.root
+    }
+
+    /// Files in the snapshot, relative to `root`.
+    pub fn files(&self) -> impl Iterator<Item = &path::Path> {
+        self.files.keys().map(|p| p.as_path())
+    }
+
+    /// Compare the directory's current content against the snapshot.
+    pub fn changes_since_snapshot(&self) -> Result<StagingDiff, error::StagingError> {
+        let current = Self::walk(self.root.clone(), false)?;
+        Ok(self.diff(&current))
+    }
+
+    /// Compare `later` against this snapshot.
+    pub fn diff(&self, later: &DirectorySnapshot) -> StagingDiff {
+        let mut diff = StagingDiff::default();
+        for (path, file) in &later.files {
+            match self.files.get(path) {
+                None => diff.added.push(path.clone()),
+                Some(previous) if previous.digest != file.digest => {
+                    diff.modified.push(path.clone())
+                }
+                Some(_) => (),
+            }
+        }
+        diff.removed = self.files
+            .keys()
+            .filter(|path| !later.files.contains_key(*path))
+            .cloned()
+            .collect();
+        diff
+    }
+
+    /// Put the directory back the way it was when the snapshot was taken, returning what had
+    /// changed.
+    ///
+    /// Added files are removed, and modified and removed files are rewritten.  Directories created
+    /// since are left in place.
+    pub fn restore(&self) -> Result<StagingDiff, error::StagingError> {
+        let diff = self.changes_since_snapshot()?;
+        for path in &diff.added {
+            fs::remove_file(self.root.join(path))
+                .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        }
+        for path in diff.modified.iter().chain(&diff.removed) {
+            let file = &self.files[path];
+            let content = file.content.as_ref().ok_or_else(|| {
+                error::ErrorKind::StagingFailed
+                    .error()
+                    .set_context(format!("Snapshot has no content for {:?}", path))
+            })?;
+            restore_file(&self.root.join(path), content).map_err(|e| {
+                error::ErrorKind::StagingFailed
+                    .error()
+                    .set_context(format!("Failed restoring {:?}", path))
+                    .set_cause(e)
+            })?;
+        }
+        Ok(diff)
+    }
+}
+
+fn snapshot_file(
+    entry: &walkdir::DirEntry,
+    keep_content: bool,
+) -> Result<SnapshotFile, error::StagingError> {
+    let mut hasher = sha2::Sha256::default();
+    let content = if entry.path_is_symlink() {
+        let target = fs::read_link(entry.path())
+            .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        hasher.input(b"symlink:");
+        hasher.input(target.to_string_lossy().as_bytes());
+        SnapshotContent::Symlink(target)
+    } else {
+        let mut file = fs::File::open(entry.path())
+            .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)
+            .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?;
+        hasher.input(&content);
+        let permissions = file.metadata()
+            .map_err(|e| error::ErrorKind::StagingFailed.error().set_cause(e))?
+            .permissions();
+        SnapshotContent::File(content, permissions)
+    };
+    Ok(SnapshotFile {
+        digest: format!("{:x}", hasher.result()),
+        content: if keep_content { Some(content) } else { None },
+    })
+}
+
+fn restore_file(path: &path::Path, content: &SnapshotContent) -> io::Result<()> {
+    // Don't write through a symbolic link that replaced the file.
+    match fs::symlink_metadata(path) {
+        Ok(_) => fs::remove_file(path)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match *content {
+        SnapshotContent::File(ref content, ref permissions) => {
+            fs::write(path, content)?;
+            fs::set_permissions(path, permissions.clone())
+        }
+        SnapshotContent::Symlink(ref target) => action::symlink(target, path),
+    }
+}
+
+/// Files that changed between two `DirectorySnapshot`s, relative to the snapshot root and sorted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "de", derive(Serialize, Deserialize))]
+pub struct StagingDiff {
+    /// Files that are new.
+    pub added: Vec<path::PathBuf>,
+    /// Files whose content changed.
+    pub modified: Vec<path::PathBuf>,
+    /// Files that no longer exist.
+    pub removed: Vec<path::PathBuf>,
+}
+
+impl StagingDiff {
+    /// Whether nothing changed.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.modified.is_empty() && self.removed.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn from_iter_validated_accepts_rel() {
+        let stage = Stage::from_iter_validated(vec![(path::PathBuf::from("bin"), vec![])]);
+        assert!(stage.is_ok());
+    }
+
+    #[test]
+    fn from_iter_validated_rejects_invalid_targets() {
+        let stage = Stage::from_iter_validated(vec![
+            (path::PathBuf::from("/bin"), vec![]),
+            (path::PathBuf::from("bin/../.."), vec![]),
+            (path::PathBuf::from("bin\0"), vec![]),
+        ]);
+        assert_eq!(stage.unwrap_err().into_iter().count(), 3);
+    }
+
+    #[test]
+    fn file_type_filter_text_vs_binary() {
+        let root = path::Path::new(env!("CARGO_MANIFEST_DIR"));
+        let text = root.join("Cargo.toml");
+        assert!(FileTypeFilter::Text.matches(&text).unwrap());
+        assert!(!FileTypeFilter::Binary.matches(&text).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_type_filter_executable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = tempfile::tempdir().unwrap();
+        let plain = temp.path().join("plain");
+        fs::write(&plain, "data").unwrap();
+        let script = temp.path().join("script");
+        fs::write(&script, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        assert!(!FileTypeFilter::Executable.matches(&plain).unwrap());
+        assert!(FileTypeFilter::Executable.matches(&script).unwrap());
+    }
+
+    #[test]
+    fn manifest_is_last() {
+        let content: Box<ActionBuilder> = Box::new(InlineContent::new("a", "a"));
+        let stage: Stage = vec![(path::PathBuf::from("z"), vec![content])]
+            .into_iter()
+            .collect();
+        let manifest = Manifest::new(Box::new(stage), "MANIFEST");
+        let actions = manifest.build(path::Path::new("/stage")).unwrap();
+        assert_eq!(actions.len(), 2);
+        assert_eq!(
+            actions[1].affects_path(),
+            Some(path::Path::new("/stage/MANIFEST"))
+        );
+    }
+
+    #[test]
+    fn source_files_missing_root() {
+        let root = path::Path::new(env!("CARGO_MANIFEST_DIR")).join("does-not-exist");
+        let errors = SourceFiles::new(root)
+            .build(path::Path::new("/stage"))
+            .unwrap_err();
+        let error = errors.iter().next().unwrap();
+        assert_eq!(error.kind(), error::ErrorKind::HarvestingFailed);
+        assert!(error.to_string().contains("Source root does not exist"));
+    }
+
+    #[test]
+    fn source_files_modified_range() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        let days_ago =
+            |days: u64| time::SystemTime::now() - time::Duration::from_secs(days * 86400);
+        for &(name, days) in &[("old", 30), ("recent", 3), ("new", 0)] {
+            let file = root.join(name);
+            fs::write(&file, name).unwrap();
+            filetime::set_file_mtime(&file, filetime::FileTime::from_system_time(days_ago(days)))
+                .unwrap();
+        }
+
+        let staged = |files: SourceFiles| {
+            let actions = files
+                .push_patterns(iter::once("*".to_owned()))
+                .build(path::Path::new("/stage")).unwrap();
+            let mut paths: Vec<_> = actions
+                .iter()
+                .filter_map(|a| a.affects_path())
+                .map(|p| p.to_owned())
+                .collect();
+            paths.sort();
+            paths
+        };
+        assert_eq!(
+            staged(SourceFiles::new(root).modified_after(Some(days_ago(7)))),
+            vec![
+                path::PathBuf::from("/stage/new"),
+                path::PathBuf::from("/stage/recent"),
+            ]
+        );
+        assert_eq!(
+            staged(
+                SourceFiles::new(root)
+                    .modified_after(Some(days_ago(7)))
+                    .modified_before(Some(days_ago(1)))
+            ),
+            vec![path::PathBuf::from("/stage/recent")]
+        );
+    }
+
+    #[test]
+    fn directory_snapshot_changes() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        let empty = DirectorySnapshot::take(root).unwrap();
+        assert_eq!(empty.files().count(), 0);
+
+        fs::create_dir_all(root.join("bin")).unwrap();
+        fs::write(root.join("bin/a"), "a").unwrap();
+        fs::write(root.join("b"), "b").unwrap();
+        fs::write(root.join("c"), "c").unwrap();
+        let snapshot = DirectorySnapshot::take(root).unwrap();
+        assert!(snapshot.changes_since_snapshot().unwrap().is_empty());
+
+        fs::write(root.join("bin/a"), "changed").unwrap();
+        fs::remove_file(root.join("b")).unwrap();
+        fs::write(root.join("d"), "d").unwrap();
+        assert_eq!(
+            snapshot.changes_since_snapshot().unwrap(),
+            StagingDiff {
+                added: vec![path::PathBuf::from("d")],
+                modified: vec![path::PathBuf::from("bin/a")],
+                removed: vec![path::PathBuf::from("b")],
+            }
+        );
+
+        assert_eq!(snapshot.restore().unwrap().added, vec![path::PathBuf::from("d")]);
+        assert!(snapshot.changes_since_snapshot().unwrap().is_empty());
+        assert_eq!(fs::read_to_string(root.join("bin/a")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(root.join("b")).unwrap(), "b");
+        assert!(!root.join("d").exists());
+    }
+
+    #[test]
+    fn source_files_max_path_length() {
+        let root = path::Path::new(env!("CARGO_MANIFEST_DIR"));
+        let source = root.join("Cargo.toml");
+        let target = path::Path::new("/stage");
+        // "/stage/Cargo.toml"
+        assert!(
+            SourceFiles::new(root)
+                .max_path_length(Some(17))
+                .copy_entry(&source, target)
+                .unwrap()
+                .is_some()
+        );
+        let error = SourceFiles::new(root)
+            .max_path_length(Some(16))
+            .copy_entry(&source, target)
+            .unwrap_err();
+        assert_eq!(error.kind(), error::ErrorKind::HarvestingFailed);
+        assert!(error.to_string().contains("max_path_length"));
+    }
+
+    #[test]
+    fn source_files_min_file_size() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        fs::write(root.join("empty.o"), "").unwrap();
+        fs::write(root.join("full.o"), "object code").unwrap();
+
+        let actions = SourceFiles::new(root)
+            .push_patterns(iter::once("*.o".to_owned()))
+            .min_file_size(Some(1))
+            .build(path::Path::new("/stage"))
+            .unwrap();
+        let paths: Vec<_> = actions.iter().filter_map(|a| a.affects_path()).collect();
+        assert_eq!(paths, vec![path::Path::new("/stage/full.o")]);
+    }
+
+    #[test]
+    fn source_files_strip_prefix() {
+        let root = path::Path::new(env!("CARGO_MANIFEST_DIR"));
+        let files = SourceFiles::new(root).strip_prefix(Some(path::PathBuf::from("src")));
+        let action = files
+            .copy_entry(&root.join("src/lib.rs"), path::Path::new("/stage"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(action.affects_path(), Some(path::Path::new("/stage/lib.rs")));
+        assert!(
+            files
+                .copy_entry(&root.join("Cargo.toml"), path::Path::new("/stage"))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn source_files_relative_to() {
+        let root = path::Path::new(env!("CARGO_MANIFEST_DIR"));
+        let files = SourceFiles::new(root).relative_to(Some(root.join("src/bin")));
+        let action = files
+            .copy_entry(
+                &root.join("src/bin/staging/main.rs"),
+                path::Path::new("/stage"),
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            action.affects_path(),
+            Some(path::Path::new("/stage/staging/main.rs"))
+        );
+        assert!(
+            files
+                .copy_entry(&root.join("src/lib.rs"), path::Path::new("/stage"))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn source_files_target_prefix() {
+        let root = path::Path::new(env!("CARGO_MANIFEST_DIR"));
+        let target = path::Path::new("/stage");
+        let files = SourceFiles::new(root).target_prefix(Some("debug".into()));
+        let action = files
+            .copy_entry(&root.join("src/lib.rs"), target)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            action.affects_path(),
+            Some(path::Path::new("/stage/debug/src/lib.rs"))
+        );
+        for prefix in &["/debug", "../../x", "debug/../.."] {
+            let files = SourceFiles::new(root)
+                .push_patterns(vec!["src/*.rs".to_owned()].into_iter())
+                .target_prefix(Some(prefix.into()));
+            let errors = files.build(target).unwrap_err();
+            assert_eq!(errors.len(), 1, "{:?}", prefix);
+        }
+    }
+
+    #[test]
+    fn source_files_path_transform() {
+        let root = path::Path::new(env!("CARGO_MANIFEST_DIR"));
+        let target = path::Path::new("/stage");
+        let files =
+            SourceFiles::new(root).with_path_transform(|p| Ok(path::Path::new("v1").join(p)));
+        let action = files
+            .copy_entry(&root.join("src/lib.rs"), target)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            action.affects_path(),
+            Some(path::Path::new("/stage/v1/src/lib.rs"))
+        );
+        for escape in &["/etc/passwd", "../passwd", "src/../../passwd"] {
+            let files = SourceFiles::new(root).with_path_transform(move |_| Ok(escape.into()));
+            assert!(files.copy_entry(&root.join("src/lib.rs"), target).is_err());
+        }
+    }
+
+    #[test]
+    fn source_file_cache_dir() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        let source = root.join("source");
+        fs::write(&source, "content").unwrap();
+        let file = || SourceFile::new(&source).cache_dir(Some(root.join("cache")));
+        let target = root.join("stage");
+
+        let actions = file().build(&target).unwrap();
+        assert_eq!(actions.len(), 2);
+        let (cached, _) = file().cache_entry().unwrap();
+        assert_eq!(actions[1].affects_path(), Some(cached.as_path()));
+        for action in &actions {
+            action.perform(&Default::default()).unwrap();
+        }
+
+        let actions = file().build(&target).unwrap();
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0].describe().source, Some(cached.clone()));
+
+        let hours_ago = |hours: u64| {
+            let time = time::SystemTime::now() - time::Duration::from_secs(hours * 60 * 60);
+            filetime::FileTime::from_system_time(time)
+        };
+        filetime::set_file_mtime(&cached, hours_ago(1)).unwrap();
+        assert!(file().cache_entry().unwrap().1);
+        let ttl = Some(time::Duration::from_secs(60));
+        assert!(!file().cache_ttl(ttl).cache_entry().unwrap().1);
+
+        // A rebuilt source is a cache miss, even with an older modification time.
+        fs::write(&source, "rebuilt").unwrap();
+        filetime::set_file_mtime(&source, hours_ago(2)).unwrap();
+        let actions = file().build(&target).unwrap();
+        assert_eq!(actions.len(), 2);
+        assert_eq!(actions[0].describe().source, Some(source.clone()));
+        assert_ne!(file().cache_entry().unwrap().0, cached);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn source_files_on_walk_error() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        fs::write(root.join("file"), "content").unwrap();
+        std::os::unix::fs::symlink(root.join("missing"), root.join("broken")).unwrap();
+
+        let files = |policy| {
+            SourceFiles::new(root)
+                .push_patterns(vec!["*".to_owned()].into_iter())
+                .follow_links(true)
+                .on_walk_error(policy)
+        };
+        let target = path::Path::new("/stage");
+        assert!(files(WalkErrorPolicy::Fail).build(target).is_err());
+        let actions = files(WalkErrorPolicy::Skip).build(target).unwrap();
+        let paths: Vec<_> = actions.iter().filter_map(|a| a.affects_path()).collect();
+        assert_eq!(paths, vec![path::Path::new("/stage/file")]);
+    }
+
+    #[test]
+    fn symlink_must_exist() {
+        let root = path::Path::new(env!("CARGO_MANIFEST_DIR"));
+        let target = path::Path::new("/stage");
+        let present = Symlink::new(root.join("Cargo.toml")).must_exist(true);
+        assert!(present.build(target).is_ok());
+        let missing = Symlink::new(root.join("missing"));
+        assert!(missing.build(target).is_ok());
+        assert!(missing.must_exist(true).build(target).is_err());
+    }
+
+    #[test]
+    fn symlink_relative_to_target() {
+        let target = path::Path::new("/stage/bin");
+        let link = Symlink::new("/stage/lib/libfoo.so").relative_to_target(true);
+        let actions = link.build(target).unwrap();
+        assert_eq!(
+            actions[0].to_string(),
+            action::Symlink::new("/stage/bin/libfoo.so", "../lib/libfoo.so").to_string()
+        );
+        let relative = Symlink::new("../lib/libfoo.so").relative_to_target(true);
+        assert_eq!(
+            relative.build(target).unwrap()[0].to_string(),
+            action::Symlink::new("/stage/bin/libfoo.so", "../lib/libfoo.so").to_string()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_tree() {
+        let temp = tempfile::tempdir().unwrap();
+        let source = temp.path().join("source");
+        for file in &["a.txt", "sub/b.txt", "c.md"] {
+            let file = source.join(file);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(file, "content").unwrap();
+        }
+        let target = temp.path().join("stage");
+
+        let actions = SymlinkTree::new(&source)
+            .push_patterns(vec!["*.txt".to_owned()].into_iter())
+            .build(&target)
+            .unwrap();
+        for action in actions {
+            action.perform(&Default::default()).unwrap();
+        }
+
+        for file in &["a.txt", "sub/b.txt"] {
+            let staged = target.join(file);
+            assert_eq!(fs::read_link(&staged).unwrap(), source.join(file));
+            assert_eq!(fs::read_to_string(&staged).unwrap(), "content");
+        }
+        assert!(!target.join("c.md").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn source_files_follow_links_to_dirs() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        fs::create_dir_all(root.join("real")).unwrap();
+        fs::write(root.join("real/file"), "content").unwrap();
+        std::os::unix::fs::symlink(root.join("real"), root.join("vendor")).unwrap();
+        std::os::unix::fs::symlink("real/file", root.join("latest")).unwrap();
+        std::os::unix::fs::symlink("missing", root.join("dangling")).unwrap();
+        // Points back at its own parent.
+        std::os::unix::fs::symlink(root.join("real"), root.join("real/loop")).unwrap();
+
+        let actions = SourceFiles::new(root)
+            .push_patterns(vec!["*".to_owned()].into_iter())
+            .follow_links_to_dirs(true)
+            .build(path::Path::new("/stage"))
+            .unwrap();
+        let mut staged: Vec<_> = actions
+            .iter()
+            .map(|a| {
+                let description = a.describe();
+                (description.destination, description.kind)
+            })
+            .collect();
+        staged.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            staged,
+            vec![
+                (path::PathBuf::from("/stage/dangling"), action::ActionKind::Symlink),
+                (path::PathBuf::from("/stage/latest"), action::ActionKind::Symlink),
+                (path::PathBuf::from("/stage/real/file"), action::ActionKind::CopyFile),
+                (path::PathBuf::from("/stage/vendor/file"), action::ActionKind::CopyFile),
+            ]
+        );
+    }
+
+    #[test]
+    fn walk_matches_gitignore_patterns() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        for file in &["top.txt", "a.rs", "dir/x.txt", "dir/sub/y.txt", "other/dir/z.txt"] {
+            let file = root.join(file);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(file, "content").unwrap();
+        }
+
+        let matches = |pattern: &str| {
+            let mut files: Vec<_> = walk(root, &[pattern.to_owned()], false, true, None)
+                .unwrap()
+                .map(|p| p.unwrap())
+                .filter(|p| p.is_file())
+                .map(|p| p.strip_prefix(root).unwrap().to_string_lossy().into_owned())
+                .collect();
+            files.sort();
+            files
+        };
+        let all = vec![
+            "a.rs",
+            "dir/sub/y.txt",
+            "dir/x.txt",
+            "other/dir/z.txt",
+            "top.txt",
+        ];
+        assert_eq!(matches("*"), all);
+        assert_eq!(matches("**/*"), all);
+        assert_eq!(
+            matches("*.txt"),
+            vec!["dir/sub/y.txt", "dir/x.txt", "other/dir/z.txt", "top.txt"]
+        );
+        assert_eq!(matches("/*.txt"), vec!["top.txt"]);
+        assert_eq!(matches("dir/*"), vec!["dir/x.txt"]);
+        assert_eq!(matches("dir/**"), vec!["dir/sub/y.txt", "dir/x.txt"]);
+        assert_eq!(matches("*/x.txt"), vec!["dir/x.txt"]);
+    }
+
+    #[test]
+    fn source_files_case_sensitive() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        fs::write(root.join("photo.PNG"), "content").unwrap();
+
+        let files = || SourceFiles::new(root).push_patterns(vec!["*.png".to_owned()].into_iter());
+        let target = path::Path::new("/stage");
+        assert!(files().build(target).is_err());
+        let actions = files().case_sensitive(false).build(target).unwrap();
+        let paths: Vec<_> = actions.iter().filter_map(|a| a.affects_path()).collect();
+        assert_eq!(paths, vec![path::Path::new("/stage/photo.PNG")]);
+    }
+
+    #[test]
+    fn source_files_max_depth() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        for file in &["top.txt", "dir/nested.txt", "dir/sub/deep.txt"] {
+            let file = root.join(file);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(file, "content").unwrap();
+        }
+
+        let staged = |depth| {
+            let actions = SourceFiles::new(root)
+                .push_patterns(vec!["*.txt".to_owned()].into_iter())
+                .max_depth(depth)
+                .build(path::Path::new("/stage"))
+                .unwrap();
+            let mut paths: Vec<_> = actions
+                .iter()
+                .filter_map(|a| a.affects_path().map(|p| p.to_owned()))
+                .collect();
+            paths.sort();
+            paths
+        };
+        assert_eq!(staged(Some(1)), vec![path::PathBuf::from("/stage/top.txt")]);
+        assert_eq!(
+            staged(Some(2)),
+            vec![
+                path::PathBuf::from("/stage/dir/nested.txt"),
+                path::PathBuf::from("/stage/top.txt"),
+            ]
+        );
+        assert_eq!(staged(None).len(), 3);
+    }
+
+    #[cfg(feature = "gitignore")]
+    #[test]
+    fn source_files_respect_gitignore() {
+        let temp = tempfile::tempdir().unwrap();
+        let repo = temp.path();
+        // `.gitignore` files only apply within a git repository.
+        fs::create_dir(repo.join(".git")).unwrap();
+        fs::write(repo.join(".gitignore"), "ignored.txt\n").unwrap();
+        let root = repo.join("src");
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join("kept.txt"), "content").unwrap();
+        fs::write(root.join("ignored.txt"), "content").unwrap();
+
+        let actions = SourceFiles::new(&root)
+            .push_patterns(vec!["*.txt".to_owned()].into_iter())
+            .build(path::Path::new("/stage"))
+            .unwrap();
+        let paths: Vec<_> = actions.iter().filter_map(|a| a.affects_path()).collect();
+        assert_eq!(paths, vec![path::Path::new("/stage/kept.txt")]);
+    }
+
+    #[test]
+    fn source_files_path_regex_filter() {
+        let root = path::Path::new(env!("CARGO_MANIFEST_DIR"));
+        let files = SourceFiles::new(root)
+            .path_regex_filter(Some(regex::Regex::new(r"^[a-z]+\.rs$").unwrap()));
+        let target = path::Path::new("/stage");
+        assert!(
+            files
+                .copy_entry(&root.join("src/lib.rs"), target)
+                .unwrap()
+                .is_some()
+        );
+        assert!(
+            files
+                .copy_entry(&root.join("Cargo.toml"), target)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn source_files_explicit_order() {
+        let root = path::Path::new(env!("CARGO_MANIFEST_DIR"));
+        let files = SourceFiles::new(root)
+            .push_patterns(vec!["src/*.rs".to_owned()].into_iter())
+            .push_explicit_order(vec!["src/lib.rs".to_owned(), "src/b*.rs".to_owned()].into_iter());
+        let actions = files.build(path::Path::new("/stage")).unwrap();
+        let paths: Vec<_> = actions.iter().filter_map(|a| a.affects_path()).collect();
+        assert_eq!(paths[0], path::Path::new("/stage/src/lib.rs"));
+        assert_eq!(paths[1], path::Path::new("/stage/src/builder.rs"));
+
+        let files = files.push_explicit_order(vec!["Cargo.toml".to_owned()].into_iter());
+        assert!(files.build(path::Path::new("/stage")).is_err());
+    }
+
+    #[test]
+    fn source_file_also_stage_to() {
+        let file = SourceFile::new("/src/libfoo.so")
+            .executable(true)
+            .also_stage_to(vec![path::PathBuf::from("compat/./x86_64")]);
+        let actions = file.build(path::Path::new("/stage/lib")).unwrap();
+        let paths: Vec<_> = actions.iter().filter_map(|a| a.affects_path()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                path::Path::new("/stage/lib/libfoo.so"),
+                path::Path::new("/stage/lib/libfoo.so"),
+                path::Path::new("/stage/lib/compat/x86_64/libfoo.so"),
+            ]
+        );
+        assert_eq!(actions[2].describe().kind, action::ActionKind::CopyFile);
+        assert_eq!(
+            actions[2].describe().source,
+            Some(path::PathBuf::from("/stage/lib/libfoo.so"))
+        );
+
+        for outside in &["../lib64", "../../..", "compat/../..", "."] {
+            let file = SourceFile::new("/src/libfoo.so")
+                .also_stage_to(vec![path::PathBuf::from(outside)]);
+            assert!(
+                file.build(path::Path::new("/stage/lib")).is_err(),
+                "{:?} is allowed",
+                outside
+            );
+        }
+    }
+
+    #[test]
+    fn source_file_push_hard_links() {
+        let file = SourceFile::new("/src/libfoo.so.1")
+            .push_hard_links(vec!["libfoo.so".to_owned()].into_iter());
+        let actions = file.build(path::Path::new("/stage/lib")).unwrap();
+        assert_eq!(actions[1].describe().kind, action::ActionKind::HardLink);
+        assert_eq!(
+            actions[1].affects_path(),
+            Some(path::Path::new("/stage/lib/libfoo.so"))
+        );
+
+        let file = SourceFile::new("/src/libfoo.so")
+            .push_hard_links(vec!["libfoo.so".to_owned()].into_iter());
+        assert!(file.build(path::Path::new("/stage/lib")).is_err());
+    }
+
+    #[test]
+    fn source_files_max_count() {
+        let root = path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        let files = SourceFiles::new(root).push_patterns(vec!["*.rs".to_owned()].into_iter());
+        let target = path::Path::new("/stage");
+        assert!(files.clone().max_count(Some(100)).build(target).is_ok());
+        let error = files.max_count(Some(1)).build(target).unwrap_err();
+        assert!(error.to_string().contains("More than 1 files found"));
+    }
+
+    #[cfg(feature = "shell-hooks")]
+    #[test]
+    fn source_file_post_copy_command() {
+        let file = SourceFile::new("/src/app")
+            .executable(true)
+            .post_copy_command(Some(("strip".to_owned(), vec!["-s".to_owned()])));
+        let actions = file.build(path::Path::new("/stage")).unwrap();
+        let kinds: Vec<_> = actions.iter().map(|a| a.describe().kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                action::ActionKind::CopyFile,
+                action::ActionKind::Other,
+                action::ActionKind::SetPermissions,
+            ]
+        );
+        assert_eq!(actions[1].to_string(), r#"strip -s "/stage/app""#);
+    }
+
+    #[cfg(all(feature = "shell-hooks", not(windows)))]
+    #[test]
+    fn source_file_strip_debug() {
+        let file = SourceFile::new("/src/app")
+            .post_copy_command(Some(("upx".to_owned(), vec![])))
+            .strip_debug(true);
+        let actions = file.build(path::Path::new("/stage")).unwrap();
+        let commands: Vec<_> = actions[1..].iter().map(|a| a.to_string()).collect();
+        let strip = env::var("STRIP").unwrap_or_else(|_| "strip".to_owned());
+        assert_eq!(
+            commands,
+            vec![
+                r#"upx "/stage/app""#.to_owned(),
+                format!(r#"{} --strip-debug "/stage/app""#, strip),
+            ]
+        );
+    }
+
+    #[test]
+    fn touch_file_rejects_directories() {
+        let target = path::Path::new("/stage");
+        assert!(TouchFile::new("STAMP").build(target).is_ok());
+        assert!(TouchFile::new("a/STAMP").build(target).is_err());
+    }
+
+    #[test]
+    fn vec_builds_each() {
+        let builders: Vec<Box<ActionBuilder>> = vec![
+            Box::new(InlineContent::new("a", "a")),
+            Box::new(InlineContent::new("b/c", "b")),
+            Box::new(InlineContent::new("d", "d")),
+            Box::new(InlineContent::new("../e", "e")),
+        ];
+        let errors = builders.build(path::Path::new("/stage")).unwrap_err();
+        assert_eq!(errors.len(), 2);
+
+        let builders: Vec<Box<ActionBuilder>> = vec![
+            Box::new(InlineContent::new("a", "a")),
+            Box::new(InlineContent::new("d", "d")),
+        ];
+        let actions = builders.build(path::Path::new("/stage")).unwrap();
+        assert_eq!(actions.len(), 2);
+    }
+
+    #[test]
+    fn build_preserves_target_order() {
+        let b: Box<ActionBuilder> = Box::new(InlineContent::new("b", "b"));
+        let a: Box<ActionBuilder> = Box::new(InlineContent::new("a", "a"));
+        let stage: Stage = vec![
+            (path::PathBuf::from("z"), vec![b]),
+            (path::PathBuf::from("y"), vec![a]),
+        ].into_iter()
+            .collect();
+        let actions = stage.build(path::Path::new("/stage")).unwrap();
+        let paths: Vec<_> = actions.iter().filter_map(|a| a.affects_path()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                path::Path::new("/stage/z/b"),
+                path::Path::new("/stage/y/a"),
+            ]
+        );
+    }
+
+    #[test]
+    fn stage_into_actions_flattens() {
+        let stage: Stage = vec![
+            (
+                path::PathBuf::from("x"),
+                vec![
+                    Box::new(InlineContent::new("a", "a")) as Box<ActionBuilder>,
+                    Box::new(InlineContent::new("b", "b")),
+                ],
+            ),
+            (
+                path::PathBuf::from("y"),
+                vec![Box::new(InlineContent::new("c", "c")) as Box<ActionBuilder>],
+            ),
+        ].into_iter()
+            .collect();
+        let actions = stage.into_actions(path::Path::new("/stage")).unwrap();
+        assert_eq!(actions.len(), 3);
+    }
+
+    #[test]
+    fn stage_depends_on_orders_targets() {
+        let stage: Stage = vec![
+            (
+                path::PathBuf::from("b"),
+                vec![Box::new(InlineContent::new("b", "b")) as Box<ActionBuilder>],
+            ),
+            (
+                path::PathBuf::from("a"),
+                vec![Box::new(InlineContent::new("a", "a")) as Box<ActionBuilder>],
+            ),
+        ].into_iter()
+            .collect();
+        let stage = stage.depends_on(path::Path::new("b"), path::Path::new("a"));
+        let actions = stage.build(path::Path::new("/stage")).unwrap();
+        let destinations: Vec<_> = actions
+            .iter()
+            .map(|a| a.describe().destination)
+            .collect();
+        assert_eq!(
+            destinations,
+            vec![
+                path::PathBuf::from("/stage/a/a"),
+                path::PathBuf::from("/stage/b/b"),
+            ]
+        );
+    }
+
+    #[test]
+    fn stage_depends_on_rejects_cycles() {
+        let stage: Stage = vec![
+            (path::PathBuf::from("a"), vec![]),
+            (path::PathBuf::from("b"), vec![]),
+        ].into_iter()
+            .collect();
+        let stage = stage
+            .depends_on(path::Path::new("a"), path::Path::new("b"))
+            .depends_on(path::Path::new("b"), path::Path::new("a"));
+        let errors = stage.build(path::Path::new("/stage")).unwrap_err();
+        assert!(
+            errors
+                .iter()
+                .all(|e| e.kind() == error::ErrorKind::InvalidConfiguration)
+        );
+    }
+
+    #[test]
+    fn stage_depends_on_reports_cycle_once() {
+        let stage: Stage = vec![
+            (path::PathBuf::from("a"), vec![]),
+            (path::PathBuf::from("b"), vec![]),
+            (path::PathBuf::from("c"), vec![]),
+            (path::PathBuf::from("d"), vec![]),
+        ].into_iter()
+            .collect();
+        let stage = stage
+            .depends_on(path::Path::new("a"), path::Path::new("b"))
+            .depends_on(path::Path::new("b"), path::Path::new("c"))
+            .depends_on(path::Path::new("c"), path::Path::new("a"))
+            .depends_on(path::Path::new("d"), path::Path::new("c"));
+        let errors = stage.build(path::Path::new("/stage")).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors.to_string().contains("circular dependency"));
+    }
+
+    #[test]
+    fn stage_rejects_unknown_targets() {
+        let stage: Stage = vec![(path::PathBuf::from("a"), vec![])]
+            .into_iter()
+            .collect();
+        let stage = stage
+            .depends_on(path::Path::new("missing"), path::Path::new("a"))
+            .on_error(path::Path::new("optional"), ErrorPolicy::Skip);
+        let errors = stage.build(path::Path::new("/stage")).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        let message = errors.to_string();
+        assert!(message.contains("\"missing\""), "{}", message);
+        assert!(message.contains("\"optional\""), "{}", message);
+    }
+
+    #[test]
+    fn dry_run_display_does_not_touch_filesystem() {
+        let stage: Stage = vec![
+            (
+                path::PathBuf::from("bin"),
+                vec![
+                    Box::new(
+                        SourceFile::new("/missing/app")
+                            .executable(true)
+                            .cache_dir(Some("/missing/cache".into()))
+                            .push_symlinks(vec!["app-1.0".to_owned()].into_iter()),
+                    ) as Box<ActionBuilder>,
+                ],
+            ),
+            (
+                path::PathBuf::from("share"),
+                vec![
+                    Box::new(
+                        SourceFiles::new("/missing/share")
+                            .push_patterns(vec!["*.txt".to_owned()].into_iter()),
+                    ) as Box<ActionBuilder>,
+                ],
+            ),
+        ].into_iter()
+            .collect();
+        assert_eq!(
+            stage.dry_run_display(path::Path::new("/stage")),
+            vec![
+                r#"cp "/missing/app" "/stage/bin/app" (cached in "/missing/cache" if fresh)"#,
+                r#"chmod 755 "/stage/bin/app""#,
+                r#"ln -s "/stage/bin/app" "/stage/bin/app-1.0""#,
+                r#"cp "/missing/share/*.txt" "/stage/share""#,
+            ]
+        );
+    }
+
+    #[test]
+    fn stage_build_hooks_run_in_order() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let calls = Rc::new(RefCell::new(vec![]));
+        let hook = |name: &'static str| {
+            let calls = calls.clone();
+            Box::new(move |target_dir: &path::Path| {
+                calls.borrow_mut().push((name, target_dir.to_owned()));
+                Ok(())
+            }) as Box<BuildHook>
+        };
+        let stage: Stage = vec![(path::PathBuf::from("a"), vec![])]
+            .into_iter()
+            .collect();
+        let stage = stage
+            .with_post_build_hook(hook("post1"))
+            .with_pre_build_hook(hook("pre1"))
+            .with_pre_build_hook(hook("pre2"))
+            .with_post_build_hook(hook("post2"));
+        stage.build(path::Path::new("/stage")).unwrap();
+        let names: Vec<_> = calls.borrow().iter().map(|c| c.0).collect();
+        assert_eq!(names, vec!["pre1", "pre2", "post1", "post2"]);
+        assert_eq!(calls.borrow()[0].1, path::Path::new("/stage"));
+
+        let failing = Stage::default()
+            .with_pre_build_hook(Box::new(|_: &path::Path| {
+                Err(error::ErrorKind::StagingFailed.error())
+            }))
+            .with_post_build_hook(hook("post3"));
+        assert!(failing.build(path::Path::new("/stage")).is_err());
+        assert_eq!(calls.borrow().len(), 4);
+    }
+
+    #[test]
+    fn stage_on_error() {
+        let stage = |policy| {
+            let missing: Box<ActionBuilder> =
+                Box::new(Symlink::new("/does-not-exist").must_exist(true));
+            let present: Box<ActionBuilder> = Box::new(Symlink::new("/usr"));
+            let stage: Stage = vec![
+                (path::PathBuf::from("optional"), vec![missing]),
+                (path::PathBuf::from("required"), vec![present]),
+            ].into_iter()
+                .collect();
+            stage.on_error(path::Path::new("optional"), policy)
+        };
+        let target = path::Path::new("/stage");
+        assert!(stage(ErrorPolicy::Fail).build(target).is_err());
+        for policy in &[ErrorPolicy::Skip, ErrorPolicy::Warn] {
+            let actions = stage(*policy).build(target).unwrap();
+            assert_eq!(actions.len(), 1);
+            assert_eq!(
+                actions[0].describe().destination,
+                path::Path::new("/stage/required/usr")
+            );
+        }
+    }
+
+    #[test]
+    fn stage_fail_fast() {
+        let missing = || -> Box<ActionBuilder> {
+            Box::new(Symlink::new("/does-not-exist").must_exist(true))
+        };
+        let stage = || -> Stage {
+            vec![
+                (path::PathBuf::from("a"), vec![missing(), missing()]),
+                (path::PathBuf::from("b"), vec![missing()]),
+            ].into_iter()
+                .collect()
+        };
+        let target = path::Path::new("/stage");
+        assert_eq!(stage().build(target).unwrap_err().len(), 3);
+        assert_eq!(stage().fail_fast(true).build(target).unwrap_err().len(), 1);
+    }
+
+    #[test]
+    fn stage_target_exists_policy() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        fs::create_dir_all(root.join("stage/bin")).unwrap();
+        let source = root.join("source");
+        fs::write(&source, "new").unwrap();
+        let staged = root.join("stage/bin/source");
+        fs::write(&staged, "old").unwrap();
+
+        let stage = |policy| {
+            let file: Box<ActionBuilder> = Box::new(SourceFile::new(&source));
+            let stage: Stage = vec![(path::PathBuf::from("bin"), vec![file])]
+                .into_iter()
+                .collect();
+            stage.target_exists_policy(policy)
+        };
+        let perform = |stage: Stage| {
+            for action in stage.build(&root.join("stage")).unwrap() {
+                action.perform(&Default::default())?;
+            }
+            Ok(())
+        };
+        perform(stage(Some(action::ConflictPolicy::Skip))).unwrap();
+        assert_eq!(fs::read_to_string(&staged).unwrap(), "old");
+        let error: error::StagingError =
+            perform(stage(Some(action::ConflictPolicy::Error))).unwrap_err();
+        assert_eq!(error.kind(), error::ErrorKind::StagingFailed);
+        perform(stage(None)).unwrap();
+        assert_eq!(fs::read_to_string(&staged).unwrap(), "new");
+
+        // The run's policy takes precedence.
+        let options = action::PerformOptions::new().on_conflict(Some(action::ConflictPolicy::Skip));
+        for action in stage(Some(action::ConflictPolicy::Error))
+            .build(&root.join("stage"))
+            .unwrap()
+        {
+            action.perform(&options).unwrap();
+        }
+    }
+
+    #[cfg(feature = "tar")]
+    #[test]
+    fn tar_members_strip_components() {
+        let temp = tempfile::tempdir().unwrap();
+        let archive = temp.path().join("members.tar");
+        {
+            let mut builder = tar::Builder::new(fs::File::create(&archive).unwrap());
+            let mut header = tar::Header::new_gnu();
+            header.set_size(5);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, "pkg-1.0/bin/hello", &b"hello"[..])
+                .unwrap();
+            builder
+                .append_data(&mut header, "pkg-1.0/README", &b"hello"[..])
+                .unwrap();
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_size(0);
+            builder
+                .append_link(&mut header, "pkg-1.0/bin/hi", "hello")
+                .unwrap();
+            builder.finish().unwrap();
+        }
+
+        let members = TarMembers::new(&archive)
+            .push_patterns(vec!["*/bin/*".to_owned()].into_iter())
+            .strip_components(1);
+        let actions = members.build(path::Path::new("/stage")).unwrap();
+        let paths: Vec<_> = actions.iter().filter_map(|a| a.affects_path()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                path::Path::new("/stage/bin/hello"),
+                path::Path::new("/stage/bin/hi"),
+            ]
+        );
+        assert_eq!(actions[1].to_string(), r#"ln -s "hello" "/stage/bin/hi""#);
+    }
+
+    #[cfg(all(feature = "tar", unix))]
+    #[test]
+    fn tar_members_keep_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = tempfile::tempdir().unwrap();
+        let archive = temp.path().join("members.tar");
+        {
+            let mut builder = tar::Builder::new(fs::File::create(&archive).unwrap());
+            for &(name, mode) in &[("bin/hello", 0o755), ("README", 0o640)] {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(5);
+                header.set_mode(mode);
+                header.set_cksum();
+                builder.append_data(&mut header, name, &b"hello"[..]).unwrap();
+            }
+            builder.finish().unwrap();
+        }
+
+        let stage = temp.path().join("stage");
+        let members = TarMembers::new(&archive).push_patterns(vec!["**".to_owned()].into_iter());
+        for action in members.build(&stage).unwrap() {
+            action.perform(&Default::default()).unwrap();
+        }
+        let mode = |name: &str| fs::metadata(stage.join(name)).unwrap().permissions().mode();
+        assert_eq!(mode("bin/hello") & 0o7777, 0o755);
+        assert_eq!(mode("README") & 0o7777, 0o640);
+    }
+
+    #[cfg(feature = "tar")]
+    #[test]
+    fn tar_members_rejects_parent_dir() {
+        let temp = tempfile::tempdir().unwrap();
+        let archive = temp.path().join("slip.tar");
+        {
+            let mut builder = tar::Builder::new(fs::File::create(&archive).unwrap());
+            let mut header = tar::Header::new_gnu();
+            // `set_path` refuses `..`, so write the name directly like a hostile archive would.
+            let name = b"pkg/../../evil";
+            header.as_old_mut().name[..name.len()].copy_from_slice(name);
+            header.set_size(5);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, &b"hello"[..]).unwrap();
+            builder.finish().unwrap();
+        }
+
+        let members = TarMembers::new(&archive).push_patterns(vec!["**".to_owned()].into_iter());
+        let errors = members.build(path::Path::new("/stage")).unwrap_err();
+        assert_eq!(
+            errors.iter().map(|e| e.kind()).collect::<Vec<_>>(),
+            vec![error::ErrorKind::HarvestingFailed]
+        );
+    }
+
+    #[cfg(feature = "tar")]
+    #[test]
+    fn tar_members_rejects_writing_through_symlink() {
+        let temp = tempfile::tempdir().unwrap();
+        let archive = temp.path().join("slip.tar");
+        {
+            let mut builder = tar::Builder::new(fs::File::create(&archive).unwrap());
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_size(0);
+            builder.append_link(&mut header, "a", "/etc").unwrap();
+            let mut header = tar::Header::new_gnu();
+            header.set_size(5);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, "a/passwd", &b"hello"[..])
+                .unwrap();
+            builder.finish().unwrap();
+        }
+
+        let members = TarMembers::new(&archive).push_patterns(vec!["**".to_owned()].into_iter());
+        let errors = members.build(path::Path::new("/stage")).unwrap_err();
+        assert_eq!(
+            errors.iter().map(|e| e.kind()).collect::<Vec<_>>(),
+            vec![
+                error::ErrorKind::HarvestingFailed,
+                error::ErrorKind::HarvestingFailed,
+            ]
+        );
+    }
+
+    #[cfg(feature = "tar")]
+    #[test]
+    fn tar_members_symlink_targets_stay_within_target() {
+        let temp = tempfile::tempdir().unwrap();
+        let archive = temp.path().join("links.tar");
+        {
+            let mut builder = tar::Builder::new(fs::File::create(&archive).unwrap());
+            for &(link, target) in &[("lib/libfoo.so", "../lib64/libfoo.so.1"), ("up", "../x")] {
+                let mut header = tar::Header::new_gnu();
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_size(0);
+                builder.append_link(&mut header, link, target).unwrap();
+            }
+            builder.finish().unwrap();
+        }
+
+        let members = TarMembers::new(&archive)
+            .push_patterns(vec!["lib/*".to_owned()].into_iter());
+        assert!(members.build(path::Path::new("/stage")).is_ok());
+        let members = TarMembers::new(&archive).push_patterns(vec!["up".to_owned()].into_iter());
+        assert!(members.build(path::Path::new("/stage")).is_err());
+    }
 }
@@ -1,14 +1,21 @@
 //! Composable file format for staging files.
 //!
-//! `stager::de::MapStage` is the recommended top-level staging configuration to include in a
+//! `stager::de::Staging` is the recommended top-level staging configuration to include in a
 //! packaging configuration struct.  If you need additional sources, you might want to consider
 //! replacing `MapStage` and `Source`, reusing the rest.
 //!
-//! `Template` fields are rendered using the [liquid][liquid] template engine. No filters or tags
-//! are available at this time.
+//! `Template` fields are rendered using the [liquid][liquid] template engine.  The standard
+//! filters are available, along with `env` for reading environment variables
+//! (`{{ "NAME" | env }}` or `{{ "NAME" | env: "default" }}`).  Other engines can be plugged in via
+//! `TemplateBackend`.
 //!
 //! [liquid]: https://shopify.github.io/liquid/
 //!
+//! Sources reject unknown fields to catch typos.  YAML anchors and aliases (`&name`, `*name`)
+//! are resolved before deserialization and are unaffected.  YAML merge keys (`<<: *name`) are
+//! not supported by `serde_yaml` and are reported as an unknown field rather than being silently
+//! dropped.
+//!
 //! ## Basic Example
 //!
 //! ```rust
@@ -19,7 +26,7 @@
 //! // #[derive(Serialize, Deserialize)]
 //! #[derive(Default)]
 //! struct Config {
-//!     stage: de::MapStage,
+//!     stage: de::Staging,
 //! }
 //! // ...
 //! let engine = de::TemplateEngine::new(Default::default()).unwrap();
@@ -28,8 +35,35 @@
 //! ```
 
 use std::collections::BTreeMap;
+use std::collections::HashMap;
+#[cfg(any(feature = "serde_json", feature = "toml"))]
+use std::convert;
+use std::env;
+use std::fmt;
+#[cfg(feature = "cbor")]
+use std::io;
+use std::iter;
+use std::marker;
+use std::mem;
 use std::path;
+use std::time;
+
+use globwalk;
+use humantime;
+use indexmap;
+use indexmap::IndexMap;
+use indexmap::IndexSet;
+use liquid;
+use regex;
+use serde;
+#[cfg(feature = "cbor")]
+use serde_cbor;
+#[cfg(feature = "serde_json")]
+use serde_json;
+#[cfg(feature = "toml")]
+use toml;
 
+use action;
 use builder;
 use error;
 
@@ -40,253 +74,2620 @@ pub trait ActionRender {
     /// Format the serialized data into an `ActionBuilder`.
     fn format(&self, engine: &TemplateEngine)
         -> Result<Box<builder::ActionBuilder>, error::Errors>;
+
+    /// Format the serialized data into `ActionBuilder`s, for sources that expand to more than one.
+    fn format_many(
+        &self,
+        engine: &TemplateEngine,
+    ) -> Result<Vec<Box<builder::ActionBuilder>>, error::Errors> {
+        self.format(engine).map(|a| vec![a])
+    }
 }
 
-/// For each stage target, a list of sources to populate it with.
-///
-/// The target is an absolute path, treating the stage as the root.  The target supports template
-/// formatting.
-pub type MapStage = CustomMapStage<Source>;
+/// The version of the `Staging` configuration format understood by this release.
+pub const STAGING_SCHEMA_VERSION: u32 = 1;
 
-/// For each stage target, a list of sources to populate it with.
+/// `Staging` fields that are deprecated, with the `STAGING_SCHEMA_VERSION` they were deprecated in.
+const DEPRECATED_FIELDS: &[(&str, u32)] = &[];
+
+/// Top-level staging configuration.
 ///
-/// The target is an absolute path, treating the stage as the root.  The target supports template
-/// formatting.
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
-pub struct CustomMapStage<R: ActionRender>(BTreeMap<Template, Vec<R>>);
+/// Targets are listed at the top-level, alongside these settings.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Staging {
+    /// For each stage target, a list of sources to populate it with.
+    #[serde(flatten)]
+    pub targets: MapStage,
+    /// The `STAGING_SCHEMA_VERSION` this configuration was written for, to warn about fields that
+    /// have since been deprecated or that this release may not support.  Zero or unset means the
+    /// current version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema_version: Option<u32>,
+    /// When true, runners like the `staging` binary only report what they would stage.
+    ///
+    /// `false` does not override a dry-run requested some other way.  Ignored when rendering.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dry_run: Option<bool>,
+    /// When true, building stops at the first source that fails rather than reporting every
+    /// failure.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fail_fast: Option<bool>,
+    /// Fail before staging anything if there are more targets than this, guarding against a
+    /// runaway generated configuration.
+    ///
+    /// When unset, the `STAGER_MAX_TARGETS` environment variable is used instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_target_count: Option<usize>,
+    /// Write a listing of all staged files.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<ManifestConfig>,
+    /// Assertions about the stage, checked after everything is staged.  A failed check fails
+    /// staging.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub post_staging_checks: Vec<CheckSpec>,
+    /// When this configuration is merged on top of another, how to combine sources for targets
+    /// present in both.  Default is `append`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merge_strategy: Option<MergeStrategy>,
+    /// Template variables, rendered before the rest of the configuration (so they may use the
+    /// `env` filter).
+    ///
+    /// Variables passed to the `TemplateEngine` take precedence.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub variables: BTreeMap<String, Template>,
+    /// Directory that relative `SourceFile` and `SourceFiles` paths are resolved against.
+    ///
+    /// When unset, the `STAGER_BASE_PATH` environment variable is used instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_path: Option<Template>,
+    /// For a target, the targets that must be staged before it (e.g. because it has symlinks into
+    /// them).
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub depends_on: IndexMap<Template, OneOrMany<Template>>,
+    /// For a target, how to handle it failing to build: `fail` (default), `skip`, or `warn`.
+    ///
+    /// Lets optional targets be left out rather than failing the whole stage.
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub on_error: IndexMap<Template, builder::ErrorPolicy>,
+    /// Free-form information about the stage (e.g. package name or maintainer), ignored when
+    /// staging.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+    #[serde(skip)]
+    non_exhaustive: (),
+}
 
-impl<R: ActionRender> CustomMapStage<R> {
-    fn format(&self, engine: &TemplateEngine) -> Result<builder::Stage, error::Errors> {
-        let mut errors = error::Errors::new();
-        let mut stage: BTreeMap<path::PathBuf, Vec<Box<builder::ActionBuilder>>> = BTreeMap::new();
-        for (target, sources) in &self.0 {
-            let target = abs_to_rel(&target.format(engine)?)?;
+impl Staging {
+    /// Canonicalize the configuration so equivalent configurations serialize identically.
+    pub fn normalize(&mut self) {
+        self.targets.normalize();
+    }
 
-            let mut actions = Vec::with_capacity(sources.len());
-            for source in sources {
-                let action = source.format(engine);
-                match action {
-                    Ok(action) => actions.push(action),
-                    Err(error) => errors.extend(error),
-                }
-            }
-            stage.insert(target, actions);
+    /// Check for targets that render to the same path.
+    ///
+    /// Targets repeated literally are already rejected when deserializing.
+    pub fn validate(&self, engine: &TemplateEngine) -> Result<(), error::Errors> {
+        self.check_target_count()?;
+        self.targets.validate(engine)
+    }
+
+    /// Check the number of targets against `max_target_count`.
+    fn check_target_count(&self) -> Result<(), error::StagingError> {
+        let max = match self.max_target_count {
+            Some(max) => max,
+            None => match env::var(MAX_TARGETS_ENV) {
+                Ok(max) => max.parse().map_err(|e| {
+                    error::ErrorKind::InvalidConfiguration
+                        .error()
+                        .set_context(format!("Invalid {}: {:?}", MAX_TARGETS_ENV, max))
+                        .set_cause(e)
+                })?,
+                Err(_) => return Ok(()),
+            },
+        };
+        if max < self.targets.len() {
+            Err(error::ErrorKind::InvalidConfiguration
+                .error()
+                .set_context(format!(
+                    "{} targets is more than max_target_count {}",
+                    self.targets.len(),
+                    max
+                )))?;
         }
+        Ok(())
+    }
 
-        let stage = builder::Stage::new(stage);
-        errors.ok(stage)
+    /// Describe how `schema_version` differs from `STAGING_SCHEMA_VERSION`, if it does.
+    fn schema_warning(&self) -> Option<String> {
+        let version = match self.schema_version {
+            None | Some(0) => return None,
+            Some(version) => version,
+        };
+        if STAGING_SCHEMA_VERSION < version {
+            Some(format!(
+                "Stage configuration is for schema version {} but only {} is supported; some \
+                 fields may be ignored or rejected",
+                version, STAGING_SCHEMA_VERSION
+            ))
+        } else if version < STAGING_SCHEMA_VERSION {
+            let deprecated: Vec<_> = DEPRECATED_FIELDS
+                .iter()
+                .filter(|&&(_, since)| version < since)
+                .map(|&(field, _)| field)
+                .collect();
+            let deprecated = if deprecated.is_empty() {
+                "none".to_owned()
+            } else {
+                deprecated.join(", ")
+            };
+            Some(format!(
+                "Stage configuration is for schema version {}, the current version is {}; \
+                 deprecated fields: {}",
+                version, STAGING_SCHEMA_VERSION, deprecated
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// Layer `overlay` on top of this configuration, using `overlay`'s `merge_strategy`.
+    ///
+    /// Settings in `overlay` take precedence.
+    pub fn merge(&mut self, overlay: Staging) {
+        let strategy = overlay.merge_strategy.unwrap_or_default();
+        self.targets.merge(overlay.targets, strategy);
+        if overlay.manifest.is_some() {
+            self.manifest = overlay.manifest;
+        }
+        if overlay.base_path.is_some() {
+            self.base_path = overlay.base_path;
+        }
+        if overlay.schema_version.is_some() {
+            self.schema_version = overlay.schema_version;
+        }
+        if overlay.dry_run.is_some() {
+            self.dry_run = overlay.dry_run;
+        }
+        if overlay.fail_fast.is_some() {
+            self.fail_fast = overlay.fail_fast;
+        }
+        if overlay.max_target_count.is_some() {
+            self.max_target_count = overlay.max_target_count;
+        }
+        self.post_staging_checks.extend(overlay.post_staging_checks);
+        self.variables.extend(overlay.variables);
+        self.depends_on.extend(overlay.depends_on);
+        self.on_error.extend(overlay.on_error);
+        self.metadata.extend(overlay.metadata);
+    }
+
+    /// Read a configuration from its CBOR encoding.
+    #[cfg(feature = "cbor")]
+    pub fn from_cbor_reader<R: io::Read>(reader: R) -> Result<Self, error::StagingError> {
+        serde_cbor::from_reader(reader).map_err(invalid_value)
+    }
+
+    /// Write the configuration's CBOR encoding.
+    #[cfg(feature = "cbor")]
+    pub fn to_cbor_writer<W: io::Write>(&self, writer: W) -> Result<(), error::StagingError> {
+        serde_cbor::to_writer(writer, self).map_err(|e| {
+            error::ErrorKind::InvalidConfiguration
+                .error()
+                .set_context("Failed writing stage configuration")
+                .set_cause(e)
+        })
+    }
+
+    /// The effective configuration: a copy with every template rendered using `engine`, as it
+    /// would be when staging.
+    ///
+    /// `variables` are rendered and made available to the rest of the configuration, as when
+    /// staging.  `glob:` paths are not expanded and relative paths are not resolved against
+    /// `base_path`.
+    pub fn render(&self, engine: &TemplateEngine) -> Result<Staging, error::Errors> {
+        let mut staging = self.clone();
+        staging.variables.render(engine)?;
+        let engine = &engine.with_default_globals(self.variables.format(engine)?)?;
+        staging.base_path.render(engine)?;
+        staging.targets.render(engine)?;
+        if let Some(ref mut manifest) = staging.manifest {
+            manifest.path.render(engine)?;
+        }
+        staging.post_staging_checks.render(engine)?;
+        staging.depends_on = render_keys(&staging.depends_on, engine)?;
+        for depends_on in staging.depends_on.values_mut() {
+            depends_on.render(engine)?;
+        }
+        staging.on_error = render_keys(&staging.on_error, engine)?;
+        Ok(staging)
     }
-}
 
-impl<R: ActionRender> ActionRender for CustomMapStage<R> {
     fn format(
         &self,
         engine: &TemplateEngine,
     ) -> Result<Box<builder::ActionBuilder>, error::Errors> {
-        self.format(engine).map(|a| {
-            let a: Box<builder::ActionBuilder> = Box::new(a);
-            a
-        })
+        if let Some(warning) = self.schema_warning() {
+            warn!("{}", warning);
+        }
+        self.check_target_count()?;
+        let engine = &engine.with_default_globals(self.variables.format(engine)?)?;
+        let base_path = match self.base_path {
+            Some(ref base_path) => Some(base_path.format(engine)?),
+            None => env::var(BASE_PATH_ENV).ok(),
+        };
+        let engine = &engine.with_base_path(base_path.map(path::PathBuf::from));
+        let mut stage = self.targets
+            .format(engine)?
+            .fail_fast(self.fail_fast.unwrap_or(false));
+        for (target, depends_on) in &self.depends_on {
+            let target = abs_to_rel(&target.format(engine)?)?;
+            for depends_on in depends_on.format(engine)? {
+                stage = stage.depends_on(&target, &abs_to_rel(&depends_on)?);
+            }
+        }
+        for (target, policy) in &self.on_error {
+            stage = stage.on_error(&abs_to_rel(&target.format(engine)?)?, *policy);
+        }
+        let stage: Box<builder::ActionBuilder> = Box::new(stage);
+        let stage = match self.manifest {
+            Some(ref manifest) => {
+                let path = abs_to_rel(&manifest.path.format(engine)?)?;
+                let manifest = builder::Manifest::new(stage, path).format(manifest.format);
+                Box::new(manifest)
+            }
+            None => stage,
+        };
+        let stage = if self.post_staging_checks.is_empty() {
+            stage
+        } else {
+            let checks = self.checks(engine)?;
+            Box::new(builder::PostStagingChecks::new(stage, checks))
+        };
+        Ok(stage)
+    }
+
+    /// Format `post_staging_checks`, like for `action::VerifyStaging::check_plan`.
+    pub fn format_checks(
+        &self,
+        engine: &TemplateEngine,
+    ) -> Result<Vec<action::StagingCheck>, error::Errors> {
+        let engine = &engine.with_default_globals(self.variables.format(engine)?)?;
+        self.checks(engine)
+    }
+
+    fn checks(&self, engine: &TemplateEngine) -> Result<Vec<action::StagingCheck>, error::Errors> {
+        self.post_staging_checks
+            .iter()
+            .map(|c| c.format(engine))
+            .collect()
     }
 }
 
-impl<R: ActionRender> Default for CustomMapStage<R> {
-    fn default() -> Self {
+impl From<MapStage> for Staging {
+    fn from(targets: MapStage) -> Self {
         Self {
-            0: Default::default(),
+            targets,
+            schema_version: None,
+            dry_run: None,
+            fail_fast: None,
+            max_target_count: None,
+            manifest: None,
+            post_staging_checks: Vec::new(),
+            merge_strategy: None,
+            variables: BTreeMap::new(),
+            base_path: None,
+            depends_on: IndexMap::new(),
+            on_error: IndexMap::new(),
+            metadata: BTreeMap::new(),
+            non_exhaustive: (),
         }
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
-#[serde(tag = "type")]
-/// Content to stage.
-pub enum Source {
-    /// Specifies a file to be staged into the target directory.
-    SourceFile(SourceFile),
-    /// Specifies a collection of files to be staged into the target directory.
-    SourceFiles(SourceFiles),
-    /// Specifies a symbolic link file to be staged into the target directory.
-    Symlink(Symlink),
-    #[doc(hidden)]
-    __Nonexhaustive,
+/// Read the configuration from an already parsed document, like a `Cargo.toml` table.
+#[cfg(feature = "toml")]
+impl convert::TryFrom<toml::Value> for Staging {
+    type Error = error::StagingError;
+
+    fn try_from(value: toml::Value) -> Result<Self, Self::Error> {
+        value.try_into().map_err(invalid_value)
+    }
 }
 
-impl ActionRender for Source {
+/// Read the configuration from an already parsed document.
+#[cfg(feature = "serde_json")]
+impl convert::TryFrom<serde_json::Value> for Staging {
+    type Error = error::StagingError;
+
+    fn try_from(value: serde_json::Value) -> Result<Self, Self::Error> {
+        serde_json::from_value(value).map_err(invalid_value)
+    }
+}
+
+#[cfg(any(feature = "serde_json", feature = "toml", feature = "cbor"))]
+fn invalid_value<E>(error: E) -> error::StagingError
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    error::ErrorKind::InvalidConfiguration
+        .error()
+        .set_context("Failed reading stage configuration")
+        .set_cause(error)
+}
+
+impl ActionRender for Staging {
     fn format(
         &self,
         engine: &TemplateEngine,
     ) -> Result<Box<builder::ActionBuilder>, error::Errors> {
-        let value: Box<builder::ActionBuilder> = match *self {
-            Source::SourceFile(ref b) => ActionRender::format(b, engine)?,
-            Source::SourceFiles(ref b) => ActionRender::format(b, engine)?,
-            Source::Symlink(ref b) => ActionRender::format(b, engine)?,
-            Source::__Nonexhaustive => unreachable!("This is a non-public case"),
-        };
-        Ok(value)
+        self.format(engine)
     }
 }
 
-/// Specifies a file to be staged into the target directory.
+/// Specifies a listing of all staged files.
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
-pub struct SourceFile {
-    ///  Specifies the full path of the file to be copied into the target directory
+pub struct ManifestConfig {
+    /// Location of the manifest, an absolute path treating the stage as the root.
     pub path: Template,
-    /// Specifies the name the target file should be renamed as when copying from the source file.
-    /// Default is the filename of the source file.
-    #[serde(default)]
-    pub rename: Option<Template>,
-    /// Specifies symbolic links to `rename` in the same target directory.
+    /// File format of the manifest: `text` (default), `json`, or `csv`.
     #[serde(default)]
-    pub symlink: Option<OneOrMany<Template>>,
+    pub format: action::ManifestFormat,
     #[serde(skip)]
     non_exhaustive: (),
 }
 
-impl SourceFile {
-    fn format(&self, engine: &TemplateEngine) -> Result<builder::SourceFile, error::Errors> {
-        let path = path::PathBuf::from(self.path.format(engine)?);
-        let symlink = self.symlink
-            .as_ref()
-            .map(|a| a.format(engine))
-            .map_or(Ok(None), |r| r.map(Some))?
-            .unwrap_or_default();
-        let rename = self.rename
-            .as_ref()
-            .map(|t| t.format(engine))
-            .map_or(Ok(None), |r| r.map(Some))?;
-        let value = builder::SourceFile::new(path)
-            .rename(rename)
-            .push_symlinks(symlink.into_iter());
-        Ok(value)
+/// Assertion about the stage, checked after everything is staged.
+///
+/// Paths are absolute, treating the stage as the root, and support template formatting.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum CheckSpec {
+    /// Staged files total at most this many bytes.
+    MaxTotalSize(u64),
+    /// This path is staged.
+    FileExists(Template),
+    /// Between `min` and `max` files, inclusive, are staged.
+    FileCount {
+        /// Fewest files allowed.
+        min: usize,
+        /// Most files allowed.
+        max: usize,
+    },
+    /// The file staged at this path has this hex-encoded SHA-256.
+    StagedFileMatchesHash(Template, Template),
+}
+
+impl CheckSpec {
+    fn format(&self, engine: &TemplateEngine) -> Result<action::StagingCheck, error::Errors> {
+        let check = match *self {
+            CheckSpec::MaxTotalSize(max) => action::StagingCheck::MaxTotalSize(max),
+            CheckSpec::FileExists(ref path) => {
+                action::StagingCheck::FileExists(abs_to_rel(&path.format(engine)?)?)
+            }
+            CheckSpec::FileCount { min, max } => action::StagingCheck::FileCount { min, max },
+            CheckSpec::StagedFileMatchesHash(ref path, ref hash) => {
+                action::StagingCheck::StagedFileMatchesHash(
+                    abs_to_rel(&path.format(engine)?)?,
+                    hash.format(engine)?,
+                )
+            }
+        };
+        Ok(check)
     }
 }
 
-impl ActionRender for SourceFile {
-    fn format(
-        &self,
-        engine: &TemplateEngine,
-    ) -> Result<Box<builder::ActionBuilder>, error::Errors> {
-        self.format(engine).map(|a| {
-            let a: Box<builder::ActionBuilder> = Box::new(a);
-            a
-        })
+impl Render for CheckSpec {
+    fn render(&mut self, engine: &TemplateEngine) -> Result<(), error::StagingError> {
+        match *self {
+            CheckSpec::FileExists(ref mut path) => path.render(engine),
+            CheckSpec::StagedFileMatchesHash(ref mut path, ref mut hash) => {
+                path.render(engine)?;
+                hash.render(engine)
+            }
+            CheckSpec::MaxTotalSize(_) | CheckSpec::FileCount { .. } => Ok(()),
+        }
     }
 }
 
-/// Specifies a collection of files to be staged into the target directory.
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
-#[serde(deny_unknown_fields)]
-pub struct SourceFiles {
-    ///  Specifies the root path that `patterns` will be run on to identify files to be copied into
-    ///  the target directory.
-    pub path: Template,
-    /// Specifies the pattern for executing the recursive/multifile match.
-    pub pattern: OneOrMany<Template>,
-    /// When true, symbolic links are followed as if they were normal directories and files.
-    /// If a symbolic link is broken or is involved in a loop, an error is yielded.
-    #[serde(default)]
-    pub follow_links: bool,
-    /// Toggles whether no results for the pattern constitutes an error.
-    ///
-    /// Generally, the default of `false` is best because it makes mistakes more obvious.  An
-    /// example of when no results are acceptable is a default staging configuration that
-    /// implements a lot of default "good enough" policy.
-    #[serde(default)]
-    pub allow_empty: bool,
-    #[serde(skip)]
-    non_exhaustive: (),
+/// Replace templates with their rendered values, for `Staging::render`.
+trait Render {
+    fn render(&mut self, engine: &TemplateEngine) -> Result<(), error::StagingError>;
 }
 
-impl SourceFiles {
-    fn format(&self, engine: &TemplateEngine) -> Result<builder::SourceFiles, error::Errors> {
-        let path = path::PathBuf::from(self.path.format(engine)?);
-        let pattern = self.pattern.format(engine)?;
-        let value = builder::SourceFiles::new(path)
-            .push_patterns(pattern.into_iter())
-            .follow_links(self.follow_links)
-            .allow_empty(self.allow_empty);
-        Ok(value)
+impl Render for Template {
+    fn render(&mut self, engine: &TemplateEngine) -> Result<(), error::StagingError> {
+        *self = Template::new(self.format(engine)?);
+        Ok(())
     }
 }
 
-impl ActionRender for SourceFiles {
-    fn format(
-        &self,
-        engine: &TemplateEngine,
-    ) -> Result<Box<builder::ActionBuilder>, error::Errors> {
-        self.format(engine).map(|a| {
-            let a: Box<builder::ActionBuilder> = Box::new(a);
-            a
-        })
+impl<T: Render> Render for Option<T> {
+    fn render(&mut self, engine: &TemplateEngine) -> Result<(), error::StagingError> {
+        match *self {
+            Some(ref mut value) => value.render(engine),
+            None => Ok(()),
+        }
     }
 }
 
-/// Specifies a symbolic link file to be staged into the target directory.
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
-#[serde(deny_unknown_fields)]
-pub struct Symlink {
-    /// The literal path for the target to point to.
-    pub target: Template,
-    /// Specifies the name the symlink should be given.
-    /// Default is the filename of the `target`.
-    #[serde(default)]
-    pub rename: Option<Template>,
-    #[serde(skip)]
-    non_exhaustive: (),
+impl<T: Render> Render for Vec<T> {
+    fn render(&mut self, engine: &TemplateEngine) -> Result<(), error::StagingError> {
+        for value in self.iter_mut() {
+            value.render(engine)?;
+        }
+        Ok(())
+    }
 }
 
-impl Symlink {
-    fn format(&self, engine: &TemplateEngine) -> Result<builder::Symlink, error::Errors> {
-        let target = path::PathBuf::from(self.target.format(engine)?);
-        let value = builder::Symlink::new(target).rename(self.rename
-            .as_ref()
-            .map(|t| t.format(engine))
-            .map_or(Ok(None), |r| r.map(Some))?);
-        Ok(value)
+impl<T: Render> Render for OneOrMany<T> {
+    fn render(&mut self, engine: &TemplateEngine) -> Result<(), error::StagingError> {
+        match *self {
+            OneOrMany::One(ref mut value) => value.render(engine),
+            OneOrMany::Many(ref mut values) => values.render(engine),
+        }
     }
 }
 
-impl ActionRender for Symlink {
-    fn format(
-        &self,
-        engine: &TemplateEngine,
-    ) -> Result<Box<builder::ActionBuilder>, error::Errors> {
-        self.format(engine).map(|a| {
-            let a: Box<builder::ActionBuilder> = Box::new(a);
-            a
-        })
+impl Render for BTreeMap<String, Template> {
+    fn render(&mut self, engine: &TemplateEngine) -> Result<(), error::StagingError> {
+        for value in self.values_mut() {
+            value.render(engine)?;
+        }
+        Ok(())
     }
 }
 
-fn abs_to_rel(abs: &str) -> Result<path::PathBuf, error::StagingError> {
-    if !abs.starts_with('/') {
-        return Err(error::ErrorKind::InvalidConfiguration
-            .error()
-            .set_context(format!("Path is not absolute (within the state): {}", abs)));
+/// Copy of `map` with its keys rendered, failing if two render to the same value.
+fn render_keys<V: Clone>(
+    map: &IndexMap<Template, V>,
+    engine: &TemplateEngine,
+) -> Result<IndexMap<Template, V>, error::StagingError> {
+    let mut rendered = IndexMap::with_capacity(map.len());
+    for (key, value) in map {
+        let mut key = key.clone();
+        key.render(engine)?;
+        if rendered.contains_key(&key) {
+            Err(error::ErrorKind::InvalidConfiguration
+                .error()
+                .set_context(format!("Multiple targets render to {:?}", key)))?;
+        }
+        rendered.insert(key, value.clone());
     }
+    Ok(rendered)
+}
 
-    let rel = abs.trim_left_matches('/');
-    let mut path = path::PathBuf::new();
-    for part in rel.split('/').filter(|s| !s.is_empty() && *s != ".") {
-        if part == ".." {
-            if !path.pop() {
-                return Err(error::ErrorKind::InvalidConfiguration
-                    .error()
-                    .set_context(format!("Path is outside of staging root: {:?}", abs)));
-            }
+/// How to combine sources for a target present in both configurations being merged.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MergeStrategy {
+    /// Overlay sources are staged after the base sources.
+    #[default]
+    Append,
+    /// Overlay sources are staged before the base sources.
+    Prepend,
+    /// Overlay sources are staged instead of the base sources.
+    Replace,
+}
+
+/// For each stage target, a list of sources to populate it with.
+///
+/// The target is an absolute path, treating the stage as the root.  The target supports template
+/// formatting.
+pub type MapStage = CustomMapStage<Source>;
+
+/// For each stage target, a list of sources to populate it with.
+///
+/// The target is an absolute path, treating the stage as the root.  The target supports template
+/// formatting.  Targets are staged in the order they are listed.  Listing a target more than once
+/// is an error.
+///
+/// Instead of a list, a target may be given `{_override: true, sources: [...]}` so that, when
+/// merged on top of another configuration, its sources replace rather than merge with the other
+/// configuration's.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CustomMapStage<R: ActionRender>(IndexMap<Template, Vec<R>>, IndexSet<Template>);
+
+impl<R: ActionRender> CustomMapStage<R> {
+    /// Add `source` to be staged into `target`.
+    pub fn push(&mut self, target: Template, source: R) {
+        self.0.entry(target).or_default().push(source);
+    }
+
+    /// When `yes`, `target`'s sources replace a base configuration's when merged on top of it,
+    /// regardless of the `MergeStrategy`.
+    pub fn set_override(&mut self, target: Template, yes: bool) {
+        if yes {
+            self.1.insert(target);
         } else {
-            path.push(part);
+            self.1.remove(&target);
         }
     }
-    Ok(path)
+
+    /// Number of targets.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether there are no targets.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Each target and its sources, in the order they are staged.
+    pub fn iter(&self) -> indexmap::map::Iter<'_, Template, Vec<R>> {
+        self.0.iter()
+    }
+
+    /// Layer `overlay`'s targets on top of these, combining sources for shared targets according
+    /// to `strategy`, or replacing them for targets `overlay` marks with `set_override`.
+    pub fn merge(&mut self, overlay: CustomMapStage<R>, strategy: MergeStrategy) {
+        let overrides = overlay.1;
+        for (target, sources) in overlay.0 {
+            let strategy = if overrides.contains(&target) {
+                MergeStrategy::Replace
+            } else {
+                strategy
+            };
+            let base = self.0.entry(target).or_default();
+            match strategy {
+                MergeStrategy::Append => base.extend(sources),
+                MergeStrategy::Prepend => {
+                    let appended = mem::replace(base, sources);
+                    base.extend(appended);
+                }
+                MergeStrategy::Replace => *base = sources,
+            }
+        }
+    }
+
+    /// Check for targets that render to the same path.
+    pub fn validate(&self, engine: &TemplateEngine) -> Result<(), error::Errors> {
+        let mut errors = error::Errors::new();
+        let mut rendered: HashMap<path::PathBuf, &Template> = HashMap::new();
+        for target in self.0.keys() {
+            let path = match target.format(engine).and_then(|t| abs_to_rel(&t)) {
+                Ok(path) => path,
+                Err(error) => {
+                    errors.push(error);
+                    continue;
+                }
+            };
+            if let Some(first) = rendered.insert(path, target) {
+                errors.push(duplicate_target(first, target));
+            }
+        }
+        errors.ok(())
+    }
+
+    fn format(&self, engine: &TemplateEngine) -> Result<builder::Stage, error::Errors> {
+        let mut errors = error::Errors::new();
+        let mut stage: IndexMap<path::PathBuf, Vec<Box<builder::ActionBuilder>>> = IndexMap::new();
+        let mut rendered: HashMap<path::PathBuf, &Template> = HashMap::new();
+        for (target, sources) in &self.0 {
+            let template = target;
+            let target = abs_to_rel(&target.format(engine)?)?;
+            if let Some(first) = rendered.insert(target.clone(), template) {
+                errors.push(duplicate_target(first, template));
+                continue;
+            }
+
+            let mut actions = Vec::with_capacity(sources.len());
+            for source in sources {
+                let action = source.format_many(engine);
+                match action {
+                    Ok(action) => actions.extend(action),
+                    Err(error) => errors.extend(error),
+                }
+            }
+            stage.insert(target, actions);
+        }
+
+        let stage = builder::Stage::new(stage);
+        errors.ok(stage)
+    }
+}
+
+fn duplicate_target(first: &Template, second: &Template) -> error::StagingError {
+    error::ErrorKind::InvalidConfiguration
+        .error()
+        .set_context(format!(
+            "Targets {:?} and {:?} are the same path",
+            first, second
+        ))
+}
+
+impl<'de, R> serde::Deserialize<'de> for CustomMapStage<R>
+where
+    R: ActionRender + serde::Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_map(CustomMapStageVisitor(marker::PhantomData))
+    }
+}
+
+impl<R> serde::Serialize for CustomMapStage<R>
+where
+    R: ActionRender + serde::Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeMap;
+
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (target, sources) in &self.0 {
+            if self.1.contains(target) {
+                map.serialize_entry(target, &OverrideSources::new(sources))?;
+            } else {
+                map.serialize_entry(target, sources)?;
+            }
+        }
+        map.end()
+    }
+}
+
+/// A target's sources, in the form marking them as replacing a base configuration's.
+#[derive(Serialize)]
+struct OverrideSources<'s, R: 's> {
+    #[serde(rename = "_override")]
+    is_override: bool,
+    sources: &'s [R],
+}
+
+impl<'s, R> OverrideSources<'s, R> {
+    fn new(sources: &'s [R]) -> Self {
+        Self {
+            is_override: true,
+            sources,
+        }
+    }
+}
+
+/// A target's sources, as either a list or `{_override, sources}`.
+struct TargetSources<R> {
+    sources: Vec<R>,
+    is_override: bool,
+}
+
+impl<'de, R> serde::Deserialize<'de> for TargetSources<R>
+where
+    R: serde::Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_any(TargetSourcesVisitor(marker::PhantomData))
+    }
+}
+
+struct TargetSourcesVisitor<R>(marker::PhantomData<R>);
+
+impl<'de, R> serde::de::Visitor<'de> for TargetSourcesVisitor<R>
+where
+    R: serde::Deserialize<'de>,
+{
+    type Value = TargetSources<R>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a list of sources or a map with `_override` and `sources`")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: serde::de::SeqAccess<'de>,
+    {
+        let mut sources = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(source) = seq.next_element()? {
+            sources.push(source);
+        }
+        Ok(TargetSources {
+            sources,
+            is_override: false,
+        })
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: serde::de::MapAccess<'de>,
+    {
+        let mut sources = None;
+        let mut is_override = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "_override" if is_override.is_none() => is_override = Some(map.next_value()?),
+                "sources" if sources.is_none() => sources = Some(map.next_value()?),
+                "_override" | "sources" => {
+                    return Err(serde::de::Error::custom(format!("duplicate field `{}`", key)))
+                }
+                _ => {
+                    return Err(serde::de::Error::unknown_field(
+                        &key,
+                        &["_override", "sources"],
+                    ))
+                }
+            }
+        }
+        Ok(TargetSources {
+            sources: sources.ok_or_else(|| serde::de::Error::missing_field("sources"))?,
+            is_override: is_override.unwrap_or(false),
+        })
+    }
+}
+
+#[cfg(feature = "toml")]
+impl<R> convert::TryFrom<toml::Value> for CustomMapStage<R>
+where
+    R: ActionRender + serde::de::DeserializeOwned,
+{
+    type Error = error::StagingError;
+
+    fn try_from(value: toml::Value) -> Result<Self, Self::Error> {
+        value.try_into().map_err(invalid_value)
+    }
+}
+
+#[cfg(feature = "serde_json")]
+impl<R> convert::TryFrom<serde_json::Value> for CustomMapStage<R>
+where
+    R: ActionRender + serde::de::DeserializeOwned,
+{
+    type Error = error::StagingError;
+
+    fn try_from(value: serde_json::Value) -> Result<Self, Self::Error> {
+        serde_json::from_value(value).map_err(invalid_value)
+    }
+}
+
+struct CustomMapStageVisitor<R>(marker::PhantomData<R>);
+
+impl<'de, R> serde::de::Visitor<'de> for CustomMapStageVisitor<R>
+where
+    R: ActionRender + serde::Deserialize<'de>,
+{
+    type Value = CustomMapStage<R>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map of targets to lists of sources")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: serde::de::MapAccess<'de>,
+    {
+        let mut stage = CustomMapStage::default();
+        while let Some((target, sources)) = map.next_entry::<Template, TargetSources<R>>()? {
+            if stage.0.contains_key(&target) {
+                return Err(serde::de::Error::custom(format!(
+                    "duplicate target {:?}",
+                    target
+                )));
+            }
+            if sources.is_override {
+                stage.1.insert(target.clone());
+            }
+            stage.0.insert(target, sources.sources);
+        }
+        Ok(stage)
+    }
+}
+
+impl CustomMapStage<Source> {
+    /// Canonicalize the configuration so equivalent configurations serialize identically.
+    ///
+    /// Targets are sorted by their raw template text and redundant entries within each source are
+    /// removed.
+    pub fn normalize(&mut self) {
+        self.0.sort_keys();
+        for sources in self.0.values_mut() {
+            for source in sources.iter_mut() {
+                source.normalize();
+            }
+        }
+    }
+}
+
+impl<R: ActionRender> ActionRender for CustomMapStage<R> {
+    fn format(
+        &self,
+        engine: &TemplateEngine,
+    ) -> Result<Box<builder::ActionBuilder>, error::Errors> {
+        self.format(engine).map(|a| {
+            let a: Box<builder::ActionBuilder> = Box::new(a);
+            a
+        })
+    }
+}
+
+impl<R: ActionRender + Clone + Render> Render for CustomMapStage<R> {
+    fn render(&mut self, engine: &TemplateEngine) -> Result<(), error::StagingError> {
+        self.0 = render_keys(&self.0, engine)?;
+        self.1 = self.1
+            .iter()
+            .map(|target| {
+                let mut target = target.clone();
+                target.render(engine).map(|()| target)
+            })
+            .collect::<Result<_, _>>()?;
+        for sources in self.0.values_mut() {
+            sources.render(engine)?;
+        }
+        Ok(())
+    }
+}
+
+// Implemented by hand since `derive` would require `R: Default`.
+impl<R: ActionRender> Default for CustomMapStage<R> {
+    fn default() -> Self {
+        Self {
+            0: Default::default(),
+            1: Default::default(),
+        }
+    }
+}
+
+/// Sources for targets that are already present are appended.
+impl<R: ActionRender> Extend<(Template, Vec<R>)> for CustomMapStage<R> {
+    fn extend<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = (Template, Vec<R>)>,
+    {
+        for (target, sources) in iter {
+            self.0.entry(target).or_default().extend(sources);
+        }
+    }
+}
+
+/// Sources for repeated targets are appended.
+impl<R: ActionRender> iter::FromIterator<(Template, Vec<R>)> for CustomMapStage<R> {
+    fn from_iter<I>(iter: I) -> Self
+    where
+        I: IntoIterator<Item = (Template, Vec<R>)>,
+    {
+        let mut stage = Self::default();
+        stage.extend(iter);
+        stage
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+/// Content to stage.
+pub enum Source {
+    /// Specifies a file to be staged into the target directory.
+    SourceFile(SourceFile),
+    /// Specifies a collection of files to be staged into the target directory.
+    SourceFiles(SourceFiles),
+    /// Specifies a symbolic link file to be staged into the target directory.
+    Symlink(Symlink),
+    /// Specifies a collection of files to be staged into the target directory as symbolic links.
+    SymlinkTree(SymlinkTree),
+    /// Specifies a file to be generated from a template into the target directory.
+    Template(TemplateFile),
+    /// Specifies a source with additional template variables.
+    WithVars(WithVars),
+    /// Specifies sources sharing common settings.
+    Batch(Batch),
+    /// Specifies a file to have its modification time updated to now.
+    Touch(TouchFile),
+    /// Specifies members of a tar archive to be staged into the target directory.
+    #[cfg(feature = "tar")]
+    TarSource(TarSource),
+    /// Specifies a file to be generated from the output of a command.
+    #[cfg(feature = "exec")]
+    ExecOutput(ExecOutput),
+    #[doc(hidden)]
+    __Nonexhaustive,
+}
+
+impl Source {
+    fn normalize(&mut self) {
+        match *self {
+            Source::SourceFile(ref mut b) => b.normalize(),
+            Source::WithVars(ref mut b) => b.source.normalize(),
+            Source::Batch(ref mut b) => {
+                for source in &mut b.sources {
+                    source.normalize();
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn apply_batch(&mut self, batch: &Batch) {
+        match *self {
+            Source::SourceFile(ref mut b) => {
+                b.executable = b.executable.or(batch.executable);
+            }
+            Source::SourceFiles(ref mut b) => {
+                b.follow_links = b.follow_links.or(batch.follow_links);
+                b.allow_empty = b.allow_empty.or(batch.allow_empty);
+            }
+            Source::SymlinkTree(ref mut b) => {
+                b.follow_links = b.follow_links.or(batch.follow_links);
+                b.allow_empty = b.allow_empty.or(batch.allow_empty);
+            }
+            Source::WithVars(ref mut b) => b.source.apply_batch(batch),
+            Source::Batch(ref mut b) => {
+                for source in &mut b.sources {
+                    source.apply_batch(batch);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Render for Source {
+    fn render(&mut self, engine: &TemplateEngine) -> Result<(), error::StagingError> {
+        match *self {
+            Source::SourceFile(ref mut b) => b.render(engine),
+            Source::SourceFiles(ref mut b) => b.render(engine),
+            Source::Symlink(ref mut b) => b.render(engine),
+            Source::SymlinkTree(ref mut b) => b.render(engine),
+            Source::Template(ref mut b) => b.render(engine),
+            Source::WithVars(ref mut b) => b.render(engine),
+            Source::Batch(ref mut b) => b.sources.render(engine),
+            Source::Touch(ref mut b) => b.rename.render(engine),
+            #[cfg(feature = "tar")]
+            Source::TarSource(ref mut b) => {
+                b.archive.render(engine)?;
+                b.pattern.render(engine)
+            }
+            #[cfg(feature = "exec")]
+            Source::ExecOutput(ref mut b) => {
+                b.command.render(engine)?;
+                b.args.render(engine)?;
+                b.rename.render(engine)
+            }
+            Source::__Nonexhaustive => unreachable!("This is a non-public case"),
+        }
+    }
+}
+
+impl ActionRender for Source {
+    fn format(
+        &self,
+        engine: &TemplateEngine,
+    ) -> Result<Box<builder::ActionBuilder>, error::Errors> {
+        let value: Box<builder::ActionBuilder> = match *self {
+            Source::SourceFile(ref b) => ActionRender::format(b, engine)?,
+            Source::SourceFiles(ref b) => ActionRender::format(b, engine)?,
+            Source::Symlink(ref b) => ActionRender::format(b, engine)?,
+            Source::SymlinkTree(ref b) => ActionRender::format(b, engine)?,
+            Source::Template(ref b) => ActionRender::format(b, engine)?,
+            Source::WithVars(ref b) => ActionRender::format(b, engine)?,
+            Source::Batch(ref b) => ActionRender::format(b, engine)?,
+            Source::Touch(ref b) => ActionRender::format(b, engine)?,
+            #[cfg(feature = "tar")]
+            Source::TarSource(ref b) => ActionRender::format(b, engine)?,
+            #[cfg(feature = "exec")]
+            Source::ExecOutput(ref b) => ActionRender::format(b, engine)?,
+            Source::__Nonexhaustive => unreachable!("This is a non-public case"),
+        };
+        Ok(value)
+    }
+
+    fn format_many(
+        &self,
+        engine: &TemplateEngine,
+    ) -> Result<Vec<Box<builder::ActionBuilder>>, error::Errors> {
+        match *self {
+            Source::SourceFile(ref b) => ActionRender::format_many(b, engine),
+            Source::SourceFiles(ref b) => ActionRender::format_many(b, engine),
+            Source::Symlink(ref b) => ActionRender::format_many(b, engine),
+            Source::SymlinkTree(ref b) => ActionRender::format_many(b, engine),
+            Source::Template(ref b) => ActionRender::format_many(b, engine),
+            Source::WithVars(ref b) => ActionRender::format_many(b, engine),
+            Source::Batch(ref b) => ActionRender::format_many(b, engine),
+            Source::Touch(ref b) => ActionRender::format_many(b, engine),
+            #[cfg(feature = "tar")]
+            Source::TarSource(ref b) => ActionRender::format_many(b, engine),
+            #[cfg(feature = "exec")]
+            Source::ExecOutput(ref b) => ActionRender::format_many(b, engine),
+            Source::__Nonexhaustive => unreachable!("This is a non-public case"),
+        }
+    }
+}
+
+/// Specifies a file to be staged into the target directory.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SourceFile {
+    ///  Specifies the full path of the file to be copied into the target directory
+    ///
+    ///  When prefixed with `glob:`, the path is expanded to every file it matches.
+    pub path: Template,
+    /// Specifies the name the target file should be renamed as when copying from the source file.
+    /// Default is the filename of the source file.  It is an error for a `glob:` path to match
+    /// more than one file when this is set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rename: Option<Template>,
+    /// Specifies symbolic links to `rename` in the same target directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlink: Option<OneOrMany<Template>>,
+    /// Specifies hard links to `rename` in the same target directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hard_link: Option<OneOrMany<Template>>,
+    /// When true, the staged file is made executable.  Default is `false`.  This is ignored on
+    /// non-unix platforms.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub executable: Option<bool>,
+    /// Octal permissions (e.g. `"644"`) for the staged file, overriding `executable`.  This is
+    /// ignored on non-unix platforms.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<Template>,
+    /// Specifies directories within the target to also stage the file into, like `compat`.
+    /// These are copies of the staged file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub also_stage_to: Option<OneOrMany<Template>>,
+    /// When true, re-read the staged file after copying and fail if it doesn't match the source.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub verify_after: bool,
+    /// Fail if the file isn't valid text in this encoding: `utf8`, `ascii`, or `latin1`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding_check: Option<action::Encoding>,
+    /// Name of a transform, registered with `action::TransformRegistry::global()`, to rewrite the
+    /// file's content with while copying it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transform: Option<String>,
+    /// Directory to cache the staged file in, after any `transform`, so later runs stage the
+    /// cached copy instead of recomputing it.  Copies are keyed by the file's path, size, and
+    /// modification time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_dir: Option<Template>,
+    /// How long a copy in `cache_dir` stays fresh, as a duration like `1h`.  Default is until the
+    /// source file changes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_ttl: Option<Template>,
+    /// Strip debug information from the staged file, with `strip` or the program in the `STRIP`
+    /// environment variable.  Does nothing on Windows.
+    #[cfg(feature = "shell-hooks")]
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub strip_debug: bool,
+    /// Notes on why this file is staged.  Not used when staging.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Only stage on these operating systems (e.g. `linux`, `windows`, `macos`), as reported by
+    /// `std::env::consts::OS`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os: Option<OneOrMany<Template>>,
+    /// Only stage on these architectures (e.g. `x86_64`, `aarch64`), as reported by
+    /// `std::env::consts::ARCH`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arch: Option<OneOrMany<Template>>,
+    #[serde(skip)]
+    non_exhaustive: (),
+}
+
+impl SourceFile {
+    /// Specifies the file at `path` to be staged.
+    pub fn new(path: Template) -> Self {
+        Self {
+            path,
+            rename: None,
+            symlink: None,
+            hard_link: None,
+            executable: None,
+            mode: None,
+            also_stage_to: None,
+            verify_after: false,
+            encoding_check: None,
+            transform: None,
+            cache_dir: None,
+            cache_ttl: None,
+            #[cfg(feature = "shell-hooks")]
+            strip_debug: false,
+            description: None,
+            os: None,
+            arch: None,
+            non_exhaustive: (),
+        }
+    }
+
+    fn normalize(&mut self) {
+        self.symlink = dedup_links(self.symlink.take());
+        self.hard_link = dedup_links(self.hard_link.take());
+    }
+
+    fn format(&self, engine: &TemplateEngine) -> Result<builder::SourceFile, error::Errors> {
+        let path = self.path.format(engine)?;
+        if path.starts_with(GLOB_PREFIX) {
+            Err(error::ErrorKind::InvalidConfiguration
+                .error()
+                .set_context(format!(
+                    "SourceFile path expands to multiple files, which isn't supported here: {:?}",
+                    path
+                )))?;
+        }
+        let path = resolve_source_path(engine, &path);
+        self.format_path(engine, path::PathBuf::from(path))
+    }
+
+    fn format_many(
+        &self,
+        engine: &TemplateEngine,
+    ) -> Result<Vec<builder::SourceFile>, error::Errors> {
+        let path = self.path.format(engine)?;
+        if !path.starts_with(GLOB_PREFIX) {
+            let path = resolve_source_path(engine, &path);
+            return self.format_path(engine, path::PathBuf::from(path))
+                .map(|a| vec![a]);
+        }
+
+        let pattern = resolve_source_path(engine, &path[GLOB_PREFIX.len()..]);
+        let paths = expand_glob(&pattern)?;
+        if self.rename.is_some() && 1 < paths.len() {
+            Err(error::ErrorKind::InvalidConfiguration
+                .error()
+                .set_context(format!(
+                    "SourceFile rename requires path to match one file, {:?} matched {}",
+                    pattern,
+                    paths.len()
+                )))?;
+        }
+        let mut values = Vec::with_capacity(paths.len());
+        for path in paths {
+            values.push(self.format_path(engine, path)?);
+        }
+        Ok(values)
+    }
+
+    fn format_path(
+        &self,
+        engine: &TemplateEngine,
+        path: path::PathBuf,
+    ) -> Result<builder::SourceFile, error::Errors> {
+        let symlink = self.symlink.format(engine)?.unwrap_or_default();
+        let hard_link = self.hard_link.format(engine)?.unwrap_or_default();
+        let rename = self.rename.format(engine)?;
+        let mode = self.mode
+            .as_ref()
+            .map(|t| t.format(engine).and_then(|m| parse_mode(&m)))
+            .map_or(Ok(None), |r| r.map(Some))?;
+        let also_stage_to = self.also_stage_to.format(engine)?.unwrap_or_default();
+        let cache_dir = self.cache_dir.format(engine)?;
+        let cache_ttl = self.cache_ttl
+            .as_ref()
+            .map(|t| t.format(engine).and_then(|d| parse_duration(&d)))
+            .map_or(Ok(None), |r| r.map(Some))?;
+        let value = builder::SourceFile::new(path)
+            .rename(rename)
+            .push_symlinks(symlink.into_iter())
+            .push_hard_links(hard_link.into_iter())
+            .executable(self.executable.unwrap_or(false))
+            .mode(mode)
+            .also_stage_to(also_stage_to.into_iter().map(path::PathBuf::from))
+            .verify_after(self.verify_after)
+            .encoding_check(self.encoding_check)
+            .transform(self.transform.clone())
+            .cache_dir(cache_dir.map(path::PathBuf::from))
+            .cache_ttl(cache_ttl);
+        #[cfg(feature = "shell-hooks")]
+        let value = value.strip_debug(self.strip_debug);
+        Ok(value)
+    }
+}
+
+fn dedup_links(links: Option<OneOrMany<Template>>) -> Option<OneOrMany<Template>> {
+    match links {
+        Some(OneOrMany::Many(links)) => {
+            let mut unique: Vec<Template> = Vec::with_capacity(links.len());
+            for link in links {
+                if !unique.contains(&link) {
+                    unique.push(link);
+                }
+            }
+            match unique.len() {
+                0 => None,
+                1 => unique.pop().map(OneOrMany::One),
+                _ => Some(OneOrMany::Many(unique)),
+            }
+        }
+        links => links,
+    }
+}
+
+impl Render for SourceFile {
+    fn render(&mut self, engine: &TemplateEngine) -> Result<(), error::StagingError> {
+        self.path.render(engine)?;
+        self.rename.render(engine)?;
+        self.symlink.render(engine)?;
+        self.hard_link.render(engine)?;
+        self.mode.render(engine)?;
+        self.also_stage_to.render(engine)?;
+        self.cache_dir.render(engine)?;
+        self.cache_ttl.render(engine)?;
+        self.os.render(engine)?;
+        self.arch.render(engine)
+    }
+}
+
+impl ActionRender for SourceFile {
+    fn format(
+        &self,
+        engine: &TemplateEngine,
+    ) -> Result<Box<builder::ActionBuilder>, error::Errors> {
+        if !platform_matches(engine, &self.os, &self.arch)? {
+            return Ok(skipped());
+        }
+        self.format(engine).map(|a| {
+            let a: Box<builder::ActionBuilder> = Box::new(a);
+            a
+        })
+    }
+
+    fn format_many(
+        &self,
+        engine: &TemplateEngine,
+    ) -> Result<Vec<Box<builder::ActionBuilder>>, error::Errors> {
+        if !platform_matches(engine, &self.os, &self.arch)? {
+            return Ok(vec![]);
+        }
+        self.format_many(engine).map(|a| {
+            a.into_iter()
+                .map(|a| {
+                    let a: Box<builder::ActionBuilder> = Box::new(a);
+                    a
+                })
+                .collect()
+        })
+    }
+}
+
+/// Specifies a collection of files to be staged into the target directory.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SourceFiles {
+    ///  Specifies the root path that `patterns` will be run on to identify files to be copied into
+    ///  the target directory.
+    pub path: Template,
+    /// Specifies the pattern for executing the recursive/multifile match.
+    pub pattern: OneOrMany<Template>,
+    /// When true, symbolic links are followed as if they were normal directories and files.
+    /// If a symbolic link is broken or is involved in a loop, an error is yielded.  Default is
+    /// `false`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub follow_links: Option<bool>,
+    /// When true, symbolic links to directories are walked as if they were normal directories,
+    /// while symbolic links to files are staged as symbolic links.  `follow_links` takes
+    /// precedence.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub follow_links_to_dirs: bool,
+    /// Toggles whether no results for the pattern constitutes an error.
+    ///
+    /// Generally, the default of `false` is best because it makes mistakes more obvious.  An
+    /// example of when no results are acceptable is a default staging configuration that
+    /// implements a lot of default "good enough" policy.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allow_empty: Option<bool>,
+    /// Toggles whether `pattern` matches case sensitively.  Default is `true` on every
+    /// platform, even with a case-insensitive filesystem, matching how `git` works.
+    #[serde(default = "default_true", skip_serializing_if = "is_true")]
+    pub case_sensitive: bool,
+    /// Only match files at most this many directories deep, where `1` is only the files directly
+    /// in `path`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_depth: Option<usize>,
+    /// Deprecated alias of `modified_after`.  Setting both is an error.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_modified_since: Option<Template>,
+    /// Only stage files modified at or after this time.
+    ///
+    /// This is either an RFC 3339 timestamp (`2018-05-03T00:00:00Z`) or a duration before now
+    /// (`7d`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified_after: Option<Template>,
+    /// Only stage files modified before this time, in the same formats as `modified_after`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified_before: Option<Template>,
+    /// Skip files smaller than this many bytes, like empty outputs of a failed build step.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_file_size: Option<u64>,
+    /// Only stage files of this type: `text`, `binary`, or `executable`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_type: Option<builder::FileTypeFilter>,
+    /// Remove this leading directory from each matched file's path, relative to `path`, like
+    /// `tar --strip-components`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strip_prefix: Option<Template>,
+    /// Stage each matched file relative to this directory instead of `path`.  Every matched file
+    /// must be under it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relative_to: Option<Template>,
+    /// Stage each matched file under this subdirectory of the target, e.g. `debug` for
+    /// `lib/debug/`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_prefix: Option<Template>,
+    /// Only stage files whose name matches this regular expression, in addition to `pattern`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path_regex: Option<Template>,
+    /// Error if more than this many files are matched, guarding against a runaway `pattern`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_count: Option<usize>,
+    /// Error if a staged path, including the target directory, is longer than this many bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_path_length: Option<usize>,
+    /// Stage files matching these globs, relative to `path`, first and in this order, followed by
+    /// the rest.  Each entry must match a file matched by `pattern`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub explicit_order: Option<Vec<Template>>,
+    /// What to do when walking `path` fails, like on a broken symbolic link with `follow_links`:
+    /// `fail` (default), `skip`, or `warn`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_walk_error: Option<builder::WalkErrorPolicy>,
+    /// Notes on why these files are staged.  Not used when staging.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Only stage on these operating systems (e.g. `linux`, `windows`, `macos`), as reported by
+    /// `std::env::consts::OS`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os: Option<OneOrMany<Template>>,
+    /// Only stage on these architectures (e.g. `x86_64`, `aarch64`), as reported by
+    /// `std::env::consts::ARCH`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arch: Option<OneOrMany<Template>>,
+    #[serde(skip)]
+    non_exhaustive: (),
+}
+
+impl SourceFiles {
+    /// Specifies a collection of files under `path`, matched by `pattern`, to be staged.
+    pub fn new(path: Template, pattern: OneOrMany<Template>) -> Self {
+        Self {
+            path,
+            pattern,
+            follow_links: None,
+            follow_links_to_dirs: false,
+            allow_empty: None,
+            case_sensitive: true,
+            max_depth: None,
+            min_modified_since: None,
+            modified_after: None,
+            modified_before: None,
+            min_file_size: None,
+            file_type: None,
+            strip_prefix: None,
+            relative_to: None,
+            target_prefix: None,
+            path_regex: None,
+            max_count: None,
+            max_path_length: None,
+            explicit_order: None,
+            on_walk_error: None,
+            description: None,
+            os: None,
+            arch: None,
+            non_exhaustive: (),
+        }
+    }
+
+    fn format(&self, engine: &TemplateEngine) -> Result<builder::SourceFiles, error::Errors> {
+        let path = path::PathBuf::from(resolve_source_path(engine, &self.path.format(engine)?));
+        let pattern = self.pattern.format(engine)?;
+        if self.min_modified_since.is_some() && self.modified_after.is_some() {
+            Err(error::ErrorKind::InvalidConfiguration
+                .error()
+                .set_context(
+                    "SourceFiles min_modified_since is an alias of modified_after; set only one",
+                ))?;
+        }
+        let modified_after = self.modified_after
+            .as_ref()
+            .or(self.min_modified_since.as_ref())
+            .map(|t| t.format(engine).and_then(|t| parse_time(&t)))
+            .map_or(Ok(None), |r| r.map(Some))?;
+        let modified_before = self.modified_before
+            .as_ref()
+            .map(|t| t.format(engine).and_then(|t| parse_time(&t)))
+            .map_or(Ok(None), |r| r.map(Some))?;
+        let strip_prefix = self.strip_prefix
+            .as_ref()
+            .map(|t| t.format(engine).map(path::PathBuf::from))
+            .map_or(Ok(None), |r| r.map(Some))?;
+        let relative_to = self.relative_to
+            .as_ref()
+            .map(|t| {
+                t.format(engine)
+                    .map(|t| path::PathBuf::from(resolve_source_path(engine, &t)))
+            })
+            .map_or(Ok(None), |r| r.map(Some))?;
+        let target_prefix = self.target_prefix.format(engine)?
+            .filter(|p| !p.is_empty())
+            .map(path::PathBuf::from);
+        let path_regex = self.path_regex
+            .as_ref()
+            .map(|t| t.format(engine).and_then(|r| parse_regex(&r)))
+            .map_or(Ok(None), |r| r.map(Some))?;
+        let explicit_order = self.explicit_order
+            .as_ref()
+            .map(|order| order.iter().map(|t| t.format(engine)).collect())
+            .map_or(Ok(None), |r: Result<Vec<_>, _>| r.map(Some))?
+            .unwrap_or_default();
+        let value = builder::SourceFiles::new(path)
+            .push_patterns(pattern.into_iter())
+            .follow_links(self.follow_links.unwrap_or(false))
+            .follow_links_to_dirs(self.follow_links_to_dirs)
+            .allow_empty(self.allow_empty.unwrap_or(false))
+            .case_sensitive(self.case_sensitive)
+            .max_depth(self.max_depth)
+            .modified_after(modified_after)
+            .modified_before(modified_before)
+            .min_file_size(self.min_file_size)
+            .file_type_filter(self.file_type)
+            .strip_prefix(strip_prefix)
+            .relative_to(relative_to)
+            .target_prefix(target_prefix)
+            .path_regex_filter(path_regex)
+            .max_count(self.max_count)
+            .max_path_length(self.max_path_length)
+            .push_explicit_order(explicit_order.into_iter())
+            .on_walk_error(self.on_walk_error.unwrap_or_default());
+        Ok(value)
+    }
+}
+
+impl Render for SourceFiles {
+    fn render(&mut self, engine: &TemplateEngine) -> Result<(), error::StagingError> {
+        self.path.render(engine)?;
+        self.pattern.render(engine)?;
+        self.min_modified_since.render(engine)?;
+        self.modified_after.render(engine)?;
+        self.modified_before.render(engine)?;
+        self.strip_prefix.render(engine)?;
+        self.relative_to.render(engine)?;
+        self.target_prefix.render(engine)?;
+        self.path_regex.render(engine)?;
+        self.explicit_order.render(engine)?;
+        self.os.render(engine)?;
+        self.arch.render(engine)
+    }
+}
+
+impl ActionRender for SourceFiles {
+    fn format(
+        &self,
+        engine: &TemplateEngine,
+    ) -> Result<Box<builder::ActionBuilder>, error::Errors> {
+        if !platform_matches(engine, &self.os, &self.arch)? {
+            return Ok(skipped());
+        }
+        self.format(engine).map(|a| {
+            let a: Box<builder::ActionBuilder> = Box::new(a);
+            a
+        })
+    }
+}
+
+/// Specifies a collection of files to be staged into the target directory as symbolic links.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SymlinkTree {
+    ///  Specifies the root path that `patterns` will be run on to identify files to be linked into
+    ///  the target directory.
+    pub path: Template,
+    /// Specifies the pattern for executing the recursive/multifile match.
+    pub pattern: OneOrMany<Template>,
+    /// When true, symbolic links are followed as if they were normal directories and files.
+    /// If a symbolic link is broken or is involved in a loop, an error is yielded.  Default is
+    /// `false`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub follow_links: Option<bool>,
+    /// Toggles whether no results for the pattern constitutes an error.  Default is `false`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allow_empty: Option<bool>,
+    #[serde(skip)]
+    non_exhaustive: (),
+}
+
+impl SymlinkTree {
+    fn format(&self, engine: &TemplateEngine) -> Result<builder::SymlinkTree, error::Errors> {
+        let path = path::PathBuf::from(self.path.format(engine)?);
+        let pattern = self.pattern.format(engine)?;
+        let value = builder::SymlinkTree::new(path)
+            .push_patterns(pattern.into_iter())
+            .follow_links(self.follow_links.unwrap_or(false))
+            .allow_empty(self.allow_empty.unwrap_or(false));
+        Ok(value)
+    }
+}
+
+impl Render for SymlinkTree {
+    fn render(&mut self, engine: &TemplateEngine) -> Result<(), error::StagingError> {
+        self.path.render(engine)?;
+        self.pattern.render(engine)
+    }
+}
+
+impl ActionRender for SymlinkTree {
+    fn format(
+        &self,
+        engine: &TemplateEngine,
+    ) -> Result<Box<builder::ActionBuilder>, error::Errors> {
+        self.format(engine).map(|a| {
+            let a: Box<builder::ActionBuilder> = Box::new(a);
+            a
+        })
+    }
+}
+
+/// Specifies a symbolic link file to be staged into the target directory.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Symlink {
+    /// The literal path for the target to point to.
+    pub target: Template,
+    /// Specifies the name the symlink should be given.
+    /// Default is the filename of the `target`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rename: Option<Template>,
+    /// When true, fail if `target` does not exist rather than staging a dangling symlink.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub must_exist: bool,
+    /// When true, point the symlink at an absolute `target` by a path relative to where it is
+    /// staged.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub relative: bool,
+    /// Only stage on these operating systems (e.g. `linux`, `windows`, `macos`), as reported by
+    /// `std::env::consts::OS`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os: Option<OneOrMany<Template>>,
+    /// Only stage on these architectures (e.g. `x86_64`, `aarch64`), as reported by
+    /// `std::env::consts::ARCH`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arch: Option<OneOrMany<Template>>,
+    #[serde(skip)]
+    non_exhaustive: (),
+}
+
+impl Symlink {
+    /// Specifies a symbolic link to `target` to be staged.
+    pub fn new(target: Template) -> Self {
+        Self {
+            target,
+            rename: None,
+            must_exist: false,
+            relative: false,
+            os: None,
+            arch: None,
+            non_exhaustive: (),
+        }
+    }
+
+    fn format(&self, engine: &TemplateEngine) -> Result<builder::Symlink, error::Errors> {
+        let target = path::PathBuf::from(self.target.format(engine)?);
+        let value = builder::Symlink::new(target).rename(self.rename.format(engine)?)
+            .must_exist(self.must_exist)
+            .relative_to_target(self.relative);
+        Ok(value)
+    }
+}
+
+impl Render for Symlink {
+    fn render(&mut self, engine: &TemplateEngine) -> Result<(), error::StagingError> {
+        self.target.render(engine)?;
+        self.rename.render(engine)?;
+        self.os.render(engine)?;
+        self.arch.render(engine)
+    }
+}
+
+impl ActionRender for Symlink {
+    fn format(
+        &self,
+        engine: &TemplateEngine,
+    ) -> Result<Box<builder::ActionBuilder>, error::Errors> {
+        if !platform_matches(engine, &self.os, &self.arch)? {
+            return Ok(skipped());
+        }
+        self.format(engine).map(|a| {
+            let a: Box<builder::ActionBuilder> = Box::new(a);
+            a
+        })
+    }
+}
+
+/// Specifies a file to be generated from a template into the target directory.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TemplateFile {
+    /// The template for the file's content.
+    pub template: Template,
+    /// Variables available to `template`, in addition to the globals.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub variables: BTreeMap<String, Template>,
+    /// Specifies the name of the generated file.
+    pub rename: Template,
+    #[serde(skip)]
+    non_exhaustive: (),
+}
+
+impl TemplateFile {
+    fn format(&self, engine: &TemplateEngine) -> Result<builder::InlineContent, error::Errors> {
+        let mut errors = error::Errors::new();
+        let mut variables = liquid::Object::new();
+        for (key, value) in &self.variables {
+            match value.format(engine) {
+                Ok(value) => {
+                    variables.insert(key.clone(), liquid::Value::scalar(value));
+                }
+                Err(e) => errors.push(
+                    error::ErrorKind::InvalidConfiguration
+                        .error()
+                        .set_context(format!("Failed to render variable {:?}", key))
+                        .set_cause(e),
+                ),
+            }
+        }
+        let variables = errors.ok(variables)?;
+
+        let content = self.template.format_with(engine, &variables)?;
+        let rename = self.rename.format(engine)?;
+        let value = builder::InlineContent::new(rename, content);
+        Ok(value)
+    }
+}
+
+/// The content is rendered with `variables`, which are then dropped as nothing else uses them.
+impl Render for TemplateFile {
+    fn render(&mut self, engine: &TemplateEngine) -> Result<(), error::StagingError> {
+        let variables = self.variables.format(engine)?;
+        self.template = Template::new(self.template.format_with(engine, &variables)?);
+        self.variables.clear();
+        self.rename.render(engine)
+    }
+}
+
+impl ActionRender for TemplateFile {
+    fn format(
+        &self,
+        engine: &TemplateEngine,
+    ) -> Result<Box<builder::ActionBuilder>, error::Errors> {
+        self.format(engine).map(|a| {
+            let a: Box<builder::ActionBuilder> = Box::new(a);
+            a
+        })
+    }
+}
+
+/// Specifies a file to have its modification time updated to now.
+///
+/// The file is created empty if it doesn't exist.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TouchFile {
+    /// Specifies the name of the file.
+    pub rename: Template,
+    #[serde(skip)]
+    non_exhaustive: (),
+}
+
+impl TouchFile {
+    fn format(&self, engine: &TemplateEngine) -> Result<builder::TouchFile, error::Errors> {
+        let rename = self.rename.format(engine)?;
+        let value = builder::TouchFile::new(rename);
+        Ok(value)
+    }
+}
+
+impl ActionRender for TouchFile {
+    fn format(
+        &self,
+        engine: &TemplateEngine,
+    ) -> Result<Box<builder::ActionBuilder>, error::Errors> {
+        self.format(engine).map(|a| {
+            let a: Box<builder::ActionBuilder> = Box::new(a);
+            a
+        })
+    }
+}
+
+/// Specifies a file to be generated from the standard output of a command, like `git describe`.
+///
+/// The command is run when the files are staged, without a shell, so dry runs and listings don't
+/// run it.  Anyone who can edit the stage file can run arbitrary programs, so only use this with
+/// trusted configuration.
+#[cfg(feature = "exec")]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExecOutput {
+    /// Specifies the program to run.
+    pub command: Template,
+    /// Specifies the arguments to pass to `command`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub args: Option<Vec<Template>>,
+    /// Specifies the name of the generated file.
+    pub rename: Template,
+    /// When true, `command` writing nothing is not an error.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub allow_empty: bool,
+    #[serde(skip)]
+    non_exhaustive: (),
+}
+
+#[cfg(feature = "exec")]
+impl ExecOutput {
+    fn format(&self, engine: &TemplateEngine) -> Result<builder::CommandOutput, error::Errors> {
+        let command = self.command.format(engine)?;
+        let args = self.args
+            .as_ref()
+            .map(|args| args.iter().map(|a| a.format(engine)).collect())
+            .unwrap_or_else(|| Ok(Vec::new()))?;
+        let rename = self.rename.format(engine)?;
+        let value = builder::CommandOutput::new(rename, command)
+            .push_args(args.into_iter())
+            .allow_empty(self.allow_empty);
+        Ok(value)
+    }
+}
+
+#[cfg(feature = "exec")]
+impl ActionRender for ExecOutput {
+    fn format(
+        &self,
+        engine: &TemplateEngine,
+    ) -> Result<Box<builder::ActionBuilder>, error::Errors> {
+        self.format(engine).map(|a| {
+            let a: Box<builder::ActionBuilder> = Box::new(a);
+            a
+        })
+    }
+}
+
+/// Specifies members of a tar archive to be staged into the target directory.
+///
+/// The archive may be compressed with gzip (`.tar.gz`), bzip2 (`.tar.bz2`), or xz (`.tar.xz`).
+#[cfg(feature = "tar")]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TarSource {
+    /// Specifies the full path of the archive.
+    pub archive: Template,
+    /// Specifies the glob pattern members of the archive must match.
+    pub pattern: OneOrMany<Template>,
+    /// Remove this many leading components from each member's path, like
+    /// `tar --strip-components`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strip_components: Option<u32>,
+    #[serde(skip)]
+    non_exhaustive: (),
+}
+
+#[cfg(feature = "tar")]
+impl TarSource {
+    fn format(&self, engine: &TemplateEngine) -> Result<builder::TarMembers, error::Errors> {
+        let archive = path::PathBuf::from(self.archive.format(engine)?);
+        let pattern = self.pattern.format(engine)?;
+        let value = builder::TarMembers::new(archive)
+            .push_patterns(pattern.into_iter())
+            .strip_components(self.strip_components.unwrap_or(0) as usize);
+        Ok(value)
+    }
+}
+
+#[cfg(feature = "tar")]
+impl ActionRender for TarSource {
+    fn format(
+        &self,
+        engine: &TemplateEngine,
+    ) -> Result<Box<builder::ActionBuilder>, error::Errors> {
+        self.format(engine).map(|a| {
+            let a: Box<builder::ActionBuilder> = Box::new(a);
+            a
+        })
+    }
+}
+
+fn parse_regex(regex: &str) -> Result<regex::Regex, error::StagingError> {
+    regex::Regex::new(regex).map_err(|e| {
+        error::ErrorKind::InvalidConfiguration
+            .error()
+            .set_context(format!("Invalid regex: {:?}", regex))
+            .set_cause(e)
+    })
+}
+
+fn parse_mode(mode: &str) -> Result<u32, error::StagingError> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .and_then(|m| if m <= 0o7777 { Some(m) } else { None })
+        .ok_or_else(|| {
+            error::ErrorKind::InvalidConfiguration
+                .error()
+                .set_context(format!("Invalid octal mode: {:?}", mode))
+        })
+}
+
+fn parse_duration(value: &str) -> Result<time::Duration, error::StagingError> {
+    humantime::parse_duration(value).map_err(|e| {
+        error::ErrorKind::InvalidConfiguration
+            .error()
+            .set_context(format!("Invalid duration: {:?}", value))
+            .set_cause(e)
+    })
+}
+
+fn parse_time(value: &str) -> Result<time::SystemTime, error::StagingError> {
+    if let Ok(ago) = humantime::parse_duration(value) {
+        let now = time::SystemTime::now();
+        return now.checked_sub(ago).ok_or_else(|| {
+            error::ErrorKind::InvalidConfiguration
+                .error()
+                .set_context(format!("Duration is too far in the past: {:?}", value))
+        });
+    }
+    humantime::parse_rfc3339_weak(value).map_err(|e| {
+        error::ErrorKind::InvalidConfiguration
+            .error()
+            .set_context(format!(
+                "Expected an RFC 3339 timestamp or a duration: {:?}",
+                value
+            ))
+            .set_cause(e)
+    })
+}
+
+/// Whether the current platform is one of `os` and one of `arch`, when they are set.
+fn platform_matches(
+    engine: &TemplateEngine,
+    os: &Option<OneOrMany<Template>>,
+    arch: &Option<OneOrMany<Template>>,
+) -> Result<bool, error::StagingError> {
+    Ok(matches_any(engine, os, env::consts::OS)? && matches_any(engine, arch, env::consts::ARCH)?)
+}
+
+fn matches_any(
+    engine: &TemplateEngine,
+    values: &Option<OneOrMany<Template>>,
+    current: &str,
+) -> Result<bool, error::StagingError> {
+    match *values {
+        Some(ref values) => Ok(values.format(engine)?.iter().any(|v| v == current)),
+        None => Ok(true),
+    }
+}
+
+/// Builder for a source that is skipped on this platform.
+fn skipped() -> Box<builder::ActionBuilder> {
+    let empty: Vec<Box<builder::ActionBuilder>> = vec![];
+    Box::new(empty)
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+fn is_true(value: &bool) -> bool {
+    *value
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Specifies a source with additional template variables.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WithVars {
+    /// Variables available to `source`, overriding the globals.
+    pub vars: BTreeMap<String, String>,
+    /// The source to format with `vars`.
+    pub source: Box<Source>,
+    #[serde(skip)]
+    non_exhaustive: (),
+}
+
+impl WithVars {
+    fn engine(&self, engine: &TemplateEngine) -> Result<TemplateEngine, error::StagingError> {
+        let vars = self.vars
+            .iter()
+            .map(|(k, v)| (k.clone(), liquid::Value::scalar(v.as_str())))
+            .collect();
+        engine.with_extra_globals(vars)
+    }
+}
+
+impl Render for WithVars {
+    fn render(&mut self, engine: &TemplateEngine) -> Result<(), error::StagingError> {
+        let engine = self.engine(engine)?;
+        self.source.render(&engine)
+    }
+}
+
+impl ActionRender for WithVars {
+    fn format(
+        &self,
+        engine: &TemplateEngine,
+    ) -> Result<Box<builder::ActionBuilder>, error::Errors> {
+        let engine = self.engine(engine)?;
+        self.source.format(&engine)
+    }
+
+    fn format_many(
+        &self,
+        engine: &TemplateEngine,
+    ) -> Result<Vec<Box<builder::ActionBuilder>>, error::Errors> {
+        let engine = self.engine(engine)?;
+        self.source.format_many(&engine)
+    }
+}
+
+/// Specifies sources sharing common settings.
+///
+/// Settings are applied to each source that supports them and leaves them unset.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Batch {
+    /// Default `follow_links` for `SourceFiles` and `SymlinkTree`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub follow_links: Option<bool>,
+    /// Default `allow_empty` for `SourceFiles` and `SymlinkTree`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allow_empty: Option<bool>,
+    /// Default `executable` for `SourceFile`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub executable: Option<bool>,
+    /// The sources to apply the settings to.
+    pub sources: Vec<Source>,
+    #[serde(skip)]
+    non_exhaustive: (),
+}
+
+impl Batch {
+    fn sources(&self) -> Vec<Source> {
+        self.sources
+            .iter()
+            .map(|s| {
+                let mut s = s.clone();
+                s.apply_batch(self);
+                s
+            })
+            .collect()
+    }
+}
+
+impl ActionRender for Batch {
+    fn format(
+        &self,
+        engine: &TemplateEngine,
+    ) -> Result<Box<builder::ActionBuilder>, error::Errors> {
+        self.format_many(engine).map(|a| {
+            let a: Box<builder::ActionBuilder> = Box::new(a);
+            a
+        })
+    }
+
+    fn format_many(
+        &self,
+        engine: &TemplateEngine,
+    ) -> Result<Vec<Box<builder::ActionBuilder>>, error::Errors> {
+        let mut errors = error::Errors::new();
+        let mut actions = vec![];
+        for source in self.sources() {
+            match source.format_many(engine) {
+                Ok(action) => actions.extend(action),
+                Err(error) => errors.extend(error),
+            }
+        }
+        errors.ok(actions)
+    }
+}
+
+const GLOB_PREFIX: &str = "glob:";
+
+const BASE_PATH_ENV: &str = "STAGER_BASE_PATH";
+
+const MAX_TARGETS_ENV: &str = "STAGER_MAX_TARGETS";
+
+/// Resolve a relative source `path` against the engine's base path.
+fn resolve_source_path(engine: &TemplateEngine, path: &str) -> String {
+    match engine.base_path() {
+        Some(base_path) if !path.starts_with('/') => {
+            base_path.join(path).to_string_lossy().into_owned()
+        }
+        _ => path.to_owned(),
+    }
+}
+
+fn expand_glob(pattern: &str) -> Result<Vec<path::PathBuf>, error::Errors> {
+    // Walk from the deepest directory that doesn't contain any glob syntax.
+    let literal_len = pattern
+        .find(&['*', '?', '[', '{'][..])
+        .unwrap_or(pattern.len());
+    let split = pattern[..literal_len].rfind('/').unwrap_or(0);
+    let (root, glob) = pattern.split_at(split);
+    let root = if root.is_empty() && pattern.starts_with('/') {
+        "/"
+    } else {
+        root
+    };
+    if !path::Path::new(root).is_absolute() {
+        Err(error::ErrorKind::InvalidConfiguration
+            .error()
+            .set_context(format!("Glob path must be absolute: {:?}", pattern)))?;
+    }
+
+    let walker = globwalk::GlobWalkerBuilder::new(root, glob)
+        .build()
+        .map_err(|e| error::ErrorKind::InvalidConfiguration.error().set_cause(e))?;
+    let entries = walker
+        .into_iter()
+        .map(|e| e.map_err(|e| error::ErrorKind::HarvestingFailed.error().set_cause(e)));
+    let (entries, mut errors) = error::ErrorCollector::new(entries).finish();
+    let mut paths: Vec<_> = entries
+        .into_iter()
+        .filter(|e| !e.file_type().is_dir())
+        .map(|e| e.path().to_owned())
+        .collect();
+    paths.sort();
+
+    if paths.is_empty() {
+        errors.push(
+            error::ErrorKind::HarvestingFailed
+                .error()
+                .set_context(format!("No files found matching {:?}", pattern)),
+        );
+    }
+
+    errors.ok(paths)
+}
+
+/// Convert a stage path, where `/` is the staging root, to a path relative to the staging root.
+///
+/// Empty and `.` components are dropped and `..` is resolved.  It is an error for `abs` to not be
+/// absolute or to escape the staging root.
+///
+/// ```rust
+/// use std::path::PathBuf;
+/// use stager::de::abs_to_rel;
+///
+/// assert_eq!(abs_to_rel("/usr/./lib//../bin").unwrap(), PathBuf::from("usr/bin"));
+/// assert!(abs_to_rel("/../etc").is_err());
+/// ```
+pub fn abs_to_rel(abs: &str) -> Result<path::PathBuf, error::StagingError> {
+    if !abs.starts_with('/') {
+        return Err(error::ErrorKind::InvalidConfiguration
+            .error()
+            .set_context(format!("Path is not absolute (within the state): {}", abs)));
+    }
+
+    let rel = abs.trim_start_matches('/');
+    let mut path = path::PathBuf::new();
+    for part in rel.split('/').filter(|s| !s.is_empty() && *s != ".") {
+        if part == ".." {
+            if !path.pop() {
+                return Err(error::ErrorKind::InvalidConfiguration
+                    .error()
+                    .set_context(format!("Path is outside of staging root: {:?}", abs)));
+            }
+        } else {
+            path.push(part);
+        }
+    }
+    Ok(path)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn source_file_normalize_dedups_symlinks() {
+        let mut source = SourceFile {
+            symlink: Some(OneOrMany::Many(vec![
+                Template::new("a"),
+                Template::new("b"),
+                Template::new("a"),
+            ])),
+            ..SourceFile::new(Template::new("/foo"))
+        };
+        source.normalize();
+        assert_eq!(
+            source.symlink,
+            Some(OneOrMany::Many(vec![Template::new("a"), Template::new("b")]))
+        );
+    }
+
+    #[test]
+    fn source_file_glob_rejects_rename_of_many() {
+        let engine = TemplateEngine::new(liquid::Object::new()).unwrap();
+        let root = path::Path::new(env!("CARGO_MANIFEST_DIR"));
+        let source = |pattern: &str| SourceFile {
+            rename: Some(Template::new("renamed")),
+            ..SourceFile::new(Template::new(format!("glob:{}/{}", root.display(), pattern)))
+        };
+        assert_eq!(source("Cargo.*ml").format_many(&engine).unwrap().len(), 1);
+        assert!(source("src/*.rs").format_many(&engine).is_err());
+    }
+
+    #[test]
+    fn batch_applies_unset_settings() {
+        let mut files = SourceFiles::new(Template::new("/foo"), Template::new("*").into());
+        files.follow_links = Some(false);
+        let batch = Batch {
+            follow_links: Some(true),
+            allow_empty: Some(true),
+            executable: None,
+            sources: vec![Source::SourceFiles(files)],
+            non_exhaustive: (),
+        };
+        match batch.sources()[0] {
+            Source::SourceFiles(ref files) => {
+                assert_eq!(files.follow_links, Some(false));
+                assert_eq!(files.allow_empty, Some(true));
+            }
+            _ => panic!("unexpected source"),
+        }
+    }
+
+    #[test]
+    fn staging_variables_are_overridden_by_globals() {
+        let mut staging = Staging::default();
+        staging
+            .variables
+            .insert("bin".to_owned(), Template::new("bin"));
+        staging
+            .variables
+            .insert("lib".to_owned(), Template::new("{{ bin }}-lib"));
+        for target in &["/{{ bin }}", "/{{ lib }}"] {
+            let symlink = Symlink::new(Template::new("/foo"));
+            staging
+                .targets
+                .push(Template::new(*target), Source::Symlink(symlink));
+        }
+
+        let mut globals = liquid::Object::new();
+        globals.insert("bin".to_owned(), liquid::Value::scalar("sbin"));
+        let engine = TemplateEngine::new(globals).unwrap();
+        let actions = ActionRender::format(&staging, &engine)
+            .unwrap()
+            .build(path::Path::new("/stage"))
+            .unwrap();
+        let paths: Vec<_> = actions.iter().filter_map(|a| a.affects_path()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                path::Path::new("/stage/sbin/foo"),
+                path::Path::new("/stage/sbin-lib/foo"),
+            ]
+        );
+    }
+
+    #[test]
+    fn with_vars_scopes_variables_to_its_source() {
+        let mut vars = BTreeMap::new();
+        vars.insert("name".to_owned(), "scoped".to_owned());
+        let scoped = WithVars {
+            vars,
+            source: Box::new(Source::Symlink(Symlink::new(Template::new("/{{ name }}")))),
+            non_exhaustive: (),
+        };
+        let mut staging = Staging::default();
+        staging
+            .targets
+            .push(Template::new("/bin"), Source::WithVars(scoped));
+        staging.targets.push(
+            Template::new("/bin"),
+            Source::Symlink(Symlink::new(Template::new("/{{ name }}"))),
+        );
+
+        let mut globals = liquid::Object::new();
+        globals.insert("name".to_owned(), liquid::Value::scalar("outer"));
+        let engine = TemplateEngine::new(globals).unwrap();
+        let actions = ActionRender::format(&staging, &engine)
+            .unwrap()
+            .build(path::Path::new("/stage"))
+            .unwrap();
+        let paths: Vec<_> = actions.iter().filter_map(|a| a.affects_path()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                path::Path::new("/stage/bin/scoped"),
+                path::Path::new("/stage/bin/outer"),
+            ]
+        );
+    }
+
+    #[cfg(all(feature = "exec", unix))]
+    #[test]
+    fn exec_output() {
+        let engine = TemplateEngine::new(liquid::Object::new()).unwrap();
+        let exec = |command: &str, args: &[&str], allow_empty| ExecOutput {
+            command: Template::new(command),
+            args: Some(args.iter().map(|a| Template::new(*a)).collect()),
+            rename: Template::new("VERSION"),
+            allow_empty,
+            non_exhaustive: (),
+        };
+
+        let temp = tempfile::tempdir().unwrap();
+        let stage = temp.path();
+        let build = |exec: &ExecOutput| {
+            ActionRender::format(exec, &engine)
+                .unwrap()
+                .build(stage)
+                .unwrap()
+        };
+
+        let actions = build(&exec("echo", &["{{ 1 | plus: 1 }}"], false));
+        assert_eq!(actions[0].affects_path(), Some(stage.join("VERSION").as_path()));
+        assert_eq!(
+            actions[0].describe().kind,
+            action::ActionKind::WriteContent
+        );
+        actions[0].perform(&Default::default()).unwrap();
+        assert_eq!(std::fs::read_to_string(stage.join("VERSION")).unwrap(), "2\n");
+
+        let error = build(&exec("false", &[], false))[0]
+            .perform(&Default::default())
+            .unwrap_err();
+        assert_eq!(error.kind(), error::ErrorKind::StagingFailed);
+        assert!(build(&exec("true", &[], false))[0]
+            .perform(&Default::default())
+            .is_err());
+        assert!(build(&exec("true", &[], true))[0]
+            .perform(&Default::default())
+            .is_ok());
+    }
+
+    #[cfg(all(feature = "exec", unix))]
+    #[test]
+    fn exec_output_runs_only_when_performed() {
+        let engine = TemplateEngine::new(liquid::Object::new()).unwrap();
+        let temp = tempfile::tempdir().unwrap();
+        let marker = temp.path().join("marker");
+        let exec = ExecOutput {
+            command: Template::new("touch"),
+            args: Some(vec![Template::new(marker.to_str().unwrap())]),
+            rename: Template::new("VERSION"),
+            allow_empty: true,
+            non_exhaustive: (),
+        };
+
+        let builder = ActionRender::format(&exec, &engine).unwrap();
+        builder.dry_run_display(temp.path());
+        let actions = builder.build(&temp.path().join("stage")).unwrap();
+        actions[0].describe();
+        assert!(!marker.exists());
+
+        actions[0].perform(&Default::default()).unwrap();
+        assert!(marker.exists());
+    }
+
+    #[test]
+    fn resolve_source_path_prepends_base_path() {
+        let engine = TemplateEngine::new(liquid::Object::new()).unwrap();
+        assert_eq!(resolve_source_path(&engine, "foo"), "foo");
+        let engine = engine.with_base_path(Some(path::PathBuf::from("/base")));
+        assert_eq!(resolve_source_path(&engine, "foo/bar"), "/base/foo/bar");
+        assert_eq!(resolve_source_path(&engine, "/foo"), "/foo");
+    }
+
+    #[test]
+    fn source_files_min_modified_since_alias() {
+        let engine = TemplateEngine::new(liquid::Object::new()).unwrap();
+        let mut files = SourceFiles::new(Template::new("/foo"), Template::new("*").into());
+        files.min_modified_since = Some(Template::new("7d"));
+        assert!(ActionRender::format(&files, &engine).is_ok());
+        files.modified_after = Some(Template::new("1d"));
+        let error = ActionRender::format(&files, &engine).unwrap_err();
+        assert!(error.to_string().contains("alias of modified_after"));
+    }
+
+    #[test]
+    fn source_skipped_on_other_platforms() {
+        let engine = TemplateEngine::new(liquid::Object::new()).unwrap();
+        let mut files = SourceFiles::new(Template::new("/foo"), Template::new("*").into());
+        files.os = Some(Template::new("not-an-os").into());
+        let actions = ActionRender::format(&files, &engine)
+            .unwrap()
+            .build(path::Path::new("/stage"))
+            .unwrap();
+        assert!(actions.is_empty());
+
+        files.os = Some(OneOrMany::Many(vec![
+            Template::new("not-an-os"),
+            Template::new(env::consts::OS),
+        ]));
+        assert!(platform_matches(&engine, &files.os, &files.arch).unwrap());
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn staging_rejects_literal_duplicate_targets() {
+        let config = r#"{
+            "/bin": [{"type": "Symlink", "target": "/a"}],
+            "/bin": [{"type": "Symlink", "target": "/b"}]
+        }"#;
+        let error = serde_json::from_str::<Staging>(config).unwrap_err();
+        assert!(error.to_string().contains("duplicate target"));
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn staging_try_from_json_value() {
+        use std::convert::TryFrom;
+
+        let value = serde_json::json!({
+            "/bin": [{"type": "Symlink", "target": "/a"}],
+            "metadata": {"name": "stager"},
+        });
+        let staging = Staging::try_from(value).unwrap();
+        assert_eq!(staging.targets.0[&Template::new("/bin")].len(), 1);
+        assert_eq!(staging.metadata["name"], "stager");
+
+        let value = serde_json::json!({"/bin": [{"type": "Unknown"}]});
+        let error = Staging::try_from(value).unwrap_err();
+        assert_eq!(error.kind(), error::ErrorKind::InvalidConfiguration);
+    }
+
+    #[test]
+    fn map_stage_iter() {
+        let mut stage = MapStage::default();
+        assert!(stage.is_empty());
+        for target in &["/z", "/a"] {
+            stage.push(
+                Template::new(*target),
+                Source::Symlink(Symlink::new(Template::new("/b"))),
+            );
+        }
+        assert_eq!(stage.len(), 2);
+        let targets: Vec<_> = stage.iter().map(|(t, _)| t).collect();
+        assert_eq!(targets, vec![&Template::new("/z"), &Template::new("/a")]);
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn source_description_round_trips() {
+        let value = serde_json::json!({
+            "/bin": [
+                {"type": "SourceFile", "path": "a", "description": "The main binary"},
+                {"type": "SourceFiles", "path": "b", "pattern": "*", "description": "Plugins"},
+            ],
+        });
+        let staging: Staging = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&staging).unwrap(), value);
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn map_stage_try_from_toml_value() {
+        use std::convert::TryFrom;
+
+        let value: toml::Value = toml::from_str(
+            r#"
+            "/bin" = [{ type = "Symlink", target = "/a" }]
+            "#,
+        ).unwrap();
+        let stage = MapStage::try_from(value).unwrap();
+        assert_eq!(stage.0[&Template::new("/bin")].len(), 1);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn staging_cbor_round_trip() {
+        let mut staging = Staging::default();
+        let symlink = Symlink::new(Template::new("/a"));
+        staging
+            .targets
+            .0
+            .insert(Template::new("/bin"), vec![Source::Symlink(symlink)]);
+        staging.schema_version = Some(STAGING_SCHEMA_VERSION);
+
+        let mut encoded = Vec::new();
+        staging.to_cbor_writer(&mut encoded).unwrap();
+        let decoded = Staging::from_cbor_reader(encoded.as_slice()).unwrap();
+        assert_eq!(decoded.targets.0[&Template::new("/bin")].len(), 1);
+        assert_eq!(decoded.schema_version, Some(STAGING_SCHEMA_VERSION));
+
+        assert!(Staging::from_cbor_reader(&b"\xff"[..]).is_err());
+    }
+
+    #[test]
+    fn staging_validate_rejects_rendered_duplicate_targets() {
+        let mut staging = Staging::default();
+        for (target, link) in &[("/{{ bin }}", "/a"), ("/bin/", "/b")] {
+            let symlink = Symlink::new(Template::new(*link));
+            staging
+                .targets
+                .push(Template::new(*target), Source::Symlink(symlink));
+        }
+        let mut globals = liquid::Object::new();
+        globals.insert("bin".to_owned(), liquid::Value::scalar("bin"));
+        let engine = TemplateEngine::new(globals).unwrap();
+        let errors = staging.validate(&engine).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors.as_slice()[0].kind(),
+            error::ErrorKind::InvalidConfiguration
+        );
+        assert!(ActionRender::format(&staging, &engine).is_err());
+    }
+
+    #[test]
+    fn map_stage_from_iter_appends_repeats() {
+        let symlink = |target: &str| Symlink::new(Template::new(target));
+        let stage: CustomMapStage<Symlink> = vec![
+            (Template::new("/bin"), vec![symlink("a")]),
+            (Template::new("/lib"), vec![symlink("b")]),
+            (Template::new("/bin"), vec![symlink("c")]),
+        ].into_iter()
+            .collect();
+        assert_eq!(stage.0.len(), 2);
+        assert_eq!(stage.0[&Template::new("/bin")], vec![symlink("a"), symlink("c")]);
+    }
+
+    #[test]
+    fn map_stage_merge_strategies() {
+        let symlink = |target: &str| Symlink::new(Template::new(target));
+        let mut base = CustomMapStage::<Symlink>::default();
+        base.push(Template::new("/bin"), symlink("a"));
+        base.push(Template::new("/lib"), symlink("b"));
+        let mut overlay = CustomMapStage::<Symlink>::default();
+        overlay.push(Template::new("/bin"), symlink("c"));
+
+        let mut merged = base.clone();
+        merged.merge(overlay.clone(), MergeStrategy::Append);
+        assert_eq!(merged.0[&Template::new("/bin")], vec![symlink("a"), symlink("c")]);
+        assert_eq!(merged.0[&Template::new("/lib")], vec![symlink("b")]);
+
+        let mut merged = base.clone();
+        merged.merge(overlay.clone(), MergeStrategy::Prepend);
+        assert_eq!(merged.0[&Template::new("/bin")], vec![symlink("c"), symlink("a")]);
+
+        let mut merged = base.clone();
+        merged.merge(overlay.clone(), MergeStrategy::Replace);
+        assert_eq!(merged.0[&Template::new("/bin")], vec![symlink("c")]);
+        assert_eq!(merged.0[&Template::new("/lib")], vec![symlink("b")]);
+    }
+
+    #[test]
+    fn staging_merge_overrides() {
+        let symlink = |target: &str| Source::Symlink(Symlink::new(Template::new(target)));
+        let mut base = Staging::default();
+        base.targets.push(Template::new("/bin"), symlink("a"));
+        base.targets.push(Template::new("/lib"), symlink("b"));
+        let mut overlay = Staging::default();
+        overlay.targets.push(Template::new("/bin"), symlink("c"));
+        overlay.targets.push(Template::new("/lib"), symlink("d"));
+        overlay.targets.set_override(Template::new("/bin"), true);
+
+        base.merge(overlay);
+        assert_eq!(base.targets.0[&Template::new("/bin")], vec![symlink("c")]);
+        assert_eq!(
+            base.targets.0[&Template::new("/lib")],
+            vec![symlink("b"), symlink("d")]
+        );
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn staging_override_marker() {
+        let config = r#"{
+            "/bin": {"_override": true, "sources": [{"type": "Symlink", "target": "/a"}]},
+            "/lib": [{"type": "Symlink", "target": "/b"}]
+        }"#;
+        let staging: Staging = serde_json::from_str(config).unwrap();
+        assert!(staging.targets.1.contains(&Template::new("/bin")));
+        assert!(!staging.targets.1.contains(&Template::new("/lib")));
+        let json = serde_json::to_string(&staging).unwrap();
+        assert_eq!(serde_json::from_str::<Staging>(&json).unwrap(), staging);
+        assert!(json.contains("_override"));
+
+        let unknown = r#"{"/bin": {"_override": true, "source": []}}"#;
+        assert!(serde_json::from_str::<Staging>(unknown).is_err());
+    }
+
+    #[test]
+    fn staging_schema_version() {
+        let mut staging = Staging::default();
+        assert_eq!(staging.schema_warning(), None);
+        staging.schema_version = Some(0);
+        assert_eq!(staging.schema_warning(), None);
+        staging.schema_version = Some(STAGING_SCHEMA_VERSION);
+        assert_eq!(staging.schema_warning(), None);
+        staging.schema_version = Some(STAGING_SCHEMA_VERSION + 1);
+        assert!(staging.schema_warning().unwrap().contains("may be ignored"));
+    }
+
+    #[test]
+    fn staging_max_target_count() {
+        let mut staging = Staging::default();
+        for target in &["/a", "/b"] {
+            staging.targets.push(
+                Template::new(*target),
+                Source::Symlink(Symlink::new(Template::new("/c"))),
+            );
+        }
+        staging.max_target_count = Some(2);
+        assert!(staging.check_target_count().is_ok());
+        staging.max_target_count = Some(1);
+        let error = staging.check_target_count().unwrap_err();
+        assert_eq!(error.kind(), error::ErrorKind::InvalidConfiguration);
+        assert!(error.to_string().contains("max_target_count"));
+    }
+
+    #[test]
+    fn staging_render() {
+        let mut staging = Staging::default();
+        staging
+            .variables
+            .insert("prefix".to_owned(), Template::new("/{{ root }}"));
+        staging.targets.push(
+            Template::new("{{ prefix }}/bin"),
+            Source::Symlink(Symlink::new(Template::new("{{ prefix }}/lib/{{ name }}"))),
+        );
+        let mut globals = liquid::Object::new();
+        globals.insert("root".to_owned(), liquid::Value::scalar("usr"));
+        globals.insert("name".to_owned(), liquid::Value::scalar("stager"));
+        let engine = TemplateEngine::new(globals).unwrap();
+
+        let rendered = staging.render(&engine).unwrap();
+        assert_eq!(rendered.variables["prefix"], Template::new("/usr"));
+        let sources = &rendered.targets.0[&Template::new("/usr/bin")];
+        match sources[0] {
+            Source::Symlink(ref symlink) => {
+                assert_eq!(symlink.target, Template::new("/usr/lib/stager"))
+            }
+            ref source => panic!("unexpected source {:?}", source),
+        }
+    }
+
+    #[test]
+    fn parse_mode_accepts_octal() {
+        assert_eq!(parse_mode("644").unwrap(), 0o644);
+        assert_eq!(parse_mode("0755").unwrap(), 0o755);
+        assert_eq!(parse_mode("4755").unwrap(), 0o4755);
+    }
+
+    #[test]
+    fn parse_mode_errors_on_garbage() {
+        assert!(parse_mode("").is_err());
+        assert!(parse_mode("rwx").is_err());
+        assert!(parse_mode("789").is_err());
+        assert!(parse_mode("77777").is_err());
+    }
+
+    #[test]
+    fn parse_time_accepts_durations() {
+        let now = time::SystemTime::now();
+        let since = parse_time("7d").unwrap();
+        assert!(since < now);
+    }
+
+    #[test]
+    fn parse_time_accepts_timestamps() {
+        assert_eq!(
+            parse_time("1970-01-01T00:00:10Z").unwrap(),
+            time::UNIX_EPOCH + time::Duration::from_secs(10)
+        );
+    }
+
+    #[test]
+    fn parse_time_errors_on_garbage() {
+        assert!(parse_time("yesterday").is_err());
+    }
+
+    #[test]
+    fn expand_glob_errors_on_rel() {
+        assert!(expand_glob("src/*.rs").is_err());
+        assert!(expand_glob("*.rs").is_err());
+    }
+
+    #[test]
+    fn expand_glob_matches_files() {
+        let root = path::Path::new(env!("CARGO_MANIFEST_DIR"));
+        let pattern = format!("{}/src/*.rs", root.display());
+        let paths = expand_glob(&pattern).unwrap();
+        assert!(paths.contains(&root.join("src/lib.rs")));
+        assert!(!paths.contains(&root.join("src/bin/staging/main.rs")));
+    }
+
     #[test]
     fn abs_to_rel_errors_on_rel() {
         assert!(abs_to_rel("./hello/world").is_err());
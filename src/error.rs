@@ -2,7 +2,10 @@
 
 use std::error::Error;
 use std::fmt;
+use std::hash;
+use std::io;
 use std::iter;
+use std::slice;
 use std::vec;
 
 type ErrorCause = Error + Send + Sync + 'static;
@@ -55,30 +58,146 @@ where
     }
 }
 
+/// Like `ErrorPartition` but owns the errors, so all items can be drained before checking them.
+#[cfg(feature = "de")]
+pub(crate) struct ErrorCollector<I> {
+    iter: I,
+    errors: Errors,
+}
+
+#[cfg(feature = "de")]
+impl<I, T> ErrorCollector<I>
+where
+    I: Iterator<Item = Result<T, StagingError>>,
+{
+    pub fn new(iter: I) -> Self {
+        Self {
+            iter,
+            errors: Errors::new(),
+        }
+    }
+
+    /// Drain the remaining items, returning them along with every error encountered.
+    pub fn finish(mut self) -> (Vec<T>, Errors) {
+        let items = self.by_ref().collect();
+        (items, self.errors)
+    }
+}
+
+#[cfg(feature = "de")]
+impl<I, T> Iterator for ErrorCollector<I>
+where
+    I: Iterator<Item = Result<T, StagingError>>,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        for item in &mut self.iter {
+            match item {
+                Ok(item) => return Some(item),
+                Err(item) => self.errors.push(item),
+            }
+        }
+
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+#[cfg(feature = "de")]
+impl<I> fmt::Debug for ErrorCollector<I>
+where
+    I: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ErrorCollector")
+            .field("iter", &self.iter)
+            .field("errors", &self.errors)
+            .finish()
+    }
+}
+
 /// Aggregation of errors from a staging operation.
 #[derive(Debug)]
 pub struct Errors {
     errors: Vec<StagingError>,
+    truncated: usize,
 }
 
 impl Errors {
     pub(crate) fn new() -> Self {
-        Self { errors: Vec::new() }
+        Self {
+            errors: Vec::new(),
+            truncated: 0,
+        }
     }
 
     pub(crate) fn with_error(error: StagingError) -> Self {
         let errors = vec![error];
-        Self { errors }
+        Self {
+            errors,
+            truncated: 0,
+        }
     }
 
     pub(crate) fn push(&mut self, error: StagingError) {
         self.errors.push(error);
     }
 
-    pub(crate) fn is_empty(&self) -> bool {
+    /// Whether no errors were recorded.
+    pub fn is_empty(&self) -> bool {
         self.errors.is_empty()
     }
 
+    /// Number of errors recorded.
+    pub fn len(&self) -> usize {
+        self.errors.len()
+    }
+
+    /// View the recorded errors.
+    pub fn as_slice(&self) -> &[StagingError] {
+        &self.errors
+    }
+
+    /// Iterate over the recorded errors, without consuming them.
+    pub fn iter(&self) -> slice::Iter<'_, StagingError> {
+        self.errors.iter()
+    }
+
+    /// Keep only the first `max` errors.  `Display` notes how many were dropped.
+    pub fn truncate(&mut self, max: usize) {
+        if max < self.errors.len() {
+            self.truncated += self.errors.len() - max;
+            self.errors.truncate(max);
+        }
+    }
+
+    /// Whether `truncate` dropped any errors.
+    pub fn is_truncated(&self) -> bool {
+        self.truncated != 0
+    }
+
+    /// Remove consecutive repeated errors.
+    pub fn dedup(&mut self) {
+        self.errors.dedup();
+    }
+
+    /// Reclassify every error of kind `from` as `to`.
+    pub fn map_kind(mut self, from: ErrorKind, to: ErrorKind) -> Self {
+        for error in self.errors.iter_mut().filter(|e| e.kind == from) {
+            error.kind = to;
+        }
+        self
+    }
+
+    /// Render the errors according to `mode`.  `Display` uses `DisplayMode::List`.
+    pub fn display(&self, mode: DisplayMode) -> impl fmt::Display + '_ {
+        ErrorsDisplay { errors: self, mode }
+    }
+
     pub(crate) fn ok<T>(self, value: T) -> Result<T, Errors> {
         if self.is_empty() {
             Ok(value)
@@ -93,9 +212,11 @@ impl Error for Errors {
         "Processing failed."
     }
 
-    fn cause(&self) -> Option<&Error> {
-        // Can't handle this until we move off of `failure`.
-        None
+    fn source(&self) -> Option<&(Error + 'static)> {
+        self.errors.first().map(|e| {
+            let e: &(Error + 'static) = e;
+            e
+        })
     }
 }
 
@@ -105,22 +226,100 @@ impl From<StagingError> for Errors {
     }
 }
 
+/// Wrap a `failure::Error` as a `HarvestingFailed` error.
+#[cfg(feature = "failure")]
+impl From<failure::Error> for Errors {
+    fn from(error: failure::Error) -> Self {
+        Errors::with_error(ErrorKind::HarvestingFailed.error().set_cause(error.compat()))
+    }
+}
+
 impl fmt::Display for Errors {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         for error in &self.errors {
             writeln!(f, "{}", error)?;
         }
+        self.fmt_truncated(f)
+    }
+}
+
+impl Errors {
+    fn fmt_truncated(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_truncated() {
+            writeln!(
+                f,
+                "... and {} more errors (use --max-errors 0 to see all)",
+                self.truncated
+            )?;
+        }
         Ok(())
     }
 }
 
+/// How `Errors::display` renders the errors.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum DisplayMode {
+    /// Each error in the order it was recorded.
+    #[default]
+    List,
+    /// A count of each kind of error, then each kind's errors under a header.
+    Grouped,
+}
+
+struct ErrorsDisplay<'e> {
+    errors: &'e Errors,
+    mode: DisplayMode,
+}
+
+impl<'e> ErrorsDisplay<'e> {
+    fn groups(&self) -> Vec<(ErrorKind, Vec<&'e StagingError>)> {
+        let mut groups = vec![];
+        for &kind in &ErrorKind::ALL {
+            let errors: Vec<_> = self.errors.iter().filter(|e| e.kind == kind).collect();
+            if !errors.is_empty() {
+                groups.push((kind, errors));
+            }
+        }
+        groups
+    }
+}
+
+impl<'e> fmt::Display for ErrorsDisplay<'e> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.mode == DisplayMode::List {
+            return self.errors.fmt(f);
+        }
+
+        let groups = self.groups();
+        let summary: Vec<_> = groups
+            .iter()
+            .map(|&(kind, ref errors)| kind.count(errors.len()))
+            .collect();
+        writeln!(f, "{}", summary.join(", "))?;
+        for (kind, errors) in groups {
+            writeln!(f)?;
+            writeln!(f, "{}:", kind.count(errors.len()))?;
+            for error in errors {
+                writeln!(f, "{}", error)?;
+            }
+        }
+        if self.errors.is_truncated() {
+            writeln!(f)?;
+        }
+        self.errors.fmt_truncated(f)
+    }
+}
+
 impl iter::FromIterator<StagingError> for Errors {
     fn from_iter<I>(iter: I) -> Self
     where
         I: IntoIterator<Item = StagingError>,
     {
         let errors = iter.into_iter().collect();
-        Self { errors }
+        Self {
+            errors,
+            truncated: 0,
+        }
     }
 }
 
@@ -133,6 +332,15 @@ impl Extend<StagingError> for Errors {
     }
 }
 
+impl<'e> IntoIterator for &'e Errors {
+    type Item = &'e StagingError;
+    type IntoIter = slice::Iter<'e, StagingError>;
+
+    fn into_iter(self) -> slice::Iter<'e, StagingError> {
+        self.iter()
+    }
+}
+
 impl IntoIterator for Errors {
     type Item = StagingError;
     type IntoIter = ErrorsIter;
@@ -168,7 +376,7 @@ impl Iterator for ErrorsIter {
 }
 
 /// For programmatically processing failures.
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum ErrorKind {
     /// Error in the configuration.
     InvalidConfiguration,
@@ -179,9 +387,26 @@ pub enum ErrorKind {
 }
 
 impl ErrorKind {
+    const ALL: [ErrorKind; 3] = [
+        ErrorKind::InvalidConfiguration,
+        ErrorKind::HarvestingFailed,
+        ErrorKind::StagingFailed,
+    ];
+
     pub(crate) fn error(self) -> StagingError {
         StagingError::new(self)
     }
+
+    /// `count` errors of this kind, like `2 configuration errors`.
+    fn count(self, count: usize) -> String {
+        let noun = match self {
+            ErrorKind::InvalidConfiguration => "configuration",
+            ErrorKind::HarvestingFailed => "harvesting",
+            ErrorKind::StagingFailed => "staging",
+        };
+        let plural = if count == 1 { "" } else { "s" };
+        format!("{} {} error{}", count, noun, plural)
+    }
 }
 
 impl fmt::Display for ErrorKind {
@@ -203,7 +428,8 @@ pub struct StagingError {
 }
 
 impl StagingError {
-    pub(crate) fn new(kind: ErrorKind) -> Self {
+    /// Create a failure of type `kind`.
+    pub fn new(kind: ErrorKind) -> Self {
         Self {
             kind,
             context: None,
@@ -211,7 +437,8 @@ impl StagingError {
         }
     }
 
-    pub(crate) fn set_context<S>(mut self, context: S) -> Self
+    /// Describe what was being done when the failure occurred.
+    pub fn set_context<S>(mut self, context: S) -> Self
     where
         S: Into<String>,
     {
@@ -220,7 +447,8 @@ impl StagingError {
         self
     }
 
-    pub(crate) fn set_cause<E>(mut self, cause: E) -> Self
+    /// Record the underlying failure.
+    pub fn set_cause<E>(mut self, cause: E) -> Self
     where
         E: Error + Send + Sync + 'static,
     {
@@ -233,6 +461,48 @@ impl StagingError {
     pub fn kind(&self) -> ErrorKind {
         self.kind
     }
+
+    /// The underlying failure, if it is an `E`.
+    pub fn downcast_cause<E>(&self) -> Option<&E>
+    where
+        E: Error + 'static,
+    {
+        self.cause.as_ref().and_then(|c| c.downcast_ref::<E>())
+    }
+
+    /// Whether staging might succeed if tried again, because the underlying I/O was interrupted
+    /// or timed out.
+    pub fn is_transient(&self) -> bool {
+        if self.kind != ErrorKind::StagingFailed {
+            return false;
+        }
+        matches!(
+            self.downcast_cause::<io::Error>().map(|e| e.kind()),
+            Some(io::ErrorKind::Interrupted)
+                | Some(io::ErrorKind::WouldBlock)
+                | Some(io::ErrorKind::TimedOut)
+        )
+    }
+}
+
+// `cause` can't be compared directly, so its message is compared instead.
+impl PartialEq for StagingError {
+    fn eq(&self, other: &StagingError) -> bool {
+        self.kind == other.kind
+            && self.context == other.context
+            && self.cause.as_ref().map(|c| c.to_string())
+                == other.cause.as_ref().map(|c| c.to_string())
+    }
+}
+
+impl Eq for StagingError {}
+
+impl hash::Hash for StagingError {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        self.kind.hash(state);
+        self.context.hash(state);
+        self.cause.as_ref().map(|c| c.to_string()).hash(state);
+    }
 }
 
 impl Error for StagingError {
@@ -240,12 +510,16 @@ impl Error for StagingError {
         "Staging failed."
     }
 
-    fn cause(&self) -> Option<&Error> {
+    fn source(&self) -> Option<&(Error + 'static)> {
         self.cause.as_ref().map(|c| {
-            let c: &Error = c.as_ref();
+            let c: &(Error + 'static) = c.as_ref();
             c
         })
     }
+
+    fn cause(&self) -> Option<&Error> {
+        self.source()
+    }
 }
 
 impl fmt::Display for StagingError {
@@ -260,3 +534,174 @@ impl fmt::Display for StagingError {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io;
+
+    #[test]
+    fn staging_error_eq_compares_cause_message() {
+        let a = ErrorKind::StagingFailed.error().set_cause(io::Error::other("foo"));
+        let b = ErrorKind::StagingFailed.error().set_cause(io::Error::other("foo"));
+        let c = ErrorKind::StagingFailed.error().set_cause(io::Error::other("bar"));
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[cfg(feature = "failure")]
+    #[test]
+    fn errors_from_failure() {
+        let errors = Errors::from(failure::err_msg("foo"));
+        let error = errors.iter().next().unwrap();
+        assert_eq!(error.kind(), ErrorKind::HarvestingFailed);
+        assert!(error.to_string().contains("foo"));
+    }
+
+    #[test]
+    fn errors_source_chain() {
+        let errors = Errors::with_error(
+            ErrorKind::StagingFailed
+                .error()
+                .set_cause(io::Error::new(io::ErrorKind::NotFound, "foo")),
+        );
+        let error = errors.source().unwrap();
+        assert!(error.downcast_ref::<StagingError>().is_some());
+        let cause = error.source().unwrap();
+        assert_eq!(
+            cause.downcast_ref::<io::Error>().map(|e| e.kind()),
+            Some(io::ErrorKind::NotFound)
+        );
+        assert!(ErrorKind::StagingFailed.error().source().is_none());
+    }
+
+    #[test]
+    fn staging_error_downcast_cause() {
+        let error = ErrorKind::StagingFailed
+            .error()
+            .set_cause(io::Error::new(io::ErrorKind::NotFound, "foo"));
+        assert_eq!(
+            error.downcast_cause::<io::Error>().map(|e| e.kind()),
+            Some(io::ErrorKind::NotFound)
+        );
+        assert!(error.downcast_cause::<fmt::Error>().is_none());
+        assert!(
+            ErrorKind::StagingFailed
+                .error()
+                .downcast_cause::<io::Error>()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn staging_error_is_transient() {
+        let error = |kind: ErrorKind, io_kind| {
+            kind.error()
+                .set_cause(io::Error::new(io_kind, "foo"))
+        };
+        assert!(error(ErrorKind::StagingFailed, io::ErrorKind::TimedOut).is_transient());
+        assert!(error(ErrorKind::StagingFailed, io::ErrorKind::Interrupted).is_transient());
+        assert!(!error(ErrorKind::StagingFailed, io::ErrorKind::NotFound).is_transient());
+        assert!(!error(ErrorKind::HarvestingFailed, io::ErrorKind::TimedOut).is_transient());
+        assert!(!ErrorKind::StagingFailed.error().is_transient());
+    }
+
+    #[test]
+    fn errors_dedup_removes_consecutive_repeats() {
+        let mut errors: Errors = vec![
+            ErrorKind::HarvestingFailed.error().set_context("foo"),
+            ErrorKind::HarvestingFailed.error().set_context("foo"),
+            ErrorKind::HarvestingFailed.error().set_context("bar"),
+            ErrorKind::HarvestingFailed.error().set_context("foo"),
+        ].into_iter()
+            .collect();
+        errors.dedup();
+        assert_eq!(errors.into_iter().count(), 3);
+    }
+
+    #[cfg(feature = "de")]
+    #[test]
+    fn error_collector_finish_drains_everything() {
+        let items = vec![
+            Ok(1),
+            Err(ErrorKind::HarvestingFailed.error().set_context("foo")),
+            Ok(2),
+            Err(ErrorKind::HarvestingFailed.error().set_context("bar")),
+        ];
+        let mut collector = ErrorCollector::new(items.into_iter());
+        assert_eq!(collector.next(), Some(1));
+        let (items, errors) = collector.finish();
+        assert_eq!(items, vec![2]);
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[test]
+    fn errors_inspect_without_consuming() {
+        let errors: Errors = vec![
+            ErrorKind::HarvestingFailed.error().set_context("foo"),
+            ErrorKind::StagingFailed.error().set_context("bar"),
+        ].into_iter()
+            .collect();
+        assert_eq!(errors.len(), 2);
+        assert!(!errors.is_empty());
+        assert_eq!(errors.as_slice()[1].kind(), ErrorKind::StagingFailed);
+        let kinds: Vec<_> = errors.iter().map(|e| e.kind()).collect();
+        assert_eq!(
+            kinds,
+            vec![ErrorKind::HarvestingFailed, ErrorKind::StagingFailed]
+        );
+    }
+
+    #[test]
+    fn errors_display_grouped() {
+        let errors: Errors = vec![
+            ErrorKind::HarvestingFailed.error().set_context("foo"),
+            ErrorKind::InvalidConfiguration.error().set_context("bar"),
+            ErrorKind::HarvestingFailed.error().set_context("baz"),
+        ].into_iter()
+            .collect();
+        assert_eq!(
+            errors.display(DisplayMode::List).to_string(),
+            errors.to_string()
+        );
+
+        let grouped = errors.display(DisplayMode::Grouped).to_string();
+        assert!(grouped.starts_with("1 configuration error, 2 harvesting errors\n"));
+        let bar = grouped.find("bar").unwrap();
+        let foo = grouped.find("foo").unwrap();
+        let baz = grouped.find("baz").unwrap();
+        assert!(grouped.find("2 harvesting errors:").unwrap() > bar);
+        assert!(bar < foo && foo < baz);
+    }
+
+    #[test]
+    fn errors_truncate() {
+        let mut errors: Errors = (0..5)
+            .map(|i| ErrorKind::HarvestingFailed.error().set_context(format!("{}", i)))
+            .collect();
+        errors.truncate(10);
+        assert!(!errors.is_truncated());
+        errors.truncate(2);
+        assert!(errors.is_truncated());
+        assert_eq!(errors.len(), 2);
+        assert!(errors
+            .to_string()
+            .ends_with("... and 3 more errors (use --max-errors 0 to see all)\n"));
+    }
+
+    #[test]
+    fn errors_map_kind() {
+        let errors: Errors = vec![
+            ErrorKind::HarvestingFailed.error().set_context("foo"),
+            ErrorKind::InvalidConfiguration.error().set_context("bar"),
+        ].into_iter()
+            .collect();
+        let errors = errors.map_kind(ErrorKind::HarvestingFailed, ErrorKind::StagingFailed);
+        let kinds: Vec<_> = errors.iter().map(|e| e.kind()).collect();
+        assert_eq!(
+            kinds,
+            vec![ErrorKind::StagingFailed, ErrorKind::InvalidConfiguration]
+        );
+    }
+}
@@ -26,15 +26,62 @@
 
 #![warn(missing_docs, missing_debug_implementations)]
 
+#[cfg(feature = "tar")]
+extern crate bzip2;
+#[cfg(feature = "chrono")]
+extern crate chrono;
+#[cfg(feature = "failure")]
+extern crate failure;
+extern crate filetime;
+#[cfg(feature = "tar")]
+extern crate flate2;
+extern crate globset;
 extern crate globwalk;
 #[cfg(feature = "de")]
+extern crate humantime;
+#[cfg(feature = "gitignore")]
+extern crate ignore;
+extern crate indexmap;
+#[macro_use]
+extern crate lazy_static;
+#[cfg(all(
+    target_os = "linux",
+    any(feature = "sendfile", feature = "sparse", feature = "reflink")
+))]
+#[cfg_attr(feature = "reflink", macro_use)]
+extern crate nix;
+#[cfg(feature = "de")]
 extern crate liquid;
+#[cfg(not(feature = "tracing"))]
 #[macro_use]
 extern crate log;
 #[cfg(feature = "de")]
+extern crate parking_lot;
+extern crate pathdiff;
+extern crate regex;
+#[cfg(feature = "de")]
 #[macro_use]
 extern crate serde;
+#[cfg(feature = "cbor")]
+extern crate serde_cbor;
+#[cfg(all(feature = "de", feature = "serde_json"))]
+extern crate serde_json;
+extern crate sha2;
+#[cfg(feature = "tar")]
+extern crate tar;
+extern crate tempfile;
+#[cfg(feature = "tera")]
+extern crate tera;
+#[cfg(all(feature = "de", feature = "toml"))]
+extern crate toml;
+#[cfg(feature = "tracing")]
+#[macro_use]
+extern crate tracing;
 extern crate walkdir;
+#[cfg(feature = "xattr")]
+extern crate xattr;
+#[cfg(feature = "tar")]
+extern crate xz2;
 
 pub mod action;
 pub mod builder;
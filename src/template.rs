@@ -1,54 +1,321 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::env;
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::path;
+use std::sync;
 
+#[cfg(feature = "chrono")]
+use chrono;
 use liquid;
+use parking_lot::RwLock;
+#[cfg(feature = "tera")]
+use tera;
 
 use error;
 
-// TODO(epage): Look into making template system pluggable
-// - Leverage traits
-// - Possibly get liquid to also work with serializables like Tera(?)
-// But should we?  Would it be better to have consistency in syntax and functionality?
-// Either way, might be better to switch to another template engine if it looks like its getting
-// traction within Rust community (like whatever is used for cargo templates) and to one that will
-// be 1.0 sooner.
-/// String-templating engine for staging fields.
-pub struct TemplateEngine {
+/// String-templating implementation used by `TemplateEngine`.
+///
+/// Liquid is used by default.  Globals are always represented as a `liquid::Object` so the same
+/// data can be fed to any backend.
+pub trait TemplateBackend: fmt::Debug + Send + Sync {
+    /// Evaluate `template` with `globals`.
+    fn render(&self, template: &str, globals: &liquid::Object)
+        -> Result<String, error::StagingError>;
+}
+
+/// [Liquid][liquid] string-templating backend.
+///
+/// [liquid]: https://shopify.github.io/liquid/
+pub struct LiquidBackend {
     parser: liquid::Parser,
-    globals: liquid::Object,
+    cache: RwLock<HashMap<String, sync::Arc<liquid::Template>>>,
 }
 
-impl TemplateEngine {
-    /// Create a new string-template engine, initialized with `global` variables.
-    pub fn new(globals: liquid::Object) -> Result<Self, error::StagingError> {
+impl LiquidBackend {
+    /// Create a new liquid backend.
+    pub fn new() -> Self {
         // TODO(eage): Better customize liquid
         // - Add raw block
         // - Remove irrelevant filters (like HTML ones)
         // - Add path manipulation filters
-        let parser = liquid::ParserBuilder::new().liquid_filters().build();
-        Ok(Self { parser, globals })
+        let parser = liquid::ParserBuilder::new()
+            .liquid_filters()
+            .filter("env", env_filter as liquid::interpreter::FnFilterValue)
+            .build();
+        let cache = Default::default();
+        Self { parser, cache }
     }
 
-    /// Evaluate `template`.
-    pub fn render(&self, template: &str) -> Result<String, error::StagingError> {
-        let template = self.parser
+    fn parse(&self, template: &str) -> Result<sync::Arc<liquid::Template>, error::StagingError> {
+        if let Some(parsed) = self.cache.read().get(template) {
+            return Ok(sync::Arc::clone(parsed));
+        }
+
+        let parsed = self.parser
             .parse(template)
             .map_err(|e| error::ErrorKind::InvalidConfiguration.error().set_cause(e))?;
+        let parsed = sync::Arc::new(parsed);
+        self.cache
+            .write()
+            .insert(template.to_owned(), sync::Arc::clone(&parsed));
+        Ok(parsed)
+    }
+}
+
+/// `{{ "NAME" | env }}` reads environment variable `NAME` at render time, erroring if it isn't
+/// set.  `{{ "NAME" | env: "default" }}` falls back to `"default"` instead.
+fn env_filter(
+    input: &liquid::Value,
+    args: &[liquid::Value],
+) -> liquid::interpreter::FilterResult {
+    if 1 < args.len() {
+        return Err(liquid::interpreter::FilterError::InvalidArgumentCount(
+            format!("expected at most 1, {} given", args.len()),
+        ));
+    }
+
+    let name = input.to_str();
+    match env::var(name.as_ref()) {
+        Ok(value) => Ok(liquid::Value::scalar(value)),
+        Err(_) => args.first().cloned().ok_or_else(|| {
+            liquid::interpreter::FilterError::InvalidType(format!(
+                "environment variable {:?} is not set",
+                name
+            ))
+        }),
+    }
+}
+
+impl Default for LiquidBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TemplateBackend for LiquidBackend {
+    fn render(
+        &self,
+        template: &str,
+        globals: &liquid::Object,
+    ) -> Result<String, error::StagingError> {
+        let template = self.parse(template)?;
         let content = template
-            .render(&self.globals)
+            .render(globals)
             .map_err(|e| error::ErrorKind::InvalidConfiguration.error().set_cause(e))?;
         Ok(content)
     }
 }
 
-impl fmt::Debug for TemplateEngine {
+impl fmt::Debug for LiquidBackend {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_struct("TemplateEngine")
-            .field("parser", &"?")
-            .field("globals", &self.globals)
+        let cache = self.cache.read();
+        let mut templates: Vec<_> = cache.keys().collect();
+        templates.sort();
+        f.debug_struct("LiquidBackend")
+            .field("parser", &format_args!("liquid::Parser"))
+            .field("templates", &templates)
             .finish()
     }
 }
 
+/// [Tera][tera] string-templating backend.
+///
+/// [tera]: https://tera.netlify.com/
+#[cfg(feature = "tera")]
+#[derive(Debug, Default)]
+pub struct TeraBackend {
+    autoescape: bool,
+}
+
+#[cfg(feature = "tera")]
+impl TeraBackend {
+    /// Create a new tera backend.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Toggles HTML-escaping of rendered variables.  Default is `false`.
+    pub fn autoescape(mut self, yes: bool) -> Self {
+        self.autoescape = yes;
+        self
+    }
+}
+
+#[cfg(feature = "tera")]
+impl TemplateBackend for TeraBackend {
+    fn render(
+        &self,
+        template: &str,
+        globals: &liquid::Object,
+    ) -> Result<String, error::StagingError> {
+        let context = tera::Context::from_serialize(globals)
+            .map_err(|e| error::ErrorKind::InvalidConfiguration.error().set_cause(e))?;
+        let content = tera::Tera::one_off(template, &context, self.autoescape)
+            .map_err(|e| error::ErrorKind::InvalidConfiguration.error().set_cause(e))?;
+        Ok(content)
+    }
+}
+
+type RenderCache = RwLock<HashMap<(u64, String), String>>;
+
+/// String-templating engine for staging fields.
+#[derive(Debug)]
+pub struct TemplateEngine {
+    backend: sync::Arc<TemplateBackend>,
+    globals: liquid::Object,
+    base_path: Option<path::PathBuf>,
+    cache: Option<sync::Arc<RenderCache>>,
+    globals_hash: Option<u64>,
+}
+
+impl TemplateEngine {
+    /// Create a new string-template engine, initialized with `global` variables.
+    pub fn new(globals: liquid::Object) -> Result<Self, error::StagingError> {
+        Self::with_backend(LiquidBackend::new(), globals)
+    }
+
+    /// Create a new string-template engine using `backend`, initialized with `global` variables.
+    pub fn with_backend<B>(backend: B, globals: liquid::Object) -> Result<Self, error::StagingError>
+    where
+        B: TemplateBackend + 'static,
+    {
+        let backend = sync::Arc::new(backend);
+        Ok(Self {
+            backend,
+            globals,
+            base_path: None,
+            cache: None,
+            globals_hash: None,
+        })
+    }
+
+    /// Toggles caching of rendered templates.  Default is `false`.
+    ///
+    /// Rendering the same template with the same globals is then only done once, shared with
+    /// engines created from this one.  Changes through `globals_mut` bypass the cache until it is
+    /// enabled again.  Results of the `env` filter are cached too, so environment changes after
+    /// the first render are not seen.
+    pub fn enable_cache(mut self, yes: bool) -> Self {
+        if yes {
+            self.cache = Some(Default::default());
+            self.globals_hash = Some(hash_globals(&self.globals));
+        } else {
+            self.cache = None;
+            self.globals_hash = None;
+        }
+        self
+    }
+
+    /// Create a string-template engine sharing this backend, with `extra` layered on top of the
+    /// globals.
+    pub fn with_extra_globals(
+        &self,
+        extra: liquid::Object,
+    ) -> Result<TemplateEngine, error::StagingError> {
+        let mut globals = self.globals.clone();
+        globals.extend(extra);
+        Ok(self.derive(globals, self.base_path.clone()))
+    }
+
+    /// Create a string-template engine sharing this backend, with `defaults` layered under the
+    /// globals.
+    pub fn with_default_globals(
+        &self,
+        defaults: liquid::Object,
+    ) -> Result<TemplateEngine, error::StagingError> {
+        let mut globals = defaults;
+        globals.extend(self.globals.iter().map(|(k, v)| (k.clone(), v.clone())));
+        Ok(self.derive(globals, self.base_path.clone()))
+    }
+
+    /// Create a string-template engine sharing this backend, with the current local time layered
+    /// under the globals as `now`, e.g. for `{{ now | date: "%Y-%m-%d" }}`.
+    #[cfg(feature = "chrono")]
+    pub fn with_now(&self) -> Result<TemplateEngine, error::StagingError> {
+        let now = chrono::Local::now();
+        let now = now.with_timezone(now.offset());
+        let mut defaults = liquid::Object::new();
+        defaults.insert("now".to_owned(), liquid::Value::scalar(now));
+        self.with_default_globals(defaults)
+    }
+
+    /// Create a string-template engine sharing this backend and globals, resolving relative
+    /// source paths against `base_path`.
+    pub fn with_base_path(&self, base_path: Option<path::PathBuf>) -> TemplateEngine {
+        self.derive(self.globals.clone(), base_path)
+    }
+
+    fn derive(&self, globals: liquid::Object, base_path: Option<path::PathBuf>) -> TemplateEngine {
+        let globals_hash = self.cache.as_ref().map(|_| hash_globals(&globals));
+        Self {
+            backend: sync::Arc::clone(&self.backend),
+            globals,
+            base_path,
+            cache: self.cache.clone(),
+            globals_hash,
+        }
+    }
+
+    /// Directory relative source paths are resolved against, if any.
+    pub fn base_path(&self) -> Option<&path::Path> {
+        self.base_path.as_deref()
+    }
+
+    /// Variables available to every template.
+    pub fn globals(&self) -> &liquid::Object {
+        &self.globals
+    }
+
+    /// Variables available to every template, for adding variables after construction.
+    pub fn globals_mut(&mut self) -> &mut liquid::Object {
+        self.globals_hash = None;
+        &mut self.globals
+    }
+
+    /// Evaluate `template`.
+    pub fn render(&self, template: &str) -> Result<String, error::StagingError> {
+        let (cache, globals_hash) = match (self.cache.as_ref(), self.globals_hash) {
+            (Some(cache), Some(globals_hash)) => (cache, globals_hash),
+            _ => return self.backend.render(template, &self.globals),
+        };
+        let key = (globals_hash, template.to_owned());
+        if let Some(rendered) = cache.read().get(&key) {
+            return Ok(rendered.clone());
+        }
+
+        let rendered = self.backend.render(template, &self.globals)?;
+        cache.write().insert(key, rendered.clone());
+        Ok(rendered)
+    }
+
+    /// Evaluate `template`, with `variables` layered on top of the globals.
+    pub fn render_with(
+        &self,
+        template: &str,
+        variables: &liquid::Object,
+    ) -> Result<String, error::StagingError> {
+        let mut globals = self.globals.clone();
+        globals.extend(variables.iter().map(|(k, v)| (k.clone(), v.clone())));
+        self.backend.render(template, &globals)
+    }
+}
+
+/// `liquid::Object` isn't `Hash` and has no stable order, so hash the sorted keys with each value's
+/// debug representation.
+fn hash_globals(globals: &liquid::Object) -> u64 {
+    let mut entries: Vec<_> = globals.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    let mut hasher = DefaultHasher::new();
+    for (key, value) in entries {
+        key.hash(&mut hasher);
+        format!("{:?}", value).hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
 /// Translate user-facing value to a staging value.
 pub trait TemplateRender {
     /// Data type the template generates.
@@ -59,7 +326,11 @@ pub trait TemplateRender {
 }
 
 /// Stager field that is a single template string.
-#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+///
+/// Templates compare and sort by their raw, unrendered text, so ordering is known without an
+/// engine.  `"/{{ prefix }}/bin"` sorts before `"/{{ prefix }}/lib"` whatever `prefix` renders to,
+/// but `"/{{ a }}"` sorts before `"/{{ b }}"` even if `b` renders to the smaller string.
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
 pub struct Template(String);
 
 impl Template {
@@ -70,6 +341,15 @@ impl Template {
     {
         Self { 0: s.into() }
     }
+
+    /// Evaluate using `engine`, with `variables` layered on top of the globals.
+    pub fn format_with(
+        &self,
+        engine: &TemplateEngine,
+        variables: &liquid::Object,
+    ) -> Result<String, error::StagingError> {
+        engine.render_with(&self.0, variables)
+    }
 }
 
 impl TemplateRender for Template {
@@ -90,6 +370,30 @@ pub enum OneOrMany<T> {
     Many(Vec<T>),
 }
 
+impl<T> Default for OneOrMany<T> {
+    fn default() -> Self {
+        OneOrMany::Many(vec![])
+    }
+}
+
+impl<T> From<T> for OneOrMany<T> {
+    fn from(value: T) -> Self {
+        OneOrMany::One(value)
+    }
+}
+
+impl<T> From<Vec<T>> for OneOrMany<T> {
+    fn from(values: Vec<T>) -> Self {
+        OneOrMany::Many(values)
+    }
+}
+
+impl<'a, T: Clone> From<&'a [T]> for OneOrMany<T> {
+    fn from(values: &'a [T]) -> Self {
+        OneOrMany::Many(values.to_vec())
+    }
+}
+
 impl<T> TemplateRender for OneOrMany<T>
 where
     T: TemplateRender,
@@ -109,3 +413,243 @@ where
         }
     }
 }
+
+impl<T> TemplateRender for Option<T>
+where
+    T: TemplateRender,
+{
+    type Rendered = Option<T::Rendered>;
+
+    fn format(&self, engine: &TemplateEngine) -> Result<Self::Rendered, error::StagingError> {
+        match *self {
+            Some(ref v) => v.format(engine).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Variables, rendered into an object for use as template globals.
+impl TemplateRender for BTreeMap<String, Template> {
+    type Rendered = liquid::Object;
+
+    fn format(&self, engine: &TemplateEngine) -> Result<Self::Rendered, error::StagingError> {
+        self.iter()
+            .map(|(k, v)| Ok((k.clone(), liquid::Value::scalar(v.format(engine)?))))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn engine_debug() {
+        let mut globals = liquid::Object::new();
+        globals.insert("name".to_owned(), liquid::Value::scalar("stager"));
+        let engine = TemplateEngine::new(globals).unwrap();
+        engine.render("{{ name }}").unwrap();
+        let debug = format!("{:?}", engine);
+        assert!(debug.contains("liquid::Parser"), "{}", debug);
+        assert!(debug.contains("{{ name }}"), "{}", debug);
+        assert!(debug.contains("stager"), "{}", debug);
+    }
+
+    #[test]
+    fn render_with_layers_variables() {
+        let mut globals = liquid::Object::new();
+        globals.insert("name".to_owned(), liquid::Value::scalar("global"));
+        globals.insert("version".to_owned(), liquid::Value::scalar("1.0"));
+        let engine = TemplateEngine::new(globals).unwrap();
+
+        let mut variables = liquid::Object::new();
+        variables.insert("name".to_owned(), liquid::Value::scalar("local"));
+        assert_eq!(
+            engine
+                .render_with("{{ name }}-{{ version }}", &variables)
+                .unwrap(),
+            "local-1.0"
+        );
+        assert_eq!(
+            engine.render("{{ name }}-{{ version }}").unwrap(),
+            "global-1.0"
+        );
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn with_now_formats_dates() {
+        let engine = TemplateEngine::new(liquid::Object::new())
+            .unwrap()
+            .with_now()
+            .unwrap();
+        let year = engine.render(r#"{{ now | date: "%Y" }}"#).unwrap();
+        assert_eq!(year, chrono::Local::now().format("%Y").to_string());
+        assert_eq!(year.len(), 4);
+    }
+
+    #[test]
+    fn globals_mut_adds_variables() {
+        let mut engine = TemplateEngine::new(liquid::Object::new()).unwrap();
+        assert!(engine.globals().is_empty());
+        engine
+            .globals_mut()
+            .insert("name".to_owned(), liquid::Value::scalar("late"));
+        assert_eq!(engine.render("{{ name }}").unwrap(), "late");
+    }
+
+    #[derive(Debug, Default)]
+    struct CountingBackend {
+        renders: sync::atomic::AtomicUsize,
+        inner: LiquidBackend,
+    }
+
+    impl TemplateBackend for CountingBackend {
+        fn render(
+            &self,
+            template: &str,
+            globals: &liquid::Object,
+        ) -> Result<String, error::StagingError> {
+            self.renders.fetch_add(1, sync::atomic::Ordering::SeqCst);
+            self.inner.render(template, globals)
+        }
+    }
+
+    #[test]
+    fn enable_cache_reuses_renders() {
+        let backend = sync::Arc::new(CountingBackend::default());
+        let mut globals = liquid::Object::new();
+        globals.insert("name".to_owned(), liquid::Value::scalar("a"));
+        let engine = TemplateEngine {
+            backend: backend.clone(),
+            globals,
+            base_path: None,
+            cache: None,
+            globals_hash: None,
+        }.enable_cache(true);
+        assert_eq!(engine.render("{{ name }}").unwrap(), "a");
+        assert_eq!(engine.render("{{ name }}").unwrap(), "a");
+        assert_eq!(backend.renders.load(sync::atomic::Ordering::SeqCst), 1);
+
+        let mut extra = liquid::Object::new();
+        extra.insert("name".to_owned(), liquid::Value::scalar("b"));
+        let engine = engine.with_extra_globals(extra).unwrap();
+        assert_eq!(engine.render("{{ name }}").unwrap(), "b");
+        assert_eq!(backend.renders.load(sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn liquid_backend_caches_templates() {
+        let backend = LiquidBackend::new();
+        let mut globals = liquid::Object::new();
+        globals.insert("name".to_owned(), liquid::Value::scalar("a"));
+        assert_eq!(backend.render("{{ name }}", &globals).unwrap(), "a");
+        globals.insert("name".to_owned(), liquid::Value::scalar("b"));
+        assert_eq!(backend.render("{{ name }}", &globals).unwrap(), "b");
+        assert_eq!(backend.cache.read().len(), 1);
+    }
+
+    #[test]
+    fn env_filter_reads_variables() {
+        env::set_var("STAGER_TEST_ENV_FILTER", "value");
+        let engine = TemplateEngine::new(liquid::Object::new()).unwrap();
+        assert_eq!(
+            engine
+                .render(r#"{{ "STAGER_TEST_ENV_FILTER" | env }}"#)
+                .unwrap(),
+            "value"
+        );
+        assert_eq!(
+            engine
+                .render(r#"{{ "STAGER_TEST_ENV_FILTER_UNSET" | env: "default" }}"#)
+                .unwrap(),
+            "default"
+        );
+        assert!(
+            engine
+                .render(r#"{{ "STAGER_TEST_ENV_FILTER_UNSET" | env }}"#)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn template_sorts_by_raw_text() {
+        let mut globals = liquid::Object::new();
+        globals.insert("a".to_owned(), liquid::Value::scalar("z"));
+        globals.insert("b".to_owned(), liquid::Value::scalar("a"));
+        let engine = TemplateEngine::new(globals).unwrap();
+
+        let mut templates = vec![
+            Template::new("/{{ b }}"),
+            Template::new("/{{ prefix }}/lib"),
+            Template::new("/{{ a }}"),
+            Template::new("/{{ prefix }}/bin"),
+        ];
+        templates.sort();
+        assert_eq!(
+            templates,
+            vec![
+                Template::new("/{{ a }}"),
+                Template::new("/{{ b }}"),
+                Template::new("/{{ prefix }}/bin"),
+                Template::new("/{{ prefix }}/lib"),
+            ]
+        );
+        assert!(templates[0].format(&engine).unwrap() > templates[1].format(&engine).unwrap());
+    }
+
+    #[test]
+    fn one_or_many_default_formats_empty() {
+        let engine = TemplateEngine::new(liquid::Object::new()).unwrap();
+        let value: OneOrMany<Template> = Default::default();
+        assert_eq!(value.format(&engine).unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn option_format() {
+        let engine = TemplateEngine::new(liquid::Object::new()).unwrap();
+        let value: Option<Template> = None;
+        assert_eq!(value.format(&engine).unwrap(), None);
+        let value = Some(Template::new("{{ 1 | plus: 1 }}"));
+        assert_eq!(value.format(&engine).unwrap(), Some("2".to_owned()));
+    }
+
+    #[test]
+    fn variables_format() {
+        let engine = TemplateEngine::new(liquid::Object::new()).unwrap();
+        let mut variables = BTreeMap::new();
+        variables.insert("sum".to_owned(), Template::new("{{ 1 | plus: 1 }}"));
+        let rendered = variables.format(&engine).unwrap();
+        assert_eq!(rendered["sum"], liquid::Value::scalar("2"));
+
+        variables.insert("bad".to_owned(), Template::new("{{ 1 | nope }}"));
+        assert!(variables.format(&engine).is_err());
+    }
+
+    #[test]
+    fn one_or_many_from() {
+        let engine = TemplateEngine::new(liquid::Object::new()).unwrap();
+
+        let value = OneOrMany::from(Template::new("a"));
+        assert_eq!(value, OneOrMany::One(Template::new("a")));
+        assert_eq!(value.format(&engine).unwrap(), vec!["a".to_owned()]);
+
+        let values = vec![Template::new("a"), Template::new("b")];
+        let value = OneOrMany::from(values.clone());
+        assert_eq!(value, OneOrMany::Many(values.clone()));
+        assert_eq!(OneOrMany::from(values.as_slice()), value);
+        assert_eq!(
+            value.format(&engine).unwrap(),
+            vec!["a".to_owned(), "b".to_owned()]
+        );
+    }
+
+    #[cfg(feature = "tera")]
+    #[test]
+    fn tera_backend_renders() {
+        let mut globals = liquid::Object::new();
+        globals.insert("name".to_owned(), liquid::Value::scalar("stager"));
+        let engine = TemplateEngine::with_backend(TeraBackend::new(), globals).unwrap();
+        assert_eq!(engine.render("{{ name | upper }}").unwrap(), "STAGER");
+    }
+}
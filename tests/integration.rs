@@ -0,0 +1,618 @@
+extern crate assert_cmd;
+extern crate assert_fs;
+#[cfg(feature = "tar")]
+extern crate flate2;
+extern crate predicates;
+#[cfg(feature = "tar")]
+extern crate tar;
+
+use std::path;
+
+use assert_cmd::Command;
+use assert_fs::prelude::*;
+use predicates::prelude::*;
+
+fn fixtures() -> path::PathBuf {
+    path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+}
+
+fn staging(stage: &str) -> Command {
+    let mut cmd = Command::cargo_bin("staging").unwrap();
+    cmd.env("STAGER_BASE_PATH", fixtures())
+        .arg("--input")
+        .arg(fixtures().join("staging").join(stage));
+    cmd
+}
+
+#[test]
+fn basic_copy() {
+    let out = assert_fs::TempDir::new().unwrap();
+    staging("copy.yaml").arg("-o").arg(out.path()).assert().success();
+    out.child("bin/main.rs")
+        .assert(predicate::path::eq_file(fixtures().join("src/main.rs")));
+}
+
+#[cfg(feature = "tar")]
+#[test]
+fn output_tar_archive() {
+    let out = assert_fs::TempDir::new().unwrap();
+    let archive = out.child("stage.tar.gz");
+    staging("copy.yaml")
+        .arg("-o")
+        .arg(archive.path())
+        .assert()
+        .success();
+    out.child("stage.tar.gz/bin").assert(predicate::path::missing());
+
+    let file = std::fs::File::open(archive.path()).unwrap();
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(file));
+    let paths: Vec<_> = archive
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap().path().unwrap().into_owned())
+        .collect();
+    assert!(paths.contains(&path::PathBuf::from("bin/main.rs")));
+}
+
+#[cfg(feature = "toml")]
+#[test]
+fn glob_patterns() {
+    let out = assert_fs::TempDir::new().unwrap();
+    staging("glob.toml").arg("-o").arg(out.path()).assert().success();
+    out.child("src/main.rs").assert(predicate::path::is_file());
+    out.child("Cargo.toml").assert(predicate::path::missing());
+}
+
+#[cfg(feature = "toml")]
+#[test]
+fn toml_inline_arrays_and_tables() {
+    let out = assert_fs::TempDir::new().unwrap();
+    staging("sources.toml")
+        .arg("-o")
+        .arg(out.path())
+        .assert()
+        .success();
+    out.child("bin/main.rs").assert(predicate::path::is_file());
+    out.child("bin/Cargo.toml.orig").assert(predicate::path::is_file());
+    out.child("etc/Cargo.toml").assert(predicate::path::is_file());
+    assert_eq!(
+        std::fs::read_link(out.child("etc/doc").path()).unwrap(),
+        path::Path::new("/usr/share/doc")
+    );
+}
+
+#[cfg(feature = "serde_json")]
+#[test]
+fn output_json_reports_actions() {
+    let out = assert_fs::TempDir::new().unwrap();
+    let report = out.child("report.json");
+    staging("copy.yaml")
+        .arg("-o")
+        .arg(out.child("stage").path())
+        .arg("--output-json")
+        .arg(report.path())
+        .assert()
+        .success();
+    report.assert(predicate::str::contains(r#""success": true"#));
+    report.assert(predicate::str::contains(r#""type": "CopyFile""#));
+    report.assert(predicate::str::contains("duration_ms"));
+}
+
+#[test]
+fn stats_reports_throughput() {
+    let out = assert_fs::TempDir::new().unwrap();
+    staging("copy.yaml")
+        .arg("-o")
+        .arg(out.path())
+        .arg("--stats")
+        .arg("--stats-output")
+        .arg("stdout")
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::contains("Copied an estimated").and(predicate::str::contains("MB/s")),
+        );
+}
+
+#[cfg(feature = "serde_json")]
+#[test]
+fn stats_in_output_json() {
+    let out = assert_fs::TempDir::new().unwrap();
+    let report = out.child("report.json");
+    staging("copy.yaml")
+        .arg("-o")
+        .arg(out.child("stage").path())
+        .arg("--output-json")
+        .arg(report.path())
+        .arg("--stats")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("MB/s"));
+    report.assert(predicate::str::contains(r#""stats""#));
+    report.assert(predicate::str::contains("estimated_bytes"));
+    report.assert(predicate::str::contains("megabytes_per_second"));
+}
+
+#[test]
+fn dry_run_changes_nothing() {
+    let out = assert_fs::TempDir::new().unwrap();
+    staging("copy.yaml")
+        .arg("-o")
+        .arg(out.path())
+        .arg("--dry-run")
+        .arg("-vvv")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("main.rs"));
+    out.child("bin").assert(predicate::path::missing());
+}
+
+#[test]
+fn output_tree_prints_staged_paths() {
+    let out = assert_fs::TempDir::new().unwrap();
+    staging("copy.yaml")
+        .arg("-o")
+        .arg(out.path())
+        .arg("--dry-run")
+        .arg("--output-tree")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "\u{2514}\u{2500}\u{2500} bin\n    \u{2514}\u{2500}\u{2500} main.rs\n",
+        ));
+}
+
+#[test]
+fn retry_skips_permanent_failures() {
+    let out = assert_fs::TempDir::new().unwrap();
+    let file = out.child("file");
+    file.touch().unwrap();
+    staging("copy.yaml")
+        .arg("-o")
+        .arg(file.path())
+        .arg("--retry")
+        .arg("2")
+        .arg("-vvv")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Retrying").not());
+}
+
+#[test]
+fn bash_completions() {
+    Command::cargo_bin("staging")
+        .unwrap()
+        .args(["completions", "bash"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("-X '!*.@(yaml|toml|json)'"))
+        .stdout(predicate::str::contains(r#"compgen -W "text json yaml""#));
+}
+
+#[test]
+fn invalid_config() {
+    let out = assert_fs::TempDir::new().unwrap();
+    staging("invalid.yaml")
+        .arg("-o")
+        .arg(out.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("invalid.yaml"));
+}
+
+#[test]
+fn missing_source() {
+    let out = assert_fs::TempDir::new().unwrap();
+    staging("missing.yaml")
+        .arg("-o")
+        .arg(out.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("missing.rs"));
+    out.child("bin/missing.rs").assert(predicate::path::missing());
+}
+
+#[test]
+fn group_errors() {
+    let out = assert_fs::TempDir::new().unwrap();
+    staging("bad_template.yaml")
+        .arg("-o")
+        .arg(out.path())
+        .arg("--group-errors")
+        .assert()
+        .code(65)
+        .stderr(predicate::str::contains("1 configuration error\n"))
+        .stderr(predicate::str::contains("undefined_variable"));
+}
+
+#[test]
+fn max_errors() {
+    let out = assert_fs::TempDir::new().unwrap();
+    staging("many_missing.yaml")
+        .arg("-o")
+        .arg(out.path())
+        .arg("--ignore-errors")
+        .arg("--max-errors")
+        .arg("1")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("missing.rs"))
+        .stderr(predicate::str::contains("gone.rs").not())
+        .stderr(predicate::str::contains("... and 2 more errors"));
+    staging("many_missing.yaml")
+        .arg("-o")
+        .arg(out.path())
+        .arg("--ignore-errors")
+        .arg("--max-errors")
+        .arg("0")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("gone.rs"))
+        .stderr(predicate::str::contains("more errors").not());
+}
+
+#[test]
+fn post_staging_checks() {
+    let out = assert_fs::TempDir::new().unwrap();
+    staging("post_staging_checks.yaml")
+        .arg("-o")
+        .arg(out.path())
+        .arg("--validate")
+        .assert()
+        .code(65)
+        .stderr(predicate::str::contains("1 files staged is outside of 2..=10"));
+    out.child("bin").assert(predicate::path::missing());
+    staging("post_staging_checks.yaml")
+        .arg("-o")
+        .arg(out.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("verify 2 checks"));
+    out.child("bin/main.rs").assert(predicate::path::exists());
+}
+
+#[test]
+fn config_check_ignores_missing_files() {
+    let out = assert_fs::TempDir::new().unwrap();
+    staging("unmatched.yaml")
+        .arg("-o")
+        .arg(out.path())
+        .arg("--config-check")
+        .assert()
+        .success();
+    staging("unmatched.yaml")
+        .arg("-o")
+        .arg(out.path())
+        .assert()
+        .failure();
+    out.child("bin").assert(predicate::path::missing());
+}
+
+#[test]
+fn config_check_reports_invalid_templates() {
+    let out = assert_fs::TempDir::new().unwrap();
+    staging("bad_template.yaml")
+        .arg("-o")
+        .arg(out.path())
+        .arg("--config-check")
+        .assert()
+        .code(65);
+}
+
+#[cfg(feature = "serde_json")]
+#[test]
+fn template_substitution() {
+    let out = assert_fs::TempDir::new().unwrap();
+    staging("template.json")
+        .env("STAGER_TEST_DIR", "etc")
+        .env("STAGER_TEST_NAME", "fixture")
+        .arg("--vars-from-env-prefix")
+        .arg("STAGER_TEST_")
+        .arg("-o")
+        .arg(out.path())
+        .assert()
+        .success();
+    out.child("etc/fixture.toml").assert(predicate::path::is_file());
+}
+
+#[cfg(all(unix, feature = "serde_json"))]
+#[test]
+fn env_prefix_skips_non_utf8() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let out = assert_fs::TempDir::new().unwrap();
+    staging("template.json")
+        .env("STAGER_TEST_DIR", "etc")
+        .env("STAGER_TEST_NAME", "fixture")
+        .env("STAGER_TEST_BINARY", OsStr::from_bytes(b"\xff"))
+        .arg("--vars-from-env-prefix")
+        .arg("STAGER_TEST_")
+        .arg("-o")
+        .arg(out.path())
+        .assert()
+        .success();
+    out.child("etc/fixture.toml").assert(predicate::path::is_file());
+}
+
+#[cfg(feature = "serde_json")]
+#[test]
+fn config_renders_templates() {
+    staging("template.json")
+        .arg("--vars-override")
+        .arg("dir=etc")
+        .arg("--vars-override")
+        .arg("name=fixture")
+        .arg("config")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("/etc:"))
+        .stdout(predicate::str::contains("rename: fixture.toml"));
+}
+
+#[test]
+fn include_merges_variables() {
+    let out = assert_fs::TempDir::new().unwrap();
+    staging("include_project.yaml")
+        .arg("--include")
+        .arg(fixtures().join("staging/include_base.yaml"))
+        .arg("-o")
+        .arg(out.path())
+        .assert()
+        .success();
+    out.child("etc/project.toml").assert(predicate::path::exists());
+    out.child("bin/main.rs").assert(predicate::path::exists());
+}
+
+#[cfg(feature = "serde_json")]
+#[test]
+fn vars_override_is_not_rendered() {
+    let out = assert_fs::TempDir::new().unwrap();
+    staging("template.json")
+        .arg("--vars-override")
+        .arg("dir=etc")
+        .arg("--vars-override")
+        .arg("name={{ secret }}")
+        .arg("-o")
+        .arg(out.path())
+        .assert()
+        .success();
+    out.child("etc/{{ secret }}.toml")
+        .assert(predicate::path::is_file());
+
+    staging("template.json")
+        .arg("--include")
+        .arg(fixtures().join("staging/include_base.yaml"))
+        .arg("--vars-override")
+        .arg("dir=bin")
+        .arg("--vars-override")
+        .arg("name={{ secret }}")
+        .arg("config")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("{{ secret }}.toml"));
+}
+
+#[test]
+fn list_uses_top_level_input() {
+    staging("copy.yaml")
+        .arg("list")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("main.rs"));
+}
+
+#[test]
+fn clean_removes_stale_files() {
+    let out = assert_fs::TempDir::new().unwrap();
+    out.child("stale.txt").write_str("stale").unwrap();
+    staging("copy.yaml")
+        .arg("-o")
+        .arg(out.path())
+        .arg("--clean")
+        .assert()
+        .success();
+    out.child("stale.txt").assert(predicate::path::missing());
+    out.child("bin/main.rs").assert(predicate::path::is_file());
+}
+
+#[test]
+fn abort_on_overwrite() {
+    let out = assert_fs::TempDir::new().unwrap();
+    staging("copy.yaml")
+        .arg("-o")
+        .arg(out.path())
+        .arg("--abort-on-overwrite")
+        .assert()
+        .success();
+    out.child("bin/main.rs").write_str("previous release").unwrap();
+    staging("copy.yaml")
+        .arg("-o")
+        .arg(out.path())
+        .arg("--abort-on-overwrite")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Failed staging files"));
+    out.child("bin/main.rs")
+        .assert(predicate::str::contains("previous release"));
+}
+
+#[test]
+fn clean_abort_on_overwrite_reruns() {
+    let out = assert_fs::TempDir::new().unwrap();
+    out.child("stale.txt").write_str("stale").unwrap();
+    for _ in 0..2 {
+        staging("copy.yaml")
+            .arg("-o")
+            .arg(out.path())
+            .arg("--clean")
+            .arg("--abort-on-overwrite")
+            .assert()
+            .success();
+    }
+    out.child("stale.txt").assert(predicate::path::missing());
+    out.child("bin/main.rs").assert(predicate::path::is_file());
+}
+
+#[test]
+fn ignore_errors() {
+    let out = assert_fs::TempDir::new().unwrap();
+    out.child("bin/main.rs").write_str("previous release").unwrap();
+    staging("ignore_errors.yaml")
+        .arg("-o")
+        .arg(out.path())
+        .arg("--abort-on-overwrite")
+        .arg("--ignore-errors")
+        .assert()
+        .code(70)
+        .stderr(predicate::str::contains("Failed staging files"));
+    out.child("bin/main.rs")
+        .assert(predicate::str::contains("previous release"));
+    out.child("etc/Cargo.toml").assert(predicate::path::is_file());
+}
+
+#[test]
+fn fail_fast_stops_at_first_failure() {
+    let out = assert_fs::TempDir::new().unwrap();
+    out.child("bin/main.rs").write_str("previous release").unwrap();
+    staging("ignore_errors.yaml")
+        .arg("-o")
+        .arg(out.path())
+        .arg("--abort-on-overwrite")
+        .arg("--ignore-errors")
+        .arg("--fail-fast")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Failed staging files"));
+    out.child("etc/Cargo.toml").assert(predicate::path::missing());
+}
+
+#[test]
+fn conflicting_sources() {
+    let out = assert_fs::TempDir::new().unwrap();
+    staging("conflict.yaml")
+        .arg("-o")
+        .arg(out.path())
+        .arg("-v")
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("overwrites"));
+    staging("conflict.yaml")
+        .arg("-o")
+        .arg(out.path())
+        .arg("--abort-on-overwrite")
+        .assert()
+        .code(65)
+        .stderr(predicate::str::contains("overwrites"));
+}
+
+#[test]
+fn max_targets_env() {
+    let out = assert_fs::TempDir::new().unwrap();
+    staging("conflict.yaml")
+        .env("STAGER_MAX_TARGETS", "0")
+        .arg("-o")
+        .arg(out.path())
+        .assert()
+        .code(65)
+        .stderr(predicate::str::contains("max_target_count"));
+    out.child("bin").assert(predicate::path::missing());
+}
+
+#[test]
+fn log_file() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let log = temp.child("staging.log");
+    staging("conflict.yaml")
+        .arg("-o")
+        .arg(temp.child("out").path())
+        .arg("-v")
+        .arg("--log-file")
+        .arg(log.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("overwrites").not());
+    log.assert(predicate::str::contains("overwrites"));
+}
+
+#[cfg(feature = "serde_json")]
+#[test]
+fn vars_override() {
+    let out = assert_fs::TempDir::new().unwrap();
+    staging("template.json")
+        .env("STAGER_TEST_DIR", "etc")
+        .env("STAGER_TEST_NAME", "fixture")
+        .arg("--vars-from-env-prefix")
+        .arg("STAGER_TEST_")
+        .arg("--vars-override")
+        .arg("name=first")
+        .arg("--vars-override")
+        .arg("name=a=b")
+        .arg("-o")
+        .arg(out.path())
+        .assert()
+        .success();
+    out.child("etc/a=b.toml").assert(predicate::path::is_file());
+
+    staging("template.json")
+        .arg("--vars-override")
+        .arg("name")
+        .arg("-o")
+        .arg(out.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Expected KEY=VALUE"));
+}
+
+#[cfg(feature = "serde_json")]
+#[test]
+fn env_file() {
+    let temp = assert_fs::TempDir::new().unwrap();
+    let base = temp.child("base.env");
+    base.write_str("# Defaults\nDIR=etc # Unix-like\nNAME=base\n").unwrap();
+    let local = temp.child("local.env");
+    local.write_str("export NAME=\"local copy\"\n").unwrap();
+    let out = temp.child("out");
+    staging("env_file.json")
+        .arg("--env-file")
+        .arg(base.path())
+        .arg("--env-file")
+        .arg(local.path())
+        .arg("-o")
+        .arg(out.path())
+        .assert()
+        .success();
+    out.child("etc/local copy.toml").assert(predicate::path::is_file());
+
+    staging("env_file.json")
+        .arg("--env-file")
+        .arg(temp.child("missing.env").path())
+        .arg("-o")
+        .arg(out.path())
+        .assert()
+        .failure();
+}
+
+#[test]
+fn stage_file_dry_run() {
+    let out = assert_fs::TempDir::new().unwrap();
+    staging("dry_run.yaml")
+        .arg("-o")
+        .arg(out.path())
+        .assert()
+        .success();
+    out.child("bin").assert(predicate::path::missing());
+}
+
+#[test]
+fn man_page() {
+    Command::cargo_bin("staging")
+        .unwrap()
+        .arg("man")
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with(".TH STAGING 1"))
+        .stdout(predicate::str::contains(".SS list\n"))
+        .stdout(predicate::str::contains("\\-\\-output"))
+        .stdout(predicate::str::contains(".SH EXAMPLES"));
+}
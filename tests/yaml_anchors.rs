@@ -0,0 +1,113 @@
+#![cfg(all(feature = "de", feature = "serde_yaml"))]
+
+extern crate serde_yaml;
+extern crate stager;
+
+use stager::de;
+
+#[test]
+fn aliased_sources() {
+    let config = r#"
+bin:
+  - &cli
+    type: SourceFile
+    path: /usr/bin/cli
+    executable: true
+"usr/bin":
+  - *cli
+"#;
+    let stage: de::Staging = serde_yaml::from_str(config).unwrap();
+    let expected: de::Staging = serde_yaml::from_str(
+        r#"
+bin:
+  - type: SourceFile
+    path: /usr/bin/cli
+    executable: true
+"usr/bin":
+  - type: SourceFile
+    path: /usr/bin/cli
+    executable: true
+"#,
+    ).unwrap();
+    assert_eq!(stage, expected);
+}
+
+#[test]
+fn aliased_source_lists() {
+    let config = r#"
+docs: &docs
+  - type: SourceFiles
+    path: /src/docs
+    pattern: ["*.md", "*.txt"]
+  - type: Symlink
+    target: README.md
+    rename: README
+"share/doc": *docs
+"#;
+    let stage: de::Staging = serde_yaml::from_str(config).unwrap();
+    let expected: de::Staging = serde_yaml::from_str(
+        r#"
+docs:
+  - type: SourceFiles
+    path: /src/docs
+    pattern: ["*.md", "*.txt"]
+  - type: Symlink
+    target: README.md
+    rename: README
+"share/doc":
+  - type: SourceFiles
+    path: /src/docs
+    pattern: ["*.md", "*.txt"]
+  - type: Symlink
+    target: README.md
+    rename: README
+"#,
+    ).unwrap();
+    assert_eq!(stage, expected);
+}
+
+#[test]
+fn aliased_fields() {
+    let config = r#"
+bin:
+  - type: SourceFiles
+    path: &root /src
+    pattern: &patterns ["*.rs"]
+lib:
+  - type: SourceFiles
+    path: *root
+    pattern: *patterns
+    allow_empty: true
+"#;
+    let stage: de::Staging = serde_yaml::from_str(config).unwrap();
+    let expected: de::Staging = serde_yaml::from_str(
+        r#"
+bin:
+  - type: SourceFiles
+    path: /src
+    pattern: ["*.rs"]
+lib:
+  - type: SourceFiles
+    path: /src
+    pattern: ["*.rs"]
+    allow_empty: true
+"#,
+    ).unwrap();
+    assert_eq!(stage, expected);
+}
+
+#[test]
+fn merge_keys_are_rejected() {
+    let config = r#"
+bin:
+  - &cli
+    type: SourceFile
+    path: /usr/bin/cli
+    executable: true
+  - type: SourceFile
+    <<: *cli
+    path: /usr/bin/cli-helper
+"#;
+    let err = serde_yaml::from_str::<de::Staging>(config).unwrap_err();
+    assert!(err.to_string().contains("<<"), "{}", err);
+}